@@ -0,0 +1,182 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use toytcp::device::{MemoryDevice, NetworkDevice};
+use toytcp::fault::{Faults, FaultyDevice};
+use toytcp::route::RoutingTable;
+use toytcp::sockopt::SocketOption;
+use toytcp::tcp::{DEFAULT_BACKLOG, TCP};
+use toytcp::SockID;
+
+const CLIENT_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PORT: u16 = 8080;
+const BULK_LEN: usize = 1 << 20;
+const MESSAGE_LEN: usize = 64;
+// of the segments in either direction
+const LOSS: f64 = 0.01;
+
+/// a client and a server stack at the two ends of a `MemoryDevice` pair, the server
+/// listening
+struct Link {
+    client: Arc<TCP>,
+    server: Arc<TCP>,
+    listener: SockID,
+}
+
+impl Link {
+    fn new() -> Self {
+        let (client_device, server_device) = MemoryDevice::pair();
+        Self::with_devices(client_device, server_device)
+    }
+
+    // losing `LOSS` of the segments, the same ones every run
+    fn lossy() -> Self {
+        let faults = Faults {
+            drop: LOSS,
+            ..Faults::default()
+        };
+        let (client_device, server_device) = MemoryDevice::pair();
+        Self::with_devices(
+            FaultyDevice::new(client_device, faults, 1),
+            FaultyDevice::new(server_device, faults, 2),
+        )
+    }
+
+    fn with_devices(
+        client_device: impl NetworkDevice + 'static,
+        server_device: impl NetworkDevice + 'static,
+    ) -> Self {
+        let client = TCP::with_device(
+            client_device,
+            RoutingTable::on_link("bench0", CLIENT_ADDR, 24, None),
+        );
+        let server = TCP::with_device(
+            server_device,
+            RoutingTable::on_link("bench1", SERVER_ADDR, 24, None),
+        );
+        let listener = server
+            .listen(SocketAddr::from((SERVER_ADDR, PORT)), DEFAULT_BACKLOG)
+            .unwrap();
+        Self {
+            client,
+            server,
+            listener,
+        }
+    }
+
+    // the client and the server end of a new connection
+    fn connect(&self) -> (SockID, SockID) {
+        let client = self.client.connect((SERVER_ADDR, PORT)).unwrap();
+        let server = self.server.accept(self.listener).unwrap();
+        (client, server)
+    }
+}
+
+// reads the server end of a connection until it closes, telling every `len` bytes
+fn sink(tcp: Arc<TCP>, sock_id: SockID, len: usize) -> mpsc::Receiver<()> {
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = vec![0; 65536];
+        let mut received = 0;
+        loop {
+            match tcp.recv(sock_id, &mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => received += n,
+            }
+            while received >= len {
+                received -= len;
+                if done_tx.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    done_rx
+}
+
+// sends back what the server end of a connection receives until it closes
+fn echo(tcp: Arc<TCP>, sock_id: SockID) {
+    thread::spawn(move || {
+        let mut buffer = vec![0; 65536];
+        loop {
+            match tcp.recv(sock_id, &mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    if tcp.send(sock_id, &buffer[..n]).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn bulk(c: &mut Criterion, name: &str, link: Link) {
+    let (client, server) = link.connect();
+    let done = sink(link.server.clone(), server, BULK_LEN);
+    let data = vec![0xab; BULK_LEN];
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(BULK_LEN as u64));
+    group.sample_size(20);
+    group.bench_function("1MiB", |b| {
+        b.iter(|| {
+            link.client.send(client, &data).unwrap();
+            done.recv().unwrap();
+        })
+    });
+    group.finish();
+    link.client.abort(client).unwrap();
+}
+
+// connect, accept and tear down again by RST, which leaves nothing in TIME_WAIT
+fn handshake(c: &mut Criterion) {
+    let link = Link::new();
+    c.bench_function("handshake", |b| {
+        b.iter(|| {
+            let (client, server) = link.connect();
+            link.server.abort(server).unwrap();
+            // unless the RST has taken it away already
+            let _ = link.client.abort(client);
+        })
+    });
+}
+
+fn throughput(c: &mut Criterion) {
+    bulk(c, "throughput", Link::new());
+}
+
+// request and response of MESSAGE_LEN bytes each, one at a time
+fn requests(c: &mut Criterion) {
+    let link = Link::new();
+    let (client, server) = link.connect();
+    for (tcp, sock_id) in [(&link.client, client), (&link.server, server)] {
+        tcp.set_option(sock_id, SocketOption::NoDelay(true)).unwrap();
+    }
+    echo(link.server.clone(), server);
+    let request = [0x5a; MESSAGE_LEN];
+    let mut response = [0; MESSAGE_LEN];
+    let mut group = c.benchmark_group("requests");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("64B", |b| {
+        b.iter(|| {
+            link.client.send(client, &request).unwrap();
+            let mut received = 0;
+            while received < MESSAGE_LEN {
+                received += link.client.recv(client, &mut response[received..]).unwrap();
+            }
+        })
+    });
+    group.finish();
+    link.client.abort(client).unwrap();
+}
+
+// bulk transfer that keeps loss recovery busy: fast retransmits, SACK and the odd
+// retransmission timeout
+fn retransmission(c: &mut Criterion) {
+    bulk(c, "retransmission", Link::lossy());
+}
+
+criterion_group!(benches, handshake, throughput, requests, retransmission);
+criterion_main!(benches);
@@ -1,22 +1,33 @@
 use anyhow::Result;
-use std::{env, net::Ipv4Addr, str};
-use toytcp::tcp::TCP;
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    str,
+};
+use toytcp::tcp::{DEFAULT_BACKLOG, TCP};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
+    // the stack's log, e.g. RUST_LOG=toytcp=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
     let args: Vec<String> = env::args().collect();
-    let addr: Ipv4Addr = args[1].parse()?;
+    let addr: IpAddr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
-    echo_server(addr, port)?;
+    echo_server(SocketAddr::new(addr, port))?;
     Ok(())
 }
 
-fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
+fn echo_server(local_addr: SocketAddr) -> Result<()> {
     let tcp = TCP::new();
-    let listening_socket = tcp.listen(local_addr, local_port)?;
-    dbg!("listening..");
+    let listening_socket = tcp.listen(local_addr, DEFAULT_BACKLOG)?;
+    info!(%local_addr, "listening");
     loop {
         let connected_socket = tcp.accept(listening_socket)?;
-        dbg!("accepted!", connected_socket.1, connected_socket.3);
+        let peer = tcp.peer_addr(connected_socket)?;
+        info!(%peer, "accepted");
         let cloned_tcp = tcp.clone();
 
         std::thread::spawn(move || {
@@ -24,7 +35,7 @@ fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
             loop {
                 let nbytes = cloned_tcp.recv(connected_socket, &mut buffer).unwrap();
                 if nbytes == 0 {
-                    dbg!("closing connection...");
+                    info!("closing connection");
                     cloned_tcp.close(connected_socket).unwrap();
                     return;
                 }
@@ -1,19 +1,24 @@
 use anyhow::Result;
-use std::{env, fs, net::Ipv4Addr, str};
+use std::{env, fs, net::ToSocketAddrs, str};
 use toytcp::tcp::TCP;
+use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
+    // the stack's log, e.g. RUST_LOG=toytcp=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
     let args: Vec<String> = env::args().collect();
-    let addr: Ipv4Addr = args[1].parse()?;
+    let host: &str = &args[1];
     let port: u16 = args[2].parse()?;
     let filepath: &str = &args[3];
-    file_client(addr, port, filepath)?;
+    file_client((host, port), filepath)?;
     Ok(())
 }
 
-fn file_client(remote_addr: Ipv4Addr, remote_port: u16, filepath: &str) -> Result<()> {
+fn file_client(remote_addr: impl ToSocketAddrs, filepath: &str) -> Result<()> {
     let tcp = TCP::new();
-    let sock_id = tcp.connect(remote_addr, remote_port)?;
+    let sock_id = tcp.connect(remote_addr)?;
     let cloned_tcp = tcp.clone();
     ctrlc::set_handler(move || {
         cloned_tcp.close(sock_id).unwrap();
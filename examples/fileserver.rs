@@ -1,29 +1,40 @@
 use anyhow::Result;
-use std::{env, fs, net::Ipv4Addr, str};
-use toytcp::tcp::TCP;
+use std::{
+    env, fs,
+    net::{IpAddr, SocketAddr},
+    str,
+};
+use toytcp::tcp::{DEFAULT_BACKLOG, TCP};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
+    // the stack's log, e.g. RUST_LOG=toytcp=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
     let args: Vec<String> = env::args().collect();
-    let addr: Ipv4Addr = args[1].parse()?;
+    let addr: IpAddr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
     let savepath: &str = &args[3];
-    file_server(addr, port, savepath);
+    file_server(SocketAddr::new(addr, port), savepath);
     Ok(())
 }
 
-fn file_server(local_addr: Ipv4Addr, local_port: u16, savepath: &str) -> Result<()> {
+fn file_server(local_addr: SocketAddr, savepath: &str) -> Result<()> {
     let tcp = TCP::new();
-    let listening_socket = tcp.listen(local_addr, local_port)?;
-    dbg!("listening..");
+    let listening_socket = tcp.listen(local_addr, DEFAULT_BACKLOG)?;
+    info!(%local_addr, "listening");
     loop {
         let connected_socket = tcp.accept(listening_socket)?;
-        dbg!("accepted!", connected_socket.1, connected_socket.3);
+        let peer = tcp.peer_addr(connected_socket)?;
+        info!(%peer, "accepted");
         let mut v = Vec::new();
         let mut buffer = [0u8; 2000];
         loop {
             let nbytes = tcp.recv(connected_socket, &mut buffer).unwrap();
             if nbytes == 0 {
-                dbg!("closing connection...");
+                info!("closing connection");
                 tcp.close(connected_socket).unwrap();
                 break;
             }
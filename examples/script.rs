@@ -0,0 +1,26 @@
+use anyhow::Result;
+use std::{env, path::Path, process};
+use toytcp::script::Script;
+use tracing_subscriber::EnvFilter;
+
+// runs each script given, e.g. `cargo run --example script scripts/*.pkt`
+fn main() -> Result<()> {
+    // the stack's log, e.g. RUST_LOG=toytcp=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let mut failed = 0;
+    for path in env::args().skip(1) {
+        match Script::from_file(Path::new(&path)).and_then(|script| script.run()) {
+            Ok(()) => println!("ok   {}", path),
+            Err(error) => {
+                println!("FAIL {}: {:#}", path, error);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,483 @@
+use crate::seq::SeqNum;
+use crate::sockopt::{AoAlgorithm, AoKey};
+use crate::tcpflags;
+use crate::tcpoption::{AUTHENTICATION, END, NOP};
+use aes::Aes128;
+use cmac::Cmac;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tracing::debug;
+
+// both algorithms of RFC 5926 truncate the MAC to 96 bits
+pub const MAC_LEN: usize = 12;
+// kind, length, KeyID and RNextKeyID in front of the MAC
+pub const AO_OPTION_LEN: usize = 4 + MAC_LEN;
+const KDF_LABEL: &[u8] = b"TCP-AO";
+
+/// TCP-AO (RFC 5925) state of a connection: the master key tuples (MKTs) it may use,
+/// which of them signs what we send, and the sequence number extensions of both directions
+#[derive(Debug, Clone)]
+pub struct Authentication {
+    keys: Vec<AoKey>,
+    current: u8, // SendID of the MKT our segments are signed with
+    rnext: u8,   // RecvID of the MKT we want the peer to sign with
+    send_sne: SeqExtension,
+    recv_sne: SeqExtension,
+}
+
+impl Authentication {
+    // None without keys: the connection goes unauthenticated
+    pub fn new(keys: Vec<AoKey>) -> Option<Self> {
+        let first = keys.first()?;
+        Some(Self {
+            current: first.send_id,
+            rnext: first.recv_id,
+            keys,
+            send_sne: SeqExtension::default(),
+            recv_sne: SeqExtension::default(),
+        })
+    }
+
+    // KeyID and RNextKeyID of the next segment we send
+    pub fn key_ids(&self) -> (u8, u8) {
+        (self.current, self.rnext)
+    }
+
+    pub fn add_key(&mut self, key: AoKey) {
+        self.keys.push(key);
+    }
+
+    // false if the MKT is the one our segments are signed with
+    pub fn remove_key(&mut self, send_id: u8) -> bool {
+        if send_id == self.current {
+            return false;
+        }
+        self.keys.retain(|key| key.send_id != send_id);
+        true
+    }
+
+    // ask the peer to move to the MKT with `recv_id`. false if there is none
+    pub fn set_rnext(&mut self, recv_id: u8) -> bool {
+        if !self.keys.iter().any(|key| key.recv_id == recv_id) {
+            return false;
+        }
+        self.rnext = recv_id;
+        true
+    }
+
+    // fill in the MAC of the TCP-AO option `segment` carries
+    pub fn sign(
+        &self,
+        segment: &mut [u8],
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        local_isn: SeqNum,
+        remote_isn: SeqNum,
+    ) {
+        let key = match self.keys.iter().find(|key| key.send_id == self.current) {
+            Some(key) => key,
+            None => return,
+        };
+        let (at, len) = match find_option(segment) {
+            Some(option) => option,
+            None => return,
+        };
+        let remote_isn = if is_syn(segment) {
+            SeqNum(0)
+        } else {
+            remote_isn
+        };
+        let traffic_key = traffic_key(key, local, remote, local_isn, remote_isn);
+        let sne = self.send_sne.sne(read_seq(segment));
+        let mac = compute_mac(
+            key,
+            &traffic_key,
+            sne,
+            (*local.ip(), *remote.ip()),
+            segment,
+            (at, len),
+        );
+        segment[at + 4..at + len].copy_from_slice(&mac[..len - 4]);
+    }
+
+    // a segment we sent up to `seq`
+    pub fn on_send(&mut self, seq: SeqNum) {
+        self.send_sne.advance(seq);
+    }
+
+    // whether the MAC of `segment` proves one of the MKTs. a segment without the option
+    // fails. on success, moves to the MKT the peer asked for with RNextKeyID
+    pub fn verify(
+        &mut self,
+        segment: &[u8],
+        remote: SocketAddrV4,
+        local: SocketAddrV4,
+        remote_isn: SeqNum,
+        local_isn: SeqNum,
+    ) -> bool {
+        let (at, len) = match find_option(segment) {
+            Some((at, len)) if len == AO_OPTION_LEN => (at, len),
+            _ => return false,
+        };
+        let (key_id, rnext_key_id) = (segment[at + 2], segment[at + 3]);
+        let key = match self.keys.iter().find(|key| key.recv_id == key_id) {
+            Some(key) => key,
+            None => {
+                debug!(key_id, "TCP-AO: unknown KeyID");
+                return false;
+            }
+        };
+        let local_isn = if is_syn(segment) {
+            SeqNum(0)
+        } else {
+            local_isn
+        };
+        let traffic_key = traffic_key(key, remote, local, remote_isn, local_isn);
+        let seq = read_seq(segment);
+        let mac = compute_mac(
+            key,
+            &traffic_key,
+            self.recv_sne.sne(seq),
+            (*remote.ip(), *local.ip()),
+            segment,
+            (at, len),
+        );
+        if mac[..] != segment[at + 4..at + len] {
+            return false;
+        }
+        self.recv_sne.advance(seq);
+        if rnext_key_id != self.current && self.keys.iter().any(|key| key.send_id == rnext_key_id) {
+            debug!(key_id = rnext_key_id, "TCP-AO: switched to KeyID");
+            self.current = rnext_key_id;
+        }
+        true
+    }
+}
+
+/// the upper 32 bits of a 64-bit sequence number (SNE), counted from the first segment
+/// seen and moved along with the highest sequence number seen since
+#[derive(Debug, Clone, Copy, Default)]
+struct SeqExtension {
+    highest: Option<SeqNum>,
+    sne: u32,
+}
+
+impl SeqExtension {
+    fn sne(&self, seq: SeqNum) -> u32 {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return self.sne,
+        };
+        if seq >= highest && seq.0 < highest.0 {
+            // past the wrap
+            self.sne.wrapping_add(1)
+        } else if seq < highest && seq.0 > highest.0 {
+            // from before the wrap
+            self.sne.wrapping_sub(1)
+        } else {
+            self.sne
+        }
+    }
+
+    fn advance(&mut self, seq: SeqNum) {
+        if self.highest.is_none_or(|highest| seq > highest) {
+            self.sne = self.sne(seq);
+            self.highest = Some(seq);
+        }
+    }
+}
+
+// the byte offset and length of the TCP-AO option of `segment`
+fn find_option(segment: &[u8]) -> Option<(usize, usize)> {
+    let header_len = (*segment.get(12)? >> 4) as usize * 4;
+    let mut at = 20;
+    while at < header_len.min(segment.len()) {
+        match segment[at] {
+            END => return None,
+            NOP => at += 1,
+            kind => {
+                let len = *segment.get(at + 1)? as usize;
+                if len < 2 || at + len > header_len {
+                    return None;
+                }
+                if kind == AUTHENTICATION && len >= 4 {
+                    return Some((at, len));
+                }
+                at += len;
+            }
+        }
+    }
+    None
+}
+
+fn is_syn(segment: &[u8]) -> bool {
+    segment[13] & (tcpflags::SYN | tcpflags::ACK) == tcpflags::SYN
+}
+
+fn read_seq(segment: &[u8]) -> SeqNum {
+    SeqNum(u32::from_be_bytes(segment[4..8].try_into().unwrap()))
+}
+
+// the traffic key for segments from `src` to `dst` (RFC 5926 section 3.1). the ISN of `dst`
+// is 0 for a SYN
+fn traffic_key(
+    key: &AoKey,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    src_isn: SeqNum,
+    dst_isn: SeqNum,
+) -> Vec<u8> {
+    let mut input = vec![1];
+    input.extend_from_slice(KDF_LABEL);
+    input.extend_from_slice(&src.ip().octets());
+    input.extend_from_slice(&dst.ip().octets());
+    input.extend_from_slice(&src.port().to_be_bytes());
+    input.extend_from_slice(&dst.port().to_be_bytes());
+    input.extend_from_slice(&src_isn.0.to_be_bytes());
+    input.extend_from_slice(&dst_isn.0.to_be_bytes());
+    match key.algorithm {
+        AoAlgorithm::HmacSha1 => {
+            input.extend_from_slice(&160u16.to_be_bytes());
+            hmac_sha1(&key.key, &input)
+        }
+        AoAlgorithm::AesCmac => {
+            input.extend_from_slice(&128u16.to_be_bytes());
+            // a master key of another length is first condensed into 128 bits
+            if key.key.len() == 16 {
+                aes_cmac(&key.key, &input)
+            } else {
+                aes_cmac(&aes_cmac(&[0; 16], &key.key), &input)
+            }
+        }
+    }
+}
+
+// the MAC over the SNE, the pseudo-header and the segment with its checksum and MAC zeroed
+// (RFC 5925 section 5.1). without `include_options`, TCP-AO at `option` is the only option
+// covered
+fn compute_mac(
+    key: &AoKey,
+    traffic_key: &[u8],
+    sne: u32,
+    (src, dst): (Ipv4Addr, Ipv4Addr),
+    segment: &[u8],
+    (at, len): (usize, usize),
+) -> [u8; MAC_LEN] {
+    let header_len = (segment[12] >> 4) as usize * 4;
+    let mut header = segment[..header_len].to_vec();
+    header[16..18].fill(0);
+    header[at + 4..at + len].fill(0);
+    let mut message = Vec::with_capacity(12 + segment.len());
+    message.extend_from_slice(&sne.to_be_bytes());
+    message.extend_from_slice(&src.octets());
+    message.extend_from_slice(&dst.octets());
+    message.extend_from_slice(&[0, 6]);
+    message.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    if key.include_options {
+        message.extend_from_slice(&header);
+    } else {
+        message.extend_from_slice(&header[..20]);
+        message.extend_from_slice(&header[at..at + len]);
+    }
+    message.extend_from_slice(&segment[header_len..]);
+    let mac = match key.algorithm {
+        AoAlgorithm::HmacSha1 => hmac_sha1(traffic_key, &message),
+        AoAlgorithm::AesCmac => aes_cmac(traffic_key, &message),
+    };
+    mac[..MAC_LEN].try_into().unwrap()
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn aes_cmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Cmac::<Aes128>::new_from_slice(key).expect("traffic keys are 128 bits");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    const MKT: &[u8] = b"testvector";
+    const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 11, 12, 13), 40000);
+    const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 11, 12, 14), 179);
+    const CLIENT_ISN: SeqNum = SeqNum(0xfbfb_ab5a);
+    const SERVER_ISN: SeqNum = SeqNum(0x11c1_4261);
+    // the TCP-AO option of `segment`
+    const OPTION: (usize, usize) = (32, AO_OPTION_LEN);
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // the client's MKT
+    fn key(algorithm: AoAlgorithm, include_options: bool) -> AoKey {
+        AoKey {
+            peer: IpAddr::V4(*SERVER.ip()),
+            send_id: 61,
+            recv_id: 84,
+            algorithm,
+            key: MKT.to_vec(),
+            include_options,
+        }
+    }
+
+    // the client's and the server's end, the same MKT with the KeyIDs the other way round
+    fn endpoints(
+        algorithm: AoAlgorithm,
+        include_options: bool,
+    ) -> (Authentication, Authentication) {
+        let client = key(algorithm, include_options);
+        let server = AoKey {
+            peer: IpAddr::V4(*CLIENT.ip()),
+            send_id: client.recv_id,
+            recv_id: client.send_id,
+            ..client.clone()
+        };
+        (
+            Authentication::new(vec![client]).unwrap(),
+            Authentication::new(vec![server]).unwrap(),
+        )
+    }
+
+    // "hello" from the client with PSH and ACK, timestamps, and a TCP-AO option of KeyID 61
+    // and RNextKeyID 84 whose MAC is zero yet
+    fn segment(seq: SeqNum) -> Vec<u8> {
+        let mut segment = hex(concat!(
+            "9c4000b3fbfbab5b11c14262c0187fff12340000",
+            "0101080a0000000100000002",
+            "1d103d54000000000000000000000000",
+            "68656c6c6f"
+        ));
+        segment[4..8].copy_from_slice(&seq.0.to_be_bytes());
+        segment
+    }
+
+    #[test]
+    fn primitives_match_their_rfcs() {
+        // RFC 2202 test case 1
+        assert_eq!(
+            hmac_sha1(&[0x0b; 20], b"Hi There"),
+            hex("b617318655057264e28bc0b6fb378c8ef146be00")
+        );
+        // RFC 4493 example 2
+        assert_eq!(
+            aes_cmac(
+                &hex("2b7e151628aed2a6abf7158809cf4f3c"),
+                &hex("6bc1bee22e409f96e93d7e117393172a")
+            ),
+            hex("070a16b46b4d4144f79bdd9dd04a287c")
+        );
+        // RFC 4615: AES-CMAC-PRF-128, which condenses a master key not of 128 bits
+        let message: Vec<u8> = (0..20).collect();
+        for (key, prf) in [
+            (
+                "000102030405060708090a0b0c0d0e0fedcb",
+                "84a348a4a45d235babfffc0d2b4da09a",
+            ),
+            ("00010203040506070809", "290d9e112edb09ee141fcf64c0b72f3d"),
+        ] {
+            assert_eq!(aes_cmac(&aes_cmac(&[0; 16], &hex(key)), &message), hex(prf));
+        }
+    }
+
+    // the expected values were computed apart from this code, after RFC 5926 section 3.1.1
+    // and RFC 5925 section 5.1
+    #[test]
+    fn traffic_keys_and_macs_match_reference() {
+        assert_eq!(find_option(&segment(CLIENT_ISN + 1)), Some(OPTION));
+        for (algorithm, include_options, expected_key, expected_mac) in [
+            (
+                AoAlgorithm::HmacSha1,
+                true,
+                "b4b10573281f5be8dea13b79b0ccbd6b65ce8571",
+                "f1a382fbb2107ad36f72c9c5",
+            ),
+            (
+                AoAlgorithm::HmacSha1,
+                false,
+                "b4b10573281f5be8dea13b79b0ccbd6b65ce8571",
+                "a66680586f7faf09fe9f24ed",
+            ),
+            (
+                AoAlgorithm::AesCmac,
+                true,
+                "ab7dd6b716ad0367911f59cdac199945",
+                "64cab6c406c4eb593b6c14c7",
+            ),
+        ] {
+            let key = key(algorithm, include_options);
+            let traffic_key = traffic_key(&key, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN);
+            assert_eq!(traffic_key, hex(expected_key));
+            let mac = compute_mac(
+                &key,
+                &traffic_key,
+                0,
+                (*CLIENT.ip(), *SERVER.ip()),
+                &segment(CLIENT_ISN + 1),
+                OPTION,
+            );
+            assert_eq!(mac.to_vec(), hex(expected_mac));
+        }
+    }
+
+    #[test]
+    fn signed_segments_verify() {
+        for algorithm in [AoAlgorithm::HmacSha1, AoAlgorithm::AesCmac] {
+            for include_options in [false, true] {
+                let (client, mut server) = endpoints(algorithm, include_options);
+                let mut segment = segment(CLIENT_ISN + 1);
+                client.sign(&mut segment, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN);
+                assert!(server.verify(&segment, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN));
+                let mut tampered = segment.clone();
+                *tampered.last_mut().unwrap() ^= 1;
+                assert!(!server.verify(&tampered, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN));
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_extension_counts_wraps() {
+        let mut extension = SeqExtension::default();
+        extension.advance(SeqNum(0xffff_f000));
+        assert_eq!(extension.sne(SeqNum(0xffff_ff00)), 0);
+        assert_eq!(extension.sne(SeqNum(0x100)), 1);
+        extension.advance(SeqNum(0x100));
+        assert_eq!(extension.sne(SeqNum(0x200)), 1);
+        // sent just before the wrap, arriving after what followed it
+        assert_eq!(extension.sne(SeqNum(0xffff_ff00)), 0);
+        extension.advance(SeqNum(0xffff_ff00));
+        assert_eq!(extension.sne(SeqNum(0x200)), 1);
+        extension.advance(SeqNum(0x8000_0000));
+        extension.advance(SeqNum(0xffff_0000));
+        assert_eq!(extension.sne(SeqNum(0x10)), 2);
+    }
+
+    #[test]
+    fn segments_either_side_of_the_wrap_verify() {
+        let (mut client, mut server) = endpoints(AoAlgorithm::HmacSha1, false);
+        let mut signed = |seq: SeqNum| {
+            let mut segment = segment(seq);
+            client.sign(&mut segment, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN);
+            client.on_send(seq + 5);
+            segment
+        };
+        let first = signed(SeqNum(0xffff_ff00));
+        let before = signed(SeqNum(0xffff_fffe));
+        let after = signed(SeqNum(0x3));
+        assert!(server.verify(&first, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN));
+        // overtaken on the way
+        assert!(server.verify(&after, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN));
+        assert!(server.verify(&before, CLIENT, SERVER, CLIENT_ISN, SERVER_ISN));
+    }
+}
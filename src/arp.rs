@@ -0,0 +1,204 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+pub type MacAddr = [u8; 6];
+pub const BROADCAST: MacAddr = [0xff; 6];
+pub const ARP_PACKET_LEN: usize = 28;
+
+// a resolved entry is trusted this long before the address is resolved again
+const REACHABLE_TIME: Duration = Duration::from_secs(60);
+// an unanswered request is repeated after this, with the packets held so far dropped after
+// MAX_REQUESTS of them
+const RETRANS_TIME: Duration = Duration::from_secs(1);
+const MAX_REQUESTS: u32 = 3;
+// packets held per address while it is being resolved, the oldest dropped beyond that
+const MAX_QUEUED: usize = 3;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpOperation {
+    Request,
+    Reply,
+}
+
+/// an ARP packet for IPv4 over Ethernet (RFC 826)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn request(sender_mac: MacAddr, sender_ip: Ipv4Addr, target_ip: Ipv4Addr) -> Self {
+        Self {
+            operation: ArpOperation::Request,
+            sender_mac,
+            sender_ip,
+            target_mac: [0; 6],
+            target_ip,
+        }
+    }
+
+    // None for anything but IPv4 over Ethernet
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < ARP_PACKET_LEN
+            || u16::from_be_bytes([bytes[0], bytes[1]]) != HTYPE_ETHERNET
+            || u16::from_be_bytes([bytes[2], bytes[3]]) != PTYPE_IPV4
+            || bytes[4] != 6
+            || bytes[5] != 4
+        {
+            return None;
+        }
+        let operation = match u16::from_be_bytes([bytes[6], bytes[7]]) {
+            OP_REQUEST => ArpOperation::Request,
+            OP_REPLY => ArpOperation::Reply,
+            _ => return None,
+        };
+        let ip = |at: usize| Ipv4Addr::new(bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]);
+        Some(Self {
+            operation,
+            sender_mac: bytes[8..14].try_into().unwrap(),
+            sender_ip: ip(14),
+            target_mac: bytes[18..24].try_into().unwrap(),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_PACKET_LEN] {
+        let mut bytes = [0; ARP_PACKET_LEN];
+        bytes[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        bytes[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        let operation = match self.operation {
+            ArpOperation::Request => OP_REQUEST,
+            ArpOperation::Reply => OP_REPLY,
+        };
+        bytes[6..8].copy_from_slice(&operation.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac);
+        bytes[14..18].copy_from_slice(&self.sender_ip.octets());
+        bytes[18..24].copy_from_slice(&self.target_mac);
+        bytes[24..28].copy_from_slice(&self.target_ip.octets());
+        bytes
+    }
+}
+
+#[derive(Debug)]
+enum Entry {
+    Resolved {
+        mac: MacAddr,
+        updated: Instant,
+    },
+    // a request is out, the packets to the address wait for its reply
+    Incomplete {
+        queue: VecDeque<Vec<u8>>,
+        requested: Instant,
+        requests: u32,
+    },
+}
+
+/// what to do with a packet to a next hop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Resolved(MacAddr),
+    // held until the address resolves. an ARP request for it is due if `request`
+    Queued { request: bool },
+}
+
+/// neighbor cache of a link-layer device: the MACs of next hops by IPv4 address. packets to
+/// an address being resolved wait here and come out once the reply arrives. nothing drives
+/// it but the packets themselves, so unanswered requests are repeated as more of them come
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    entries: HashMap<Ipv4Addr, Entry>,
+}
+
+impl ArpCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `packet` is kept only if `addr` has to be resolved first
+    pub fn resolve(&mut self, addr: Ipv4Addr, packet: &[u8]) -> Resolution {
+        let now = Instant::now();
+        match self.entries.get_mut(&addr) {
+            Some(Entry::Resolved { mac, updated }) if now - *updated < REACHABLE_TIME => {
+                return Resolution::Resolved(*mac);
+            }
+            Some(Entry::Incomplete {
+                queue,
+                requested,
+                requests,
+            }) => {
+                let due = now - *requested >= RETRANS_TIME;
+                if due && *requests >= MAX_REQUESTS {
+                    debug!(%addr, dropped = queue.len(), "ARP: no reply");
+                    queue.clear();
+                    *requests = 0;
+                }
+                if queue.len() >= MAX_QUEUED {
+                    queue.pop_front();
+                }
+                queue.push_back(packet.to_vec());
+                if due {
+                    *requested = now;
+                    *requests += 1;
+                }
+                return Resolution::Queued { request: due };
+            }
+            _ => {}
+        }
+        // unknown or stale
+        self.entries.insert(
+            addr,
+            Entry::Incomplete {
+                queue: VecDeque::from([packet.to_vec()]),
+                requested: now,
+                requests: 1,
+            },
+        );
+        Resolution::Queued { request: true }
+    }
+
+    // an ARP packet came in. the sender is learned if it is in the cache already or the
+    // packet is for us (RFC 826), which releases the packets waiting for it. returns the
+    // reply to send if it asks for one of `own_addrs`, and the released packets
+    pub fn process(
+        &mut self,
+        arp: &ArpPacket,
+        own_mac: MacAddr,
+        own_addrs: &[Ipv4Addr],
+    ) -> (Option<ArpPacket>, Vec<Vec<u8>>) {
+        let for_us = own_addrs.contains(&arp.target_ip);
+        let mut released = Vec::new();
+        // a probe (RFC 5227) has no sender address to learn
+        if !arp.sender_ip.is_unspecified() && (for_us || self.entries.contains_key(&arp.sender_ip))
+        {
+            let entry = Entry::Resolved {
+                mac: arp.sender_mac,
+                updated: Instant::now(),
+            };
+            if let Some(Entry::Incomplete { queue, .. }) = self.entries.insert(arp.sender_ip, entry)
+            {
+                released.extend(queue);
+            }
+        }
+        let reply = (for_us && arp.operation == ArpOperation::Request).then_some(ArpPacket {
+            operation: ArpOperation::Reply,
+            sender_mac: own_mac,
+            sender_ip: arp.target_ip,
+            target_mac: arp.sender_mac,
+            target_ip: arp.sender_ip,
+        });
+        (reply, released)
+    }
+}
@@ -0,0 +1,171 @@
+use crate::socket::{SockID, TcpStatus};
+use crate::sockopt::BufferSizes;
+use crate::stream::{into_io_error, TcpListener, TcpStream};
+use crate::tcp::TCP;
+use anyhow::Result;
+use std::future;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// TcpListener whose accept() waits on the task instead of the thread
+pub struct AsyncTcpListener {
+    inner: TcpListener,
+}
+
+impl AsyncTcpListener {
+    pub fn bind(tcp: &Arc<TCP>, local_addr: SocketAddr) -> Result<Self> {
+        let inner = TcpListener::bind(tcp, local_addr)?;
+        inner.set_nonblocking(true)?;
+        Ok(Self { inner })
+    }
+
+    pub async fn accept(&self) -> io::Result<AsyncTcpStream> {
+        let tcp = &self.inner.tcp;
+        let sock_id = future::poll_fn(|cx| {
+            poll_socket(tcp, self.inner.sock_id, cx, || {
+                tcp.accept(self.inner.sock_id)
+            })
+        })
+        .await?;
+        AsyncTcpStream::from_stream(TcpStream {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn sock_id(&self) -> SockID {
+        self.inner.sock_id
+    }
+}
+
+/// TcpStream implementing tokio's AsyncRead/AsyncWrite.
+/// dropping it closes the socket on tokio's blocking pool when inside a runtime
+pub struct AsyncTcpStream {
+    // dropped by hand, see the Drop impl
+    inner: ManuallyDrop<TcpStream>,
+}
+
+impl AsyncTcpStream {
+    pub async fn connect(tcp: &Arc<TCP>, addr: SocketAddr) -> io::Result<Self> {
+        Self::connect_with(tcp, addr, BufferSizes::default()).await
+    }
+
+    pub async fn connect_with(
+        tcp: &Arc<TCP>,
+        addr: SocketAddr,
+        buffers: BufferSizes,
+    ) -> io::Result<Self> {
+        let sock_id = tcp.start_connect(addr, buffers).map_err(into_io_error)?;
+        let inner = TcpStream {
+            tcp: tcp.clone(),
+            sock_id,
+        };
+        future::poll_fn(|cx| {
+            poll_socket(tcp, sock_id, cx, || match tcp.status(sock_id)? {
+                TcpStatus::SynSent | TcpStatus::SynRcvd => {
+                    Err(io::Error::from(io::ErrorKind::WouldBlock).into())
+                }
+                _ => Ok(()),
+            })
+        })
+        .await?;
+        Self::from_stream(inner)
+    }
+
+    fn from_stream(inner: TcpStream) -> io::Result<Self> {
+        inner.set_nonblocking(true).map_err(into_io_error)?;
+        Ok(Self {
+            inner: ManuallyDrop::new(inner),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn sock_id(&self) -> SockID {
+        self.inner.sock_id
+    }
+}
+
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let TcpStream { tcp, sock_id } = &*self.inner;
+        let n = match poll_socket(tcp, *sock_id, cx, || {
+            tcp.recv(*sock_id, buf.initialize_unfilled())
+        }) {
+            Poll::Ready(Ok(n)) => n,
+            Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+            Poll::Pending => return Poll::Pending,
+        };
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let TcpStream { tcp, sock_id } = &*self.inner;
+        poll_socket(tcp, *sock_id, cx, || tcp.send(*sock_id, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let TcpStream { tcp, sock_id } = &self.inner;
+        Poll::Ready(tcp.flush(*sock_id).map_err(into_io_error))
+    }
+
+    // sends FIN; the socket itself is released when the stream is dropped
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.shutdown(Shutdown::Write).map_err(into_io_error))
+    }
+}
+
+// TcpStream's drop waits for the FIN handshake, which must not hold up a worker thread
+impl Drop for AsyncTcpStream {
+    fn drop(&mut self) {
+        // SAFETY: inner is not used again after this
+        let inner = unsafe { ManuallyDrop::take(&mut self.inner) };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || drop(inner));
+            }
+            Err(_) => drop(inner),
+        }
+    }
+}
+
+// run a nonblocking operation, parking the task on the socket's events if it would block.
+// the waker is registered before trying so that an event in between is not lost.
+fn poll_socket<T>(
+    tcp: &TCP,
+    sock_id: SockID,
+    cx: &mut Context<'_>,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Poll<io::Result<T>> {
+    tcp.register_waker(sock_id, cx.waker());
+    match operation().map_err(into_io_error) {
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
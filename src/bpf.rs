@@ -0,0 +1,71 @@
+use std::io;
+use std::mem;
+use std::os::fd::RawFd;
+
+// not in libc for every target
+const SO_ATTACH_FILTER: libc::c_int = 26;
+// a filter program jumps forward at most this far, which bounds the ports it checks
+const MAX_PORTS: usize = u8::MAX as usize;
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+// classic BPF on a raw TCP socket, whose packets start at the IP header: keeps the segments
+// to one of `ports` and drops the rest. with too many ports to check, everything is kept
+fn port_filter(ports: &[u16]) -> Vec<libc::sock_filter> {
+    const ACCEPT: u32 = u32::MAX;
+    if ports.len() > MAX_PORTS {
+        return vec![statement(libc::BPF_RET | libc::BPF_K, ACCEPT)];
+    }
+    let mut program = vec![
+        // X = length of the IP header
+        statement(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0),
+        // A = destination port
+        statement(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, 2),
+    ];
+    for (i, &port) in ports.iter().enumerate() {
+        // on a match, over the remaining checks and the drop
+        let to_accept = (ports.len() - i) as u8;
+        program.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            port as u32,
+            to_accept,
+            0,
+        ));
+    }
+    program.push(statement(libc::BPF_RET | libc::BPF_K, 0));
+    program.push(statement(libc::BPF_RET | libc::BPF_K, ACCEPT));
+    program
+}
+
+// replaces the filter of the socket, packets already queued stay
+pub fn attach_port_filter(fd: RawFd, ports: &[u16]) -> io::Result<()> {
+    let mut program = port_filter(ports);
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_ATTACH_FILTER,
+            &fprog as *const libc::sock_fprog as *const libc::c_void,
+            mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// virtual time moves on in steps this long, the resolution of our TCP timestamps, so that
+// every timer on the way fires at its own time
+const VIRTUAL_STEP: Duration = Duration::from_millis(1);
+
+/// the time the timers of a `TCP` run on: retransmissions, delayed ACKs, keepalives,
+/// TIME_WAIT and the rest. the system clock, or a virtual one that tests move by hand
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    // a virtual clock doesn't move by itself: nothing sleeps on it, what is waiting runs
+    // from `on_advance` instead
+    fn is_virtual(&self) -> bool {
+        false
+    }
+
+    // `wake` is called whenever the clock is moved by hand
+    fn on_advance(&self, _wake: Box<dyn Fn() + Send + Sync>) {}
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// time that stands still until `advance` moves it on. the timers that come due on the
+/// way run on the thread that moves it, before `advance` returns, so a test decides
+/// exactly when a retransmission timeout fires instead of sleeping through it
+pub struct VirtualClock {
+    now: Mutex<SystemTime>,
+    wakers: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+}
+
+impl VirtualClock {
+    // starts at the epoch, the same for every run
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(UNIX_EPOCH),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut left = duration;
+        while !left.is_zero() {
+            let step = left.min(VIRTUAL_STEP);
+            left -= step;
+            *self.now.lock().unwrap() += step;
+            for wake in self.wakers.lock().unwrap().iter() {
+                wake();
+            }
+        }
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn is_virtual(&self) -> bool {
+        true
+    }
+
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) {
+        self.wakers.lock().unwrap().push(wake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtualClock;
+    use crate::device::MemoryDevice;
+    use crate::ip;
+    use crate::packet::TCPPacket;
+    use crate::route::RoutingTable;
+    use crate::seq::SeqNum;
+    use crate::sockopt::BufferSizes;
+    use crate::tcp::TCP;
+    use crate::tcpflags;
+    use crate::tcpoption::TcpOption;
+    use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+    use pnet::util;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+    const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const LOCAL_PORT: u16 = 40000;
+    const REMOTE_PORT: u16 = 8080;
+    const REMOTE_ISS: u32 = 1000;
+
+    // the segments the stack has sent so far
+    fn sent(peer: &MemoryDevice) -> Vec<TCPPacket> {
+        let mut buffer = vec![0; 65535];
+        let mut packets = Vec::new();
+        while let Ok(Some(len)) = peer.recv_packet_timeout(&mut buffer, Duration::ZERO) {
+            let header = ip::parse(&buffer[..len]).unwrap();
+            let segment = &buffer[header.header_len..header.total_len];
+            packets.push(TCPPacket::from(TcpPacket::new(segment).unwrap()));
+        }
+        packets
+    }
+
+    fn inject(tcp: &TCP, mut packet: TCPPacket) {
+        packet.set_src(REMOTE_PORT);
+        packet.set_dest(LOCAL_PORT);
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &REMOTE_ADDR,
+            &LOCAL_ADDR,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        tcp.packet_handler(&ip::build(
+            REMOTE_ADDR,
+            LOCAL_ADDR,
+            ip::TCP,
+            64,
+            0,
+            packet.packet(),
+        ));
+    }
+
+    #[test]
+    fn retransmission_timeout_fires_once_on_time() {
+        let (device, peer) = MemoryDevice::pair();
+        let routes = RoutingTable::on_link("clock0", LOCAL_ADDR, 24, None);
+        let clock = Arc::new(VirtualClock::new());
+        let tcp = TCP::with_clock(device, routes, clock.clone());
+        let sock_id = tcp
+            .start_connect_from(
+                (LOCAL_ADDR, LOCAL_PORT).into(),
+                (REMOTE_ADDR, REMOTE_PORT).into(),
+                BufferSizes::default(),
+            )
+            .unwrap();
+        let iss = sent(&peer)[0].get_seq();
+        // a round trip of 100ms puts the RTO (300ms) ahead of a tail loss probe (400ms)
+        clock.advance(Duration::from_millis(100));
+        let mut syn_ack = TCPPacket::new(0);
+        syn_ack.set_seq(SeqNum(REMOTE_ISS));
+        syn_ack.set_ack(iss + 1);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK);
+        syn_ack.set_window_size(u16::MAX);
+        syn_ack.set_options(&[TcpOption::MaxSegmentSize(1460)]);
+        inject(&tcp, syn_ack);
+        sent(&peer);
+
+        tcp.set_nonblocking(sock_id, true).unwrap();
+        assert_eq!(tcp.send(sock_id, &[0; 100]).unwrap(), 100);
+        assert_eq!(sent(&peer).len(), 1);
+        let rto = tcp.socket_info(sock_id).unwrap().rto;
+        clock.advance(rto / 2);
+        assert!(sent(&peer).is_empty());
+        // short of the backed-off timeout of the retransmission
+        clock.advance(rto);
+        let retransmitted = sent(&peer);
+        assert_eq!(retransmitted.len(), 1);
+        assert_eq!(retransmitted[0].get_seq(), iss + 1);
+        assert_eq!(retransmitted[0].payload().len(), 100);
+        assert_eq!(tcp.socket_info(sock_id).unwrap().retransmissions, 1);
+    }
+}
@@ -0,0 +1,269 @@
+mod bbr;
+mod cubic;
+mod hystart;
+mod reno;
+
+use crate::seq::SeqNum;
+use crate::sockopt::CongestionAlgorithm;
+use hystart::HyStart;
+use std::cmp;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+/// window growth and reduction of a congestion control algorithm.
+/// fast recovery itself is driven by Congestion and is the same for every algorithm.
+pub trait CongestionControl: Debug + Send + Sync {
+    fn cwnd(&self) -> usize;
+    fn set_cwnd(&mut self, cwnd: usize);
+    fn ssthresh(&self) -> usize;
+    // a copy of the whole state, to go back to if a reduction turns out to be spurious
+    fn snapshot(&self) -> Box<dyn CongestionControl>;
+    // `acked` newly acknowledged bytes outside of fast recovery, at `now` by the clock of
+    // the stack
+    fn on_ack(&mut self, acked: usize, now: SystemTime);
+    // duplicate ACKs reported a loss: set ssthresh and the window to recover with
+    fn on_loss(&mut self, flight_size: usize);
+    // the retransmission timer expired
+    fn on_timeout(&mut self, flight_size: usize);
+    // fast recovery is over: continue from ssthresh by default
+    fn on_recovery_exit(&mut self) {
+        let ssthresh = self.ssthresh();
+        self.set_cwnd(ssthresh);
+    }
+    // round-trip time measured on an ACK, never on a retransmitted segment
+    fn on_rtt_sample(&mut self, _rtt: Duration, _now: SystemTime) {}
+    // whether the window grows by slow start below ssthresh, so that HyStart++ applies
+    fn has_slow_start(&self) -> bool {
+        true
+    }
+    // HyStart++ ended slow start: go on in congestion avoidance from the current window
+    fn exit_slow_start(&mut self) {}
+    // bytes per second to space segments out at, None to send as the window allows
+    fn pacing_rate(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// congestion state of a connection: the selected algorithm plus NewReno fast recovery
+/// (RFC 6582), during which Proportional Rate Reduction (RFC 6937) spreads what is sent
+/// over the ACKs that arrive
+#[derive(Debug)]
+pub struct Congestion {
+    mss: usize,
+    controller: Box<dyn CongestionControl>,
+    in_recovery: bool,
+    recover: Option<SeqNum>, // SND.NXT when fast recovery was last entered
+    ecn_recover: Option<SeqNum>, // SND.NXT when the window was last reduced for ECE
+    undo: Option<Box<dyn CongestionControl>>, // the state before a reduction that may be undone
+    recover_fs: usize,       // flight size when fast recovery was entered
+    prr_delivered: usize,    // bytes the peer received since then
+    prr_out: usize,          // bytes sent since then, retransmissions included
+    hystart: HyStart,
+}
+
+impl Congestion {
+    pub fn new(algorithm: CongestionAlgorithm, mss: usize) -> Self {
+        let controller: Box<dyn CongestionControl> = match algorithm {
+            CongestionAlgorithm::Reno => Box::new(reno::Reno::new(mss)),
+            CongestionAlgorithm::Cubic => Box::new(cubic::Cubic::new(mss)),
+            CongestionAlgorithm::Bbr => Box::new(bbr::Bbr::new(mss)),
+        };
+        Self {
+            mss,
+            controller,
+            in_recovery: false,
+            recover: None,
+            ecn_recover: None,
+            undo: None,
+            recover_fs: 0,
+            prr_delivered: 0,
+            prr_out: 0,
+            hystart: HyStart::new(),
+        }
+    }
+
+    pub fn cwnd(&self) -> usize {
+        self.controller.cwnd()
+    }
+
+    pub fn ssthresh(&self) -> usize {
+        self.controller.ssthresh()
+    }
+
+    pub fn in_recovery(&self) -> bool {
+        self.in_recovery
+    }
+
+    // the initial slow start, while HyStart++ watches it
+    fn in_slow_start(&self) -> bool {
+        self.controller.has_slow_start() && !self.hystart.is_done() && self.cwnd() < self.ssthresh()
+    }
+
+    // `acked` newly acknowledged bytes, up to `snd_una`.
+    // returns true for a partial ACK in fast recovery: the segment at SND.UNA is lost too
+    // and has to be resent right away. the window itself follows on_recovery_delivered then
+    pub fn on_ack(
+        &mut self,
+        acked: usize,
+        snd_una: SeqNum,
+        snd_nxt: SeqNum,
+        now: SystemTime,
+    ) -> bool {
+        if !self.in_recovery {
+            let acked = if self.in_slow_start() {
+                match self.hystart.on_ack(acked, snd_una, snd_nxt) {
+                    Some(growth) => growth,
+                    None => {
+                        debug!(cwnd = self.cwnd(), "HyStart++: congestion avoidance");
+                        self.controller.exit_slow_start();
+                        acked
+                    }
+                }
+            } else {
+                acked
+            };
+            self.controller.on_ack(acked, now);
+            return false;
+        }
+        if self.recover.is_some_and(|recover| snd_una < recover) {
+            return true;
+        }
+        // full ACK: everything outstanding at the loss is acknowledged
+        self.in_recovery = false;
+        self.controller.on_recovery_exit();
+        false
+    }
+
+    // duplicate ACKs reached the threshold. returns false if they only repeat a loss
+    // already recovered from, in which case nothing should be resent (RFC 6582 section 4.1)
+    pub fn on_fast_retransmit(
+        &mut self,
+        flight_size: usize,
+        snd_una: SeqNum,
+        snd_nxt: SeqNum,
+    ) -> bool {
+        self.on_loss_detected(flight_size, snd_una, snd_nxt)
+    }
+
+    // reduce the window and enter fast recovery for a lost segment. returns false during
+    // recovery, or for a loss already recovered from
+    pub fn on_loss_detected(
+        &mut self,
+        flight_size: usize,
+        snd_una: SeqNum,
+        snd_nxt: SeqNum,
+    ) -> bool {
+        if self.in_recovery || self.recover.is_some_and(|recover| snd_una < recover) {
+            return false;
+        }
+        self.controller.on_loss(flight_size);
+        self.hystart.finish();
+        self.in_recovery = true;
+        self.recover = Some(snd_nxt);
+        self.recover_fs = flight_size;
+        self.prr_delivered = 0;
+        self.prr_out = 0;
+        true
+    }
+
+    // a tail loss probe repaired a loss on its own: reduce the window all the same
+    pub fn on_tail_loss(&mut self, flight_size: usize) {
+        self.controller.on_loss(flight_size);
+        self.hystart.finish();
+    }
+
+    // PRR: an ACK in fast recovery reported `delivered` bytes as received, `pipe` bytes are
+    // still in the network. while pipe exceeds ssthresh, sending keeps pace with delivery in
+    // the ratio ssthresh / RecoverFS; below it, it catches up to ssthresh at most one MSS
+    // ahead of delivery (the slow start reduction bound).
+    // the window is set so that exactly the allowed bytes fit next to the `flight_size`
+    // bytes sendable_size counts against it
+    pub fn on_recovery_delivered(&mut self, delivered: usize, pipe: usize, flight_size: usize) {
+        if !self.in_recovery {
+            return;
+        }
+        self.prr_delivered += delivered;
+        let ssthresh = self.ssthresh();
+        let sndcnt = if pipe > ssthresh {
+            (self.prr_delivered * ssthresh)
+                .div_ceil(cmp::max(self.recover_fs, 1))
+                .saturating_sub(self.prr_out)
+        } else {
+            let limit =
+                cmp::max(self.prr_delivered.saturating_sub(self.prr_out), delivered) + self.mss;
+            cmp::min(ssthresh - pipe, limit)
+        };
+        self.controller.set_cwnd(flight_size + sndcnt);
+    }
+
+    // `bytes` went out, new or resent
+    pub fn on_send(&mut self, bytes: usize) {
+        if self.in_recovery {
+            self.prr_out += bytes;
+        }
+    }
+
+    // the peer echoed congestion experienced marks: reduce the window as for a loss, but
+    // at most once per window of data and without resending anything (RFC 3168 section 6.1.2).
+    // returns false if the window was not reduced
+    pub fn on_congestion_experienced(
+        &mut self,
+        flight_size: usize,
+        snd_una: SeqNum,
+        snd_nxt: SeqNum,
+    ) -> bool {
+        if self.in_recovery || self.ecn_recover.is_some_and(|recover| snd_una < recover) {
+            return false;
+        }
+        self.controller.on_loss(flight_size);
+        self.hystart.finish();
+        self.ecn_recover = Some(snd_nxt);
+        true
+    }
+
+    pub fn on_timeout(&mut self, flight_size: usize) {
+        self.controller.on_timeout(flight_size);
+        self.hystart.finish();
+        self.in_recovery = false;
+    }
+
+    // keep the current state to return to if the next reduction turns out to be spurious
+    pub fn save_undo(&mut self) {
+        self.undo = Some(self.controller.snapshot());
+    }
+
+    // the reduction since save_undo was spurious
+    pub fn undo(&mut self) {
+        if let Some(controller) = self.undo.take() {
+            self.controller = controller;
+            self.in_recovery = false;
+        }
+    }
+
+    pub fn discard_undo(&mut self) {
+        self.undo = None;
+    }
+
+    pub fn on_rtt_sample(&mut self, rtt: Duration, now: SystemTime) {
+        if self.in_slow_start() {
+            self.hystart.on_rtt_sample(rtt);
+        }
+        self.controller.on_rtt_sample(rtt, now);
+    }
+
+    pub fn pacing_rate(&self) -> Option<f64> {
+        self.controller.pacing_rate()
+    }
+}
+
+// IW of RFC 5681 section 3.1
+fn initial_window(mss: usize) -> usize {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
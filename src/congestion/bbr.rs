@@ -0,0 +1,206 @@
+use super::{initial_window, CongestionControl};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+// 2/ln(2): the smallest gain that doubles the delivery rate every round in startup
+const HIGH_GAIN: f64 = 2.885;
+const CWND_GAIN: f64 = 2.0;
+// probe for more bandwidth, drain the queue it built, then cruise
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const BW_FILTER_ROUNDS: u64 = 10;
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+const MIN_CWND_SEGMENTS: usize = 4;
+// startup ends once three rounds in a row grow the bandwidth by less than 25%
+const FULL_BW_GROWTH: f64 = 1.25;
+const FULL_BW_ROUNDS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Startup,
+    Drain,
+    ProbeBw { cycle_index: usize },
+    ProbeRtt { until: SystemTime },
+}
+
+/// model-based control in the spirit of BBR: the window and the pacing rate follow the
+/// estimated bottleneck bandwidth and minimum RTT instead of reacting to losses
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    mss: usize,
+    cwnd: usize,
+    mode: Mode,
+    bw_samples: VecDeque<(u64, f64)>, // (round, bytes per second) over the last rounds
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Option<SystemTime>, // when min_rtt was last taken
+    round: u64,
+    round_start: Option<SystemTime>, // None until the first ACK
+    delivered_in_round: usize,
+    full_bw: f64,
+    full_bw_rounds: u8,
+}
+
+impl Bbr {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss),
+            mode: Mode::Startup,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: None,
+            round: 0,
+            round_start: None,
+            delivered_in_round: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+        }
+    }
+
+    // windowed max of the delivery rate samples, in bytes per second
+    fn bandwidth(&self) -> Option<f64> {
+        self.bw_samples.iter().map(|&(_, bw)| bw).reduce(f64::max)
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => HIGH_GAIN,
+            Mode::Drain => 1.0 / HIGH_GAIN,
+            Mode::ProbeBw { cycle_index } => PROBE_BW_GAINS[cycle_index],
+            Mode::ProbeRtt { .. } => 1.0,
+        }
+    }
+
+    fn min_cwnd(&self) -> usize {
+        MIN_CWND_SEGMENTS * self.mss
+    }
+
+    // bandwidth-delay product scaled by `gain`
+    fn target_cwnd(&self, gain: f64) -> Option<usize> {
+        let bdp = self.bandwidth()? * self.min_rtt?.as_secs_f64();
+        Some(((gain * bdp) as usize).max(self.min_cwnd()))
+    }
+
+    // a round trip has passed: take a delivery rate sample and move the state machine on
+    fn end_round(&mut self, round_start: SystemTime, now: SystemTime) {
+        let elapsed = now
+            .duration_since(round_start)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if elapsed > 0.0 {
+            let bw = self.delivered_in_round as f64 / elapsed;
+            self.bw_samples.push_back((self.round, bw));
+        }
+        while self
+            .bw_samples
+            .front()
+            .is_some_and(|&(round, _)| round + BW_FILTER_ROUNDS <= self.round)
+        {
+            self.bw_samples.pop_front();
+        }
+        self.round += 1;
+        self.round_start = Some(now);
+        self.delivered_in_round = 0;
+
+        let bw = self.bandwidth().unwrap_or(0.0);
+        match self.mode {
+            Mode::Startup => {
+                if bw >= self.full_bw * FULL_BW_GROWTH {
+                    self.full_bw = bw;
+                    self.full_bw_rounds = 0;
+                } else {
+                    self.full_bw_rounds += 1;
+                    if self.full_bw_rounds >= FULL_BW_ROUNDS {
+                        debug!(bw, "bbr: startup -> drain");
+                        self.mode = Mode::Drain;
+                    }
+                }
+            }
+            // one round at the inverse gain empties the queue startup built
+            Mode::Drain => self.mode = Mode::ProbeBw { cycle_index: 0 },
+            Mode::ProbeBw { cycle_index } => {
+                self.mode = Mode::ProbeBw {
+                    cycle_index: (cycle_index + 1) % PROBE_BW_GAINS.len(),
+                }
+            }
+            Mode::ProbeRtt { until } => {
+                if now >= until {
+                    self.min_rtt_stamp = Some(now);
+                    self.mode = Mode::ProbeBw { cycle_index: 0 };
+                }
+            }
+        }
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn set_cwnd(&mut self, cwnd: usize) {
+        self.cwnd = cwnd.max(self.min_cwnd());
+    }
+
+    // there is no slow start threshold, the model decides when startup ends
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+
+    fn snapshot(&self) -> Box<dyn CongestionControl> {
+        Box::new(self.clone())
+    }
+
+    fn on_ack(&mut self, acked: usize, now: SystemTime) {
+        self.delivered_in_round += acked;
+        let round_start = *self.round_start.get_or_insert(now);
+        let round_length = self.min_rtt.unwrap_or(Duration::from_millis(100));
+        if now.duration_since(round_start).unwrap_or_default() >= round_length {
+            self.end_round(round_start, now);
+        }
+        self.cwnd = match self.mode {
+            // grow exponentially until the pipe is full
+            Mode::Startup => self.cwnd + acked,
+            Mode::ProbeRtt { .. } => self.min_cwnd(),
+            _ => self.target_cwnd(CWND_GAIN).unwrap_or(self.cwnd + acked),
+        };
+    }
+
+    // losses are not a congestion signal here; fast recovery still conserves packets
+    fn on_loss(&mut self, _flight_size: usize) {}
+
+    fn on_timeout(&mut self, _flight_size: usize) {
+        self.cwnd = self.mss;
+    }
+
+    fn on_recovery_exit(&mut self) {
+        self.cwnd = self.target_cwnd(CWND_GAIN).unwrap_or(self.cwnd);
+    }
+
+    fn on_rtt_sample(&mut self, rtt: Duration, now: SystemTime) {
+        let expired = self
+            .min_rtt_stamp
+            .is_some_and(|stamp| now.duration_since(stamp).unwrap_or_default() >= MIN_RTT_WINDOW);
+        if self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt) {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = Some(now);
+        } else if expired && matches!(self.mode, Mode::ProbeBw { .. }) {
+            // shrink the window for a moment to see the path without our own queue
+            debug!("bbr: probe rtt");
+            self.min_rtt = Some(rtt);
+            self.mode = Mode::ProbeRtt {
+                until: now + PROBE_RTT_DURATION,
+            };
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        Some(self.pacing_gain() * self.bandwidth()?)
+    }
+
+    // startup ends by the bandwidth model instead
+    fn has_slow_start(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,117 @@
+use super::{initial_window, CongestionControl};
+use std::cmp;
+use std::time::SystemTime;
+
+const C: f64 = 0.4;
+const BETA: f64 = 0.7;
+// additive increase that makes the Reno-friendly estimate as fair as Reno (RFC 9438 section 4.3)
+const ALPHA: f64 = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+
+/// CUBIC window growth (RFC 9438). windows below are in segments unless noted.
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    mss: usize,
+    cwnd: usize,                     // bytes
+    ssthresh: usize,                 // bytes
+    w_max: f64,                      // window just before the last reduction
+    k: f64,                          // seconds from the epoch until the window is back at w_max
+    epoch_start: Option<SystemTime>, // start of the current congestion avoidance stage
+    w_est: f64,                      // window Reno would have reached in the same time
+}
+
+impl Cubic {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 0.0,
+        }
+    }
+
+    fn segments(&self) -> f64 {
+        self.cwnd as f64 / self.mss as f64
+    }
+
+    fn reduce(&mut self) {
+        let cwnd = self.segments();
+        // fast convergence: leave some room to flows that joined recently
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.ssthresh = cmp::max((self.cwnd as f64 * BETA) as usize, 2 * self.mss);
+        self.epoch_start = None;
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn set_cwnd(&mut self, cwnd: usize) {
+        self.cwnd = cwnd;
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn snapshot(&self) -> Box<dyn CongestionControl> {
+        Box::new(self.clone())
+    }
+
+    fn on_ack(&mut self, acked: usize, now: SystemTime) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += cmp::min(acked, self.mss);
+            return;
+        }
+        let cwnd = self.segments();
+        let epoch_start = match self.epoch_start {
+            Some(epoch_start) => epoch_start,
+            None => {
+                self.w_max = self.w_max.max(cwnd);
+                self.k = ((self.w_max - cwnd) / C).cbrt();
+                self.w_est = cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+        let t = now
+            .duration_since(epoch_start)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let w_cubic = C * (t - self.k).powi(3) + self.w_max;
+        let acked_segments = acked as f64 / self.mss as f64;
+        self.w_est += ALPHA * acked_segments / cwnd;
+        let next = if w_cubic < self.w_est {
+            // Reno-friendly region
+            self.w_est
+        } else {
+            let target = w_cubic.clamp(cwnd, 1.5 * cwnd);
+            cwnd + (target - cwnd) / cwnd * acked_segments
+        };
+        self.cwnd = cmp::max(self.cwnd, (next * self.mss as f64) as usize);
+    }
+
+    fn on_loss(&mut self, _flight_size: usize) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    // the cubic epoch starts from the current window
+    fn exit_slow_start(&mut self) {
+        self.ssthresh = self.cwnd;
+        self.epoch_start = None;
+    }
+
+    fn on_timeout(&mut self, _flight_size: usize) {
+        self.reduce();
+        self.cwnd = self.mss;
+    }
+}
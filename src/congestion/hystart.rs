@@ -0,0 +1,108 @@
+use crate::seq::SeqNum;
+use std::time::Duration;
+use tracing::debug;
+
+const MIN_RTT_THRESH: Duration = Duration::from_millis(4);
+const MAX_RTT_THRESH: Duration = Duration::from_millis(16);
+const MIN_RTT_DIVISOR: u32 = 8;
+// samples a round needs before its minimum RTT is trusted
+const N_RTT_SAMPLE: u32 = 8;
+const CSS_GROWTH_DIVISOR: usize = 4;
+const CSS_ROUNDS: u32 = 5;
+
+/// HyStart++ (RFC 9406): leaves slow start once the round-trip time shows a queue building
+/// up, rather than once the queue overflows. conservative slow start (CSS) grows the window
+/// at a quarter of the rate for a few rounds first, in case the RTT increase was a fluke.
+#[derive(Debug, Clone)]
+pub struct HyStart {
+    window_end: Option<SeqNum>, // the current round ends once this is acknowledged
+    last_round_min_rtt: Option<Duration>,
+    current_round_min_rtt: Option<Duration>,
+    rtt_sample_count: u32,
+    css_baseline_min_rtt: Option<Duration>, // set while in CSS
+    css_rounds: u32,
+    css_acked: usize, // acknowledged in CSS but not yet turned into growth
+    done: bool,       // slow start is over for good
+}
+
+impl HyStart {
+    pub fn new() -> Self {
+        Self {
+            window_end: None,
+            last_round_min_rtt: None,
+            current_round_min_rtt: None,
+            rtt_sample_count: 0,
+            css_baseline_min_rtt: None,
+            css_rounds: 0,
+            css_acked: 0,
+            done: false,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // a loss or congestion mark set ssthresh, which ends slow start by itself
+    pub fn finish(&mut self) {
+        self.done = true;
+    }
+
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        if self.done {
+            return;
+        }
+        let current = self
+            .current_round_min_rtt
+            .map_or(rtt, |current| current.min(rtt));
+        self.current_round_min_rtt = Some(current);
+        self.rtt_sample_count += 1;
+        if self.rtt_sample_count < N_RTT_SAMPLE {
+            return;
+        }
+        match (self.css_baseline_min_rtt, self.last_round_min_rtt) {
+            (None, Some(last)) => {
+                let thresh = (last / MIN_RTT_DIVISOR).clamp(MIN_RTT_THRESH, MAX_RTT_THRESH);
+                if current >= last + thresh {
+                    debug!(rtt = ?current, "HyStart++: conservative slow start");
+                    self.css_baseline_min_rtt = Some(current);
+                    self.css_rounds = 0;
+                }
+            }
+            // the delay went away again: back to slow start
+            (Some(baseline), _) if current < baseline => {
+                debug!(rtt = ?current, "HyStart++: back to slow start");
+                self.css_baseline_min_rtt = None;
+            }
+            _ => {}
+        }
+    }
+
+    // an ACK in slow start moved SND.UNA. turns `acked` bytes into the growth slow start
+    // should apply, None once CSS has lasted long enough to continue in congestion avoidance
+    pub fn on_ack(&mut self, acked: usize, snd_una: SeqNum, snd_nxt: SeqNum) -> Option<usize> {
+        if self
+            .window_end
+            .is_none_or(|window_end| snd_una >= window_end)
+        {
+            // a new round
+            self.window_end = Some(snd_nxt);
+            self.last_round_min_rtt = self.current_round_min_rtt.take();
+            self.rtt_sample_count = 0;
+            if self.css_baseline_min_rtt.is_some() {
+                self.css_rounds += 1;
+                if self.css_rounds >= CSS_ROUNDS {
+                    self.done = true;
+                    return None;
+                }
+            }
+        }
+        if self.css_baseline_min_rtt.is_none() {
+            return Some(acked);
+        }
+        self.css_acked += acked;
+        let growth = self.css_acked / CSS_GROWTH_DIVISOR;
+        self.css_acked %= CSS_GROWTH_DIVISOR;
+        Some(growth)
+    }
+}
@@ -0,0 +1,75 @@
+use super::{initial_window, CongestionControl};
+use std::cmp;
+use std::time::SystemTime;
+
+/// slow start and congestion avoidance of RFC 5681
+#[derive(Debug, Clone)]
+pub struct Reno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    bytes_acked: usize, // counted toward the next one-MSS increase in congestion avoidance
+}
+
+impl Reno {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            bytes_acked: 0,
+        }
+    }
+
+    fn reduced_ssthresh(&self, flight_size: usize) -> usize {
+        cmp::max(flight_size / 2, 2 * self.mss)
+    }
+}
+
+impl CongestionControl for Reno {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn set_cwnd(&mut self, cwnd: usize) {
+        self.cwnd = cwnd;
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn snapshot(&self) -> Box<dyn CongestionControl> {
+        Box::new(self.clone())
+    }
+
+    fn on_ack(&mut self, acked: usize, _now: SystemTime) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += cmp::min(acked, self.mss);
+            return;
+        }
+        // about one MSS per round trip
+        self.bytes_acked += acked;
+        if self.bytes_acked >= self.cwnd {
+            self.bytes_acked -= self.cwnd;
+            self.cwnd += self.mss;
+        }
+    }
+
+    fn on_loss(&mut self, flight_size: usize) {
+        self.ssthresh = self.reduced_ssthresh(flight_size);
+        self.cwnd = self.ssthresh;
+        self.bytes_acked = 0;
+    }
+
+    fn exit_slow_start(&mut self) {
+        self.ssthresh = self.cwnd;
+    }
+
+    // start over from one segment
+    fn on_timeout(&mut self, flight_size: usize) {
+        self.ssthresh = self.reduced_ssthresh(flight_size);
+        self.cwnd = self.mss;
+        self.bytes_acked = 0;
+    }
+}
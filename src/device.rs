@@ -0,0 +1,655 @@
+use crate::arp::{ArpCache, ArpPacket, MacAddr, Resolution, BROADCAST};
+#[cfg(target_os = "linux")]
+use crate::bpf;
+#[cfg(target_os = "linux")]
+use crate::io::{RawIpSocket, Received};
+use crate::ip;
+#[cfg(target_os = "macos")]
+pub use crate::macos::BpfDevice;
+use crate::route::RoutingTable;
+#[cfg(feature = "io_uring")]
+pub use crate::uring::UringSocket;
+#[cfg(windows)]
+pub use crate::windows::NpcapDevice;
+#[cfg(feature = "xdp")]
+use crate::xdp::XdpSocket;
+use anyhow::{Context, Result};
+use pnet::datalink;
+#[cfg(target_os = "linux")]
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(target_os = "linux")]
+use std::io::{Read, Write};
+#[cfg(target_os = "linux")]
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+#[cfg(target_os = "linux")]
+const TUN_CLONE_DEVICE: &str = "/dev/net/tun";
+
+/// where the stack's IPv4 packets go out and come in, picked when the `TCP` is made. the IP
+/// header is built by the stack whichever the device, so TTL, ECN field and source address
+/// are the socket's own
+pub trait NetworkDevice: Send + Sync {
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()>;
+
+    // waits for the next IPv4 packet for us, header included
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    // `packets` in order, in as few system calls as the device can
+    fn send_packets(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        for packet in packets {
+            self.send_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    // waits for at least one packet, then takes as many as are there up to one per buffer.
+    // returns how many, their lengths in `lens`
+    fn recv_packets(&self, buffers: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        lens[0] = self.recv_packet(buffers[0])?;
+        Ok(1)
+    }
+
+    // only TCP segments to `ports` have to come in from now on. devices that can't filter
+    // deliver everything, the stack drops what isn't for it anyway
+    fn filter_ports(&self, _ports: &[u16]) -> io::Result<()> {
+        Ok(())
+    }
+
+    // the device for sockets bound to the interface `ifname`, None if this one will do
+    fn bound_to(&self, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("the device can't be bound to {}", ifname),
+        )
+        .into())
+    }
+}
+
+// a device on one interface only can't be bound to another
+fn on_interface(name: &str, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+    if name == ifname {
+        Ok(None)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the stack runs on {} only", name),
+        )
+        .into())
+    }
+}
+
+/// IP_HDRINCL raw socket to send, and raw sockets of TCP and ICMP to receive. packets go
+/// in and out in batches, one sendmmsg(2) or recvmmsg(2) for as many as there are
+#[cfg(target_os = "linux")]
+pub struct RawSocket {
+    sender: RawIpSocket,
+    // TCP and ICMP, none for a sender bound to a device
+    receivers: Vec<RawIpSocket>,
+}
+
+#[cfg(target_os = "linux")]
+impl RawSocket {
+    pub fn open() -> Result<Self> {
+        let receivers = [libc::IPPROTO_TCP, libc::IPPROTO_ICMP]
+            .into_iter()
+            .map(RawIpSocket::open)
+            .collect::<io::Result<_>>()
+            .context("failed to open raw socket")?;
+        Ok(Self {
+            sender: RawIpSocket::open(libc::IPPROTO_RAW).context("failed to open raw socket")?,
+            receivers,
+        })
+    }
+
+    // SO_BINDTODEVICE: sends out of `ifname` whatever the route says, and receives nothing
+    pub fn bound_to(ifname: &str) -> Result<Self> {
+        let sender = RawIpSocket::open(libc::IPPROTO_RAW).context("failed to open raw socket")?;
+        sender
+            .bind_to_device(ifname)
+            .context("failed to bind to device")?;
+        Ok(Self {
+            sender,
+            receivers: Vec::new(),
+        })
+    }
+
+    // SO_SNDBUF of the sender and SO_RCVBUF of the receivers
+    pub fn set_buffer_sizes(&self, send: usize, recv: usize) -> io::Result<()> {
+        self.sender.set_send_buffer_size(send)?;
+        for receiver in &self.receivers {
+            receiver.set_recv_buffer_size(recv)?;
+        }
+        Ok(())
+    }
+
+    // for the errors queued about sent packets, see RawIpSocket::set_recv_errors
+    pub fn sender(&self) -> &RawIpSocket {
+        &self.sender
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkDevice for RawSocket {
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()> {
+        self.sender.send(packet)
+    }
+
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        self.recv_packets(&mut [buffer], std::slice::from_mut(&mut len))?;
+        Ok(len)
+    }
+
+    fn send_packets(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        let mut sent = 0;
+        while sent < packets.len() {
+            sent += self.sender.send_batch(&packets[sent..])?;
+        }
+        Ok(())
+    }
+
+    // a BPF filter on the TCP socket, ICMP errors come in whatever port they quote
+    fn filter_ports(&self, ports: &[u16]) -> io::Result<()> {
+        match self.receivers.first() {
+            Some(fd) => bpf::attach_port_filter(fd.as_raw_fd(), ports),
+            None => Ok(()),
+        }
+    }
+
+    fn recv_packets(&self, buffers: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        if self.receivers.is_empty() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        loop {
+            let mut fds: Vec<_> = self
+                .receivers
+                .iter()
+                .map(|fd| libc::pollfd {
+                    fd: fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            let mut received = vec![Received::default(); buffers.len()];
+            let mut count = 0;
+            for (receiver, pollfd) in self.receivers.iter().zip(&fds) {
+                if pollfd.revents != 0 && count < buffers.len() {
+                    count += receiver.recv_batch(&mut buffers[count..], &mut received[count..])?;
+                }
+            }
+            if count > 0 {
+                for (len, received) in lens.iter_mut().zip(&received).take(count) {
+                    *len = received.len;
+                }
+                return Ok(count);
+            }
+        }
+    }
+
+    // SO_BINDTODEVICE on a sender of its own
+    fn bound_to(&self, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+        Ok(Some(Arc::new(RawSocket::bound_to(ifname)?)))
+    }
+}
+
+// where the frames of an EthernetDevice go out and come in
+enum Frames {
+    // an AF_PACKET socket, which sees the frames the host sends too
+    #[cfg(target_os = "linux")]
+    Packet(OwnedFd),
+    // a TAP device, all of whose frames are for the stack
+    #[cfg(target_os = "linux")]
+    Tap(OwnedFd),
+    #[cfg(feature = "xdp")]
+    Xdp(Box<XdpSocket>),
+    #[cfg(target_os = "macos")]
+    Bpf(BpfDevice),
+    #[cfg(windows)]
+    Npcap(NpcapDevice),
+}
+
+/// Ethernet frames of an AF_PACKET socket bound to one interface, of a TAP device, of an
+/// AF_XDP socket, of a BPF device on macOS or of npcap on Windows. the stack builds the
+/// whole frame and resolves next hops itself
+pub struct EthernetDevice {
+    frames: Frames,
+    name: String,
+    mac: MacAddr,
+    addrs: Vec<Ipv4Addr>,
+    // next hops, as of opening
+    routes: RoutingTable,
+    arp: Mutex<ArpCache>,
+}
+
+impl EthernetDevice {
+    // the stack runs where raw IP sockets are restricted, but shares the interface with the
+    // kernel: the kernel still sees the segments and resets those of ports it doesn't know,
+    // unless a firewall rule drops them
+    #[cfg(target_os = "linux")]
+    pub fn packet_socket(ifname: &str) -> Result<Self> {
+        let (index, mac, addrs) = interface(ifname)?;
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as libc::c_int) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("failed to open packet socket");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = index as libc::c_int;
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error()).context("failed to bind packet socket");
+        }
+        Ok(Self {
+            frames: Frames::Packet(fd),
+            name: ifname.to_string(),
+            mac,
+            addrs,
+            routes: RoutingTable::discover(),
+            arp: Mutex::new(ArpCache::new()),
+        })
+    }
+
+    // the stack is a host of its own on the link of the TAP device `name`, at `addr`, apart
+    // from the kernel's stack, which sits on the other side of the device
+    #[cfg(target_os = "linux")]
+    pub fn tap(
+        name: &str,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+    ) -> Result<Self> {
+        let file = open_tun(name, libc::IFF_TAP)?;
+        // a random unicast address, locally administered
+        let mut mac: MacAddr = rand::random();
+        mac[0] = (mac[0] & 0xfe) | 0x02;
+        Ok(Self {
+            frames: Frames::Tap(file.into()),
+            name: name.to_string(),
+            mac,
+            addrs: vec![addr],
+            routes: RoutingTable::on_link(name, addr, prefix_len, gateway),
+            arp: Mutex::new(ArpCache::new()),
+        })
+    }
+
+    // the frames arriving on queue `queue_id` of the interface `ifname`, all of them, ARP
+    // included: the kernel doesn't see them anymore, so it is meant for an interface set
+    // aside for benchmarks. the driver fills the frames in place if it can (zero-copy). a
+    // veth peer has to have checksum offload off, or its segments arrive unchecksummed
+    #[cfg(feature = "xdp")]
+    pub fn xdp(ifname: &str, queue_id: u32) -> Result<Self> {
+        let (index, mac, addrs) = interface(ifname)?;
+        Ok(Self {
+            frames: Frames::Xdp(Box::new(XdpSocket::open(index, queue_id)?)),
+            name: ifname.to_string(),
+            mac,
+            addrs,
+            routes: RoutingTable::discover(),
+            arp: Mutex::new(ArpCache::new()),
+        })
+    }
+
+    // the frames of the interface `ifname` on macOS, where raw IP sockets never see TCP. as
+    // with a packet socket the kernel resets the segments of ports it doesn't know, unless a
+    // pf rule blocks them
+    #[cfg(target_os = "macos")]
+    pub fn bpf(ifname: &str) -> Result<Self> {
+        let (_, mac, addrs) = interface(ifname)?;
+        Ok(Self {
+            frames: Frames::Bpf(BpfDevice::open(ifname)?),
+            name: ifname.to_string(),
+            mac,
+            addrs,
+            routes: RoutingTable::discover(),
+            arp: Mutex::new(ArpCache::new()),
+        })
+    }
+
+    // the frames of the adapter `ifname` on Windows, which has no raw sockets for TCP. the
+    // host's stack still resets the segments of ports it doesn't know, unless the firewall
+    // drops them
+    #[cfg(windows)]
+    pub fn npcap(ifname: &str) -> Result<Self> {
+        let (_, mac, addrs) = interface(ifname)?;
+        Ok(Self {
+            frames: Frames::Npcap(NpcapDevice::open(ifname)?),
+            name: ifname.to_string(),
+            mac,
+            addrs,
+            routes: RoutingTable::discover(),
+            arp: Mutex::new(ArpCache::new()),
+        })
+    }
+
+    // None for a frame that is not for us
+    fn recv_frame(&self, frame: &mut [u8]) -> io::Result<Option<usize>> {
+        let len = match &self.frames {
+            #[cfg(target_os = "linux")]
+            Frames::Packet(fd) => {
+                let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+                let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+                let len = unsafe {
+                    libc::recvfrom(
+                        fd.as_raw_fd(),
+                        frame.as_mut_ptr() as *mut libc::c_void,
+                        frame.len(),
+                        0,
+                        &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                        &mut addr_len,
+                    )
+                };
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // our own frames come back as outgoing, and others' are not for us
+                let for_us = matches!(addr.sll_pkttype, libc::PACKET_HOST | libc::PACKET_BROADCAST);
+                return Ok(for_us.then_some(len as usize));
+            }
+            #[cfg(target_os = "linux")]
+            Frames::Tap(fd) => {
+                let len = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        frame.as_mut_ptr() as *mut libc::c_void,
+                        frame.len(),
+                    )
+                };
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                len as usize
+            }
+            #[cfg(feature = "xdp")]
+            Frames::Xdp(xdp) => xdp.recv(frame)?,
+            #[cfg(target_os = "macos")]
+            Frames::Bpf(bpf) => bpf.recv(frame)?,
+            #[cfg(windows)]
+            Frames::Npcap(npcap) => npcap.recv(frame)?,
+        };
+        let for_us =
+            len >= ETHERNET_HEADER_LEN && (frame[..6] == self.mac || frame[..6] == BROADCAST);
+        Ok(for_us.then_some(len))
+    }
+
+    fn process_arp(&self, payload: &[u8]) {
+        let arp = match ArpPacket::parse(payload) {
+            Some(arp) => arp,
+            None => return,
+        };
+        let (reply, released) = self
+            .arp
+            .lock()
+            .unwrap()
+            .process(&arp, self.mac, &self.addrs);
+        if let Some(reply) = reply {
+            if let Err(error) = self.send_frame(reply.target_mac, ETHERTYPE_ARP, &reply.to_bytes())
+            {
+                warn!(%error, "failed to send ARP reply");
+            }
+        }
+        for packet in released {
+            if let Err(error) = self.send_frame(arp.sender_mac, ETHERTYPE_IPV4, &packet) {
+                warn!(%error, "failed to send packet held for ARP");
+            }
+        }
+    }
+
+    fn send_frame(&self, dst: MacAddr, ethertype: u16, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        match &self.frames {
+            #[cfg(target_os = "linux")]
+            Frames::Packet(fd) | Frames::Tap(fd) => {
+                let sent = unsafe {
+                    libc::write(
+                        fd.as_raw_fd(),
+                        frame.as_ptr() as *const libc::c_void,
+                        frame.len(),
+                    )
+                };
+                if sent < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            #[cfg(feature = "xdp")]
+            Frames::Xdp(xdp) => xdp.send(&frame),
+            #[cfg(target_os = "macos")]
+            Frames::Bpf(bpf) => bpf.send(&frame),
+            #[cfg(windows)]
+            Frames::Npcap(npcap) => npcap.send(&frame),
+        }
+    }
+}
+
+impl NetworkDevice for EthernetDevice {
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()> {
+        let dst = ip::destination(packet).ok_or(io::ErrorKind::InvalidInput)?;
+        if dst.is_broadcast() {
+            return self.send_frame(BROADCAST, ETHERTYPE_IPV4, packet);
+        }
+        let next_hop = self
+            .routes
+            .lookup(dst)
+            .and_then(|route| route.gateway)
+            .unwrap_or(dst);
+        let resolution = self.arp.lock().unwrap().resolve(next_hop, packet);
+        match resolution {
+            Resolution::Resolved(mac) => self.send_frame(mac, ETHERTYPE_IPV4, packet),
+            Resolution::Queued { request: true } => {
+                // asked from the address the packet comes from, if it is one of ours
+                let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+                let src = match self.addrs.first() {
+                    Some(&first) if !self.addrs.contains(&src) => first,
+                    _ => src,
+                };
+                let request = ArpPacket::request(self.mac, src, next_hop);
+                self.send_frame(BROADCAST, ETHERTYPE_ARP, &request.to_bytes())
+            }
+            Resolution::Queued { request: false } => Ok(()),
+        }
+    }
+
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + buffer.len()];
+        loop {
+            let len = match self.recv_frame(&mut frame) {
+                Ok(Some(len)) if len >= ETHERNET_HEADER_LEN => len,
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            };
+            let payload = &frame[ETHERNET_HEADER_LEN..len];
+            match u16::from_be_bytes([frame[12], frame[13]]) {
+                ETHERTYPE_ARP => self.process_arp(payload),
+                ETHERTYPE_IPV4 => {
+                    // short frames are padded to the minimum length of Ethernet
+                    let len = ip::parse(payload).map_or(payload.len(), |header| header.total_len);
+                    buffer[..len].copy_from_slice(&payload[..len]);
+                    return Ok(len);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn bound_to(&self, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+        on_interface(&self.name, ifname)
+    }
+}
+
+/// a TUN device: the kernel routes IPv4 packets to the stack through it as to another host,
+/// whose segments it never mistakes for its own
+#[cfg(target_os = "linux")]
+pub struct TunDevice {
+    file: File,
+    name: String,
+}
+
+#[cfg(target_os = "linux")]
+impl TunDevice {
+    pub fn open(name: &str) -> Result<Self> {
+        Ok(Self {
+            file: open_tun(name, libc::IFF_TUN)?,
+            name: name.to_string(),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl NetworkDevice for TunDevice {
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()> {
+        (&self.file).write_all(packet)
+    }
+
+    // whatever the kernel routes to the device, IPv6 too
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buffer)
+    }
+
+    fn bound_to(&self, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+        on_interface(&self.name, ifname)
+    }
+}
+
+/// one end of a link in memory to another `TCP` of the same process, for tests, or back to
+/// the same one: the packets sent on one end are received on the other as they are
+pub struct MemoryDevice {
+    sender: Mutex<mpsc::Sender<Vec<u8>>>,
+    receiver: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl MemoryDevice {
+    pub fn pair() -> (Self, Self) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        let end = |sender, receiver| Self {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        };
+        (end(a_sender, a_receiver), end(b_sender, b_receiver))
+    }
+
+    // the next packet from the other end if one comes within `timeout`, for tests that
+    // play the other end themselves
+    pub fn recv_packet_timeout(
+        &self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<usize>> {
+        let packet = match self.receiver.lock().unwrap().recv_timeout(timeout) {
+            Ok(packet) => packet,
+            Err(mpsc::RecvTimeoutError::Timeout) => return Ok(None),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(io::ErrorKind::BrokenPipe.into())
+            }
+        };
+        let len = packet.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&packet[..len]);
+        Ok(Some(len))
+    }
+
+    // a link to itself: the packets sent are received on the same end
+    pub fn looped() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl NetworkDevice for MemoryDevice {
+    // dropped once the other end is gone, like on a link that is down
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()> {
+        let _ = self.sender.lock().unwrap().send(packet.to_vec());
+        Ok(())
+    }
+
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .receiver
+            .lock()
+            .unwrap()
+            .recv()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let len = packet.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+}
+
+// index, MAC address and IPv4 addresses of the interface `ifname`
+fn interface(ifname: &str) -> Result<(u32, MacAddr, Vec<Ipv4Addr>)> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == ifname)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such device"))?;
+    let mac = interface
+        .mac
+        .map(|mac| [mac.0, mac.1, mac.2, mac.3, mac.4, mac.5])
+        .context("the device has no MAC address")?;
+    let addrs = interface
+        .ips
+        .iter()
+        .filter_map(|network| match network.ip() {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    Ok((interface.index, mac, addrs))
+}
+
+// attach to the TUN or TAP device `name` (`kind` IFF_TUN or IFF_TAP), without the packet
+// information header. the device is created if it doesn't exist, which takes CAP_NET_ADMIN;
+// one set up beforehand for the user (`ip tuntap add dev tun0 mode tun user $USER`) doesn't
+#[cfg(target_os = "linux")]
+fn open_tun(name: &str, kind: libc::c_int) -> Result<File> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "device name too long").into());
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(TUN_CLONE_DEVICE)
+        .context("failed to open tun clone device")?;
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    request.ifr_ifru.ifru_flags = (kind | libc::IFF_NO_PI) as libc::c_short;
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut request) };
+    if result < 0 {
+        return Err(io::Error::last_os_error()).context("failed to attach to tun device");
+    }
+    Ok(file)
+}
@@ -0,0 +1,271 @@
+use crate::device::NetworkDevice;
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// a packet held back to be overtaken goes out on its own after this if nothing follows
+const REORDER_HOLD: Duration = Duration::from_millis(50);
+
+/// what a `FaultyDevice` does to the packets it sends, each the probability (0 to 1) of it
+/// happening to a packet. all zero passes everything through as it is
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Faults {
+    pub drop: f64,
+    pub duplicate: f64,
+    // held back until the next packet has gone out
+    pub reorder: f64,
+    // one bit flipped anywhere in the packet, which the checksums should catch
+    pub corrupt: f64,
+    // held back for up to `max_delay`, uniformly
+    pub delay: f64,
+    pub max_delay: Duration,
+}
+
+/// another device behind a lossy network of its own: the packets it sends are dropped,
+/// duplicated, reordered, corrupted or delayed at random, the same way every time for the
+/// same seed and sequence of packets. packets coming in are left alone; wrapping both ends
+/// of a `MemoryDevice` pair impairs both directions
+pub struct FaultyDevice<D: NetworkDevice> {
+    shared: Arc<Shared<D>>,
+    faults: Faults,
+}
+
+struct Shared<D> {
+    inner: D,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    rng: StdRng,
+    // ordered by release, then by when they were delayed
+    delayed: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    delayed_count: u64,
+    held: Option<(Instant, Vec<u8>)>,
+    closed: bool,
+}
+
+impl<D: NetworkDevice + 'static> FaultyDevice<D> {
+    pub fn new(inner: D, faults: Faults, seed: u64) -> Self {
+        for p in [
+            faults.drop,
+            faults.duplicate,
+            faults.reorder,
+            faults.corrupt,
+            faults.delay,
+        ] {
+            assert!((0.0..=1.0).contains(&p), "not a probability: {}", p);
+        }
+        let shared = Arc::new(Shared {
+            inner,
+            state: Mutex::new(State {
+                rng: StdRng::seed_from_u64(seed),
+                delayed: BinaryHeap::new(),
+                delayed_count: 0,
+                held: None,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let cloned_shared = shared.clone();
+        thread::spawn(move || {
+            // releases the delayed and held back packets
+            cloned_shared.release();
+        });
+        Self { shared, faults }
+    }
+
+    // what goes out now in place of `packet`
+    fn impair(&self, packet: &[u8]) -> Vec<Vec<u8>> {
+        let faults = &self.faults;
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+        if state.rng.gen_bool(faults.drop) {
+            return Vec::new();
+        }
+        let mut packet = packet.to_vec();
+        if state.rng.gen_bool(faults.corrupt) && !packet.is_empty() {
+            let bit = state.rng.gen_range(0..packet.len() * 8);
+            packet[bit / 8] ^= 1 << (bit % 8);
+        }
+        let copies = if state.rng.gen_bool(faults.duplicate) {
+            2
+        } else {
+            1
+        };
+        if state.rng.gen_bool(faults.delay) {
+            let release = Instant::now() + state.rng.gen_range(Duration::ZERO..=faults.max_delay);
+            for _ in 0..copies {
+                state.delayed_count += 1;
+                state
+                    .delayed
+                    .push(Reverse((release, state.delayed_count, packet.clone())));
+            }
+            self.shared.changed.notify_one();
+            return Vec::new();
+        }
+        if state.held.is_none() && state.rng.gen_bool(faults.reorder) {
+            state.held = Some((Instant::now() + REORDER_HOLD, packet));
+            self.shared.changed.notify_one();
+            return Vec::new();
+        }
+        let mut packets = vec![packet; copies];
+        packets.extend(state.held.take().map(|(_, held)| held));
+        packets
+    }
+}
+
+impl<D: NetworkDevice> Shared<D> {
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed {
+            let now = Instant::now();
+            let mut due = Vec::new();
+            while let Some(Reverse((release, _, _))) = state.delayed.peek() {
+                if *release > now {
+                    break;
+                }
+                let Reverse((_, _, packet)) = state.delayed.pop().unwrap();
+                due.push(packet);
+            }
+            if state
+                .held
+                .as_ref()
+                .is_some_and(|(release, _)| *release <= now)
+            {
+                due.extend(state.held.take().map(|(_, held)| held));
+            }
+            if !due.is_empty() {
+                drop(state);
+                if let Err(error) = self.inner.send_packets(&due) {
+                    warn!(%error, "failed to send delayed packets");
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            let next = state
+                .delayed
+                .peek()
+                .map(|Reverse((release, _, _))| *release)
+                .into_iter()
+                .chain(state.held.as_ref().map(|(release, _)| *release))
+                .min();
+            state = match next {
+                Some(next) => {
+                    let timeout = next.saturating_duration_since(now);
+                    self.changed.wait_timeout(state, timeout).unwrap().0
+                }
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl<D: NetworkDevice> Drop for FaultyDevice<D> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+impl<D: NetworkDevice + 'static> NetworkDevice for FaultyDevice<D> {
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()> {
+        self.shared.inner.send_packets(&self.impair(packet))
+    }
+
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.shared.inner.recv_packet(buffer)
+    }
+
+    fn send_packets(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        let packets: Vec<Vec<u8>> = packets
+            .iter()
+            .flat_map(|packet| self.impair(packet))
+            .collect();
+        self.shared.inner.send_packets(&packets)
+    }
+
+    fn recv_packets(&self, buffers: &mut [&mut [u8]], lens: &mut [usize]) -> io::Result<usize> {
+        self.shared.inner.recv_packets(buffers, lens)
+    }
+
+    fn filter_ports(&self, ports: &[u16]) -> io::Result<()> {
+        self.shared.inner.filter_ports(ports)
+    }
+
+    // a socket bound to another device than the inner one sends without impairment
+    fn bound_to(&self, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+        self.shared.inner.bound_to(ifname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Faults, FaultyDevice};
+    use crate::device::MemoryDevice;
+    use crate::route::RoutingTable;
+    use crate::tcp::TCP;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::thread;
+
+    const CLIENT_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SERVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const PORT: u16 = 8080;
+
+    #[test]
+    fn data_survives_a_lossy_link() {
+        let faults = Faults {
+            drop: 0.05,
+            duplicate: 0.05,
+            reorder: 0.1,
+            ..Faults::default()
+        };
+        let (client_device, server_device) = MemoryDevice::pair();
+        let client = TCP::with_device(
+            FaultyDevice::new(client_device, faults, 1),
+            RoutingTable::on_link("fault0", CLIENT_ADDR, 24, None),
+        );
+        let server = TCP::with_device(
+            FaultyDevice::new(server_device, faults, 2),
+            RoutingTable::on_link("fault1", SERVER_ADDR, 24, None),
+        );
+        let listener = server
+            .listen(SocketAddr::from((SERVER_ADDR, PORT)), 8)
+            .unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let sent = data.clone();
+        let sender = thread::spawn(move || {
+            let sock_id = client.connect((SERVER_ADDR, PORT)).unwrap();
+            let mut offset = 0;
+            while offset < sent.len() {
+                offset += client.send(sock_id, &sent[offset..]).unwrap();
+            }
+            client.close(sock_id).unwrap();
+        });
+        let sock_id = server.accept(listener).unwrap();
+        let mut received = Vec::new();
+        let mut buffer = vec![0; 4096];
+        loop {
+            match server.recv(sock_id, &mut buffer).unwrap() {
+                0 => break,
+                n => received.extend_from_slice(&buffer[..n]),
+            }
+        }
+        // the client's close waits for our FIN
+        server.close(sock_id).unwrap();
+        sender.join().unwrap();
+        assert!(
+            received == data,
+            "{} of {} bytes",
+            received.len(),
+            data.len()
+        );
+    }
+}
@@ -0,0 +1,530 @@
+use crate::clock::VirtualClock;
+use crate::device::MemoryDevice;
+use crate::ip;
+use crate::mptcp::MptcpOption;
+use crate::packet::TCPPacket;
+use crate::route::RoutingTable;
+use crate::seq::SeqNum;
+use crate::socket::SockID;
+use crate::sockopt::{BufferSizes, SocketOption};
+use crate::tcp::TCP;
+use crate::tcpflags;
+use crate::tcpoption::TcpOption;
+use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+use pnet::util;
+use std::net::{Ipv4Addr, Shutdown};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+// the stack under test, and the peer the inputs play
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const LOCAL_PORT: u16 = 8080;
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const REMOTE_PORT: u16 = 40000;
+const CONNECTION: SockID = SockID(LOCAL_ADDR, REMOTE_ADDR, LOCAL_PORT, REMOTE_PORT);
+// close below the wrap, so that sequence numbers of the peer wrap around early on
+const REMOTE_ISS: u32 = 0xffff_ff00;
+const TCP_HEADER_LEN: usize = 20;
+const BACKLOG: usize = 8;
+// what an established connection has in flight when the segments of the input come in
+const QUEUED_DATA: usize = 4000;
+
+/// the state a connection is brought into before the segments of an input reach it,
+/// picked by the first byte of the input
+#[derive(Debug, Clone, Copy)]
+enum Scenario {
+    Listen,
+    SynSent,
+    SynRcvd,
+    ActiveEstablished,
+    PassiveEstablished,
+    FinWait1,
+    CloseWait,
+    LastAck,
+}
+
+const SCENARIOS: [Scenario; 8] = [
+    Scenario::Listen,
+    Scenario::SynSent,
+    Scenario::SynRcvd,
+    Scenario::ActiveEstablished,
+    Scenario::PassiveEstablished,
+    Scenario::FinWait1,
+    Scenario::CloseWait,
+    Scenario::LastAck,
+];
+
+// the bits of the first byte above the scenario
+const ECN: u8 = 0x08;
+const SYN_COOKIES: u8 = 0x10;
+// timestamps, SACK and window scaling offered in the handshake
+const OPTIONS: u8 = 0x20;
+const SEND_DATA: u8 = 0x40;
+// MP_CAPABLE offered in the handshake, to a listener that takes Multipath TCP
+const MULTIPATH: u8 = 0x80;
+const REMOTE_KEY: u64 = 0x0123_4567_89ab_cdef;
+
+/// a stack of its own on a virtual clock that every input runs against, emptied in
+/// between: one per input would leave its receive threads behind
+struct Harness {
+    tcp: Arc<TCP>,
+    peer: MemoryDevice,
+    clock: Arc<VirtualClock>,
+    // of the connection, once the stack has sent its SYN
+    local_iss: SeqNum,
+    local_key: Option<u64>,
+}
+
+static HARNESS: OnceLock<Mutex<Harness>> = OnceLock::new();
+
+// a TCP segment, header and payload, or an IPv4 packet carrying one, parsed and taken
+// apart every way the receive path does. mustn't panic whatever the bytes are
+#[doc(hidden)]
+pub fn parse_segment(data: &[u8]) {
+    let segment = match ip::parse(data) {
+        Some(header) => &data[header.header_len..header.total_len],
+        None => data,
+    };
+    let packet = match TcpPacket::new(segment) {
+        Some(tcp_packet) => TCPPacket::from(tcp_packet),
+        None => return,
+    };
+    packet.get_options();
+    packet.get_mptcp_option();
+    packet.get_timestamps();
+    packet.get_urgent_pointer();
+    packet.segment_len();
+    packet.is_correct_checksum(LOCAL_ADDR, REMOTE_ADDR);
+    let _ = format!("{:?}", packet);
+}
+
+// the segments of an input, from the peer to a connection brought into the state the
+// first byte picks. each segment is a byte of the time to let pass before it, in 10ms,
+// two of its length and then that many bytes of TCP header and payload. ports and
+// checksum are filled in, and sequence and acknowledgment numbers and SACK blocks are
+// taken relative to the initial ones of the peer and the stack, so that the segments
+// don't simply miss the connection. mustn't panic whatever the bytes are
+#[doc(hidden)]
+pub fn receive_segments(data: &[u8]) {
+    let (&first, mut data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let harness = HARNESS.get_or_init(|| Mutex::new(Harness::new()));
+    // an earlier input that panicked poisons the lock, which doesn't matter to the next
+    let mut harness = harness.lock().unwrap_or_else(|error| error.into_inner());
+    harness.reset(first);
+    harness.open(SCENARIOS[(first & 0x07) as usize], first);
+    while data.len() >= 3 {
+        let wait = Duration::from_millis(u64::from(data[0]) * 10);
+        let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let (segment, rest) = data[3..].split_at(len.min(data.len() - 3));
+        data = rest;
+        harness.clock.advance(wait);
+        harness.inject(segment);
+        harness.drain();
+    }
+}
+
+impl Harness {
+    fn new() -> Self {
+        let (device, peer) = MemoryDevice::pair();
+        let routes = RoutingTable::on_link("fuzz0", LOCAL_ADDR, 24, None);
+        let clock = Arc::new(VirtualClock::new());
+        Self {
+            tcp: TCP::with_clock(device, routes, clock.clone()),
+            peer,
+            clock,
+            local_iss: SeqNum(0),
+            local_key: None,
+        }
+    }
+
+    fn reset(&mut self, first: u8) {
+        self.tcp.clear();
+        self.drain();
+        self.local_iss = SeqNum(0);
+        self.local_key = None;
+        self.tcp.set_ecn(first & ECN != 0);
+        self.tcp
+            .set_syn_cookie_threshold(if first & SYN_COOKIES != 0 {
+                0
+            } else {
+                usize::MAX
+            });
+    }
+
+    // errors leave the connection in whatever state it got to, which is as good to
+    // start from as any
+    fn open(&mut self, scenario: Scenario, first: u8) {
+        let local = (LOCAL_ADDR, LOCAL_PORT).into();
+        let remote = (REMOTE_ADDR, REMOTE_PORT).into();
+        let options = first & OPTIONS != 0;
+        let multipath = first & MULTIPATH != 0;
+        let active = matches!(
+            scenario,
+            Scenario::SynSent
+                | Scenario::ActiveEstablished
+                | Scenario::FinWait1
+                | Scenario::CloseWait
+                | Scenario::LastAck
+        );
+        if active {
+            if self
+                .tcp
+                .start_connect_from(local, remote, BufferSizes::default())
+                .is_err()
+            {
+                return;
+            }
+            self.drain();
+            if let Scenario::SynSent = scenario {
+                return;
+            }
+            self.handshake(tcpflags::SYN | tcpflags::ACK, options, false);
+        } else {
+            let listener = match self.tcp.listen(local, BACKLOG) {
+                Ok(listener) => listener,
+                Err(_) => return,
+            };
+            if multipath {
+                let _ = self.tcp.set_option(listener, SocketOption::Multipath(true));
+            }
+            if let Scenario::Listen = scenario {
+                return;
+            }
+            self.handshake(tcpflags::SYN, options, multipath);
+            if let Scenario::SynRcvd = scenario {
+                return;
+            }
+            self.control(tcpflags::ACK, 1, options, multipath);
+        }
+        // nothing called on the connection may block the harness
+        let _ = self.tcp.set_nonblocking(CONNECTION, true);
+        if first & SEND_DATA != 0 {
+            let _ = self.tcp.send(CONNECTION, &[0; QUEUED_DATA]);
+        }
+        match scenario {
+            Scenario::FinWait1 => {
+                let _ = self.tcp.shutdown(CONNECTION, Shutdown::Write);
+            }
+            Scenario::CloseWait => self.control(tcpflags::FIN | tcpflags::ACK, 1, options, false),
+            Scenario::LastAck => {
+                self.control(tcpflags::FIN | tcpflags::ACK, 1, options, false);
+                let _ = self.tcp.shutdown(CONNECTION, Shutdown::Write);
+            }
+            _ => {}
+        }
+        self.drain();
+    }
+
+    // the SYN or SYN-ACK of the peer
+    fn handshake(&mut self, flag: u8, options: bool, multipath: bool) {
+        let mut packet = TCPPacket::new(0);
+        packet.set_seq(SeqNum(REMOTE_ISS));
+        if flag & tcpflags::ACK != 0 {
+            packet.set_ack(self.local_iss + 1);
+        }
+        packet.set_flag(flag);
+        packet.set_window_size(u16::MAX);
+        let mut handshake_options = vec![TcpOption::MaxSegmentSize(1460)];
+        if options {
+            handshake_options.extend([
+                TcpOption::WindowScale(7),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamps {
+                    value: 1,
+                    echo_reply: 0,
+                },
+            ]);
+        }
+        if multipath {
+            handshake_options.push(TcpOption::Mptcp(MptcpOption::Capable {
+                sender_key: None,
+                receiver_key: None,
+                checksum_required: false,
+            }));
+        }
+        packet.set_options(&handshake_options);
+        self.send(packet);
+        self.drain();
+    }
+
+    // a segment without data of the peer, `offset` past its ISN. the ACK of the handshake
+    // completes that of MPTCP with `multipath`
+    fn control(&mut self, flag: u8, offset: u32, options: bool, multipath: bool) {
+        let mut packet = TCPPacket::new(0);
+        packet.set_seq(SeqNum(REMOTE_ISS) + offset);
+        packet.set_ack(self.local_iss + 1);
+        packet.set_flag(flag);
+        packet.set_window_size(u16::MAX);
+        let mut control_options = Vec::new();
+        if options {
+            control_options.push(TcpOption::Timestamps {
+                value: 2,
+                echo_reply: 0,
+            });
+        }
+        if multipath {
+            control_options.push(TcpOption::Mptcp(MptcpOption::Capable {
+                sender_key: Some(REMOTE_KEY),
+                receiver_key: self.local_key,
+                checksum_required: false,
+            }));
+        }
+        packet.set_options(&control_options);
+        self.send(packet);
+        self.drain();
+    }
+
+    fn inject(&mut self, segment: &[u8]) {
+        let mut bytes = segment.to_vec();
+        if bytes.len() < TCP_HEADER_LEN {
+            bytes.resize(TCP_HEADER_LEN, 0);
+        }
+        let mut packet = TCPPacket::from(TcpPacket::new(&bytes).unwrap());
+        packet.set_seq(SeqNum(REMOTE_ISS) + packet.get_seq().0);
+        packet.set_ack(self.local_iss + packet.get_ack().0);
+        // SACK blocks too, at the cost of the options it doesn't know
+        let mut options = packet.get_options();
+        if options
+            .iter()
+            .any(|option| matches!(option, TcpOption::Sack(_)))
+        {
+            for option in &mut options {
+                if let TcpOption::Sack(blocks) = option {
+                    for (left, right) in blocks {
+                        *left = self.local_iss + left.0;
+                        *right = self.local_iss + right.0;
+                    }
+                }
+            }
+            packet.set_options(&options);
+        }
+        self.send(packet);
+    }
+
+    // straight into the stack, which has answered by the time it returns
+    fn send(&self, mut packet: TCPPacket) {
+        packet.set_src(REMOTE_PORT);
+        packet.set_dest(LOCAL_PORT);
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &REMOTE_ADDR,
+            &LOCAL_ADDR,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        let packet = ip::build(REMOTE_ADDR, LOCAL_ADDR, ip::TCP, 64, 0, packet.packet());
+        self.tcp.packet_handler(&packet);
+    }
+
+    // takes what the stack has sent, noting its ISN and MPTCP key
+    fn drain(&mut self) {
+        let mut buffer = vec![0; 65535];
+        while let Ok(Some(len)) = self.peer.recv_packet_timeout(&mut buffer, Duration::ZERO) {
+            let packet = match ip::parse(&buffer[..len]) {
+                Some(header) if header.protocol == ip::TCP => {
+                    TcpPacket::new(&buffer[header.header_len..header.total_len])
+                        .map(TCPPacket::from)
+                }
+                _ => None,
+            };
+            if let Some(packet) = packet {
+                if packet.get_flag() & tcpflags::SYN != 0 {
+                    self.local_iss = packet.get_seq();
+                    if let Some(MptcpOption::Capable { sender_key, .. }) = packet.get_mptcp_option()
+                    {
+                        self.local_key = sender_key;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sockopt::SocketOptionName;
+    use proptest::prelude::*;
+
+    /// what happens to the connection next: a segment from the peer, a call of the
+    /// application, or time passing with the timers that come due on the way
+    #[derive(Debug, Clone)]
+    enum Event {
+        Segment {
+            flag: u8,
+            seq: u32, // relative to the ISN of the peer
+            ack: u32, // to that of the stack
+            window: u16,
+            len: usize,
+            sack: Option<(u32, u32)>,
+            urgent: u16,
+        },
+        Send(usize),
+        Recv(usize),
+        Shutdown(Shutdown),
+        Abort,
+        Advance(Duration),
+    }
+
+    // numbers mostly around where the connection is at, now and then anywhere
+    fn near() -> impl Strategy<Value = u32> {
+        prop_oneof![4 => 0..8000u32, 1 => any::<u32>()]
+    }
+
+    fn flag() -> impl Strategy<Value = u8> {
+        prop_oneof![
+            8 => (any::<bool>(), prop::bool::weighted(0.1), prop::bool::weighted(0.1)).prop_map(
+                |(psh, fin, urg)| {
+                    let mut flag = tcpflags::ACK;
+                    for (set, bit) in [(psh, tcpflags::PSH), (fin, tcpflags::FIN), (urg, tcpflags::URG)] {
+                        if set {
+                            flag |= bit;
+                        }
+                    }
+                    flag
+                }
+            ),
+            1 => any::<u8>(),
+        ]
+    }
+
+    fn event() -> impl Strategy<Value = Event> {
+        prop_oneof![
+            6 => (
+                flag(),
+                near(),
+                near(),
+                any::<u16>(),
+                prop_oneof![Just(0usize), 1..3000usize],
+                prop::option::weighted(0.2, (near(), 1..3000u32)),
+                0..64u16,
+            )
+                .prop_map(|(flag, seq, ack, window, len, sack, urgent)| Event::Segment {
+                    flag,
+                    seq,
+                    ack,
+                    window,
+                    len,
+                    sack,
+                    urgent,
+                }),
+            1 => (0..20000usize).prop_map(Event::Send),
+            1 => (0..20000usize).prop_map(Event::Recv),
+            1 => prop_oneof![
+                Just(Shutdown::Read),
+                Just(Shutdown::Write),
+                Just(Shutdown::Both),
+            ]
+            .prop_map(Event::Shutdown),
+            1 => Just(Event::Abort),
+            2 => (0..3000u64).prop_map(|millis| Event::Advance(Duration::from_millis(millis))),
+        ]
+    }
+
+    impl Harness {
+        fn apply(&mut self, event: &Event) {
+            // a connection that a SYN among the events opened isn't yet
+            let _ = self.tcp.set_nonblocking(CONNECTION, true);
+            match *event {
+                Event::Segment {
+                    flag,
+                    seq,
+                    ack,
+                    window,
+                    len,
+                    sack,
+                    urgent,
+                } => {
+                    let mut packet = TCPPacket::new(len);
+                    packet.set_seq(SeqNum(seq));
+                    packet.set_ack(SeqNum(ack));
+                    packet.set_flag(flag);
+                    packet.set_window_size(window);
+                    packet.set_urgent_pointer(urgent);
+                    if let Some((left, len)) = sack {
+                        packet.set_options(&[TcpOption::Sack(vec![(
+                            SeqNum(left),
+                            SeqNum(left) + len,
+                        )])]);
+                    }
+                    self.inject(packet.packet());
+                }
+                Event::Send(len) => {
+                    let _ = self.tcp.send(CONNECTION, &vec![0; len]);
+                }
+                Event::Recv(len) => {
+                    let _ = self.tcp.recv(CONNECTION, &mut vec![0; len]);
+                }
+                Event::Shutdown(how) => {
+                    let _ = self.tcp.shutdown(CONNECTION, how);
+                }
+                Event::Abort => {
+                    let _ = self.tcp.abort(CONNECTION);
+                }
+                Event::Advance(duration) => self.clock.advance(duration),
+            }
+            self.drain();
+        }
+    }
+
+    // what holds of the connection, if it is still there, whatever happened to it
+    fn check_invariants(harness: &Harness) -> Result<(), TestCaseError> {
+        let info = match harness.tcp.socket_info(CONNECTION) {
+            Ok(info) => info,
+            Err(_) => return Ok(()),
+        };
+        let unacked = SeqNum(info.send_unacked);
+        let next = SeqNum(info.send_next);
+        prop_assert!(
+            unacked <= next,
+            "SND.UNA {:?} past SND.NXT {:?}",
+            unacked,
+            next
+        );
+        // nothing beyond the send buffer is in flight, but for SYN and FIN
+        let send_buffer_size = match harness
+            .tcp
+            .get_option(CONNECTION, SocketOptionName::SendBufferSize)
+        {
+            Ok(SocketOption::SendBufferSize(size)) => size,
+            _ => return Ok(()),
+        };
+        prop_assert!((next - unacked) as usize <= send_buffer_size + 2);
+        let recv_buffer_size = match harness
+            .tcp
+            .get_option(CONNECTION, SocketOptionName::RecvBufferSize)
+        {
+            Ok(SocketOption::RecvBufferSize(size)) => size,
+            _ => return Ok(()),
+        };
+        prop_assert!(
+            info.recv_window as usize <= recv_buffer_size,
+            "receive window {} beyond the buffer of {}",
+            info.recv_window,
+            recv_buffer_size
+        );
+        prop_assert!(info.recv_queue_bytes + info.recv_window as usize == recv_buffer_size);
+        Ok(())
+    }
+
+    proptest! {
+        // each case brings a connection into a state of its own and plays random events
+        // at it, checking after every one
+        #[test]
+        fn connection_invariants_hold(first: u8, events in prop::collection::vec(event(), 1..40)) {
+            let harness = HARNESS.get_or_init(|| Mutex::new(Harness::new()));
+            let mut harness = harness.lock().unwrap_or_else(|error| error.into_inner());
+            harness.reset(first);
+            harness.open(SCENARIOS[(first & 0x07) as usize], first);
+            check_invariants(&harness)?;
+            for event in &events {
+                harness.apply(event);
+                check_invariants(&harness)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::seq::SeqNum;
+use crate::socket::SockID;
+use std::io;
+use std::net::Ipv4Addr;
+
+const DESTINATION_UNREACHABLE: u8 = 3;
+// codes of destination unreachable
+const NET_UNREACHABLE: u8 = 0;
+const PROTOCOL_UNREACHABLE: u8 = 2;
+const PORT_UNREACHABLE: u8 = 3;
+const FRAGMENTATION_NEEDED: u8 = 4;
+
+const ICMP_HEADER_SIZE: usize = 8;
+const IPV4_HEADER_SIZE: usize = 20;
+const TCP_PROTOCOL: u8 = 6;
+// smallest MTU every IPv4 link has to support, anything less in a report is bogus
+const MIN_MTU: u16 = 68;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpError {
+    Unreachable(io::ErrorKind),
+    // the next-hop MTU of a router that had to drop a segment with DF set
+    FragmentationNeeded { mtu: u16 },
+}
+
+/// an ICMP error about a segment one of our sockets sent
+#[derive(Debug, Clone)]
+pub struct IcmpMessage {
+    pub error: IcmpError,
+    pub sock_id: SockID,
+    pub seq: SeqNum, // of the segment that caused it
+}
+
+// `bytes` is an ICMP message quoting the IP header and the first 8 bytes of a TCP segment
+pub fn parse(bytes: &[u8]) -> Option<IcmpMessage> {
+    if bytes.len() < ICMP_HEADER_SIZE || bytes[0] != DESTINATION_UNREACHABLE {
+        return None;
+    }
+    let error = match bytes[1] {
+        NET_UNREACHABLE => IcmpError::Unreachable(io::ErrorKind::NetworkUnreachable),
+        PROTOCOL_UNREACHABLE | PORT_UNREACHABLE => {
+            IcmpError::Unreachable(io::ErrorKind::ConnectionRefused)
+        }
+        FRAGMENTATION_NEEDED => {
+            let mtu = u16::from_be_bytes([bytes[6], bytes[7]]);
+            if mtu < MIN_MTU {
+                return None;
+            }
+            IcmpError::FragmentationNeeded { mtu }
+        }
+        // host unreachable (1), administratively prohibited and the like
+        _ => IcmpError::Unreachable(io::ErrorKind::HostUnreachable),
+    };
+    let ip = &bytes[ICMP_HEADER_SIZE..];
+    if ip.len() < IPV4_HEADER_SIZE || ip[0] >> 4 != 4 || ip[9] != TCP_PROTOCOL {
+        return None;
+    }
+    let header_len = (ip[0] & 0x0f) as usize * 4;
+    let tcp = ip.get(header_len..header_len + 8)?;
+    // the quoted segment went from us to the peer
+    let local_addr = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let remote_addr = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+    Some(IcmpMessage {
+        error,
+        sock_id: SockID(
+            local_addr,
+            remote_addr,
+            u16::from_be_bytes([tcp[0], tcp[1]]),
+            u16::from_be_bytes([tcp[2], tcp[3]]),
+        ),
+        seq: SeqNum(u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]])),
+    })
+}
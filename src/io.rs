@@ -0,0 +1,302 @@
+use crate::ip;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// not in libc for every target. SCM_TIMESTAMPNS is the same number
+const SO_TIMESTAMPNS: libc::c_int = 35;
+// room for the control messages of a datagram: a timestamp, or an extended error and the
+// address of its offender
+const CONTROL_LEN: usize = 64;
+
+/// a raw IPv4 socket of one protocol. with IPPROTO_RAW it sends whole packets, header
+/// included (IP_HDRINCL), and receives nothing. with another protocol it receives that
+/// protocol's packets for the host, header included
+#[derive(Debug)]
+pub struct RawIpSocket {
+    fd: OwnedFd,
+}
+
+/// a datagram taken by `recv_batch`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Received {
+    pub len: usize,
+    pub timestamp: Option<SystemTime>, // when the kernel got it, with timestamps on
+}
+
+/// what the kernel reports about a packet we sent, with errors queued
+#[derive(Debug, Clone, Copy)]
+pub struct QueuedError {
+    pub errno: i32,
+    pub origin: u8, // SO_EE_ORIGIN_LOCAL, or SO_EE_ORIGIN_ICMP for an ICMP message
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub offender: Option<Ipv4Addr>, // the router that sent the ICMP message
+    pub len: usize,                 // of the packet, or the part of it returned
+}
+
+impl RawIpSocket {
+    pub fn open(protocol: libc::c_int) -> io::Result<Self> {
+        let fd =
+            unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // SO_BINDTODEVICE: packets go out of `ifname` whatever the route says, and only those
+    // that came in on it are received
+    pub fn bind_to_device(&self, ifname: &str) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                ifname.as_ptr() as *const libc::c_void,
+                ifname.len() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // SO_SNDBUF. the kernel doubles it for its bookkeeping, as with any socket
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.set_option(libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+    }
+
+    // SO_RCVBUF, how much may queue up before packets are dropped
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.set_option(libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+    }
+
+    // SO_TIMESTAMPNS: received packets carry the time the kernel got them
+    pub fn set_timestamps(&self, on: bool) -> io::Result<()> {
+        self.set_option(libc::SOL_SOCKET, SO_TIMESTAMPNS, on as libc::c_int)
+    }
+
+    // IP_RECVERR: errors about sent packets queue up for `recv_error` instead of failing a
+    // later send
+    pub fn set_recv_errors(&self, on: bool) -> io::Result<()> {
+        self.set_option(libc::IPPROTO_IP, libc::IP_RECVERR, on as libc::c_int)
+    }
+
+    fn set_option(
+        &self,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // a whole IPv4 packet, to the destination in its header
+    pub fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let addr = sockaddr(packet)?;
+        let sent = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // `packets` with one sendmmsg(2), as many as the socket takes. returns how many
+    pub fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<usize> {
+        let mut addrs = packets
+            .iter()
+            .map(|packet| sockaddr(packet))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = addrs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .map(|(addr, iovec)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = addr as *mut libc::sockaddr_in as *mut libc::c_void;
+                header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+        let sent = unsafe {
+            libc::sendmmsg(
+                self.fd.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    // whatever packets are waiting, one per buffer, with one recvmmsg(2) that doesn't
+    // block. returns how many
+    pub fn recv_batch(
+        &self,
+        buffers: &mut [&mut [u8]],
+        received: &mut [Received],
+    ) -> io::Result<usize> {
+        let mut iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+        let mut controls = vec![[0u64; CONTROL_LEN / 8]; iovecs.len()];
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(controls.iter_mut())
+            .map(|(iovec, control)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                header.msg_hdr.msg_controllen = CONTROL_LEN as _;
+                header
+            })
+            .collect();
+        let count = unsafe {
+            libc::recvmmsg(
+                self.fd.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock {
+                return Ok(0);
+            }
+            return Err(error);
+        }
+        for (received, header) in received.iter_mut().zip(&headers).take(count as usize) {
+            *received = Received {
+                len: header.msg_len as usize,
+                timestamp: timestamp(&header.msg_hdr),
+            };
+        }
+        Ok(count as usize)
+    }
+
+    // the next queued error, without blocking. the packet it is about goes into `buffer`
+    pub fn recv_error(&self, buffer: &mut [u8]) -> io::Result<Option<QueuedError>> {
+        let mut iovec = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        let mut control = [0u64; CONTROL_LEN / 8];
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_iov = &mut iovec;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = CONTROL_LEN as _;
+        let len = unsafe {
+            libc::recvmsg(
+                self.fd.as_raw_fd(),
+                &mut header,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if len < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(error);
+        }
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&header) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if level == libc::IPPROTO_IP && kind == libc::IP_RECVERR {
+                let error = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::sock_extended_err;
+                let extended = unsafe { ptr::read_unaligned(error) };
+                let offender = unsafe {
+                    ptr::read_unaligned(libc::SO_EE_OFFENDER(error) as *const libc::sockaddr_in)
+                };
+                return Ok(Some(QueuedError {
+                    errno: extended.ee_errno as i32,
+                    origin: extended.ee_origin,
+                    icmp_type: extended.ee_type,
+                    icmp_code: extended.ee_code,
+                    offender: (offender.sin_family == libc::AF_INET as libc::sa_family_t)
+                        .then(|| Ipv4Addr::from(u32::from_be(offender.sin_addr.s_addr))),
+                    len: len as usize,
+                }));
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&header, cmsg) };
+        }
+        Ok(None)
+    }
+}
+
+impl AsRawFd for RawIpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+fn sockaddr(packet: &[u8]) -> io::Result<libc::sockaddr_in> {
+    let dst = ip::destination(packet).ok_or(io::ErrorKind::InvalidInput)?;
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_addr.s_addr = u32::from(dst).to_be();
+    Ok(addr)
+}
+
+// from the SCM_TIMESTAMPNS message of a received datagram
+fn timestamp(header: &libc::msghdr) -> Option<SystemTime> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if level == libc::SOL_SOCKET && kind == SO_TIMESTAMPNS {
+            let time =
+                unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec) };
+            let since_epoch = Duration::new(time.tv_sec as u64, time.tv_nsec as u32);
+            return Some(UNIX_EPOCH + since_epoch);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(header, cmsg) };
+    }
+    None
+}
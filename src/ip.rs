@@ -0,0 +1,81 @@
+use pnet::util;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const ICMP: u8 = 1;
+pub const TCP: u8 = 6;
+const DONT_FRAGMENT: u16 = 0x4000;
+const MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+// identification of the packets we build. DF is always set, so it only has to tell apart
+// packets a middlebox might still fragment (RFC 6864)
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+/// the fields of an IPv4 header the stack looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ecn: u8,
+    pub header_len: usize,
+    pub total_len: usize,
+}
+
+// None for anything but a whole IPv4 packet. fragments are left to the kernel, which
+// reassembles them for raw sockets
+pub fn parse(packet: &[u8]) -> Option<Ipv4Header> {
+    if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if header_len < IPV4_HEADER_LEN
+        || total_len < header_len
+        || total_len > packet.len()
+        || fragment & (MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0
+    {
+        return None;
+    }
+    Some(Ipv4Header {
+        src: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+        dst: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        protocol: packet[9],
+        ecn: packet[1] & 0b11,
+        header_len,
+        total_len,
+    })
+}
+
+// `payload` behind a header without options, DF set
+pub fn build(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    ttl: u8,
+    tos: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_len = (IPV4_HEADER_LEN + payload.len()) as u16;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[0x45, tos]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[ttl, protocol, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    let checksum = util::checksum(&packet, 5);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub fn destination(packet: &[u8]) -> Option<Ipv4Addr> {
+    let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+    Some(Ipv4Addr::from(dst))
+}
@@ -1,13 +1,47 @@
+mod ao;
+pub mod arp;
+#[cfg(feature = "tokio")]
+pub mod async_stream;
+#[cfg(target_os = "linux")]
+mod bpf;
+pub mod clock;
+mod congestion;
+pub mod device;
+pub mod fault;
+#[doc(hidden)]
+pub mod fuzz;
+mod icmp;
+#[cfg(target_os = "linux")]
+pub mod io;
+mod ip;
+#[cfg(target_os = "macos")]
+mod macos;
+mod mptcp;
+#[cfg(all(test, target_os = "linux", feature = "netns"))]
+mod netns;
+mod pacing;
 mod packet;
+pub mod pcap;
+pub mod poll;
+mod rack;
+pub mod route;
+mod rtt;
+pub mod script;
+mod seq;
 mod socket;
+pub mod sockopt;
+pub mod stats;
+pub mod stream;
+mod syncookie;
 pub mod tcp;
 mod tcpflags;
+mod tcpoption;
+mod timer;
+#[cfg(feature = "io_uring")]
+mod uring;
+#[cfg(windows)]
+mod windows;
+#[cfg(feature = "xdp")]
+mod xdp;
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
-    }
-}
+pub use socket::{SockID, TcpInfo, TcpStatus};
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::ptr;
+use std::sync::Mutex;
+
+// /dev/bpf0, /dev/bpf1, ... each open by one process at a time
+const MAX_DEVICES: usize = 256;
+// asked for, the kernel may settle on less
+const BUFFER_SIZE: libc::c_uint = 1 << 17;
+// not in libc
+const BIOCSSEESENT: libc::c_ulong = 0x80044277;
+// each frame of a read starts on a word boundary
+const BPF_ALIGNMENT: usize = mem::size_of::<i32>();
+
+/// a BPF device attached to an interface: the frames arriving on it come in through reads,
+/// as many at a time as have arrived, and a frame written goes out as it is
+pub struct BpfDevice {
+    file: File,
+    read: Mutex<ReadBuffer>,
+}
+
+/// what the last read returned, and how far the frames in it have been taken
+struct ReadBuffer {
+    data: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl BpfDevice {
+    // opening a BPF device takes root, or read and write access granted on /dev/bpf*
+    pub fn open(ifname: &str) -> Result<Self> {
+        let file = (0..MAX_DEVICES)
+            .find_map(|n| {
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(format!("/dev/bpf{}", n))
+                    .ok()
+            })
+            .context("failed to open a BPF device")?;
+        if ifname.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "device name too long").into());
+        }
+        let mut len = BUFFER_SIZE;
+        // the buffer size is set before the interface
+        ioctl(&file, libc::BIOCSBLEN, &mut len).context("failed to set BPF buffer size")?;
+        let mut request: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(ifname.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        ioctl(&file, libc::BIOCSETIF, &mut request).context("failed to attach BPF device")?;
+        let mut on: libc::c_uint = 1;
+        // frames come as they arrive rather than once the buffer is full
+        ioctl(&file, libc::BIOCIMMEDIATE, &mut on).context("failed to set immediate mode")?;
+        // the source MAC of the frames written is ours to set
+        ioctl(&file, libc::BIOCSHDRCMPLT, &mut on).context("failed to set header complete")?;
+        // frames the host sends, ours included, don't come back
+        let mut off: libc::c_uint = 0;
+        ioctl(&file, BIOCSSEESENT, &mut off).context("failed to hide sent frames")?;
+        ioctl(&file, libc::BIOCGBLEN, &mut len).context("failed to get BPF buffer size")?;
+        Ok(Self {
+            file,
+            read: Mutex::new(ReadBuffer {
+                data: vec![0; len as usize],
+                start: 0,
+                end: 0,
+            }),
+        })
+    }
+
+    // the next frame, read from the device once those of the last read are taken. a read
+    // is of the whole buffer, or it fails
+    pub fn recv(&self, frame: &mut [u8]) -> io::Result<usize> {
+        let mut read = self.read.lock().unwrap();
+        let read = &mut *read;
+        loop {
+            if read.start + mem::size_of::<libc::bpf_hdr>() <= read.end {
+                let header: libc::bpf_hdr = unsafe {
+                    ptr::read_unaligned(read.data[read.start..].as_ptr() as *const libc::bpf_hdr)
+                };
+                let begin = read.start + header.bh_hdrlen as usize;
+                let caplen = header.bh_caplen as usize;
+                read.start = (begin + caplen + BPF_ALIGNMENT - 1) & !(BPF_ALIGNMENT - 1);
+                if begin + caplen > read.end {
+                    read.start = read.end;
+                    continue;
+                }
+                let len = caplen.min(frame.len());
+                frame[..len].copy_from_slice(&read.data[begin..begin + len]);
+                return Ok(len);
+            }
+            read.end = (&self.file).read(&mut read.data)?;
+            read.start = 0;
+        }
+    }
+
+    pub fn send(&self, frame: &[u8]) -> io::Result<()> {
+        (&self.file).write_all(frame)
+    }
+}
+
+fn ioctl<T>(file: &File, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+    if unsafe { libc::ioctl(file.as_raw_fd(), request, arg as *mut T) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,804 @@
+use crate::seq::SeqNum;
+use crate::socket::SockID;
+use crate::tcpflags;
+use crate::tcpoption::{MPTCP, NOP};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+
+pub const MP_CAPABLE: u8 = 0;
+pub const MP_JOIN: u8 = 1;
+pub const DSS: u8 = 2;
+const VERSION: u8 = 1;
+// MP_CAPABLE flags: DSS checksums are required (A), keys are hashed with HMAC-SHA256 (H)
+const CHECKSUM_REQUIRED: u8 = 0x80;
+const HMAC_SHA256: u8 = 0x01;
+// MP_JOIN flag: the subflow is only to be used if the others fail
+const BACKUP: u8 = 0x01;
+// DSS flags
+const DATA_ACK: u8 = 0x01;
+const DATA_ACK_64: u8 = 0x02;
+const MAPPING: u8 = 0x04;
+const DATA_SEQ_64: u8 = 0x08;
+// a DSS with a DATA_ACK and a mapping, both 64-bit, and its padding. every data segment
+// carries one, so the MSS of a subflow leaves room for it
+pub const DSS_OPTION_LEN: usize = 28;
+// how far beyond what the application has read data is taken out of the subflows. what
+// arrives farther ahead stays in the receive buffer of its subflow and closes its window
+const CONNECTION_RECV_WINDOW: u64 = 1 << 20;
+
+/// the MPTCP option (RFC 8684), kind 30, in the forms this implementation understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MptcpOption {
+    // no key on SYN, the sender's on SYN-ACK, the sender's then the receiver's on the ACK
+    Capable {
+        sender_key: Option<u64>,
+        receiver_key: Option<u64>,
+        checksum_required: bool,
+    },
+    // a new subflow for the connection the receiver knows by `token`
+    JoinSyn {
+        token: u32,
+        nonce: u32,
+        address_id: u8,
+        backup: bool,
+    },
+    // the leftmost 64 bits of the HMAC proving the responder knows the keys
+    JoinSynAck {
+        hmac: u64,
+        nonce: u32,
+        address_id: u8,
+        backup: bool,
+    },
+    // the leftmost 160 bits of the initiator's HMAC
+    JoinAck {
+        hmac: [u8; 20],
+    },
+    // data sequence signal: the cumulative DATA_ACK and the mapping of the payload.
+    // `data_seq_64` is false when the mapping carries only the lower 32 bits
+    Dss {
+        data_ack: Option<u64>,
+        mapping: Option<Mapping>,
+        data_seq_64: bool,
+    },
+}
+
+/// `len` bytes of a subflow from `subflow_seq` on, relative to its ISN, carry the
+/// connection-level bytes from `data_seq` on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub data_seq: u64,
+    pub subflow_seq: SeqNum,
+    pub len: u16,
+}
+
+impl Mapping {
+    fn end(&self) -> SeqNum {
+        self.subflow_seq + self.len as u32
+    }
+
+    fn contains(&self, subflow_seq: SeqNum) -> bool {
+        self.subflow_seq <= subflow_seq && subflow_seq < self.end()
+    }
+}
+
+// the body of the option behind kind and length, None for forms we don't handle
+pub fn parse(data: &[u8]) -> Option<MptcpOption> {
+    let subtype = data.first()? >> 4;
+    match subtype {
+        MP_CAPABLE => {
+            if data[0] & 0x0f != VERSION || data.len() < 2 {
+                return None;
+            }
+            let checksum_required = data[1] & CHECKSUM_REQUIRED > 0;
+            let key = |at: usize| Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?));
+            Some(MptcpOption::Capable {
+                sender_key: key(2),
+                receiver_key: key(10),
+                checksum_required,
+            })
+        }
+        MP_JOIN => match data.len() {
+            10 => Some(MptcpOption::JoinSyn {
+                token: u32::from_be_bytes(data[2..6].try_into().ok()?),
+                nonce: u32::from_be_bytes(data[6..10].try_into().ok()?),
+                address_id: data[1],
+                backup: data[0] & BACKUP > 0,
+            }),
+            14 => Some(MptcpOption::JoinSynAck {
+                hmac: u64::from_be_bytes(data[2..10].try_into().ok()?),
+                nonce: u32::from_be_bytes(data[10..14].try_into().ok()?),
+                address_id: data[1],
+                backup: data[0] & BACKUP > 0,
+            }),
+            22 => Some(MptcpOption::JoinAck {
+                hmac: data[2..22].try_into().ok()?,
+            }),
+            _ => None,
+        },
+        DSS => {
+            let flags = *data.get(1)?;
+            let mut rest = &data[2..];
+            let mut take = |len: usize| {
+                let bytes = rest.get(..len)?;
+                rest = &rest[len..];
+                Some(
+                    bytes
+                        .iter()
+                        .fold(0u64, |value, &byte| value << 8 | byte as u64),
+                )
+            };
+            let data_ack = match (flags & DATA_ACK > 0, flags & DATA_ACK_64 > 0) {
+                (false, _) => None,
+                (true, false) => Some(take(4)?),
+                (true, true) => Some(take(8)?),
+            };
+            let data_seq_64 = flags & DATA_SEQ_64 > 0;
+            let mapping = if flags & MAPPING > 0 {
+                Some(Mapping {
+                    data_seq: take(if data_seq_64 { 8 } else { 4 })?,
+                    subflow_seq: SeqNum(take(4)? as u32),
+                    len: take(2)? as u16,
+                })
+            } else {
+                None
+            };
+            Some(MptcpOption::Dss {
+                data_ack,
+                mapping,
+                data_seq_64,
+            })
+        }
+        _ => None,
+    }
+}
+
+// kind and length included, padded in front with NOPs to a multiple of 4 bytes
+pub fn serialize(option: &MptcpOption, bytes: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    match option {
+        MptcpOption::Capable {
+            sender_key,
+            receiver_key,
+            checksum_required,
+        } => {
+            body.push(MP_CAPABLE << 4 | VERSION);
+            body.push(if *checksum_required {
+                CHECKSUM_REQUIRED | HMAC_SHA256
+            } else {
+                HMAC_SHA256
+            });
+            for key in sender_key.iter().chain(receiver_key) {
+                body.extend_from_slice(&key.to_be_bytes());
+            }
+        }
+        MptcpOption::JoinSyn {
+            token,
+            nonce,
+            address_id,
+            backup,
+        } => {
+            body.extend_from_slice(&[MP_JOIN << 4 | *backup as u8, *address_id]);
+            body.extend_from_slice(&token.to_be_bytes());
+            body.extend_from_slice(&nonce.to_be_bytes());
+        }
+        MptcpOption::JoinSynAck {
+            hmac,
+            nonce,
+            address_id,
+            backup,
+        } => {
+            body.extend_from_slice(&[MP_JOIN << 4 | *backup as u8, *address_id]);
+            body.extend_from_slice(&hmac.to_be_bytes());
+            body.extend_from_slice(&nonce.to_be_bytes());
+        }
+        MptcpOption::JoinAck { hmac } => {
+            body.extend_from_slice(&[MP_JOIN << 4, 0]);
+            body.extend_from_slice(hmac);
+        }
+        MptcpOption::Dss {
+            data_ack,
+            mapping,
+            data_seq_64,
+        } => {
+            let mut flags = 0;
+            if data_ack.is_some() {
+                flags |= DATA_ACK | DATA_ACK_64;
+            }
+            if mapping.is_some() {
+                flags |= MAPPING;
+                if *data_seq_64 {
+                    flags |= DATA_SEQ_64;
+                }
+            }
+            body.extend_from_slice(&[DSS << 4, flags]);
+            if let Some(data_ack) = data_ack {
+                body.extend_from_slice(&data_ack.to_be_bytes());
+            }
+            if let Some(mapping) = mapping {
+                if *data_seq_64 {
+                    body.extend_from_slice(&mapping.data_seq.to_be_bytes());
+                } else {
+                    body.extend_from_slice(&(mapping.data_seq as u32).to_be_bytes());
+                }
+                body.extend_from_slice(&mapping.subflow_seq.0.to_be_bytes());
+                body.extend_from_slice(&mapping.len.to_be_bytes());
+            }
+        }
+    }
+    let len = body.len() + 2;
+    bytes.resize(bytes.len() + (4 - len % 4) % 4, NOP);
+    bytes.extend_from_slice(&[MPTCP, len as u8]);
+    bytes.extend_from_slice(&body);
+}
+
+fn key_digest(key: u64) -> [u8; 32] {
+    Sha256::digest(key.to_be_bytes()).into()
+}
+
+// the connection's name toward its peer: the most significant 32 bits of SHA-256 of a key
+pub fn token(key: u64) -> u32 {
+    let digest = key_digest(key);
+    u32::from_be_bytes(digest[..4].try_into().unwrap())
+}
+
+// the data sequence number a key's owner starts from: the least significant 64 bits of
+// SHA-256 of the key
+pub fn initial_data_seq(key: u64) -> u64 {
+    let digest = key_digest(key);
+    u64::from_be_bytes(digest[24..].try_into().unwrap())
+}
+
+// HMAC-SHA256 keyed with both keys over both nonces, each time the sender's first
+// (RFC 8684 section 3.2)
+fn join_hmac(key: u64, other_key: u64, nonce: u32, other_nonce: u32) -> [u8; 32] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&[key.to_be_bytes(), other_key.to_be_bytes()].concat())
+            .expect("HMAC takes keys of any length");
+    mac.update(&nonce.to_be_bytes());
+    mac.update(&other_nonce.to_be_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+// the 64 bits of `data_seq` closest to `reference` whose lower 32 bits are those of `data_seq`
+fn expand_data_seq(data_seq: u64, reference: u64) -> u64 {
+    let candidate = (reference & !0xffff_ffff) | (data_seq & 0xffff_ffff);
+    if candidate > reference && candidate - reference > 1 << 31 {
+        candidate.wrapping_sub(1 << 32)
+    } else if candidate < reference && reference - candidate > 1 << 31 {
+        candidate.wrapping_add(1 << 32)
+    } else {
+        candidate
+    }
+}
+
+/// handshake state of an MP_JOIN subflow
+#[derive(Debug, Clone)]
+pub struct Join {
+    pub address_id: u8,
+    pub local_nonce: u32,
+    pub remote_nonce: u32,
+}
+
+/// a TCP connection that is one subflow of a Multipath TCP connection
+#[derive(Debug, Clone)]
+pub struct Subflow {
+    pub token: u32, // our token of the connection
+    pub local_key: u64,
+    pub remote_key: Option<u64>,
+    pub join: Option<Join>, // None for the subflow that started the connection
+    // ACKs repeat the third ACK of our handshake until a DSS shows the peer got it
+    pub handshake_ack_pending: bool,
+    // the connection-level receive point, carried as DATA_ACK by every segment
+    pub data_ack: Option<u64>,
+    send_mappings: VecDeque<Mapping>, // of data written but not sent yet
+    recv_mappings: VecDeque<Mapping>, // of data received but not taken by the connection
+    mapped: bool,                     // a mapping has arrived
+}
+
+impl Subflow {
+    // the subflow that starts a connection, with MP_CAPABLE
+    pub fn new(local_key: u64) -> Self {
+        Self {
+            token: token(local_key),
+            local_key,
+            remote_key: None,
+            join: None,
+            handshake_ack_pending: false,
+            data_ack: None,
+            send_mappings: VecDeque::new(),
+            recv_mappings: VecDeque::new(),
+            mapped: false,
+        }
+    }
+
+    // another subflow of a connection whose keys were exchanged, with MP_JOIN
+    pub fn joining(local_key: u64, remote_key: u64, join: Join) -> Self {
+        Self {
+            remote_key: Some(remote_key),
+            join: Some(join),
+            ..Self::new(local_key)
+        }
+    }
+
+    // the MPTCP option of a segment with `flag` starting at `subflow_seq`
+    pub fn option(
+        &mut self,
+        flag: u8,
+        subflow_seq: SeqNum,
+        has_payload: bool,
+    ) -> Option<MptcpOption> {
+        if flag & tcpflags::RST > 0 {
+            return None;
+        }
+        if flag & tcpflags::SYN > 0 {
+            return Some(match (&self.join, flag & tcpflags::ACK > 0) {
+                (None, false) => MptcpOption::Capable {
+                    sender_key: None,
+                    receiver_key: None,
+                    checksum_required: false,
+                },
+                (None, true) => MptcpOption::Capable {
+                    sender_key: Some(self.local_key),
+                    receiver_key: None,
+                    checksum_required: false,
+                },
+                (Some(join), false) => MptcpOption::JoinSyn {
+                    token: token(self.remote_key?),
+                    nonce: join.local_nonce,
+                    address_id: join.address_id,
+                    backup: false,
+                },
+                (Some(join), true) => {
+                    let hmac = join_hmac(
+                        self.local_key,
+                        self.remote_key?,
+                        join.local_nonce,
+                        join.remote_nonce,
+                    );
+                    MptcpOption::JoinSynAck {
+                        hmac: u64::from_be_bytes(hmac[..8].try_into().unwrap()),
+                        nonce: join.local_nonce,
+                        address_id: join.address_id,
+                        backup: false,
+                    }
+                }
+            });
+        }
+        if has_payload {
+            // data that went out has no more use for its mapping
+            while self
+                .send_mappings
+                .front()
+                .is_some_and(|mapping| mapping.end() <= subflow_seq)
+            {
+                self.send_mappings.pop_front();
+            }
+            let mapping = self
+                .send_mappings
+                .iter()
+                .find(|mapping| mapping.contains(subflow_seq))
+                .copied();
+            return Some(MptcpOption::Dss {
+                data_ack: self.data_ack,
+                mapping,
+                data_seq_64: true,
+            });
+        }
+        if self.handshake_ack_pending {
+            return Some(match &self.join {
+                None => MptcpOption::Capable {
+                    sender_key: Some(self.local_key),
+                    receiver_key: self.remote_key,
+                    checksum_required: false,
+                },
+                Some(join) => {
+                    let hmac = join_hmac(
+                        self.local_key,
+                        self.remote_key?,
+                        join.local_nonce,
+                        join.remote_nonce,
+                    );
+                    MptcpOption::JoinAck {
+                        hmac: hmac[..20].try_into().unwrap(),
+                    }
+                }
+            });
+        }
+        self.data_ack.map(|data_ack| MptcpOption::Dss {
+            data_ack: Some(data_ack),
+            mapping: None,
+            data_seq_64: true,
+        })
+    }
+
+    // whether `hmac` from the peer's SYN-ACK proves it knows both keys
+    pub fn verify_syn_ack(&self, hmac: u64) -> bool {
+        match (&self.join, self.remote_key) {
+            (Some(join), Some(remote_key)) => {
+                let expected = join_hmac(
+                    remote_key,
+                    self.local_key,
+                    join.remote_nonce,
+                    join.local_nonce,
+                );
+                hmac == u64::from_be_bytes(expected[..8].try_into().unwrap())
+            }
+            _ => false,
+        }
+    }
+
+    // whether `hmac` from the peer's third ACK proves it knows both keys
+    pub fn verify_ack(&self, hmac: &[u8; 20]) -> bool {
+        match (&self.join, self.remote_key) {
+            (Some(join), Some(remote_key)) => {
+                let expected = join_hmac(
+                    remote_key,
+                    self.local_key,
+                    join.remote_nonce,
+                    join.local_nonce,
+                );
+                hmac[..] == expected[..20]
+            }
+            _ => false,
+        }
+    }
+
+    // a join subflow carries data once the peer has confirmed the handshake
+    pub fn can_send(&self) -> bool {
+        self.remote_key.is_some() && (self.join.is_none() || !self.handshake_ack_pending)
+    }
+
+    pub fn map(&mut self, mapping: Mapping) {
+        self.send_mappings.push_back(mapping);
+    }
+
+    // bytes from `subflow_seq` to the end of its mapping: a segment never spans two
+    pub fn mapping_room(&self, subflow_seq: SeqNum) -> usize {
+        self.send_mappings
+            .iter()
+            .find(|mapping| mapping.contains(subflow_seq))
+            .map_or(usize::MAX, |mapping| (mapping.end() - subflow_seq) as usize)
+    }
+
+    // a DSS arrived on the subflow
+    pub fn on_dss(&mut self, mapping: Option<Mapping>, data_seq_64: bool) {
+        self.handshake_ack_pending = false;
+        let mut mapping = match mapping {
+            Some(mapping) if mapping.len > 0 => mapping,
+            _ => return,
+        };
+        self.mapped = true;
+        if !data_seq_64 {
+            mapping.data_seq = expand_data_seq(mapping.data_seq, self.data_ack.unwrap_or(0));
+        }
+        // retransmissions bring the same mapping again
+        if self.recv_mappings.contains(&mapping) {
+            return;
+        }
+        let at = self
+            .recv_mappings
+            .iter()
+            .position(|other| mapping.subflow_seq < other.subflow_seq)
+            .unwrap_or(self.recv_mappings.len());
+        self.recv_mappings.insert(at, mapping);
+    }
+
+    // where the byte at `subflow_seq` belongs in the connection, and how many bytes from
+    // there on its mapping covers. None while no mapping for it has arrived
+    pub fn data_seq_at(&mut self, subflow_seq: SeqNum) -> Option<(u64, usize)> {
+        while self
+            .recv_mappings
+            .front()
+            .is_some_and(|mapping| mapping.end() <= subflow_seq)
+        {
+            self.recv_mappings.pop_front();
+        }
+        let mapping = self
+            .recv_mappings
+            .iter()
+            .find(|mapping| mapping.contains(subflow_seq))?;
+        let offset = subflow_seq - mapping.subflow_seq;
+        Some((
+            mapping.data_seq + offset as u64,
+            (mapping.len as u32 - offset) as usize,
+        ))
+    }
+
+    // data without a mapping on the first subflow means the peer fell back to plain TCP
+    pub fn is_unmapped(&self) -> bool {
+        self.join.is_none() && !self.mapped
+    }
+}
+
+/// the connection-level state of a Multipath TCP connection, shared by its subflows
+#[derive(Debug)]
+pub struct MptcpConnection {
+    pub local_key: u64,
+    pub remote_key: Option<u64>,
+    pub subflows: Vec<SockID>,
+    // the peer doesn't speak MPTCP: the first subflow is a plain TCP connection
+    pub fallback: bool,
+    pub send_next: u64, // data sequence number of the next byte written
+    pub recv_next: u64, // of the next byte expected from the peer
+    reassembly: BTreeMap<u64, Vec<u8>>, // arrived ahead of recv_next
+    pub received: VecDeque<u8>, // in order, not read yet
+    next_address_id: u8,
+}
+
+impl MptcpConnection {
+    pub fn new(local_key: u64) -> Self {
+        Self {
+            local_key,
+            remote_key: None,
+            subflows: Vec::new(),
+            fallback: false,
+            send_next: initial_data_seq(local_key).wrapping_add(1),
+            recv_next: 0,
+            reassembly: BTreeMap::new(),
+            received: VecDeque::new(),
+            next_address_id: 1,
+        }
+    }
+
+    pub fn set_remote_key(&mut self, remote_key: u64) {
+        self.remote_key = Some(remote_key);
+        self.recv_next = initial_data_seq(remote_key).wrapping_add(1);
+    }
+
+    // address ID of the next subflow we join, 0 being the first subflow's
+    pub fn next_address_id(&mut self) -> u8 {
+        let id = self.next_address_id;
+        self.next_address_id = self.next_address_id.wrapping_add(1).max(1);
+        id
+    }
+
+    // whether data from `data_seq` on may be taken out of its subflow now
+    pub fn accepts(&self, data_seq: u64) -> bool {
+        let read = self.recv_next.wrapping_sub(self.received.len() as u64);
+        data_seq.wrapping_sub(read) < CONNECTION_RECV_WINDOW || self.reaches(data_seq)
+    }
+
+    // file `bytes` from `data_seq` on, releasing whatever becomes contiguous
+    pub fn insert(&mut self, data_seq: u64, bytes: Vec<u8>) {
+        if !self.reaches(data_seq) {
+            self.reassembly.entry(data_seq).or_insert(bytes);
+            return;
+        }
+        self.append(data_seq, &bytes);
+        while let Some(&data_seq) = self.reassembly.keys().next() {
+            if !self.reaches(data_seq) {
+                break;
+            }
+            let bytes = self.reassembly.remove(&data_seq).unwrap();
+            self.append(data_seq, &bytes);
+        }
+    }
+
+    // `data_seq` is at or before recv_next
+    fn reaches(&self, data_seq: u64) -> bool {
+        self.recv_next.wrapping_sub(data_seq) < 1 << 63
+    }
+
+    // what `bytes` from `data_seq` on have beyond recv_next. the same data may have
+    // arrived on two subflows
+    fn append(&mut self, data_seq: u64, bytes: &[u8]) {
+        let behind = self.recv_next.wrapping_sub(data_seq) as usize;
+        if behind < bytes.len() {
+            self.received.extend(&bytes[behind..]);
+            self.recv_next = data_seq.wrapping_add(bytes.len() as u64);
+        }
+    }
+}
+
+// a fresh key whose token `taken` doesn't know yet
+pub fn new_key(taken: impl Fn(u32) -> bool) -> u64 {
+    loop {
+        let key = rand::random();
+        if !taken(token(key)) {
+            return key;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the body `serialize` writes behind NOP padding, kind and length, parsed back
+    fn round_trip(option: &MptcpOption) -> Option<MptcpOption> {
+        let mut bytes = Vec::new();
+        serialize(option, &mut bytes);
+        assert_eq!(bytes.len() % 4, 0);
+        let at = bytes.iter().position(|&byte| byte != NOP).unwrap();
+        assert_eq!(bytes[at], MPTCP);
+        assert_eq!(bytes[at + 1] as usize, bytes.len() - at);
+        parse(&bytes[at + 2..])
+    }
+
+    #[test]
+    fn options_round_trip() {
+        let mapping = Some(Mapping {
+            data_seq: 0x0123_4567_89ab_cdef,
+            subflow_seq: SeqNum(1),
+            len: 1400,
+        });
+        let options = [
+            MptcpOption::Capable {
+                sender_key: None,
+                receiver_key: None,
+                checksum_required: false,
+            },
+            MptcpOption::Capable {
+                sender_key: Some(0x0123_4567_89ab_cdef),
+                receiver_key: None,
+                checksum_required: true,
+            },
+            MptcpOption::Capable {
+                sender_key: Some(0x0123_4567_89ab_cdef),
+                receiver_key: Some(0xfedc_ba98_7654_3210),
+                checksum_required: false,
+            },
+            MptcpOption::JoinSyn {
+                token: 0xdead_beef,
+                nonce: 42,
+                address_id: 3,
+                backup: true,
+            },
+            MptcpOption::JoinSynAck {
+                hmac: 0x0123_4567_89ab_cdef,
+                nonce: 43,
+                address_id: 4,
+                backup: false,
+            },
+            MptcpOption::JoinAck { hmac: [0xa5; 20] },
+            MptcpOption::Dss {
+                data_ack: Some(u64::MAX),
+                mapping: None,
+                data_seq_64: false,
+            },
+            MptcpOption::Dss {
+                data_ack: None,
+                mapping,
+                data_seq_64: true,
+            },
+            MptcpOption::Dss {
+                data_ack: Some(7),
+                mapping,
+                data_seq_64: true,
+            },
+        ];
+        for option in options {
+            assert_eq!(round_trip(&option), Some(option));
+        }
+    }
+
+    #[test]
+    fn dss_with_32_bit_data_seq_keeps_the_lower_bits() {
+        let option = MptcpOption::Dss {
+            data_ack: Some(7),
+            mapping: Some(Mapping {
+                data_seq: 0x0123_4567_89ab_cdef,
+                subflow_seq: SeqNum(1),
+                len: 1400,
+            }),
+            data_seq_64: false,
+        };
+        let expected = MptcpOption::Dss {
+            data_ack: Some(7),
+            mapping: Some(Mapping {
+                data_seq: 0x89ab_cdef,
+                subflow_seq: SeqNum(1),
+                len: 1400,
+            }),
+            data_seq_64: false,
+        };
+        assert_eq!(round_trip(&option), Some(expected));
+    }
+
+    #[test]
+    fn dss_parses_either_width() {
+        // a 4-byte DATA_ACK and a 4-byte data sequence number
+        let short = [
+            &[DSS << 4, DATA_ACK | MAPPING][..],
+            &0x1122_3344u32.to_be_bytes(),
+            &0x89ab_cdefu32.to_be_bytes(),
+            &1u32.to_be_bytes(),
+            &1400u16.to_be_bytes(),
+        ]
+        .concat();
+        assert_eq!(
+            parse(&short),
+            Some(MptcpOption::Dss {
+                data_ack: Some(0x1122_3344),
+                mapping: Some(Mapping {
+                    data_seq: 0x89ab_cdef,
+                    subflow_seq: SeqNum(1),
+                    len: 1400,
+                }),
+                data_seq_64: false,
+            })
+        );
+        // both 8 bytes wide
+        let long = [
+            &[DSS << 4, DATA_ACK | DATA_ACK_64 | MAPPING | DATA_SEQ_64][..],
+            &0x01_1122_3344u64.to_be_bytes(),
+            &0x0123_4567_89ab_cdefu64.to_be_bytes(),
+            &1u32.to_be_bytes(),
+            &1400u16.to_be_bytes(),
+        ]
+        .concat();
+        assert_eq!(
+            parse(&long),
+            Some(MptcpOption::Dss {
+                data_ack: Some(0x01_1122_3344),
+                mapping: Some(Mapping {
+                    data_seq: 0x0123_4567_89ab_cdef,
+                    subflow_seq: SeqNum(1),
+                    len: 1400,
+                }),
+                data_seq_64: true,
+            })
+        );
+        // cut short
+        assert_eq!(parse(&long[..long.len() - 1]), None);
+    }
+
+    #[test]
+    fn expand_data_seq_picks_the_closest() {
+        assert_eq!(expand_data_seq(0x5, 0x1_0000_0000), 0x1_0000_0005);
+        // past a wrap of the lower 32 bits
+        assert_eq!(expand_data_seq(0x10, 0x1_ffff_fff0), 0x2_0000_0010);
+        // from before one
+        assert_eq!(expand_data_seq(0xffff_fff0, 0x2_0000_0010), 0x1_ffff_fff0);
+        // the upper bits of `data_seq` don't count
+        assert_eq!(expand_data_seq(0x7_0000_0005, 0x1_0000_0000), 0x1_0000_0005);
+    }
+
+    fn connection(recv_next: u64) -> MptcpConnection {
+        let mut connection = MptcpConnection::new(1);
+        connection.recv_next = recv_next;
+        connection
+    }
+
+    fn received(connection: &MptcpConnection) -> Vec<u8> {
+        connection.received.iter().copied().collect()
+    }
+
+    #[test]
+    fn out_of_order_data_is_reassembled() {
+        let mut connection = connection(1000);
+        connection.insert(1010, b"klmno".to_vec());
+        connection.insert(1005, b"fghij".to_vec());
+        assert!(connection.received.is_empty());
+        assert_eq!(connection.recv_next, 1000);
+        connection.insert(1000, b"abcde".to_vec());
+        assert_eq!(received(&connection), b"abcdefghijklmno");
+        assert_eq!(connection.recv_next, 1015);
+    }
+
+    #[test]
+    fn data_arriving_on_two_subflows_is_taken_once() {
+        let mut connection = connection(1000);
+        connection.insert(1000, b"abcde".to_vec());
+        // the same data, reinjected on another subflow, and a little more
+        connection.insert(1003, b"defgh".to_vec());
+        connection.insert(1000, b"abc".to_vec());
+        assert_eq!(received(&connection), b"abcdefgh");
+        // overlapping out of order as well
+        connection.insert(1012, b"mnopq".to_vec());
+        connection.insert(1010, b"klmno".to_vec());
+        connection.insert(1008, b"ijk".to_vec());
+        assert_eq!(received(&connection), b"abcdefghijklmnopq");
+        assert_eq!(connection.recv_next, 1017);
+    }
+
+    #[test]
+    fn reassembly_goes_across_the_end_of_the_data_sequence_space() {
+        let mut connection = connection(u64::MAX - 2);
+        connection.insert(0, b"def".to_vec());
+        connection.insert(u64::MAX - 2, b"abc".to_vec());
+        assert_eq!(received(&connection), b"abcdef");
+        assert_eq!(connection.recv_next, 3);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::device::EthernetDevice;
+use crate::route::RoutingTable;
+use crate::tcp::TCP;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::process::Command;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+// sets TX checksum offload, struct ethtool_value
+const ETHTOOL_STXCSUM: u32 = 0x17;
+const TOYTCP_ADDR: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 1);
+const KERNEL_ADDR: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 2);
+// the kernel's on toytcp's side, which puts the prefix on the link there
+const LINK_ADDR: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 3);
+const PREFIX_LEN: u8 = 24;
+const PORT: u16 = 8080;
+const BULK_LEN: usize = 4 << 20;
+
+/// two network namespaces joined by a veth pair: toytcp on an AF_PACKET socket of its end
+/// in the one, at an address the kernel there doesn't have and drops packets to silently,
+/// and the kernel's TCP in the other. removed when dropped. takes root and iproute2
+struct Link {
+    name: String,
+}
+
+#[repr(C)]
+struct EthtoolValue {
+    cmd: u32,
+    data: u32,
+}
+
+impl Link {
+    // `name` tells the namespaces and devices of each test apart, short enough for an
+    // interface name
+    fn new(name: &str) -> Self {
+        let link = Self {
+            name: name.to_string(),
+        };
+        ip(&["netns", "add", &link.namespace('a')]);
+        ip(&["netns", "add", &link.namespace('b')]);
+        ip(&[
+            "link",
+            "add",
+            &link.device('a'),
+            "netns",
+            &link.namespace('a'),
+            "type",
+            "veth",
+            "peer",
+            "name",
+            &link.device('b'),
+            "netns",
+            &link.namespace('b'),
+        ]);
+        for (side, addr) in [('a', LINK_ADDR), ('b', KERNEL_ADDR)] {
+            let addr = format!("{}/{}", addr, PREFIX_LEN);
+            ip(&[
+                "-n",
+                &link.namespace(side),
+                "addr",
+                "add",
+                &addr,
+                "dev",
+                &link.device(side),
+            ]);
+            ip(&[
+                "-n",
+                &link.namespace(side),
+                "link",
+                "set",
+                &link.device(side),
+                "up",
+            ]);
+        }
+        // nobody answers ARP for toytcp's address
+        let mac = ip(&[
+            "-n",
+            &link.namespace('a'),
+            "-br",
+            "link",
+            "show",
+            &link.device('a'),
+        ]);
+        let mac = mac.split_whitespace().nth(2).expect("no MAC address");
+        ip(&[
+            "-n",
+            &link.namespace('b'),
+            "neigh",
+            "add",
+            &TOYTCP_ADDR.to_string(),
+            "lladdr",
+            mac,
+            "dev",
+            &link.device('b'),
+            "nud",
+            "permanent",
+        ]);
+        // the kernel would leave the checksums of its segments to the veth, and the packet
+        // socket at the other end sees them without
+        link.in_namespace('b', || disable_tx_checksum(&link.device('b')))
+            .expect("failed to turn checksum offload off");
+        link
+    }
+
+    fn namespace(&self, side: char) -> String {
+        format!("toytcp-{}-{}", self.name, side)
+    }
+
+    fn device(&self, side: char) -> String {
+        format!("{}{}", self.name, side)
+    }
+
+    // runs `f` on this thread in the namespace of `side`. sockets opened there stay in it
+    fn in_namespace<T>(&self, side: char, f: impl FnOnce() -> T) -> T {
+        let original = File::open("/proc/thread-self/ns/net").expect("no network namespace");
+        let namespace = File::open(format!("/run/netns/{}", self.namespace(side)))
+            .expect("no such network namespace");
+        setns(&namespace);
+        let result = f();
+        setns(&original);
+        result
+    }
+
+    fn toytcp(&self) -> Arc<TCP> {
+        self.in_namespace('a', || {
+            let device =
+                EthernetDevice::packet_socket(&self.device('a')).expect("failed to open device");
+            TCP::with_device(
+                device,
+                RoutingTable::on_link(&self.device('a'), TOYTCP_ADDR, PREFIX_LEN, None),
+            )
+        })
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        for side in ['a', 'b'] {
+            let _ = Command::new("ip")
+                .args(["netns", "del", &self.namespace(side)])
+                .status();
+        }
+    }
+}
+
+// runs ip with `args`, returning what it printed
+fn ip(args: &[&str]) -> String {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .expect("failed to run ip");
+    assert!(
+        output.status.success(),
+        "ip {}: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn setns(namespace: &File) {
+    let result = unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) };
+    assert!(result == 0, "setns: {}", io::Error::last_os_error());
+}
+
+fn disable_tx_checksum(device: &str) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut value = EthtoolValue {
+        cmd: ETHTOOL_STXCSUM,
+        data: 0,
+    };
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(device.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    request.ifr_ifru.ifru_data = &mut value as *mut EthtoolValue as *mut libc::c_char;
+    let result = unsafe { libc::ioctl(fd, libc::SIOCETHTOOL, &mut request) };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if result < 0 {
+        return Err(error);
+    }
+    Ok(())
+}
+
+// bytes that tell a lost, repeated or misplaced segment from the right one
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// from a toytcp socket until the peer closes
+fn recv_to_end(tcp: &TCP, sock_id: crate::SockID) -> Vec<u8> {
+    let mut received = Vec::new();
+    let mut buffer = vec![0; 65536];
+    loop {
+        let len = tcp.recv(sock_id, &mut buffer).expect("recv failed");
+        if len == 0 {
+            return received;
+        }
+        received.extend_from_slice(&buffer[..len]);
+    }
+}
+
+// the kernel's end of an echo: reads until the peer shuts down its side, then writes back
+// what it read and closes
+fn kernel_echo(mut stream: TcpStream) {
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    stream.write_all(&received).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+}
+
+#[test]
+fn connect_to_the_kernel() {
+    let link = Link::new("ttc");
+    let tcp = link.toytcp();
+    let listener = link.in_namespace('b', || TcpListener::bind((KERNEL_ADDR, PORT)).unwrap());
+    let kernel = thread::spawn(move || kernel_echo(listener.accept().unwrap().0));
+    let sock_id = tcp.connect((KERNEL_ADDR, PORT)).unwrap();
+    let data = pattern(BULK_LEN);
+    assert_eq!(tcp.send(sock_id, &data).unwrap(), BULK_LEN);
+    tcp.shutdown(sock_id, Shutdown::Write).unwrap();
+    assert!(recv_to_end(&tcp, sock_id) == data, "echo differs");
+    tcp.close(sock_id).unwrap();
+    kernel.join().unwrap();
+}
+
+#[test]
+fn accept_from_the_kernel() {
+    let link = Link::new("tta");
+    let tcp = link.toytcp();
+    let listener = tcp.listen((TOYTCP_ADDR, PORT).into(), 1).unwrap();
+    let stream = link.in_namespace('b', || TcpStream::connect((TOYTCP_ADDR, PORT)).unwrap());
+    let data = pattern(BULK_LEN);
+    let sent = data.clone();
+    let kernel = thread::spawn(move || {
+        let mut stream = stream;
+        stream.write_all(&sent).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    let sock_id = tcp.accept(listener).unwrap();
+    let received = recv_to_end(&tcp, sock_id);
+    assert!(received == data, "data differs");
+    assert_eq!(tcp.send(sock_id, &received).unwrap(), BULK_LEN);
+    tcp.close(sock_id).unwrap();
+    assert!(kernel.join().unwrap() == data, "echo differs");
+}
+
+#[test]
+fn reset_by_the_kernel() {
+    let link = Link::new("ttr");
+    let tcp = link.toytcp();
+    let listener = link.in_namespace('b', || TcpListener::bind((KERNEL_ADDR, PORT)).unwrap());
+    let sock_id = tcp.connect((KERNEL_ADDR, PORT)).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    // closing with a zero linger time sends RST
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(result, 0);
+    drop(stream);
+    let error = tcp.recv(sock_id, &mut [0; 16]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(io::ErrorKind::ConnectionReset)
+    );
+}
+
+#[test]
+fn reset_by_toytcp() {
+    let link = Link::new("ttk");
+    let tcp = link.toytcp();
+    let listener = link.in_namespace('b', || TcpListener::bind((KERNEL_ADDR, PORT)).unwrap());
+    let sock_id = tcp.connect((KERNEL_ADDR, PORT)).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+    let data = pattern(BULK_LEN);
+    let (received_tx, received_rx) = mpsc::channel();
+    let kernel = thread::spawn(move || {
+        let mut received = vec![0; BULK_LEN];
+        stream.read_exact(&mut received).unwrap();
+        received_tx.send(received).unwrap();
+        stream.read(&mut [0; 16]).unwrap_err()
+    });
+    assert_eq!(tcp.send(sock_id, &data).unwrap(), BULK_LEN);
+    // all of it has arrived before the RST
+    assert!(received_rx.recv().unwrap() == data, "data differs");
+    tcp.abort(sock_id).unwrap();
+    assert_eq!(
+        kernel.join().unwrap().kind(),
+        io::ErrorKind::ConnectionReset
+    );
+}
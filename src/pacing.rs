@@ -0,0 +1,31 @@
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+/// spaces segments out so that they leave no faster than the congestion controller's
+/// pacing rate. both the send path and the retransmission timer charge it.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    next_release: SystemTime,
+}
+
+impl Pacer {
+    pub fn new(now: SystemTime) -> Self {
+        Self { next_release: now }
+    }
+
+    // time to wait at `now` before the next segment may leave
+    pub fn delay(&self, now: SystemTime) -> Duration {
+        self.next_release.duration_since(now).unwrap_or_default()
+    }
+
+    // `bytes` just went out; None means the socket is not paced.
+    // idle time doesn't build up credit for a burst.
+    pub fn on_send(&mut self, bytes: usize, rate: Option<f64>, now: SystemTime) {
+        let rate = match rate {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return,
+        };
+        let start = cmp::max(self.next_release, now);
+        self.next_release = start + Duration::from_secs_f64(bytes as f64 / rate);
+    }
+}
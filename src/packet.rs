@@ -1,6 +1,11 @@
+use crate::mptcp::MptcpOption;
+use crate::pcap::Direction;
+use crate::seq::SeqNum;
 use crate::tcpflags;
+use crate::tcpoption::{self, TcpOption};
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
 use pnet::util;
+use tracing::{trace, Level};
 
 use std::fmt::{self, Debug};
 use std::net::Ipv4Addr;
@@ -14,9 +19,11 @@ pub struct TCPPacket {
 
 impl TCPPacket {
     pub fn new(payload_len: usize) -> Self {
-        Self {
+        let mut packet = Self {
             buffer: vec![0; TCP_HEADER_SIZE + payload_len],
-        }
+        };
+        packet.set_data_offset(5);
+        packet
     }
 
     pub fn get_src(&self) -> u16 {
@@ -28,23 +35,52 @@ impl TCPPacket {
     }
 
     // sequence number
-    pub fn get_seq(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn get_seq(&self) -> SeqNum {
+        SeqNum(u32::from_be_bytes([
             self.buffer[4],
             self.buffer[5],
             self.buffer[6],
             self.buffer[7],
-        ])
+        ]))
     }
 
     // next sequnce number to be received
-    pub fn get_ack(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn get_ack(&self) -> SeqNum {
+        SeqNum(u32::from_be_bytes([
             self.buffer[8],
             self.buffer[9],
             self.buffer[10],
             self.buffer[11],
-        ])
+        ]))
+    }
+
+    // header length including options
+    pub fn get_header_len(&self) -> usize {
+        let len = (self.buffer[12] >> 4) as usize * 4;
+        len.clamp(TCP_HEADER_SIZE, self.buffer.len())
+    }
+
+    pub fn get_options(&self) -> Vec<TcpOption> {
+        tcpoption::parse(&self.buffer[TCP_HEADER_SIZE..self.get_header_len()])
+    }
+
+    pub fn get_mptcp_option(&self) -> Option<MptcpOption> {
+        self.get_options()
+            .into_iter()
+            .find_map(|option| match option {
+                TcpOption::Mptcp(option) => Some(option),
+                _ => None,
+            })
+    }
+
+    // (TSval, TSecr) of the timestamps option
+    pub fn get_timestamps(&self) -> Option<(u32, u32)> {
+        self.get_options()
+            .into_iter()
+            .find_map(|option| match option {
+                TcpOption::Timestamps { value, echo_reply } => Some((value, echo_reply)),
+                _ => None,
+            })
     }
 
     pub fn get_flag(&self) -> u8 {
@@ -60,6 +96,11 @@ impl TCPPacket {
         u16::from_be_bytes([self.buffer[16], self.buffer[17]])
     }
 
+    // offset from the sequence number to the byte following the urgent data, with URG set
+    pub fn get_urgent_pointer(&self) -> u16 {
+        u16::from_be_bytes([self.buffer[18], self.buffer[19]])
+    }
+
     pub fn set_src(&mut self, port: u16) {
         self.buffer[0..2].copy_from_slice(&port.to_be_bytes())
     }
@@ -68,12 +109,12 @@ impl TCPPacket {
         self.buffer[2..4].copy_from_slice(&port.to_be_bytes())
     }
 
-    pub fn set_seq(&mut self, num: u32) {
-        self.buffer[4..8].copy_from_slice(&num.to_be_bytes())
+    pub fn set_seq(&mut self, num: SeqNum) {
+        self.buffer[4..8].copy_from_slice(&num.0.to_be_bytes())
     }
 
-    pub fn set_ack(&mut self, num: u32) {
-        self.buffer[8..12].copy_from_slice(&num.to_be_bytes())
+    pub fn set_ack(&mut self, num: SeqNum) {
+        self.buffer[8..12].copy_from_slice(&num.0.to_be_bytes())
     }
 
     pub fn set_data_offset(&mut self, offset: u8) {
@@ -92,9 +133,49 @@ impl TCPPacket {
         self.buffer[16..18].copy_from_slice(&checksum.to_be_bytes())
     }
 
+    pub fn set_urgent_pointer(&mut self, pointer: u16) {
+        self.buffer[18..20].copy_from_slice(&pointer.to_be_bytes())
+    }
+
     pub fn set_payload(&mut self, payload: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + payload.len() as usize]
-            .copy_from_slice(payload)
+        let header_len = self.get_header_len();
+        self.buffer[header_len..header_len + payload.len() as usize].copy_from_slice(payload)
+    }
+
+    // replaces the options already set, keeping the payload
+    pub fn set_options(&mut self, options: &[TcpOption]) {
+        let bytes = tcpoption::serialize(options);
+        let data_offset = ((TCP_HEADER_SIZE + bytes.len()) / 4) as u8;
+        let header_len = self.get_header_len();
+        self.buffer.splice(TCP_HEADER_SIZE..header_len, bytes);
+        self.set_data_offset(data_offset);
+    }
+
+    // the segment with its header and options but without data or FIN
+    pub fn without_payload(&self) -> Self {
+        let header_len = self.buffer.len() - self.payload().len();
+        let mut packet = Self {
+            buffer: self.buffer[..header_len].to_vec(),
+        };
+        packet.set_flag(self.get_flag() & !tcpflags::FIN);
+        packet
+    }
+
+    // the segment as it goes on the wire, for TCP-AO to fill in its MAC
+    pub fn packet_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    // sequence space occupied by the segment: payload plus SYN and FIN
+    pub fn segment_len(&self) -> u32 {
+        let mut len = self.payload().len() as u32;
+        if self.get_flag() & tcpflags::SYN > 0 {
+            len += 1;
+        }
+        if self.get_flag() & tcpflags::FIN > 0 {
+            len += 1;
+        }
+        len
     }
 
     pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
@@ -116,7 +197,7 @@ impl Packet for TCPPacket {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.get_header_len()..]
     }
 }
 
@@ -144,3 +225,20 @@ impl<'a> From<TcpPacket<'a>> for TCPPacket {
         }
     }
 }
+
+// one TRACE event for a segment going out or coming in, within the span of its connection
+pub fn trace_segment(segment: &[u8], direction: Direction) {
+    if !tracing::enabled!(Level::TRACE) || segment.len() < TCP_HEADER_SIZE {
+        return;
+    }
+    let seq = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    let ack = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    let flags = tcpflags::flag_to_string(segment[13]);
+    let flags = flags.trim_end();
+    let header_len = ((segment[12] >> 4) as usize * 4).clamp(TCP_HEADER_SIZE, segment.len());
+    let len = segment.len() - header_len;
+    match direction {
+        Direction::Incoming => trace!(seq, ack, flags, len, "segment received"),
+        Direction::Outgoing => trace!(seq, ack, flags, len, "segment sent"),
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: u32 = 0xa1b2_c3d4; // microsecond timestamps
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+const VERSION: (u16, u16) = (2, 4);
+const SNAPLEN: u32 = 65535;
+// Linux "cooked" capture: a pseudo-header in place of the link layer, which IP packets
+// without a link layer fit into
+const LINKTYPE_LINUX_SLL: u32 = 113;
+// link types read besides: BSD loopback, Ethernet, and bare IP packets
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+// no link-layer address, as for a tunnel
+const ARPHRD_NONE: u16 = 0xfffe;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERNET_HEADER_LEN: usize = 14;
+const AF_INET: u32 = 2;
+const SLL_HEADER_LEN: usize = 16;
+
+/// which way a packet went, from the socket's side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// an IPv4 packet read from a capture
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp: Duration, // since the epoch
+    // known for cooked captures only
+    pub direction: Option<Direction>,
+    pub packet: Vec<u8>,
+}
+
+/// a .pcap file the IPv4 packets of a socket go into as they are sent and received, each
+/// behind a Linux cooked-capture header that says which way it went
+#[derive(Debug)]
+pub struct Capture {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    // truncates the file if it exists
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&MAGIC.to_le_bytes())?;
+        writer.write_all(&VERSION.0.to_le_bytes())?;
+        writer.write_all(&VERSION.1.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?; // time zone, UTC
+        writer.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_LINUX_SLL.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    // one record, flushed so that the file can be read while the connection goes on
+    pub fn record(&self, packet: &[u8], direction: Direction) -> io::Result<()> {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let packet_type: u16 = match direction {
+            Direction::Incoming => 0, // to us
+            Direction::Outgoing => 4, // sent by us
+        };
+        let len = (SLL_HEADER_LEN + packet.len()) as u32;
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        writer.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        writer.write_all(&len.min(SNAPLEN).to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        // the pseudo-header is big-endian, as on the wire
+        writer.write_all(&packet_type.to_be_bytes())?;
+        writer.write_all(&ARPHRD_NONE.to_be_bytes())?;
+        writer.write_all(&0u16.to_be_bytes())?; // address length
+        writer.write_all(&[0; 8])?; // address
+        writer.write_all(&ETHERTYPE_IPV4.to_be_bytes())?;
+        let captured = packet.len().min(SNAPLEN as usize - SLL_HEADER_LEN);
+        writer.write_all(&packet[..captured])?;
+        writer.flush()
+    }
+}
+
+// the IPv4 packets of a .pcap file in either byte order, with their link-layer headers
+// taken off. records of other protocols are skipped
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let data = fs::read(path)?;
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let header = data
+        .get(..FILE_HEADER_LEN)
+        .ok_or_else(|| invalid("not a pcap file"))?;
+    let magic = [header[0], header[1], header[2], header[3]];
+    let (little_endian, nanos) = if magic == MAGIC.to_le_bytes() {
+        (true, false)
+    } else if magic == MAGIC.to_be_bytes() {
+        (false, false)
+    } else if magic == MAGIC_NANOS.to_le_bytes() {
+        (true, true)
+    } else if magic == MAGIC_NANOS.to_be_bytes() {
+        (false, true)
+    } else {
+        return Err(invalid("not a pcap file"));
+    };
+    let u32_at = |bytes: &[u8], offset: usize| {
+        let field = [
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ];
+        if little_endian {
+            u32::from_le_bytes(field)
+        } else {
+            u32::from_be_bytes(field)
+        }
+    };
+    let link_type = u32_at(header, 20) & 0xffff;
+    let mut records = Vec::new();
+    let mut offset = FILE_HEADER_LEN;
+    while offset + RECORD_HEADER_LEN <= data.len() {
+        let secs = u32_at(&data, offset);
+        let fraction = u32_at(&data, offset + 4);
+        let len = u32_at(&data, offset + 8) as usize;
+        offset += RECORD_HEADER_LEN;
+        let frame = data
+            .get(offset..offset + len)
+            .ok_or_else(|| invalid("truncated record"))?;
+        offset += len;
+        let nanos = if nanos {
+            fraction
+        } else {
+            fraction.saturating_mul(1000)
+        };
+        let (direction, packet) = match link_type {
+            LINKTYPE_LINUX_SLL => match sll_payload(frame) {
+                Some(found) => found,
+                None => continue,
+            },
+            LINKTYPE_ETHERNET => match ethernet_payload(frame) {
+                Some(packet) => (None, packet),
+                None => continue,
+            },
+            LINKTYPE_NULL if frame.len() >= 4 => {
+                // the address family, in the byte order of the capturing host
+                let family = [frame[0], frame[1], frame[2], frame[3]];
+                if u32::from_le_bytes(family) != AF_INET && u32::from_be_bytes(family) != AF_INET {
+                    continue;
+                }
+                (None, &frame[4..])
+            }
+            LINKTYPE_RAW | LINKTYPE_IPV4 => (None, frame),
+            LINKTYPE_NULL => continue,
+            _ => return Err(invalid("unsupported link type")),
+        };
+        if packet.first().map(|byte| byte >> 4) != Some(4) {
+            continue;
+        }
+        records.push(Record {
+            timestamp: Duration::new(secs as u64, nanos),
+            direction,
+            packet: packet.to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+fn sll_payload(frame: &[u8]) -> Option<(Option<Direction>, &[u8])> {
+    let header = frame.get(..SLL_HEADER_LEN)?;
+    if u16::from_be_bytes([header[14], header[15]]) != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let direction = match u16::from_be_bytes([header[0], header[1]]) {
+        4 => Direction::Outgoing,
+        _ => Direction::Incoming,
+    };
+    Some((Some(direction), &frame[SLL_HEADER_LEN..]))
+}
+
+// behind any number of VLAN tags
+fn ethernet_payload(frame: &[u8]) -> Option<&[u8]> {
+    let mut offset = ETHERNET_HEADER_LEN - 2;
+    loop {
+        let ether_type = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+        match ether_type {
+            ETHERTYPE_IPV4 => return frame.get(offset + 2..),
+            ETHERTYPE_VLAN => offset += 4,
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::MemoryDevice;
+    use crate::route::RoutingTable;
+    use crate::socket::{SockID, TcpStatus};
+    use crate::tcp::TCP;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::path::Path;
+
+    // fixtures/syn.pcap is a cooked capture of a SYN from 192.0.2.1:40000 with ISN 1000,
+    // and of the SYN-ACK it was answered with on the way out
+    #[test]
+    fn replayed_syn_opens_a_connection() {
+        let local_addr = Ipv4Addr::new(192, 168, 0, 1);
+        let (device, _peer) = MemoryDevice::pair();
+        let routes = RoutingTable::on_link("replay0", local_addr, 24, None);
+        let tcp = TCP::with_device(device, routes);
+        tcp.listen(SocketAddr::from((local_addr, 8080)), 8).unwrap();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/syn.pcap");
+        // the outgoing SYN-ACK is not fed back
+        assert_eq!(tcp.replay(&path).unwrap(), 1);
+        let sock_id = SockID(local_addr, Ipv4Addr::new(192, 0, 2, 1), 8080, 40000);
+        let info = tcp.socket_info(sock_id).unwrap();
+        assert_eq!(info.status, TcpStatus::SynRcvd);
+        assert_eq!(info.recv_next, 1001);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::socket::SockID;
+use crate::tcp::TCP;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const READABLE: u8 = 1;
+pub const WRITABLE: u8 = 1 << 1;
+pub const ERROR: u8 = 1 << 2;
+// urgent data is waiting for recv_oob
+pub const PRIORITY: u8 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub sock_id: SockID,
+    pub readiness: u8,
+}
+
+/// level-triggered readiness notification over a set of registered sockets
+pub struct Poller {
+    tcp: Arc<TCP>,
+    interests: HashMap<SockID, u8>,
+}
+
+impl Poller {
+    pub fn new(tcp: &Arc<TCP>) -> Self {
+        Self {
+            tcp: tcp.clone(),
+            interests: HashMap::new(),
+        }
+    }
+
+    // registering an already registered socket replaces its interest
+    pub fn register(&mut self, sock_id: SockID, interest: u8) {
+        self.interests.insert(sock_id, interest);
+    }
+
+    pub fn deregister(&mut self, sock_id: SockID) {
+        self.interests.remove(&sock_id);
+    }
+
+    // wait until at least one registered socket is ready or the timeout expires.
+    // ERROR is always reported regardless of the registered interest.
+    pub fn poll(&self, timeout: Option<Duration>) -> Vec<Event> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // take the generation before scanning so that no change in between is missed
+            let generation = self.tcp.readiness_generation();
+            let events: Vec<Event> = self
+                .interests
+                .iter()
+                .filter_map(|(&sock_id, &interest)| {
+                    let readiness = self.tcp.readiness(sock_id) & (interest | ERROR);
+                    if readiness == 0 {
+                        None
+                    } else {
+                        Some(Event { sock_id, readiness })
+                    }
+                })
+                .collect();
+            if !events.is_empty() {
+                return events;
+            }
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return events;
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            self.tcp.wait_readiness_change(generation, remaining);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::seq::SeqNum;
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+// the reordering window grows by this much of the minimum RTT per D-SACK round
+const REO_WND_STEP_DIVISOR: u32 = 4;
+// rounds of recovery the grown reordering window is kept for after the last D-SACK
+const REO_WND_PERSIST: u8 = 16;
+// the peer may delay the ACK of a lone segment this long (WCDelAckT of RFC 8985)
+const MAX_ACK_DELAY: Duration = Duration::from_millis(200);
+// probe timeout before the first round trip was measured
+const INITIAL_PTO: Duration = Duration::from_secs(1);
+
+/// time-based loss detection and tail loss probes (RACK-TLP, RFC 8985).
+/// a segment is lost once one sent sufficiently later has been delivered, rather than once
+/// enough duplicate ACKs have arrived.
+#[derive(Debug, Clone)]
+pub struct Rack {
+    xmit_ts: Option<SystemTime>, // sent most recently of the segments delivered so far
+    end_seq: SeqNum,             // end of that segment
+    rtt: Duration,               // round trip of that segment
+    min_rtt: Option<Duration>,
+    reordering_seen: bool,
+    reo_wnd_mult: u32,
+    reo_wnd_persist: u8,
+    dsack_round: Option<SeqNum>, // SND.NXT when the window was last grown for a D-SACK
+    pub tlp_end_seq: Option<SeqNum>, // SND.NXT when the outstanding probe was sent
+    pub tlp_is_retrans: bool,    // the probe resent a segment instead of sending new data
+    tlp_dsack: bool,             // a D-SACK showed the probe was unnecessary
+}
+
+impl Rack {
+    pub fn new() -> Self {
+        Self {
+            xmit_ts: None,
+            end_seq: SeqNum(0),
+            rtt: Duration::ZERO,
+            min_rtt: None,
+            reordering_seen: false,
+            reo_wnd_mult: 1,
+            reo_wnd_persist: 0,
+            dsack_round: None,
+            tlp_end_seq: None,
+            tlp_is_retrans: false,
+            tlp_dsack: false,
+        }
+    }
+
+    // a segment sent at `xmit_ts` and ending at `end_seq` was acknowledged or SACKed
+    pub fn on_delivered(
+        &mut self,
+        xmit_ts: SystemTime,
+        end_seq: SeqNum,
+        retransmitted: bool,
+        now: SystemTime,
+    ) {
+        let rtt = now.duration_since(xmit_ts).unwrap_or_default();
+        if retransmitted && self.min_rtt.is_some_and(|min_rtt| rtt < min_rtt) {
+            // too quick to be the ACK of the retransmission: the original made it
+            return;
+        }
+        if !retransmitted {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| cmp::min(min_rtt, rtt)));
+            if self.xmit_ts.is_some_and(|latest| xmit_ts < latest) {
+                // delivered after a segment sent later
+                self.reordering_seen = true;
+            }
+        }
+        self.rtt = rtt;
+        if self.sent_after(xmit_ts, end_seq) {
+            self.xmit_ts = Some(xmit_ts);
+            self.end_seq = end_seq;
+        }
+    }
+
+    // the peer reported data it received twice
+    pub fn on_dsack(&mut self, snd_una: SeqNum, snd_nxt: SeqNum) {
+        if self.tlp_end_seq.is_some() {
+            self.tlp_dsack = true;
+        }
+        // once per round trip
+        if self.dsack_round.is_some_and(|round| snd_una < round) {
+            return;
+        }
+        self.dsack_round = Some(snd_nxt);
+        self.reo_wnd_mult += 1;
+        self.reo_wnd_persist = REO_WND_PERSIST;
+    }
+
+    // fast recovery is over
+    pub fn on_recovery_exit(&mut self) {
+        if self.reo_wnd_persist > 0 {
+            self.reo_wnd_persist -= 1;
+            if self.reo_wnd_persist == 0 {
+                self.reo_wnd_mult = 1;
+            }
+        }
+    }
+
+    // how much later than the most recently delivered segment an outstanding one may
+    // arrive before it is deemed lost. without reordering seen, none in fast recovery
+    pub fn reo_wnd(&self, srtt: Option<Duration>, in_recovery: bool) -> Duration {
+        if !self.reordering_seen && in_recovery {
+            return Duration::ZERO;
+        }
+        let min_rtt = match self.min_rtt {
+            Some(min_rtt) => min_rtt,
+            None => return Duration::ZERO,
+        };
+        let reo_wnd = min_rtt / REO_WND_STEP_DIVISOR * self.reo_wnd_mult;
+        srtt.map_or(reo_wnd, |srtt| cmp::min(reo_wnd, srtt))
+    }
+
+    // time left before an outstanding segment is lost, zero once it is.
+    // None if no segment sent after it has been delivered yet
+    pub fn time_to_loss(
+        &self,
+        xmit_ts: SystemTime,
+        end_seq: SeqNum,
+        reo_wnd: Duration,
+        now: SystemTime,
+    ) -> Option<Duration> {
+        if self.xmit_ts.is_none() || self.sent_after(xmit_ts, end_seq) {
+            return None;
+        }
+        let deadline = xmit_ts + self.rtt + reo_wnd;
+        Some(deadline.duration_since(now).unwrap_or_default())
+    }
+
+    // probe timeout: two round trips, plus the peer's delayed ACK for a lone segment
+    pub fn pto(&self, srtt: Option<Duration>, single_segment: bool) -> Duration {
+        let srtt = match srtt {
+            Some(srtt) => srtt,
+            None => return INITIAL_PTO,
+        };
+        if single_segment {
+            srtt * 2 + MAX_ACK_DELAY
+        } else {
+            srtt * 2
+        }
+    }
+
+    pub fn on_probe_sent(&mut self, snd_nxt: SeqNum, retransmitted: bool) {
+        self.tlp_end_seq = Some(snd_nxt);
+        self.tlp_is_retrans = retransmitted;
+        self.tlp_dsack = false;
+    }
+
+    // the ACK of everything up to the probe arrived. returns true if the probe repaired a
+    // loss the congestion window has to account for: a retransmission no D-SACK disowned
+    pub fn on_probe_acked(&mut self, snd_una: SeqNum) -> bool {
+        match self.tlp_end_seq {
+            Some(end_seq) if end_seq <= snd_una => {
+                self.tlp_end_seq = None;
+                self.tlp_is_retrans && !self.tlp_dsack
+            }
+            _ => false,
+        }
+    }
+
+    // whether a segment sent at `xmit_ts` and ending at `end_seq` went out after the most
+    // recently sent of the delivered ones
+    fn sent_after(&self, xmit_ts: SystemTime, end_seq: SeqNum) -> bool {
+        match self.xmit_ts {
+            None => true,
+            Some(latest) => xmit_ts > latest || (xmit_ts == latest && end_seq > self.end_seq),
+        }
+    }
+}
@@ -0,0 +1,567 @@
+use anyhow::{Context, Result};
+use pnet::datalink;
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(any(target_os = "macos", windows))]
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(target_os = "macos")]
+use std::{mem, ptr};
+use tracing::{debug, warn};
+#[cfg(windows)]
+use windows_sys::Win32::NetworkManagement::IpHelper::{
+    GetBestRoute, GetIfEntry, MIB_IFROW, MIB_IPFORWARDROW, MIB_IPROUTE_TYPE_INDIRECT,
+};
+
+// the kernel's routes in its main table, one per line after the header
+#[cfg(target_os = "linux")]
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+#[cfg(target_os = "linux")]
+const RTF_UP: u32 = 0x1;
+#[cfg(target_os = "linux")]
+const RTF_GATEWAY: u32 = 0x2;
+// _IOWR('i', 51, struct ifreq), not in libc
+#[cfg(target_os = "macos")]
+const SIOCGIFMTU: libc::c_ulong = 0xc0206933;
+
+/// a route of the stack's own table: destinations within the first `prefix_len` bits of
+/// `destination` are reached through `device`, via `gateway` unless they are on its link.
+/// the table picks the source address of new connections, and the next hop on link-layer
+/// devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub device: String,
+    // preferred source address, else one of the device's
+    pub source: Option<Ipv4Addr>,
+    // MTU of the path, else that of the device
+    pub mtu: Option<usize>,
+}
+
+impl Route {
+    pub fn default_via(gateway: Ipv4Addr, device: &str) -> Self {
+        Self {
+            destination: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            gateway: Some(gateway),
+            device: device.to_string(),
+            source: None,
+            mtu: None,
+        }
+    }
+
+    fn contains(&self, addr: Ipv4Addr) -> bool {
+        in_prefix(addr, self.destination, self.prefix_len)
+    }
+}
+
+/// an address of one of the host's interfaces
+#[derive(Debug, Clone)]
+struct LocalAddr {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+    device: String,
+}
+
+/// local addresses and routes, discovered from the host and installed by the user. a
+/// destination takes the route with the longest prefix containing it, an installed one
+/// over a discovered one of the same length
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    local_addrs: Vec<LocalAddr>,
+    discovered: Vec<Route>,
+    installed: Vec<Route>,
+    // of a stack apart from the host's, with nothing to discover
+    isolated: bool,
+}
+
+impl RoutingTable {
+    pub fn discover() -> Self {
+        let mut table = Self::default();
+        table.refresh();
+        table
+    }
+
+    // the table of a host of its own on the link of `device`, at `addr`: the prefix is on the
+    // link and everything else is via `gateway`, or handed to the device as it is without one
+    pub fn on_link(
+        device: &str,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+    ) -> Self {
+        let route = |destination, prefix_len, gateway| Route {
+            destination,
+            prefix_len,
+            gateway,
+            device: device.to_string(),
+            source: Some(addr),
+            mtu: None,
+        };
+        Self {
+            local_addrs: vec![LocalAddr {
+                addr,
+                prefix_len,
+                device: device.to_string(),
+            }],
+            discovered: vec![
+                route(mask(addr, prefix_len), prefix_len, None),
+                route(Ipv4Addr::UNSPECIFIED, 0, gateway),
+            ],
+            installed: Vec::new(),
+            isolated: true,
+        }
+    }
+
+    // enumerate the addresses of the interfaces that are up again, with a route to the
+    // prefix of each, and read the kernel's routes. installed routes stay
+    pub fn refresh(&mut self) {
+        if self.isolated {
+            return;
+        }
+        self.local_addrs.clear();
+        self.discovered.clear();
+        for interface in datalink::interfaces() {
+            if !interface.is_up() {
+                continue;
+            }
+            for network in &interface.ips {
+                let addr = match network.ip() {
+                    IpAddr::V4(addr) => addr,
+                    IpAddr::V6(_) => continue,
+                };
+                self.local_addrs.push(LocalAddr {
+                    addr,
+                    prefix_len: network.prefix(),
+                    device: interface.name.clone(),
+                });
+                self.discovered.push(Route {
+                    destination: mask(addr, network.prefix()),
+                    prefix_len: network.prefix(),
+                    gateway: None,
+                    device: interface.name.clone(),
+                    source: Some(addr),
+                    mtu: None,
+                });
+            }
+        }
+        match read_kernel_routes() {
+            Ok(routes) => self.discovered.extend(routes),
+            Err(error) => {
+                warn!(%error, "failed to read kernel routes");
+            }
+        }
+        debug!(count = self.discovered.len(), "routes discovered");
+    }
+
+    pub fn add(&mut self, route: Route) {
+        self.installed
+            .retain(|r| (r.destination, r.prefix_len) != (route.destination, route.prefix_len));
+        self.installed.push(route);
+    }
+
+    // false if no installed route has this prefix
+    pub fn remove(&mut self, destination: Ipv4Addr, prefix_len: u8) -> bool {
+        let len = self.installed.len();
+        self.installed
+            .retain(|r| (r.destination, r.prefix_len) != (destination, prefix_len));
+        self.installed.len() != len
+    }
+
+    pub fn routes(&self) -> Vec<Route> {
+        self.installed
+            .iter()
+            .chain(&self.discovered)
+            .cloned()
+            .collect()
+    }
+
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<&Route> {
+        // max_by_key keeps the last of equals, so installed routes go last
+        self.discovered
+            .iter()
+            .chain(&self.installed)
+            .filter(|route| route.contains(addr))
+            .max_by_key(|route| route.prefix_len)
+    }
+
+    // the address a connection to `addr` goes out from: `addr` itself if it is ours,
+    // otherwise the route's preferred source, or the address of its device on the link of
+    // the next hop
+    pub fn source_for(&self, addr: Ipv4Addr) -> Option<Ipv4Addr> {
+        if self.is_local(addr) {
+            return Some(addr);
+        }
+        let route = self.lookup(addr)?;
+        if let Some(source) = route.source {
+            return Some(source);
+        }
+        let next_hop = route.gateway.unwrap_or(addr);
+        let on_device = || {
+            self.local_addrs
+                .iter()
+                .filter(|local| local.device == route.device)
+        };
+        on_device()
+            .find(|local| in_prefix(next_hop, local.addr, local.prefix_len))
+            .or_else(|| on_device().next())
+            .map(|local| local.addr)
+    }
+
+    // one of our addresses, all of 127.0.0.0/8 included
+    pub fn is_local(&self, addr: Ipv4Addr) -> bool {
+        addr.is_loopback() || self.local_addrs.iter().any(|local| local.addr == addr)
+    }
+
+    // an MTU set on the route wins over that of the device
+    pub fn mtu_to(&self, addr: Ipv4Addr) -> Result<usize> {
+        let route = self.lookup(addr).context("no route to host")?;
+        if let Some(mtu) = route.mtu {
+            return Ok(mtu);
+        }
+        interface_mtu(&route.device)
+    }
+}
+
+// a prefix longer than 32 bits is taken as 32
+fn mask(addr: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32u32.saturating_sub(prefix_len.into()))
+        .unwrap_or(0);
+    Ipv4Addr::from(u32::from(addr) & mask)
+}
+
+fn in_prefix(addr: Ipv4Addr, prefix: Ipv4Addr, prefix_len: u8) -> bool {
+    mask(addr, prefix_len) == mask(prefix, prefix_len)
+}
+
+#[cfg(target_os = "linux")]
+fn interface_mtu(device: &str) -> Result<usize> {
+    let mtu = fs::read_to_string(format!("/sys/class/net/{}/mtu", device))
+        .context("failed to read interface mtu")?;
+    debug!(device, mtu = mtu.trim(), "mtu");
+    mtu.trim().parse().context("failed to parse interface mtu")
+}
+
+#[cfg(target_os = "macos")]
+fn interface_mtu(device: &str) -> Result<usize> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("failed to read interface mtu");
+    }
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(device.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let result = unsafe { libc::ioctl(fd, SIOCGIFMTU, &mut request) };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if result < 0 {
+        return Err(error).context("failed to read interface mtu");
+    }
+    let mtu = unsafe { request.ifr_ifru.ifru_mtu };
+    debug!(device, mtu, "mtu");
+    Ok(mtu as usize)
+}
+
+// the routes of /proc/net/route that are up. addresses there are the bytes in network
+// order printed as a native u32
+#[cfg(target_os = "linux")]
+fn read_kernel_routes() -> Result<Vec<Route>> {
+    let table = fs::read_to_string(PROC_NET_ROUTE).context("failed to read routes")?;
+    let mut routes = Vec::new();
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+        // Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT
+        if fields.len() < 11 {
+            continue;
+        }
+        let hex = |field: &str| u32::from_str_radix(field, 16).context("malformed route");
+        let flags = hex(fields[3])?;
+        if flags & RTF_UP == 0 {
+            continue;
+        }
+        let addr = |field: &str| hex(field).map(|raw| Ipv4Addr::from(raw.to_ne_bytes()));
+        let gateway = addr(fields[2])?;
+        let mtu: usize = fields[8].parse().context("malformed route")?;
+        routes.push(Route {
+            destination: addr(fields[1])?,
+            prefix_len: hex(fields[7])?.count_ones() as u8,
+            gateway: (flags & RTF_GATEWAY != 0).then_some(gateway),
+            device: fields[0].to_string(),
+            source: None,
+            mtu: (mtu != 0).then_some(mtu),
+        });
+    }
+    Ok(routes)
+}
+
+// the routes of the routing sysctl that are up. each message is an rt_msghdr followed by a
+// sockaddr for each bit of rtm_addrs, in the order of the bits, padded to 4 bytes. a
+// netmask may be cut short after its last nonzero byte, family and all
+#[cfg(target_os = "macos")]
+fn read_kernel_routes() -> Result<Vec<Route>> {
+    let mut mib = [
+        libc::CTL_NET,
+        libc::PF_ROUTE,
+        0,
+        libc::AF_INET,
+        libc::NET_RT_DUMP,
+        0,
+    ];
+    let mut dump = |buffer: *mut libc::c_void, len: &mut usize| -> Result<()> {
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                mib.len() as libc::c_uint,
+                buffer,
+                len,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error()).context("failed to read routes");
+        }
+        Ok(())
+    };
+    let mut len = 0;
+    dump(ptr::null_mut(), &mut len)?;
+    // room for routes added in between
+    len += len / 8;
+    let mut buffer = vec![0u8; len];
+    dump(buffer.as_mut_ptr() as *mut libc::c_void, &mut len)?;
+    buffer.truncate(len);
+    Ok(parse_route_messages(&buffer))
+}
+
+#[cfg(target_os = "macos")]
+fn parse_route_messages(buffer: &[u8]) -> Vec<Route> {
+    let interfaces = datalink::interfaces();
+    let mut routes = Vec::new();
+    let mut offset = 0;
+    while offset + mem::size_of::<libc::rt_msghdr>() <= buffer.len() {
+        let header: libc::rt_msghdr =
+            unsafe { ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::rt_msghdr) };
+        let message_len = header.rtm_msglen as usize;
+        if message_len == 0 {
+            break;
+        }
+        let message = &buffer[offset..buffer.len().min(offset + message_len)];
+        offset += message_len;
+        if header.rtm_flags & libc::RTF_UP == 0 {
+            continue;
+        }
+        let mut addrs: [Option<&[u8]>; libc::RTAX_MAX as usize] = [None; libc::RTAX_MAX as usize];
+        let mut at = mem::size_of::<libc::rt_msghdr>();
+        for (i, addr) in addrs.iter_mut().enumerate() {
+            if header.rtm_addrs & (1 << i) == 0 {
+                continue;
+            }
+            let len = match message.get(at) {
+                Some(&len) => len as usize,
+                None => break,
+            };
+            *addr = message.get(at..at + len);
+            at += if len == 0 { 4 } else { (len + 3) & !3 };
+        }
+        let sockaddr_in = |addr: Option<&[u8]>| -> Option<Ipv4Addr> {
+            let addr = addr?;
+            if addr.len() < 8 || addr[1] != libc::AF_INET as u8 {
+                return None;
+            }
+            Some(Ipv4Addr::new(addr[4], addr[5], addr[6], addr[7]))
+        };
+        let destination = match sockaddr_in(addrs[libc::RTAX_DST as usize]) {
+            Some(destination) => destination,
+            None => continue,
+        };
+        let prefix_len = if header.rtm_flags & libc::RTF_HOST != 0 {
+            32
+        } else {
+            addrs[libc::RTAX_NETMASK as usize].map_or(0, |mask| {
+                mask.iter()
+                    .skip(4)
+                    .take(4)
+                    .map(|byte| byte.count_ones() as u8)
+                    .sum()
+            })
+        };
+        // on-link routes have the link-layer address of the interface as their gateway
+        let gateway = sockaddr_in(addrs[libc::RTAX_GATEWAY as usize])
+            .filter(|_| header.rtm_flags & libc::RTF_GATEWAY != 0);
+        let device = match interfaces
+            .iter()
+            .find(|interface| interface.index == header.rtm_index as u32)
+        {
+            Some(interface) => interface.name.clone(),
+            None => continue,
+        };
+        let mtu = header.rtm_rmx.rmx_mtu as usize;
+        routes.push(Route {
+            destination,
+            prefix_len,
+            gateway,
+            device,
+            source: None,
+            mtu: (mtu != 0).then_some(mtu),
+        });
+    }
+    routes
+}
+
+// the interfaces' prefixes are on the list already. beyond them a lab machine has a default
+// route, which GetBestRoute finds as the best route to 0.0.0.0
+#[cfg(windows)]
+fn read_kernel_routes() -> Result<Vec<Route>> {
+    let mut row: MIB_IPFORWARDROW = unsafe { std::mem::zeroed() };
+    let result = unsafe { GetBestRoute(0, 0, &mut row) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result as i32)).context("failed to read routes");
+    }
+    let device = match datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.index == row.dwForwardIfIndex)
+    {
+        Some(interface) => interface.name,
+        None => return Ok(Vec::new()),
+    };
+    // addresses are the bytes in network order read as a native u32
+    let addr = |raw: u32| Ipv4Addr::from(raw.to_ne_bytes());
+    let indirect = unsafe { row.Anonymous1.dwForwardType } == MIB_IPROUTE_TYPE_INDIRECT as u32;
+    Ok(vec![Route {
+        destination: addr(row.dwForwardDest),
+        prefix_len: row.dwForwardMask.count_ones() as u8,
+        gateway: indirect.then(|| addr(row.dwForwardNextHop)),
+        device,
+        source: None,
+        mtu: None,
+    }])
+}
+
+#[cfg(windows)]
+fn interface_mtu(device: &str) -> Result<usize> {
+    let index = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == device)
+        .context("no such device")?
+        .index;
+    let mut row: MIB_IFROW = unsafe { std::mem::zeroed() };
+    row.dwIndex = index;
+    let result = unsafe { GetIfEntry(&mut row) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result as i32))
+            .context("failed to read interface mtu");
+    }
+    debug!(device, mtu = row.dwMtu, "mtu");
+    Ok(row.dwMtu as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 10);
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
+    fn route(
+        destination: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+        device: &str,
+    ) -> Route {
+        Route {
+            destination,
+            prefix_len,
+            gateway,
+            device: device.to_string(),
+            source: None,
+            mtu: None,
+        }
+    }
+
+    fn table() -> RoutingTable {
+        let mut table = RoutingTable {
+            local_addrs: vec![LocalAddr {
+                addr: ADDR,
+                prefix_len: 24,
+                device: "eth0".to_string(),
+            }],
+            discovered: vec![
+                Route {
+                    source: Some(ADDR),
+                    ..route(Ipv4Addr::new(192, 168, 0, 0), 24, None, "eth0")
+                },
+                Route::default_via(GATEWAY, "eth0"),
+            ],
+            ..RoutingTable::default()
+        };
+        table.add(route(
+            Ipv4Addr::new(10, 0, 0, 0),
+            8,
+            Some(Ipv4Addr::new(192, 168, 0, 254)),
+            "eth0",
+        ));
+        table.add(route(
+            Ipv4Addr::new(10, 1, 0, 0),
+            16,
+            Some(Ipv4Addr::new(192, 168, 0, 253)),
+            "eth0",
+        ));
+        table
+    }
+
+    #[test]
+    fn lookup_takes_the_longest_prefix() {
+        let table = table();
+        let lookup = |addr| table.lookup(addr).map(|route| route.prefix_len);
+        assert_eq!(lookup(Ipv4Addr::new(10, 1, 2, 3)), Some(16));
+        assert_eq!(lookup(Ipv4Addr::new(10, 2, 0, 1)), Some(8));
+        assert_eq!(lookup(Ipv4Addr::new(192, 168, 0, 5)), Some(24));
+        let default = table.lookup(Ipv4Addr::new(198, 51, 100, 1)).unwrap();
+        assert_eq!(default.prefix_len, 0);
+        assert_eq!(default.gateway, Some(GATEWAY));
+    }
+
+    #[test]
+    fn installed_route_beats_a_discovered_one() {
+        let mut table = table();
+        table.add(route(Ipv4Addr::new(192, 168, 0, 0), 24, None, "eth1"));
+        let route = table.lookup(Ipv4Addr::new(192, 168, 0, 5)).unwrap();
+        assert_eq!(route.device, "eth1");
+        assert!(table.remove(Ipv4Addr::new(192, 168, 0, 0), 24));
+        let route = table.lookup(Ipv4Addr::new(192, 168, 0, 5)).unwrap();
+        assert_eq!(route.device, "eth0");
+    }
+
+    #[test]
+    fn source_for_picks_the_address_a_connection_goes_out_from() {
+        let mut table = table();
+        // ours already
+        assert_eq!(table.source_for(ADDR), Some(ADDR));
+        // the preferred source of the route
+        let mut preferred = route(Ipv4Addr::new(203, 0, 113, 0), 24, Some(GATEWAY), "eth0");
+        preferred.source = Some(Ipv4Addr::new(192, 168, 0, 11));
+        table.add(preferred);
+        assert_eq!(
+            table.source_for(Ipv4Addr::new(203, 0, 113, 7)),
+            Some(Ipv4Addr::new(192, 168, 0, 11))
+        );
+        // the device's address on the link of the gateway
+        assert_eq!(table.source_for(Ipv4Addr::new(10, 1, 2, 3)), Some(ADDR));
+        // a device without an address of ours
+        table.add(route(Ipv4Addr::new(172, 16, 0, 0), 12, None, "eth1"));
+        assert_eq!(table.source_for(Ipv4Addr::new(172, 16, 0, 1)), None);
+    }
+
+    #[test]
+    fn prefix_longer_than_32_bits_is_a_host_route() {
+        let addr = Ipv4Addr::new(192, 168, 0, 10);
+        assert_eq!(mask(addr, 40), addr);
+        assert_eq!(mask(addr, 32), addr);
+        assert_eq!(mask(addr, 0), Ipv4Addr::UNSPECIFIED);
+        assert!(!in_prefix(Ipv4Addr::new(192, 168, 0, 11), addr, 33));
+    }
+}
@@ -0,0 +1,66 @@
+use std::cmp;
+use std::time::Duration;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// where data transfer starts from when the handshake gave no sample (RFC 6298 section 5.7)
+const SYN_TIMEOUT_RTO: Duration = Duration::from_secs(3);
+// clock granularity G of RFC 6298
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// retransmission timeout computed from smoothed round-trip time samples (RFC 6298)
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    // callers must not pass samples taken from retransmitted segments (Karn's algorithm)
+    pub fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                // RTTVAR <- 3/4 * RTTVAR + 1/4 * |SRTT - R'|, SRTT <- 7/8 * SRTT + 1/8 * R'
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + cmp::max(GRANULARITY, self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // the timer expired: double the timeout until the next valid sample
+    pub fn backoff(&mut self) {
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+    }
+
+    // the SYN had to be resent: the backed-off timeout doesn't carry over to the data
+    pub fn reset_after_syn_timeout(&mut self) {
+        if self.srtt.is_none() {
+            self.rto = SYN_TIMEOUT_RTO;
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+}
@@ -0,0 +1,748 @@
+use crate::clock::{Clock, VirtualClock};
+use crate::device::MemoryDevice;
+use crate::ip;
+use crate::packet::TCPPacket;
+use crate::route::RoutingTable;
+use crate::seq::SeqNum;
+use crate::socket::SockID;
+use crate::sockopt::{CongestionAlgorithm, LossDetection, SocketOption};
+use crate::tcp::TCP;
+use crate::tcpflags;
+use crate::tcpoption::TcpOption;
+use anyhow::{Context, Result};
+use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+use pnet::util;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+// the stack under test listens and connects from here, the script plays the peer
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const LOCAL_PORT: u16 = 8080;
+const REMOTE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const REMOTE_PORT: u16 = 40000;
+// how far an outbound segment may be off the time the script gives it
+const DEFAULT_TOLERANCE: Duration = Duration::from_millis(25);
+const DEFAULT_WINDOW: u16 = 65535;
+// the script moves virtual time on a tick of the timers at a time while it waits for the
+// stack, giving a call in the background this long in real time to get on with it
+const STEP: Duration = Duration::from_millis(10);
+const CALL_WAIT: Duration = Duration::from_millis(5);
+// how long a segment expected at any time may take
+const ANY_TIME_LIMIT: Duration = Duration::from_secs(600);
+const BACKLOG: usize = 8;
+
+/// a packetdrill-style script, run against a `TCP` of its own over a `MemoryDevice` on a
+/// `VirtualClock`, so that waiting out a timeout takes no time. each line is a time,
+/// absolute in seconds from the start or `+` relative to the line before, and an event:
+///
+/// ```text
+/// 0    listen()
+/// +0   < S 0:0(0) win 65535 <mss 1460,sackOK,nop,wscale 7>
+/// +0   > S. 0:0(0) ack 1 <mss 1460,wscale 7,sackOK>
+/// +0.1 < . 1:1(0) ack 1 win 65535
+/// +0   accept() = 0
+/// +0   write(1000) = 1000
+/// +0   > . 1:1001(1000) ack 1
+/// ```
+///
+/// `<` is a segment the script sends to the stack and `>` one the stack has to send then,
+/// with flags (S, F, R, P, E for ECE, W for CWR, `.` for ACK), `start:end(len)` of sequence
+/// space, and `ack`, `win` and `<options>` if they matter. the sequence numbers of the
+/// stack are relative to its ISN, the script's are as written. `*` as the time of an
+/// outbound segment takes it whenever it comes. a segment the script doesn't expect fails it.
+///
+/// calls are listen(), accept(), connect(), write(n), read(n) and close(), optionally with
+/// the value they must return (`= -1` for an error). one that blocks goes on in the
+/// background, and has to return by the next call. setsockopt(name, value) sets an option
+/// of the connection, or of the listener its connections inherit it from, and ecn(0 | 1)
+/// turns ECN on or off for connections set up from then on
+pub struct Script {
+    lines: Vec<Line>,
+    tolerance: Duration,
+}
+
+struct Line {
+    number: usize,
+    time: Time,
+    event: Event,
+}
+
+#[derive(Clone, Copy)]
+enum Time {
+    Absolute(Duration),
+    Relative(Duration),
+    Any,
+}
+
+enum Event {
+    Inbound(Segment),
+    Outbound(Segment),
+    Call(Call, Option<i64>),
+}
+
+struct Segment {
+    flag: u8,
+    start: u32,
+    end: u32,
+    ack: Option<u32>,
+    window: Option<u16>,
+    options: Option<Vec<TcpOption>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Call {
+    Listen,
+    Accept,
+    Connect,
+    Write(usize),
+    Read(usize),
+    Close,
+    SetOption(SocketOption),
+    Ecn(bool),
+}
+
+/// a call going on in the background
+struct Pending {
+    number: usize,
+    expected: Option<i64>,
+    result: mpsc::Receiver<Result<(i64, Option<SockID>)>>,
+    returned: Option<Result<(i64, Option<SockID>)>>, // taken from `result` already
+}
+
+/// the state of a script being run
+struct Run<'a> {
+    script: &'a Script,
+    tcp: Arc<TCP>,
+    peer: MemoryDevice,
+    clock: Arc<VirtualClock>,
+    start: SystemTime,
+    now: Duration, // script time of the last line
+    local_iss: Option<SeqNum>,
+    last_tsval: u32, // of the stack, echoed back by the script's segments
+    listener: Option<SockID>,
+    connection: Option<SockID>,
+    pending: Option<Pending>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut lines = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = match line.find("//") {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let number = index + 1;
+            lines.push(parse_line(number, line).with_context(|| format!("line {}", number))?);
+        }
+        Ok(Self {
+            lines,
+            tolerance: DEFAULT_TOLERANCE,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).context("failed to read script")?;
+        Self::parse(&text)
+    }
+
+    pub fn with_tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    // the first line that doesn't go as written fails the run
+    pub fn run(&self) -> Result<()> {
+        let (device, peer) = MemoryDevice::pair();
+        let routes = RoutingTable::on_link("script0", LOCAL_ADDR, 24, None);
+        let clock = Arc::new(VirtualClock::new());
+        let mut run = Run {
+            script: self,
+            tcp: TCP::with_clock(device, routes, clock.clone()),
+            peer,
+            start: clock.now(),
+            clock,
+            now: Duration::ZERO,
+            local_iss: None,
+            last_tsval: 0,
+            listener: None,
+            connection: None,
+            pending: None,
+        };
+        for line in &self.lines {
+            run.line(line)
+                .with_context(|| format!("line {}", line.number))?;
+        }
+        // segments that are still on their way count as unexpected too
+        run.finish_call(run.now + self.tolerance)?;
+        run.wait_until(run.now + self.tolerance);
+        run.unexpected()
+    }
+}
+
+fn parse_line(number: usize, line: &str) -> Result<Line> {
+    let (time, event) = line.split_once(char::is_whitespace).context("no event")?;
+    let time = match time {
+        "*" => Time::Any,
+        _ => match time.strip_prefix('+') {
+            Some(relative) => Time::Relative(seconds(relative)?),
+            None => Time::Absolute(seconds(time)?),
+        },
+    };
+    let event = event.trim();
+    let event = if let Some(segment) = event.strip_prefix('<') {
+        Event::Inbound(parse_segment(segment)?)
+    } else if let Some(segment) = event.strip_prefix('>') {
+        Event::Outbound(parse_segment(segment)?)
+    } else {
+        let (call, expected) = parse_call(event)?;
+        Event::Call(call, expected)
+    };
+    if matches!(time, Time::Any) && !matches!(event, Event::Outbound(_)) {
+        anyhow::bail!("only an outbound segment may come at any time");
+    }
+    Ok(Line {
+        number,
+        time,
+        event,
+    })
+}
+
+fn seconds(text: &str) -> Result<Duration> {
+    let seconds: f64 = text
+        .parse()
+        .with_context(|| format!("invalid time: {}", text))?;
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("invalid time: {}", text))
+}
+
+fn number<T: std::str::FromStr>(text: Option<&str>) -> Result<T> {
+    let text = text.context("number missing")?;
+    text.parse()
+        .map_err(|_| anyhow::anyhow!("invalid number: {}", text))
+}
+
+// `flags start:end(len) [ack n] [win n] [<options>]`
+fn parse_segment(text: &str) -> Result<Segment> {
+    let (fields, options) = match text.split_once('<') {
+        Some((fields, options)) => {
+            let options = options
+                .trim()
+                .strip_suffix('>')
+                .context("unclosed options")?;
+            (fields, Some(parse_options(options)?))
+        }
+        None => (text, None),
+    };
+    let mut fields = fields.split_whitespace();
+    let mut flag = 0;
+    for c in fields.next().context("no flags")?.chars() {
+        flag |= match c {
+            'S' => tcpflags::SYN,
+            'F' => tcpflags::FIN,
+            'R' => tcpflags::RST,
+            'P' => tcpflags::PSH,
+            '.' => tcpflags::ACK,
+            'E' => tcpflags::ECE,
+            'W' => tcpflags::CWR,
+            'U' => tcpflags::URG,
+            _ => anyhow::bail!("unknown flag: {}", c),
+        };
+    }
+    let range = fields.next().context("no sequence numbers")?;
+    let (start, rest) = range.split_once(':').context("invalid sequence numbers")?;
+    let (end, len) = rest
+        .strip_suffix(')')
+        .and_then(|rest| rest.split_once('('))
+        .context("invalid sequence numbers")?;
+    let (start, end, len): (u32, u32, u32) =
+        (number(Some(start))?, number(Some(end))?, number(Some(len))?);
+    if end.wrapping_sub(start) != len {
+        anyhow::bail!("{}:{} is not {} bytes", start, end, len);
+    }
+    let mut segment = Segment {
+        flag,
+        start,
+        end,
+        ack: None,
+        window: None,
+        options,
+    };
+    while let Some(field) = fields.next() {
+        match field {
+            "ack" => segment.ack = Some(number(fields.next())?),
+            "win" => segment.window = Some(number(fields.next())?),
+            _ => anyhow::bail!("unknown field: {}", field),
+        }
+    }
+    if (segment.flag & tcpflags::ACK > 0) != segment.ack.is_some() {
+        anyhow::bail!("the ACK flag goes with an ack number");
+    }
+    Ok(segment)
+}
+
+// mss n, wscale n, sackOK, sack a:b ..., TS val n ecr n, uto n in seconds. the NOPs and END
+// that align them are left to tcpoption::serialize
+fn parse_options(text: &str) -> Result<Vec<TcpOption>> {
+    let mut options = Vec::new();
+    for option in text.split(',') {
+        let mut words = option.split_whitespace();
+        match words.next() {
+            Some("nop") | Some("eol") => {}
+            Some("mss") => options.push(TcpOption::MaxSegmentSize(number(words.next())?)),
+            Some("wscale") => options.push(TcpOption::WindowScale(number(words.next())?)),
+            Some("sackOK") => options.push(TcpOption::SackPermitted),
+            Some("sack") => {
+                let blocks = words
+                    .map(|block| {
+                        let (left, right) = block.split_once(':').context("invalid SACK block")?;
+                        Ok((SeqNum(number(Some(left))?), SeqNum(number(Some(right))?)))
+                    })
+                    .collect::<Result<_>>()?;
+                options.push(TcpOption::Sack(blocks));
+            }
+            Some("TS") => {
+                let mut value = 0;
+                let mut echo_reply = 0;
+                while let Some(word) = words.next() {
+                    match word {
+                        "val" => value = number(words.next())?,
+                        "ecr" => echo_reply = number(words.next())?,
+                        _ => anyhow::bail!("unknown timestamp field: {}", word),
+                    }
+                }
+                options.push(TcpOption::Timestamps { value, echo_reply });
+            }
+            Some("uto") => options.push(TcpOption::UserTimeout(Duration::from_secs(number(
+                words.next(),
+            )?))),
+            _ => anyhow::bail!("unknown option: {}", option.trim()),
+        }
+    }
+    Ok(options)
+}
+
+// `name(arg) [= value]`
+fn parse_call(text: &str) -> Result<(Call, Option<i64>)> {
+    let (call, expected) = match text.split_once('=') {
+        Some((call, expected)) => (call.trim(), Some(number(Some(expected.trim()))?)),
+        None => (text, None),
+    };
+    let (name, arg) = call
+        .strip_suffix(')')
+        .and_then(|call| call.split_once('('))
+        .with_context(|| format!("unknown event: {}", text))?;
+    let arg = Some(arg.trim()).filter(|arg| !arg.is_empty());
+    let call = match name {
+        "listen" => Call::Listen,
+        "accept" => Call::Accept,
+        "connect" => Call::Connect,
+        "write" => Call::Write(number(arg)?),
+        "read" => Call::Read(number(arg)?),
+        "close" => Call::Close,
+        "setsockopt" => Call::SetOption(parse_option(arg.context("option missing")?)?),
+        "ecn" => Call::Ecn(number::<u8>(arg)? != 0),
+        _ => anyhow::bail!("unknown call: {}", name),
+    };
+    Ok((call, expected))
+}
+
+// `name, value`: nodelay and cork 0 or 1, keepalive and user_timeout in seconds with 0 for
+// off, rcvbuf and sndbuf in bytes, congestion reno, cubic or bbr, loss_detection dupack or
+// rack
+fn parse_option(text: &str) -> Result<SocketOption> {
+    let (name, value) = text.split_once(',').context("option value missing")?;
+    let value = value.trim();
+    let seconds = |value: &str| -> Result<Option<Duration>> {
+        Ok(Some(Duration::from_secs(number(Some(value))?)).filter(|secs| !secs.is_zero()))
+    };
+    Ok(match name.trim() {
+        "nodelay" => SocketOption::NoDelay(number::<u8>(Some(value))? != 0),
+        "cork" => SocketOption::Cork(number::<u8>(Some(value))? != 0),
+        "keepalive" => SocketOption::KeepAlive(seconds(value)?),
+        "user_timeout" => SocketOption::UserTimeout(seconds(value)?),
+        "rcvbuf" => SocketOption::RecvBufferSize(number(Some(value))?),
+        "sndbuf" => SocketOption::SendBufferSize(number(Some(value))?),
+        "congestion" => SocketOption::CongestionControl(match value {
+            "reno" => CongestionAlgorithm::Reno,
+            "cubic" => CongestionAlgorithm::Cubic,
+            "bbr" => CongestionAlgorithm::Bbr,
+            _ => anyhow::bail!("unknown congestion control: {}", value),
+        }),
+        "loss_detection" => SocketOption::LossDetection(match value {
+            "dupack" => LossDetection::DuplicateAcks,
+            "rack" => LossDetection::Rack,
+            _ => anyhow::bail!("unknown loss detection: {}", value),
+        }),
+        name => anyhow::bail!("unknown option: {}", name),
+    })
+}
+
+impl Run<'_> {
+    fn line(&mut self, line: &Line) -> Result<()> {
+        let at = match line.time {
+            Time::Absolute(time) => time,
+            Time::Relative(delta) => self.now + delta,
+            Time::Any => self.elapsed(),
+        };
+        match &line.event {
+            Event::Inbound(segment) => {
+                self.wait_until(at);
+                self.unexpected()?;
+                self.inject(segment)?;
+            }
+            Event::Outbound(segment) => {
+                let any_time = matches!(line.time, Time::Any);
+                let arrival = self.expect(segment, at, any_time)?;
+                if any_time {
+                    self.now = arrival;
+                    return Ok(());
+                }
+            }
+            Event::Call(call, expected) => {
+                self.finish_call(at + self.script.tolerance)?;
+                self.wait_until(at);
+                self.unexpected()?;
+                self.start_call(line.number, *call, *expected)?;
+            }
+        }
+        self.now = at;
+        Ok(())
+    }
+
+    // virtual time since the start
+    fn elapsed(&self) -> Duration {
+        self.clock
+            .now()
+            .duration_since(self.start)
+            .unwrap_or_default()
+    }
+
+    // the timers that come due on the way run before it returns
+    fn wait_until(&self, at: Duration) {
+        if let Some(left) = at.checked_sub(self.elapsed()) {
+            self.clock.advance(left);
+        }
+    }
+
+    // the next segment of the stack by `deadline`. those the timers send are there as soon
+    // as the clock gets to them, one a call sends takes it some real time
+    fn next_segment(&mut self, deadline: Duration) -> Result<Option<TCPPacket>> {
+        let mut buffer = vec![0; 65535];
+        loop {
+            // once the call has returned, virtual time can go on at full speed
+            let wait = match &mut self.pending {
+                Some(pending) if pending.returned.is_none() => match pending.result.try_recv() {
+                    Ok(result) => {
+                        pending.returned = Some(result);
+                        Duration::ZERO
+                    }
+                    Err(_) => CALL_WAIT,
+                },
+                _ => Duration::ZERO,
+            };
+            if let Some(len) = self.peer.recv_packet_timeout(&mut buffer, wait)? {
+                if let Some(packet) = tcp_packet(&buffer[..len]) {
+                    return Ok(Some(packet));
+                }
+                continue;
+            }
+            let elapsed = self.elapsed();
+            if elapsed >= deadline {
+                return Ok(None);
+            }
+            self.clock.advance(STEP.min(deadline - elapsed));
+        }
+    }
+
+    // a segment the stack sent without the script expecting it
+    fn unexpected(&mut self) -> Result<()> {
+        if let Some(packet) = self.next_segment(self.elapsed())? {
+            anyhow::bail!("unexpected segment: {}", self.describe(&packet));
+        }
+        Ok(())
+    }
+
+    fn inject(&mut self, segment: &Segment) -> Result<()> {
+        let len = segment.end.wrapping_sub(segment.start) as usize;
+        let mut packet = TCPPacket::new(len);
+        packet.set_src(REMOTE_PORT);
+        packet.set_dest(LOCAL_PORT);
+        packet.set_seq(SeqNum(segment.start));
+        // what the script acknowledges is relative to the ISN of the stack
+        let local_iss = self.local_iss.unwrap_or(SeqNum(0));
+        if let Some(ack) = segment.ack {
+            self.local_iss
+                .context("an ACK before the stack sent its SYN")?;
+            packet.set_ack(local_iss + ack);
+        }
+        packet.set_flag(segment.flag);
+        packet.set_window_size(segment.window.unwrap_or(DEFAULT_WINDOW));
+        if let Some(options) = &segment.options {
+            // the timestamp echoed is the latest the stack sent, whatever the script says
+            let options: Vec<TcpOption> = options
+                .iter()
+                .map(|option| match option {
+                    TcpOption::Timestamps { value, .. } => TcpOption::Timestamps {
+                        value: *value,
+                        echo_reply: self.last_tsval,
+                    },
+                    TcpOption::Sack(blocks) => TcpOption::Sack(
+                        blocks
+                            .iter()
+                            .map(|(left, right)| (local_iss + left.0, local_iss + right.0))
+                            .collect(),
+                    ),
+                    option => option.clone(),
+                })
+                .collect();
+            packet.set_options(&options);
+        }
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &REMOTE_ADDR,
+            &LOCAL_ADDR,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        let packet = ip::build(REMOTE_ADDR, LOCAL_ADDR, ip::TCP, 64, 0, packet.packet());
+        // straight into the stack rather than through its receive thread, so that whatever
+        // it answers is there before the next line
+        self.tcp.packet_handler(&packet);
+        Ok(())
+    }
+
+    // waits for the next segment of the stack, which has to be `segment` and come at `at`.
+    // returns when it came
+    fn expect(&mut self, segment: &Segment, at: Duration, any_time: bool) -> Result<Duration> {
+        let tolerance = self.script.tolerance;
+        let deadline = if any_time {
+            self.elapsed() + ANY_TIME_LIMIT
+        } else {
+            at + tolerance
+        };
+        let packet = self
+            .next_segment(deadline)?
+            .context("expected segment not sent")?;
+        let arrival = self.elapsed();
+        if !any_time && arrival + tolerance < at {
+            anyhow::bail!(
+                "segment sent {:?} early: {}",
+                at - arrival,
+                self.describe(&packet)
+            );
+        }
+        if packet.get_flag() & tcpflags::SYN > 0 && self.local_iss.is_none() {
+            self.local_iss = Some(packet.get_seq() - segment.start);
+        }
+        if let Some((value, _)) = packet.get_timestamps() {
+            self.last_tsval = value;
+        }
+        let local_iss = self.local_iss.unwrap_or(SeqNum(0));
+        let start = (packet.get_seq() - local_iss.0).0;
+        let matches = packet.get_flag() == segment.flag
+            && start == segment.start
+            && start.wrapping_add(packet.payload().len() as u32) == segment.end
+            && (packet.get_flag() & tcpflags::ACK == 0 || Some(packet.get_ack().0) == segment.ack)
+            && segment
+                .window
+                .is_none_or(|window| window == packet.get_window_size())
+            && segment
+                .options
+                .as_ref()
+                .is_none_or(|options| same_options(options, &packet.get_options()));
+        if !matches {
+            anyhow::bail!("segment sent: {}", self.describe(&packet));
+        }
+        Ok(arrival)
+    }
+
+    // the call that goes on in the background has to return by `deadline`
+    fn finish_call(&mut self, deadline: Duration) -> Result<()> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let result = match pending.returned {
+            Some(result) => Some(result),
+            None => loop {
+                match pending.result.recv_timeout(CALL_WAIT) {
+                    Ok(result) => break Some(result),
+                    Err(_) if self.elapsed() >= deadline => break None,
+                    Err(_) => self.clock.advance(STEP.min(deadline - self.elapsed())),
+                }
+            },
+        };
+        let (value, connection) = match result {
+            Some(Ok(result)) => result,
+            Some(Err(error)) if pending.expected == Some(-1) => {
+                debug!(%error, "call failed as expected");
+                (-1, None)
+            }
+            Some(Err(error)) => {
+                return Err(error.context(format!("line {}: call failed", pending.number)))
+            }
+            None => anyhow::bail!("line {}: call still blocked", pending.number),
+        };
+        if pending.expected.is_some_and(|expected| expected != value) {
+            anyhow::bail!("line {}: call returned {}", pending.number, value);
+        }
+        if connection.is_some() {
+            self.connection = connection;
+        }
+        Ok(())
+    }
+
+    fn start_call(&mut self, number: usize, call: Call, expected: Option<i64>) -> Result<()> {
+        let local = SocketAddr::from((LOCAL_ADDR, LOCAL_PORT));
+        let remote = SocketAddr::from((REMOTE_ADDR, REMOTE_PORT));
+        match call {
+            Call::Listen => {
+                self.listener = Some(self.tcp.listen(local, BACKLOG)?);
+                return Ok(());
+            }
+            Call::SetOption(option) => {
+                let sock_id = self.connection.or(self.listener).context("no socket")?;
+                return self.tcp.set_option(sock_id, option);
+            }
+            Call::Ecn(enabled) => {
+                self.tcp.set_ecn(enabled);
+                return Ok(());
+            }
+            _ => {}
+        }
+        let listener = self.listener;
+        let connection = self.connection;
+        let tcp = self.tcp.clone();
+        let (sender, result) = mpsc::channel();
+        thread::spawn(move || {
+            let result = match call {
+                Call::Listen | Call::SetOption(_) | Call::Ecn(_) => unreachable!(),
+                Call::Accept => listener
+                    .context("not listening")
+                    .and_then(|listener| tcp.accept(listener))
+                    .map(|sock_id| (0, Some(sock_id))),
+                Call::Connect => tcp
+                    .connect_from(local, remote)
+                    .map(|sock_id| (0, Some(sock_id))),
+                Call::Write(len) => connection
+                    .context("not connected")
+                    .and_then(|sock_id| tcp.send(sock_id, &vec![0; len]))
+                    .map(|sent| (sent as i64, None)),
+                Call::Read(len) => connection
+                    .context("not connected")
+                    .and_then(|sock_id| tcp.recv(sock_id, &mut vec![0; len]))
+                    .map(|received| (received as i64, None)),
+                Call::Close => connection
+                    .or(listener)
+                    .context("no socket")
+                    .and_then(|sock_id| tcp.close(sock_id))
+                    .map(|_| (0, None)),
+            };
+            let _ = sender.send(result);
+        });
+        self.pending = Some(Pending {
+            number,
+            expected,
+            result,
+            returned: None,
+        });
+        Ok(())
+    }
+
+    // in script syntax, relative to the ISN of the stack
+    fn describe(&self, packet: &TCPPacket) -> String {
+        let local_iss = self.local_iss.unwrap_or(SeqNum(0));
+        let start = (packet.get_seq() - local_iss.0).0;
+        let mut text = String::new();
+        for (flag, c) in [
+            (tcpflags::SYN, 'S'),
+            (tcpflags::FIN, 'F'),
+            (tcpflags::RST, 'R'),
+            (tcpflags::PSH, 'P'),
+            (tcpflags::ECE, 'E'),
+            (tcpflags::CWR, 'W'),
+            (tcpflags::URG, 'U'),
+            (tcpflags::ACK, '.'),
+        ] {
+            if packet.get_flag() & flag > 0 {
+                text.push(c);
+            }
+        }
+        let len = packet.payload().len() as u32;
+        text += &format!(" {}:{}({})", start, start.wrapping_add(len), len);
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            text += &format!(" ack {}", packet.get_ack().0);
+        }
+        text += &format!(" win {}", packet.get_window_size());
+        let options: Vec<String> = packet
+            .get_options()
+            .iter()
+            .map(|option| match option {
+                TcpOption::MaxSegmentSize(mss) => format!("mss {}", mss),
+                TcpOption::WindowScale(shift) => format!("wscale {}", shift),
+                TcpOption::SackPermitted => "sackOK".to_string(),
+                TcpOption::Sack(blocks) => blocks.iter().fold("sack".to_string(), |text, block| {
+                    format!("{} {}:{}", text, block.0 .0, block.1 .0)
+                }),
+                TcpOption::Timestamps { value, echo_reply } => {
+                    format!("TS val {} ecr {}", value, echo_reply)
+                }
+                TcpOption::UserTimeout(timeout) => format!("uto {}", timeout.as_secs()),
+                option => format!("{:?}", option),
+            })
+            .collect();
+        if !options.is_empty() {
+            text += &format!(" <{}>", options.join(","));
+        }
+        text
+    }
+}
+
+// the TCP segment of an IPv4 packet from the stack
+fn tcp_packet(packet: &[u8]) -> Option<TCPPacket> {
+    let header = ip::parse(packet).filter(|header| header.protocol == ip::TCP)?;
+    TcpPacket::new(&packet[header.header_len..header.total_len]).map(TCPPacket::from)
+}
+
+// timestamps only have to be there, their values are the stack's own
+fn same_options(expected: &[TcpOption], sent: &[TcpOption]) -> bool {
+    expected.len() == sent.len()
+        && expected.iter().zip(sent).all(|pair| match pair {
+            (TcpOption::Timestamps { .. }, TcpOption::Timestamps { .. }) => true,
+            (expected, sent) => expected == sent,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Script;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn scripts_pass() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts");
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "pkt"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        for path in paths {
+            if let Err(error) = Script::from_file(&path).and_then(|script| script.run()) {
+                panic!("{}: {:#}", path.display(), error);
+            }
+        }
+    }
+}
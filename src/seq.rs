@@ -0,0 +1,108 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::ops::{Add, AddAssign, Sub};
+
+/// TCP sequence number. arithmetic wraps around 2^32 and ordering follows RFC 1982:
+/// a number is greater than the 2^31 - 1 numbers behind it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SeqNum(pub u32);
+
+impl SeqNum {
+    // the later of the two numbers
+    pub fn max(self, other: SeqNum) -> SeqNum {
+        if self < other {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl Display for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, rhs: u32) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(rhs))
+    }
+}
+
+// distance from rhs forward to self
+impl Sub for SeqNum {
+    type Output = u32;
+
+    fn sub(self, rhs: SeqNum) -> u32 {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+// numbers exactly 2^31 apart are not comparable
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &SeqNum) -> Option<Ordering> {
+        match (*self - *other) as i32 {
+            0 => Some(Ordering::Equal),
+            i32::MIN => None,
+            distance if distance > 0 => Some(Ordering::Greater),
+            _ => Some(Ordering::Less),
+        }
+    }
+}
+
+impl From<SeqNum> for u32 {
+    fn from(seq: SeqNum) -> Self {
+        seq.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeqNum;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn later_numbers_compare_greater(start: u32, distance in 1..(1u32 << 31)) {
+            let seq = SeqNum(start);
+            prop_assert!(seq + distance > seq);
+            prop_assert!(seq < seq + distance);
+        }
+
+        #[test]
+        fn distance_survives_wraparound(start: u32, distance: u32) {
+            let seq = SeqNum(start);
+            prop_assert_eq!((seq + distance) - seq, distance);
+            prop_assert_eq!(seq + distance - distance, seq);
+        }
+
+        #[test]
+        fn max_picks_the_later_number(start in (u32::MAX - 100)..=u32::MAX, distance in 1..1000u32) {
+            let seq = SeqNum(start);
+            prop_assert_eq!(seq.max(seq + distance), seq + distance);
+            prop_assert_eq!((seq + distance).max(seq), seq + distance);
+        }
+    }
+
+    #[test]
+    fn opposite_numbers_are_not_comparable() {
+        assert_eq!(SeqNum(0).partial_cmp(&SeqNum(1 << 31)), None);
+    }
+}
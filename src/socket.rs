@@ -1,19 +1,82 @@
-use crate::packet::TCPPacket;
+use crate::ao::{Authentication, AO_OPTION_LEN, MAC_LEN};
+use crate::congestion::Congestion;
+use crate::device::NetworkDevice;
+use crate::ip;
+use crate::mptcp::{MptcpOption, Subflow, DSS_OPTION_LEN};
+use crate::pacing::Pacer;
+use crate::packet::{trace_segment, TCPPacket};
+use crate::pcap::Direction;
+use crate::poll;
+use crate::rack::Rack;
+use crate::rtt::RttEstimator;
+use crate::seq::SeqNum;
+use crate::sockopt::{BufferSizes, LossDetection, SocketOptions};
+use crate::stats::{Counter, Counters, Stats};
+use crate::tcp::{DUPACK_THRESHOLD, KEEPALIVE_INTERVAL, MSS};
 use crate::tcpflags;
+use crate::tcpoption::{self, TcpOption};
+use crate::timer::{TimerKind, TimerQueue};
 use anyhow::{Context, Result};
 use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
-use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
 use pnet::util;
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Display};
-use std::net::{IpAddr, Ipv4Addr};
-use std::time::SystemTime;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::field::display;
+use tracing::{debug, debug_span, trace, warn, Span};
 
 const SOCKET_BUFFER_SIZE: usize = 4380;
+// assumed when the peer sends no MSS option (RFC 9293 section 3.7.1)
+pub(crate) const DEFAULT_MSS: usize = 536;
+// keeps a bogus MSS option from shrinking segments to nothing
+const MIN_MSS: usize = 88;
+// TSopt with its padding, carried by every segment once negotiated
+const TIMESTAMPS_OPTION_LEN: usize = 12;
+// the least a peer's UTO option may set our user timeout to (RFC 5482 section 3.1)
+const MIN_USER_TIMEOUT: Duration = Duration::from_secs(100);
+const MAX_WINDOW_SCALE: u8 = 14;
+// ECN field of the IP header
+const ECT0: u8 = 0b10;
+const CE: u8 = 0b11;
+// the largest window a scaled 16-bit field can advertise
+const MAX_WINDOW: usize = (u16::MAX as usize) << MAX_WINDOW_SCALE;
+// receive buffer autotuning stops here, as Linux's tcp_rmem does by default
+const MAX_AUTOTUNED_RECV_BUFFER: usize = 6 * 1024 * 1024;
+const MAX_OPTIONS_LEN: usize = 40;
+// two NOPs, kind and length in front of the blocks
+const SACK_OPTION_HEADER_LEN: usize = 4;
+const SACK_BLOCK_LEN: usize = 8;
+const MAX_DUPACK_THRESHOLD: u8 = 10;
+// TS.Recent of a connection idle this long may have wrapped and no longer protects (RFC 7323 section 5.5)
+const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
 
-/// distinguish socket by tuple(local_addr, remote_addr, local_port, remote_port)
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
-pub struct SockID(pub Ipv4Addr, pub Ipv4Addr, pub u16, pub u16);
+/// handle of a socket. inside the stack it is the tuple
+/// (local_addr, remote_addr, local_port, remote_port) that tells connections apart
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct SockID(
+    pub(crate) Ipv4Addr,
+    pub(crate) Ipv4Addr,
+    pub(crate) u16,
+    pub(crate) u16,
+);
+
+impl SockID {
+    // the ID the socket at the other end of the connection has, if it is one of ours
+    pub(crate) fn reversed(&self) -> SockID {
+        SockID(self.1, self.0, self.3, self.2)
+    }
+}
+
+// local and remote address and port, as the log shows a connection
+impl Display for SockID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} -> {}:{}", self.0, self.2, self.1, self.3)
+    }
+}
 
 pub struct Socket {
     pub local_addr: Ipv4Addr,
@@ -25,42 +88,146 @@ pub struct Socket {
     pub status: TcpStatus,
     pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
     pub recv_buffer: Vec<u8>,
+    pub recv_buffer_locked: bool, // sized by the user, so not autotuned
+    recv_space: usize,            // bytes received in the last measured round trip
+    recv_space_seq: Option<SeqNum>, // RCV.NXT when the current measurement started
+    recv_space_time: Instant,
+    pub urgent_byte: Option<u8>, // the last urgent byte, until recv_oob takes it
+    pub send_buffer: VecDeque<u8>, // written by the application but not sent yet
+    pub fin_pending: bool,       // FIN goes out once the send buffer is drained
     pub connection_established_queue: VecDeque<SockID>,
+    pub backlog: usize, // of a listener: how many connections each of its queues holds
     pub listening_socket: Option<SockID>,
-    pub sender: TransportSender,
+    pub reuse_port: bool, // a listener that shares its address with others, see BindOptions
+    pub device: Arc<dyn NetworkDevice>, // where segments go out, the TCP's unless bound to an interface
+    pub nonblocking: bool,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub read_shutdown: bool,
+    pub options: SocketOptions,
+    pub last_activity: SystemTime, // last time a segment arrived from the peer
+    pub keepalive_probes: u8,      // probes sent without an answer
+    pub unacked_segments: u8,      // data segments received since our last ACK
+    pub delayed_ack: Option<SystemTime>,
+    pub persist_probes: u8, // zero window probes sent since the window closed
+    pub next_persist: Option<SystemTime>,
+    pub retransmissions: u32,
+    pub duplicate_acks: u8, // ACKs in a row that didn't advance SND.UNA
+    pub rtt: RttEstimator,
+    pub mss: usize, // payload of a full-sized segment, negotiated on the handshake
+    pub local_mss: usize, // advertised on our SYN, from the MTU toward the peer
+    pub congestion: Congestion,
+    pub pacer: Pacer,
+    pub sack_permitted: bool,  // both ends sent SACK-permitted on their SYN
+    pub window_scaling: bool,  // both ends sent the window scale option on their SYN
+    pub send_window_scale: u8, // shift of the windows the peer advertises
+    pub recv_window_scale: u8, // shift of the windows we advertise
+    pub dupack_threshold: u8,  // raised when D-SACK shows a fast retransmit was spurious
+    pub spurious_retransmissions: u32,
+    pub rack: Rack,
+    pub frto: Option<FrtoState>, // set while the ACKs after a timeout are examined
+    pub reorder_deadline: Option<SystemTime>, // RACK waits for an overtaken segment until then
+    pub timestamps: bool,        // both ends sent the timestamps option on their SYN
+    pub ts_recent: u32,          // TSval to echo back to the peer
+    ts_recent_stamp: Instant,
+    ts_clock: SystemTime, // our TSval counts milliseconds from here
+    pub peer_user_timeout: Option<Duration>, // from the peer's UTO option
+    pub ecn: bool,        // ECN requested on our SYN, then agreed by both ends
+    pub ece_pending: bool, // a CE mark arrived: set ECE on every ACK until the peer sends CWR
+    pub cwr_pending: bool, // the window was reduced for ECE: set CWR on the next data segment
+    pub time_wait_expiry: Option<SystemTime>, // restarted by every FIN received in TIME_WAIT
+    pub orphaned: bool,   // closed by the user, reaped by the timer when TIME_WAIT expires
+    pub armed_timers: HashMap<TimerKind, SystemTime>, // the deadlines filed in the timer wheel
+    timers: Arc<TimerQueue>,
+    // counts of this connection alone, and of the whole stack
+    stats: Counters,
+    counters: Arc<Counters>,
+    pub mptcp: Option<Subflow>, // set on a subflow of a Multipath TCP connection
+    pub ao: Option<Authentication>, // set if the connection is authenticated with TCP-AO
+    batch: Option<Vec<Vec<u8>>>, // packets held while `transmit` runs, sent together at its end
+    // the connection's addresses and state, entered by whatever works on the socket
+    pub span: Span,
 }
 
 #[derive(Clone, Debug)]
 pub struct SendParam {
-    pub unacked_seq: u32,
-    pub next: u32,
-    pub window: u16,
-    pub initial_seq: u32,
+    pub urgent: Option<SeqNum>, // SND.UP: the byte following the urgent data
+    pub unacked_seq: SeqNum,
+    pub next: SeqNum,
+    pub window: u32,
+    pub wl1: SeqNum, // SEG.SEQ of the segment that last updated the window
+    pub wl2: SeqNum, // SEG.ACK of the segment that last updated the window
+    pub initial_seq: SeqNum,
 }
 
 #[derive(Clone, Debug)]
 pub struct RecvParam {
-    pub tail: u32,
-    pub next: u32,
-    pub window: u16,
-    pub initial_seq: u32,
+    pub urgent: Option<SeqNum>, // RCV.UP: the byte following the urgent data
+    pub out_of_order: Vec<(SeqNum, SeqNum)>, // blocks received above next, most recent first
+    pub duplicate: Option<(SeqNum, SeqNum)>, // data received twice, to report in the next ACK
+    pub advertised_edge: SeqNum, // right edge of the window last advertised
+    pub next: SeqNum,
+    pub window: u32,
+    pub initial_seq: SeqNum,
 }
 
 #[derive(Clone, Debug)]
 pub struct RetransmissionQueueEntry {
-    pub packet: TCPPacket,
+    pub packet: Arc<TCPPacket>, // shared, so that resending or splitting never copies it
+    pub first_transmission_time: SystemTime,
     pub latest_transmission_time: SystemTime,
     pub transmission_count: u8,
+    pub sacked: bool, // the peer holds it already, so it is never resent
 }
 
 impl RetransmissionQueueEntry {
-    fn new(packet: TCPPacket) -> Self {
+    fn new(packet: Arc<TCPPacket>, now: SystemTime) -> Self {
         Self {
             packet,
-            latest_transmission_time: SystemTime::now(),
+            first_transmission_time: now,
+            latest_transmission_time: now,
             transmission_count: 1,
+            sacked: false,
         }
     }
+
+    // the sequence number after the segment, its SYN and FIN included
+    pub fn end_seq(&self) -> SeqNum {
+        self.packet.get_seq() + self.packet.segment_len()
+    }
+}
+
+/// progress of F-RTO after a retransmission timeout (RFC 5682)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrtoState {
+    // the first ACK tells whether the retransmission was needed. `recover` is SND.NXT
+    // when the timer expired
+    FirstAck { recover: SeqNum },
+    // new data went out on the first ACK, the second decides
+    SecondAck,
+}
+
+/// snapshot of a connection's state, like Linux TCP_INFO
+#[derive(Debug, Clone)]
+pub struct TcpInfo {
+    pub status: TcpStatus,
+    pub send_next: u32,
+    pub send_unacked: u32,
+    pub recv_next: u32,
+    pub recv_window: u32, // advertised to the peer
+    pub send_window: u32, // advertised by the peer
+    pub retransmissions: u32,
+    pub spurious_retransmissions: u32, // detected with D-SACK
+    pub rtt: Option<Duration>,         // smoothed, None until the first sample
+    pub rto: Duration,
+    pub mss: usize,
+    pub cwnd: usize,
+    pub ssthresh: usize,
+    pub ecn: bool,
+    pub retransmission_queue_len: usize,
+    pub recv_queue_bytes: usize, // received but not yet read
+    pub accept_queue_len: usize,
+    pub ao_key_ids: Option<(u8, u8)>, // KeyID and RNextKeyID we send with TCP-AO
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -71,9 +238,11 @@ pub enum TcpStatus {
     Established,
     FinWait1,
     FinWait2,
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
+    Closed,
 }
 
 impl Display for TcpStatus {
@@ -85,58 +254,185 @@ impl Display for TcpStatus {
             TcpStatus::Established => write!(f, "ESTABLISHED"),
             TcpStatus::FinWait1 => write!(f, "FINWAIT1"),
             TcpStatus::FinWait2 => write!(f, "FINWAIT2"),
+            TcpStatus::Closing => write!(f, "CLOSING"),
             TcpStatus::TimeWait => write!(f, "TIMEWAIT"),
             TcpStatus::CloseWait => write!(f, "CLOSEWAIT"),
             TcpStatus::LastAck => write!(f, "LASTACK"),
+            TcpStatus::Closed => write!(f, "CLOSED"),
         }
     }
 }
 
 impl Socket {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         local_addr: Ipv4Addr,
         remote_addr: Ipv4Addr,
         local_port: u16,
         remote_port: u16,
         status: TcpStatus,
+        buffers: BufferSizes,
+        device: Arc<dyn NetworkDevice>,
+        timers: Arc<TimerQueue>,
+        counters: Arc<Counters>,
     ) -> Result<Self> {
-        let (sender, _) = transport::transport_channel(
-            65535,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4((IpNextHeaderProtocols::Tcp))),
-        )?;
+        let recv_buffer_size = buffers.recv.unwrap_or(SOCKET_BUFFER_SIZE);
+        if recv_buffer_size == 0 || recv_buffer_size > MAX_WINDOW {
+            anyhow::bail!("invalid receive buffer size: {}", recv_buffer_size);
+        }
+        let options = SocketOptions::new(buffers.send.unwrap_or(SOCKET_BUFFER_SIZE));
+        let congestion = Congestion::new(options.congestion_control, MSS);
+        let span = debug_span!(
+            "connection",
+            local = %SocketAddrV4::new(local_addr, local_port),
+            remote = %SocketAddrV4::new(remote_addr, remote_port),
+            state = %status,
+        );
         Ok(Self {
             local_addr,
             remote_addr,
             local_port,
             remote_port,
             send_param: SendParam {
-                unacked_seq: 0,
-                initial_seq: 0,
-                next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
+                urgent: None,
+                unacked_seq: SeqNum(0),
+                initial_seq: SeqNum(0),
+                next: SeqNum(0),
+                window: SOCKET_BUFFER_SIZE as u32,
+                wl1: SeqNum(0),
+                wl2: SeqNum(0),
             },
             recv_param: RecvParam {
-                tail: 0,
-                initial_seq: 0,
-                next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
+                urgent: None,
+                out_of_order: Vec::new(),
+                duplicate: None,
+                advertised_edge: SeqNum(0),
+                initial_seq: SeqNum(0),
+                next: SeqNum(0),
+                window: recv_buffer_size as u32,
             },
             status,
-            recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
+            recv_buffer: vec![0; recv_buffer_size],
+            recv_buffer_locked: buffers.recv.is_some(),
+            recv_space: 0,
+            recv_space_seq: None,
+            recv_space_time: Instant::now(),
+            urgent_byte: None,
+            send_buffer: VecDeque::new(),
+            fin_pending: false,
             retransmission_queue: VecDeque::new(),
             connection_established_queue: VecDeque::new(),
+            backlog: 0,
             listening_socket: None,
-            sender,
+            reuse_port: false,
+            device,
+            nonblocking: false,
+            read_timeout: None,
+            write_timeout: None,
+            read_shutdown: false,
+            options,
+            last_activity: timers.now(),
+            keepalive_probes: 0,
+            unacked_segments: 0,
+            delayed_ack: None,
+            persist_probes: 0,
+            next_persist: None,
+            retransmissions: 0,
+            duplicate_acks: 0,
+            rtt: RttEstimator::new(),
+            mss: MSS,
+            local_mss: MSS,
+            congestion,
+            pacer: Pacer::new(timers.now()),
+            sack_permitted: false,
+            window_scaling: false,
+            send_window_scale: 0,
+            recv_window_scale: 0,
+            dupack_threshold: DUPACK_THRESHOLD,
+            spurious_retransmissions: 0,
+            rack: Rack::new(),
+            frto: None,
+            reorder_deadline: None,
+            timestamps: false,
+            ts_recent: 0,
+            ts_recent_stamp: Instant::now(),
+            ts_clock: timers.now(),
+            peer_user_timeout: None,
+            ecn: false,
+            ece_pending: false,
+            cwr_pending: false,
+            time_wait_expiry: None,
+            orphaned: false,
+            armed_timers: HashMap::new(),
+            timers,
+            stats: Counters::new(),
+            counters,
+            mptcp: None,
+            ao: None,
+            batch: None,
+            span,
         })
     }
 
     pub fn send_tcp_packet(
         &mut self,
-        seq: u32,
-        ack: u32,
+        seq: SeqNum,
+        ack: SeqNum,
         flag: u8,
         payload: &[u8],
     ) -> Result<usize> {
+        let options = self.tcp_options(seq, flag, !payload.is_empty());
+        let ecn_flags = self.ecn_flags(flag, payload);
+        let tcp_packet = Arc::new(self.build_packet(seq, ack, flag | ecn_flags, &options, payload));
+        if let Some(ao) = self.ao.as_mut() {
+            ao.on_send(seq);
+        }
+        // only new data is ECN-capable, never SYNs, pure ACKs or retransmissions (RFC 3168 section 6.1)
+        let ect = self.ecn && !payload.is_empty();
+        let sent_size = if self.batch.is_some() {
+            let packet =
+                self.ip_packet(self.local_addr, self.remote_addr, tcp_packet.packet(), ect);
+            if let Some(batch) = self.batch.as_mut() {
+                batch.push(packet);
+            }
+            tcp_packet.packet().len()
+        } else {
+            self.send_segment(tcp_packet.packet(), ect)
+                .context(format!("failed to send: \n{:?}", tcp_packet))?
+        };
+
+        if flag & tcpflags::RST > 0 {
+            self.count(Counter::ResetsOut);
+        }
+        if flag & tcpflags::ACK > 0 {
+            // any segment acknowledges everything received so far
+            self.unacked_segments = 0;
+            self.delayed_ack = None;
+            self.recv_param.advertised_edge = ack + self.advertised_window_size(flag);
+        }
+        // pure ACKs and RSTs are never retransmitted
+        if payload.is_empty() && flag == tcpflags::ACK || flag & tcpflags::RST > 0 {
+            return Ok(sent_size);
+        }
+        self.pacer.on_send(
+            payload.len(),
+            self.congestion.pacing_rate(),
+            self.timers.now(),
+        );
+        self.retransmission_queue
+            .push_back(RetransmissionQueueEntry::new(tcp_packet, self.timers.now()));
+        self.schedule_timers();
+        Ok(sent_size)
+    }
+
+    fn build_packet(
+        &self,
+        seq: SeqNum,
+        ack: SeqNum,
+        flag: u8,
+        options: &[TcpOption],
+        payload: &[u8],
+    ) -> TCPPacket {
         let mut tcp_packet = TCPPacket::new(payload.len());
         tcp_packet.set_src(self.local_port);
         tcp_packet.set_dest(self.remote_port);
@@ -144,8 +440,27 @@ impl Socket {
         tcp_packet.set_ack(ack);
         tcp_packet.set_data_offset(5);
         tcp_packet.set_flag(flag);
-        tcp_packet.set_window_size(self.recv_param.window);
+        if let Some(urgent) = self.send_param.urgent {
+            // every segment before the urgent mark points at it, as far as 16 bits reach
+            if seq < urgent && urgent - seq <= u16::MAX as u32 {
+                tcp_packet.set_flag(flag | tcpflags::URG);
+                tcp_packet.set_urgent_pointer((urgent - seq) as u16);
+            }
+        }
+        tcp_packet.set_window_size(self.advertised_window(flag));
         tcp_packet.set_payload(payload);
+        if !options.is_empty() {
+            tcp_packet.set_options(options);
+        }
+        if let Some(ao) = &self.ao {
+            ao.sign(
+                tcp_packet.packet_mut(),
+                SocketAddrV4::new(self.local_addr, self.local_port),
+                SocketAddrV4::new(self.remote_addr, self.remote_port),
+                self.send_param.initial_seq,
+                self.recv_param.initial_seq,
+            );
+        }
         tcp_packet.set_checksum(util::ipv4_checksum(
             &tcp_packet.packet(),
             8,
@@ -154,20 +469,1154 @@ impl Socket {
             &self.remote_addr,
             IpNextHeaderProtocols::Tcp,
         ));
-        let sent_size = self
-            .sender
-            .send_to(tcp_packet.clone(), IpAddr::V4(self.remote_addr))
-            .context(format!("failed to send: \n{:?}", tcp_packet))?;
+        tcp_packet
+    }
 
-        dbg!("sent", &tcp_packet);
-        if payload.is_empty() && tcp_packet.get_flag() == tcpflags::ACK {
-            return Ok(sent_size);
+    // our own setting wins, otherwise the peer's is adopted within limits
+    pub fn user_timeout(&self) -> Option<Duration> {
+        self.options
+            .user_timeout
+            .or_else(|| Some(cmp::max(self.peer_user_timeout?, MIN_USER_TIMEOUT)))
+    }
+
+    // when each of the timers may have something to do next, None if it has nothing to wait for
+    fn timer_deadlines(&self) -> [(TimerKind, Option<SystemTime>); 9] {
+        let now = self.timers.now();
+        let retransmission = self
+            .retransmission_queue
+            .iter()
+            .find(|item| !item.sacked)
+            .map(|item| item.latest_transmission_time + self.rtt.rto());
+        let persist = self.next_persist.or_else(|| {
+            // the timer arms the first probe
+            (matches!(self.status, TcpStatus::Established | TcpStatus::CloseWait)
+                && self.send_param.window == 0
+                && self.flight_size() == 0
+                && !self.send_buffer.is_empty())
+            .then_some(now)
+        });
+        let keepalive = match self.options.keepalive {
+            Some(idle) if self.status == TcpStatus::Established => Some(
+                self.last_activity
+                    + idle
+                    + Duration::from_secs(KEEPALIVE_INTERVAL) * self.keepalive_probes as u32,
+            ),
+            _ => None,
+        };
+        let user_timeout = self.user_timeout().and_then(|timeout| {
+            self.retransmission_queue
+                .iter()
+                .map(|item| item.first_transmission_time + timeout)
+                .min()
+        });
+        let pacing = (!self.send_buffer.is_empty() && !self.pacer.delay(now).is_zero())
+            .then(|| now + self.pacer.delay(now));
+        let time_wait = self.time_wait_expiry.filter(|_| self.orphaned);
+        [
+            (TimerKind::Retransmission, retransmission),
+            (TimerKind::DelayedAck, self.delayed_ack),
+            (TimerKind::Persist, persist),
+            (TimerKind::Keepalive, keepalive),
+            (TimerKind::UserTimeout, user_timeout),
+            (TimerKind::Pacing, pacing),
+            (TimerKind::TimeWait, time_wait),
+            (TimerKind::Reordering, self.reorder_deadline),
+            (TimerKind::TailLossProbe, self.tail_loss_probe_deadline()),
+        ]
+    }
+
+    // a probe goes out a PTO after the last transmission or ACK while data is outstanding,
+    // unless a probe or fast recovery is under way or the retransmission timer comes first.
+    // whichever way losses are detected, as a lost tail draws no duplicate ACKs
+    pub fn tail_loss_probe_deadline(&self) -> Option<SystemTime> {
+        if !matches!(
+            self.status,
+            TcpStatus::Established
+                | TcpStatus::CloseWait
+                | TcpStatus::FinWait1
+                | TcpStatus::Closing
+                | TcpStatus::LastAck
+        ) || self.rack.tlp_end_seq.is_some()
+            || self.congestion.in_recovery()
+            || self.flight_size() == 0
+        {
+            return None;
         }
-        self.retransmission_queue
-            .push_back(RetransmissionQueueEntry::new(tcp_packet));
+        let outstanding = self.retransmission_queue.iter().filter(|item| !item.sacked);
+        let last_sent = outstanding
+            .clone()
+            .map(|item| item.latest_transmission_time)
+            .max()?;
+        let retransmission = outstanding
+            .map(|item| item.latest_transmission_time)
+            .next()?
+            + self.rtt.rto();
+        let pto = self
+            .rack
+            .pto(self.rtt.srtt(), self.flight_size() <= self.mss);
+        let deadline = cmp::max(last_sent, self.last_activity) + pto;
+        (deadline < retransmission).then_some(deadline)
+    }
+
+    // arm, move or cancel the timers of this socket in the wheel.
+    // called whenever a timer may have been armed or moved; a timer that fires early finds
+    // nothing to do and is armed again
+    pub fn schedule_timers(&mut self) {
+        let sock_id = self.get_sock_id();
+        for (kind, deadline) in self.timer_deadlines() {
+            if self.armed_timers.get(&kind) == deadline.as_ref() {
+                continue;
+            }
+            match deadline {
+                Some(deadline) => {
+                    self.armed_timers.insert(kind, deadline);
+                    self.timers.schedule(sock_id, kind, deadline);
+                }
+                None => {
+                    self.armed_timers.remove(&kind);
+                    self.timers.cancel(sock_id, kind);
+                }
+            }
+        }
+    }
+
+    // some data has been waiting for an ACK longer than the user timeout
+    pub fn is_user_timed_out(&self) -> bool {
+        let now = self.timers.now();
+        self.user_timeout().is_some_and(|timeout| {
+            self.retransmission_queue.iter().any(|item| {
+                now.duration_since(item.first_transmission_time)
+                    .unwrap_or_default()
+                    >= timeout
+            })
+        })
+    }
+
+    // urgent data stays inline in the stream; the last urgent byte (RFC 6093) is also kept
+    // aside for recv_oob once it has been received
+    pub fn process_urgent(&mut self, packet: &TCPPacket) {
+        if packet.get_flag() & tcpflags::URG > 0 {
+            let urgent = packet.get_seq() + packet.get_urgent_pointer() as u32;
+            if self
+                .recv_param
+                .urgent
+                .is_none_or(|current| current < urgent)
+            {
+                debug!(%urgent, "urgent data");
+                self.recv_param.urgent = Some(urgent);
+                self.urgent_byte = None;
+            }
+        }
+        let last = match self.recv_param.urgent {
+            Some(urgent) if self.urgent_byte.is_none() => urgent - 1,
+            _ => return,
+        };
+        // unread bytes end at RCV.NXT
+        let unread = self.recv_buffer.len() - self.recv_param.window as usize;
+        let behind = (self.recv_param.next - last) as usize;
+        if last < self.recv_param.next && behind <= unread {
+            self.urgent_byte = Some(self.recv_buffer[unread - behind]);
+        }
+    }
+
+    // the next byte recv returns is the last urgent byte
+    pub fn at_urgent_mark(&self) -> bool {
+        let unread = self.recv_buffer.len() - self.recv_param.window as usize;
+        self.recv_param
+            .urgent
+            .is_some_and(|urgent| urgent - 1 == self.recv_param.next - unread as u32)
+    }
+
+    // send `payload` as new data at SND.NXT. on an MPTCP subflow, in as many segments
+    // as it spans mappings
+    pub fn send_data(&mut self, mut payload: &[u8]) -> Result<()> {
+        while !payload.is_empty() {
+            let subflow_seq = SeqNum(self.send_param.next - self.send_param.initial_seq);
+            let size = self.mptcp.as_ref().map_or(payload.len(), |subflow| {
+                cmp::min(payload.len(), subflow.mapping_room(subflow_seq))
+            });
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &payload[..size],
+            )?;
+            self.send_param.next += size as u32;
+            self.congestion.on_send(size);
+            payload = &payload[size..];
+        }
+        Ok(())
+    }
+
+    // a segment smaller than the MSS waits while the socket is corked, or with Nagle's
+    // algorithm (RFC 896) while data is in flight
+    pub fn holds_back(&self, size: usize) -> bool {
+        size < self.mss && (self.options.cork || !self.options.nodelay && self.flight_size() > 0)
+    }
+
+    // push buffered data onto the wire as far as the windows and pacing allow, followed by
+    // a pending FIN. runs on writes, on ACKs and from the timer.
+    // `force` also sends a small last segment Nagle's algorithm or cork would hold back
+    pub fn transmit(&mut self, force: bool) -> Result<()> {
+        // the segments go to the device together, in as few system calls as it can
+        self.batch = Some(Vec::new());
+        let result = self.transmit_segments(force);
+        let packets = self.batch.take().unwrap_or_default();
+        if !packets.is_empty() {
+            self.device
+                .send_packets(&packets)
+                .context("failed to send")?;
+        }
+        result
+    }
+
+    fn transmit_segments(&mut self, force: bool) -> Result<()> {
+        // nothing is held back behind a FIN
+        let force = force || self.fin_pending;
+        while !self.send_buffer.is_empty() {
+            let size = cmp::min(
+                self.mss,
+                cmp::min(self.sendable_size(), self.send_buffer.len()),
+            );
+            if size == 0 || !self.pacer.delay(self.timers.now()).is_zero() {
+                // the pacer or the persist timer picks it up
+                self.schedule_timers();
+                return Ok(());
+            }
+            if size == self.send_buffer.len() && !force && self.holds_back(size) {
+                return Ok(());
+            }
+            let payload: Vec<u8> = self.send_buffer.drain(..size).collect();
+            self.send_data(&payload)?;
+        }
+        if self.fin_pending {
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::FIN | tcpflags::ACK,
+                &[],
+            )?;
+            self.send_param.next += 1;
+            self.fin_pending = false;
+        }
+        Ok(())
+    }
+
+    // limited transmit (RFC 3042): an early duplicate ACK lets one new segment out even if
+    // the congestion window is full, up to two segments beyond it. the segments that arrive
+    // after a lost one draw enough duplicate ACKs for fast retransmit then, where a small
+    // window would otherwise leave it to the retransmission timeout
+    pub fn limited_transmit(&mut self) -> Result<()> {
+        let in_flight = self.flight_size();
+        let size = cmp::min(
+            cmp::min(self.mss, self.send_buffer.len()),
+            cmp::min(
+                (self.send_param.window as usize).saturating_sub(in_flight),
+                (self.congestion.cwnd() + 2 * self.mss).saturating_sub(in_flight),
+            ),
+        );
+        if size == 0 {
+            return Ok(());
+        }
+        trace!(seq = %self.send_param.next, "limited transmit");
+        let payload: Vec<u8> = self.send_buffer.drain(..size).collect();
+        self.send_data(&payload)
+    }
+
+    // room left in the send buffer, which also holds the data in flight
+    pub fn send_buffer_space(&self) -> usize {
+        self.options
+            .send_buffer_size
+            .saturating_sub(self.flight_size() + self.send_buffer.len())
+    }
+
+    // ECE and CWR on a synchronized ECN-capable connection
+    fn ecn_flags(&mut self, flag: u8, payload: &[u8]) -> u8 {
+        if !self.ecn || flag & (tcpflags::SYN | tcpflags::RST) > 0 {
+            return 0;
+        }
+        let mut ecn_flags = 0;
+        if self.ece_pending {
+            ecn_flags |= tcpflags::ECE;
+        }
+        if self.cwr_pending && !payload.is_empty() {
+            self.cwr_pending = false;
+            ecn_flags |= tcpflags::CWR;
+        }
+        ecn_flags
+    }
+
+    // the segment goes out from our address in an IPv4 header with the socket's TTL,
+    // marked ECT(0) if `ect`
+    pub fn send_segment(&self, segment: &[u8], ect: bool) -> Result<usize> {
+        self.send_segment_between(self.local_addr, self.remote_addr, segment, ect)
+    }
+
+    // for a listener, which answers from the address a segment came to
+    fn send_segment_between(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        segment: &[u8],
+        ect: bool,
+    ) -> Result<usize> {
+        let packet = self.ip_packet(local_addr, remote_addr, segment, ect);
+        self.device.send_packet(&packet)?;
+        Ok(segment.len())
+    }
+
+    fn ip_packet(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        segment: &[u8],
+        ect: bool,
+    ) -> Vec<u8> {
+        let tos = if ect { ECT0 } else { 0 };
+        let packet = ip::build(
+            local_addr,
+            remote_addr,
+            ip::TCP,
+            self.options.ttl,
+            tos,
+            segment,
+        );
+        self.capture(&packet, Direction::Outgoing);
+        trace_segment(segment, Direction::Outgoing);
+        self.count(Counter::SegmentsOut);
+        packet
+    }
+
+    // in the socket's counts and the stack's
+    pub fn count(&self, counter: Counter) {
+        self.stats.bump(counter);
+        self.counters.bump(counter);
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    // into the socket's capture file, if it has one. a failed write loses the record,
+    // not the packet
+    pub fn capture(&self, packet: &[u8], direction: Direction) {
+        if let Some(capture) = self.options.capture.as_ref() {
+            if let Err(error) = capture.record(packet, direction) {
+                warn!(%error, "failed to capture packet");
+            }
+        }
+    }
+
+    // a socket bound to a device ignores segments from other interfaces
+    pub fn accepts_on(&self, local_addr: Ipv4Addr) -> bool {
+        self.options
+            .device
+            .as_ref()
+            .is_none_or(|device| device.addrs.contains(&local_addr))
+    }
+
+    // TCP-AO: an authenticated connection takes only segments whose MAC one of its MKTs
+    // proves
+    pub fn authenticate(&mut self, packet: &TCPPacket) -> bool {
+        let ao = match self.ao.as_mut() {
+            Some(ao) => ao,
+            None => return true,
+        };
+        let remote_isn = if packet.get_flag() & tcpflags::SYN > 0 {
+            packet.get_seq()
+        } else {
+            self.recv_param.initial_seq
+        };
+        ao.verify(
+            packet.packet(),
+            SocketAddrV4::new(self.remote_addr, self.remote_port),
+            SocketAddrV4::new(self.local_addr, self.local_port),
+            remote_isn,
+            self.send_param.initial_seq,
+        )
+    }
+
+    // RCV.NXT counts the FIN as well
+    pub fn fin_received(&self) -> bool {
+        matches!(
+            self.status,
+            TcpStatus::CloseWait | TcpStatus::Closing | TcpStatus::LastAck | TcpStatus::TimeWait
+        )
+    }
+
+    // the DSS of a segment on an MPTCP subflow: the peer got our handshake, and this is
+    // where the payload belongs in the connection
+    pub fn process_dss(&mut self, packet: &TCPPacket) {
+        let subflow = match self.mptcp.as_mut() {
+            Some(subflow) => subflow,
+            None => return,
+        };
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            return;
+        }
+        for option in packet.get_options() {
+            if let TcpOption::Mptcp(MptcpOption::Dss {
+                mapping,
+                data_seq_64,
+                ..
+            }) = option
+            {
+                subflow.on_dss(mapping, data_seq_64);
+            }
+        }
+    }
+
+    // CE marks seen by the IP layer are echoed until the peer confirms with CWR
+    pub fn process_ecn(&mut self, packet: &TCPPacket, ecn_field: u8) {
+        if !self.ecn {
+            return;
+        }
+        if packet.get_flag() & tcpflags::CWR > 0 {
+            self.ece_pending = false;
+        }
+        if ecn_field == CE {
+            debug!(seq = %packet.get_seq(), "congestion experienced");
+            self.ece_pending = true;
+        }
+    }
+
+    // the peer echoed a CE mark: back off as for a loss, once per window of data
+    pub fn process_ecn_echo(&mut self, packet: &TCPPacket) {
+        if !self.ecn || packet.get_flag() & tcpflags::ECE == 0 {
+            return;
+        }
+        let flight_size = self.flight_size();
+        if self.congestion.on_congestion_experienced(
+            flight_size,
+            self.send_param.unacked_seq,
+            self.send_param.next,
+        ) {
+            debug!(cwnd = self.congestion.cwnd(), "ECE: cwnd reduced");
+            self.cwr_pending = true;
+        }
+    }
+
+    // path MTU discovery (RFC 1191): segments in flight that no longer fit are split
+    // and resent right away instead of waiting for the retransmission timer
+    pub fn reduce_mss(&mut self, mss: usize) -> Result<()> {
+        let mut mss = cmp::max(mss, MIN_MSS);
+        if self.timestamps {
+            mss -= TIMESTAMPS_OPTION_LEN;
+        }
+        if self.mptcp.is_some() {
+            mss -= DSS_OPTION_LEN;
+        }
+        if self.ao.is_some() {
+            mss -= AO_OPTION_LEN;
+        }
+        if mss >= self.mss {
+            return Ok(());
+        }
+        debug!(from = self.mss, to = mss, "mss reduced");
+        self.mss = mss;
+        for item in mem::take(&mut self.retransmission_queue) {
+            if item.packet.payload().len() <= mss || item.sacked {
+                self.retransmission_queue.push_back(item);
+                continue;
+            }
+            let options = item.packet.get_options();
+            let chunks = item.packet.payload().chunks(mss);
+            let last = chunks.len() - 1;
+            for (i, chunk) in chunks.enumerate() {
+                let mut flag = item.packet.get_flag();
+                if i != last {
+                    flag &= !tcpflags::FIN;
+                }
+                let packet = self.build_packet(
+                    item.packet.get_seq() + (i * mss) as u32,
+                    item.packet.get_ack(),
+                    flag,
+                    &options,
+                    chunk,
+                );
+                self.send_segment(packet.packet(), false)
+                    .context(format!("failed to send: \n{:?}", packet))?;
+                self.retransmissions += 1;
+                self.count(Counter::Retransmissions);
+                self.retransmission_queue
+                    .push_back(RetransmissionQueueEntry {
+                        packet: Arc::new(packet),
+                        first_transmission_time: item.first_transmission_time,
+                        latest_transmission_time: self.timers.now(),
+                        transmission_count: item.transmission_count + 1,
+                        sacked: false,
+                    });
+            }
+        }
+        Ok(())
+    }
+
+    // options carried by a segment with `flag` starting at `seq`
+    fn tcp_options(&mut self, seq: SeqNum, flag: u8, has_payload: bool) -> Vec<TcpOption> {
+        let mut options = Vec::new();
+        let is_syn = flag & tcpflags::SYN > 0;
+        // offered on SYN, confirmed on SYN-ACK only if the peer offered it too,
+        // then carried by every segment but RST
+        if (is_syn && flag & tcpflags::ACK == 0 || self.timestamps) && flag & tcpflags::RST == 0 {
+            options.push(TcpOption::Timestamps {
+                value: self.ts_value(),
+                echo_reply: self.ts_recent,
+            });
+        }
+        if is_syn {
+            options.push(TcpOption::MaxSegmentSize(self.local_mss as u16));
+            if let Some(timeout) = self.options.user_timeout {
+                options.push(TcpOption::UserTimeout(timeout));
+            }
+            if flag & tcpflags::ACK == 0 || self.window_scaling {
+                // enough to advertise the whole receive buffer, however far it may grow
+                let size = if self.recv_buffer_locked {
+                    self.recv_buffer.len()
+                } else {
+                    cmp::max(self.recv_buffer.len(), MAX_AUTOTUNED_RECV_BUFFER)
+                };
+                self.recv_window_scale = (0..MAX_WINDOW_SCALE)
+                    .find(|shift| size >> shift <= u16::MAX as usize)
+                    .unwrap_or(MAX_WINDOW_SCALE);
+                options.push(TcpOption::WindowScale(self.recv_window_scale));
+            }
+            if flag & tcpflags::ACK == 0 || self.sack_permitted {
+                options.push(TcpOption::SackPermitted);
+            }
+        }
+        if let Some(ao) = &self.ao {
+            // on every segment, the MAC filled in once the segment is built. next to the
+            // other options of a SYN, the user timeout doesn't fit anymore
+            if tcpoption::serialize(&options).len() + AO_OPTION_LEN > MAX_OPTIONS_LEN {
+                options.retain(|option| !matches!(option, TcpOption::UserTimeout(_)));
+            }
+            let (key_id, rnext_key_id) = ao.key_ids();
+            options.push(TcpOption::Authentication {
+                key_id,
+                rnext_key_id,
+                mac: vec![0; MAC_LEN],
+            });
+        }
+        let subflow_seq = SeqNum(seq - self.send_param.initial_seq);
+        if let Some(option) = self
+            .mptcp
+            .as_mut()
+            .and_then(|subflow| subflow.option(flag, subflow_seq, has_payload))
+        {
+            // left out if it doesn't fit: the peer takes the connection for plain TCP then
+            options.push(TcpOption::Mptcp(option));
+            if tcpoption::serialize(&options).len() > MAX_OPTIONS_LEN {
+                options.pop();
+            }
+        }
+        // as many blocks as fit into the option space left
+        let blocks = (MAX_OPTIONS_LEN - tcpoption::serialize(&options).len())
+            .saturating_sub(SACK_OPTION_HEADER_LEN)
+            / SACK_BLOCK_LEN;
+        if !is_syn
+            && self.sack_permitted
+            && blocks > 0
+            && (self.recv_param.duplicate.is_some() || !self.recv_param.out_of_order.is_empty())
+        {
+            // a D-SACK block goes first and is reported only once (RFC 2883)
+            options.push(TcpOption::Sack(
+                self.recv_param
+                    .duplicate
+                    .take()
+                    .into_iter()
+                    .chain(self.recv_param.out_of_order.iter().copied())
+                    .take(blocks)
+                    .collect(),
+            ));
+        }
+        options
+    }
+
+    // TSval for a segment sent now, never 0 which stands for nothing to echo
+    fn ts_value(&self) -> u32 {
+        let elapsed = self
+            .timers
+            .now()
+            .duration_since(self.ts_clock)
+            .unwrap_or_default();
+        (elapsed.as_millis() as u32).wrapping_add(1)
+    }
+
+    // take the options the peer offered on its SYN
+    pub fn negotiate_options(&mut self, packet: &TCPPacket) {
+        let mut peer_mss = DEFAULT_MSS;
+        let mut multipath = false;
+        for option in packet.get_options() {
+            match option {
+                TcpOption::MaxSegmentSize(mss) => peer_mss = mss as usize,
+                TcpOption::UserTimeout(timeout) => self.peer_user_timeout = Some(timeout),
+                TcpOption::WindowScale(shift) => {
+                    self.window_scaling = true;
+                    self.send_window_scale = cmp::min(shift, MAX_WINDOW_SCALE);
+                }
+                TcpOption::SackPermitted => self.sack_permitted = true,
+                TcpOption::Timestamps { value, .. } => {
+                    self.timestamps = true;
+                    self.update_ts_recent(value);
+                }
+                TcpOption::Mptcp(_) => multipath = self.mptcp.is_some(),
+                _ => {}
+            }
+        }
+        if !self.window_scaling {
+            // the shift offered on our SYN is void
+            self.recv_window_scale = 0;
+        }
+        // the MSS counts no options, so leave room for the ones on every segment (RFC 6691)
+        self.mss = peer_mss.clamp(MIN_MSS, cmp::max(self.local_mss, MIN_MSS));
+        if self.timestamps {
+            self.mss -= TIMESTAMPS_OPTION_LEN;
+        }
+        if multipath {
+            self.mss -= DSS_OPTION_LEN;
+        }
+        if self.ao.is_some() {
+            self.mss -= AO_OPTION_LEN;
+        }
+        // nothing has been sent yet: start over with windows in the negotiated segment size
+        self.congestion = Congestion::new(self.options.congestion_control, self.mss);
+    }
+
+    // windows on SYNs are never scaled
+    fn advertised_window(&self, flag: u8) -> u16 {
+        let window = if flag & tcpflags::SYN > 0 {
+            self.recv_param.window
+        } else {
+            self.recv_param.window >> self.recv_window_scale
+        };
+        cmp::min(window, u16::MAX as u32) as u16
+    }
+
+    // the window the peer reads from the field, which the scaling rounds down and clamps.
+    // it shrinks as unread data fills the buffer, down to zero
+    fn advertised_window_size(&self, flag: u8) -> u32 {
+        let window = self.advertised_window(flag) as u32;
+        if flag & tcpflags::SYN > 0 {
+            window
+        } else {
+            window << self.recv_window_scale
+        }
+    }
+
+    // take the window of `packet` as SND.WND, on the handshake
+    pub fn set_send_window(&mut self, packet: &TCPPacket) {
+        self.send_param.window = self.peer_window(packet);
+        self.send_param.wl1 = packet.get_seq();
+        self.send_param.wl2 = packet.get_ack();
+    }
+
+    // update SND.WND from an acceptable ACK unless it is older than the segment the window
+    // was last taken from, which may be reordered (RFC 9293 section 3.10.7.4).
+    // returns true if the window opened
+    pub fn update_send_window(&mut self, packet: &TCPPacket) -> bool {
+        let (seq, ack) = (packet.get_seq(), packet.get_ack());
+        if packet.get_flag() & tcpflags::ACK == 0
+            || ack < self.send_param.unacked_seq
+            || self.send_param.next < ack
+            || seq < self.send_param.wl1
+            || seq == self.send_param.wl1 && ack < self.send_param.wl2
+        {
+            return false;
+        }
+        let window = self.peer_window(packet);
+        let opened = window > self.send_param.window;
+        if window != self.send_param.window {
+            trace!(window, "send window");
+        }
+        self.send_param.window = window;
+        self.send_param.wl1 = seq;
+        self.send_param.wl2 = ack;
+        if window == 0 {
+            // buffered data waits for the persist timer
+            self.schedule_timers();
+        }
+        opened
+    }
+
+    // the window advertised by the peer on `packet`, in bytes
+    pub fn peer_window(&self, packet: &TCPPacket) -> u32 {
+        let window = packet.get_window_size() as u32;
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            window
+        } else {
+            window << self.send_window_scale
+        }
+    }
+
+    // remember the peer's TSval to echo, and measure the round trip from the echo of ours.
+    // only a segment at or below RCV.NXT updates TS.Recent, so a delayed ACK echoes the
+    // oldest unacknowledged segment (RFC 7323 section 4.3)
+    pub fn process_timestamps(&mut self, packet: &TCPPacket) -> Option<Duration> {
+        if !self.timestamps {
+            return None;
+        }
+        let (value, echo_reply) = packet.get_timestamps()?;
+        if packet.get_seq() <= self.recv_param.next {
+            self.update_ts_recent(value);
+        }
+        // the echo of a retransmission is that of its first transmission, which only
+        // overestimates the RTT, so unlike Karn's algorithm every ACK can be sampled
+        if packet.get_flag() & tcpflags::ACK == 0 || echo_reply == 0 {
+            return None;
+        }
+        Some(Duration::from_millis(
+            self.ts_value().wrapping_sub(echo_reply) as u64,
+        ))
+    }
+
+    fn update_ts_recent(&mut self, value: u32) {
+        self.ts_recent = value;
+        self.ts_recent_stamp = Instant::now();
+    }
+
+    // PAWS: a segment whose TSval is older than TS.Recent is an old duplicate, even if its
+    // sequence number falls into the window after wrapping around (RFC 7323 section 5)
+    pub fn is_old_duplicate(&self, packet: &TCPPacket) -> bool {
+        if !self.timestamps || packet.get_flag() & tcpflags::RST > 0 {
+            return false;
+        }
+        packet.get_timestamps().is_some_and(|(value, _)| {
+            (value.wrapping_sub(self.ts_recent) as i32) < 0
+                && self.ts_recent_stamp.elapsed() < PAWS_IDLE_LIMIT
+        })
+    }
+
+    // answer a segment that belongs to no connection with RST (RFC 793)
+    pub fn send_reset(
+        &mut self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        if packet.get_flag() & tcpflags::RST > 0 {
+            return Ok(());
+        }
+        let mut reset = TCPPacket::new(0);
+        reset.set_src(packet.get_dest());
+        reset.set_dest(packet.get_src());
+        reset.set_data_offset(5);
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            reset.set_seq(packet.get_ack());
+            reset.set_flag(tcpflags::RST);
+        } else {
+            reset.set_ack(packet.get_seq() + packet.segment_len());
+            reset.set_flag(tcpflags::RST | tcpflags::ACK);
+        }
+        reset.set_checksum(util::ipv4_checksum(
+            reset.packet(),
+            8,
+            &[],
+            &local_addr,
+            &remote_addr,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        self.send_segment_between(local_addr, remote_addr, reset.packet(), false)
+            .context(format!("failed to send: \n{:?}", reset))?;
+        self.count(Counter::ResetsOut);
+        Ok(())
+    }
+
+    // SYN-ACK with a SYN cookie as its ISN, sent by a listener to the SYN `packet` without
+    // a socket for the connection. only the MSS option fits into the cookie
+    pub fn send_syn_cookie(
+        &mut self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        packet: &TCPPacket,
+        cookie: SeqNum,
+        mss: u16,
+    ) -> Result<()> {
+        let mut syn_ack = TCPPacket::new(0);
+        syn_ack.set_src(packet.get_dest());
+        syn_ack.set_dest(packet.get_src());
+        syn_ack.set_seq(cookie);
+        syn_ack.set_ack(packet.get_seq() + 1);
+        syn_ack.set_flag(tcpflags::SYN | tcpflags::ACK);
+        syn_ack.set_window_size(cmp::min(self.recv_buffer.len(), u16::MAX as usize) as u16);
+        syn_ack.set_options(&[TcpOption::MaxSegmentSize(mss)]);
+        syn_ack.set_checksum(util::ipv4_checksum(
+            syn_ack.packet(),
+            8,
+            &[],
+            &local_addr,
+            &remote_addr,
+            IpNextHeaderProtocols::Tcp,
+        ));
+        self.send_segment_between(local_addr, remote_addr, syn_ack.packet(), false)
+            .context(format!("failed to send: \n{:?}", syn_ack))?;
+        debug!(%cookie, "sent SYN cookie");
+        Ok(())
+    }
+
+    pub fn readiness(&self) -> u8 {
+        let mut readiness = 0;
+        if self.status == TcpStatus::Listen {
+            if !self.connection_established_queue.is_empty() {
+                readiness |= poll::READABLE;
+            }
+            return readiness;
+        }
+        // a closed peer is readable too: recv returns 0 without waiting
+        if self.recv_buffer.len() > self.recv_param.window as usize
+            || self.read_shutdown
+            || matches!(
+                self.status,
+                TcpStatus::CloseWait
+                    | TcpStatus::Closing
+                    | TcpStatus::LastAck
+                    | TcpStatus::TimeWait
+            )
+        {
+            readiness |= poll::READABLE;
+        }
+        if self.urgent_byte.is_some() {
+            readiness |= poll::PRIORITY;
+        }
+        if matches!(self.status, TcpStatus::Established | TcpStatus::CloseWait)
+            && self.send_buffer_space() > 0
+        {
+            readiness |= poll::WRITABLE;
+        }
+        readiness
+    }
+
+    // buffered data is kept in place so that offsets derived from the window stay valid
+    pub fn set_recv_buffer_size(&mut self, size: usize) -> Result<()> {
+        let used = self.recv_buffer.len() - self.recv_param.window as usize;
+        if size < used || size > MAX_WINDOW {
+            anyhow::bail!("invalid receive buffer size: {}", size);
+        }
+        if size < self.recv_buffer.len() {
+            // out-of-order data beyond the new end is lost
+            self.recv_param.out_of_order.clear();
+        }
+        self.recv_buffer.resize(size, 0);
+        self.recv_param.window = (size - used) as u32;
+        Ok(())
+    }
+
+    // move in-order data out of the receive buffer into `buffer`
+    pub fn take_received(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let received_size = self.recv_buffer.len() - self.recv_param.window as usize;
+        let copy_size = cmp::min(buffer.len(), received_size);
+        buffer[..copy_size].copy_from_slice(&self.recv_buffer[..copy_size]);
+        self.recv_buffer.copy_within(copy_size.., 0);
+        self.recv_param.window += copy_size as u32;
+        // receiver side silly window avoidance (RFC 1122 section 4.2.3.3): the peer hears of
+        // the freed space once the window has opened by a full segment or half the buffer
+        let opened = (self.recv_param.next + self.recv_param.window
+            - self.recv_param.advertised_edge) as i32;
+        if opened >= cmp::min(self.mss, self.recv_buffer.len() / 2) as i32 {
+            self.send_window_update()?;
+        }
+        Ok(copy_size)
+    }
+
+    // tell the peer about a window that opened up, unless the connection isn't synchronized
+    // or the peer has nothing more to send
+    pub fn send_window_update(&mut self) -> Result<()> {
+        if !matches!(
+            self.status,
+            TcpStatus::Established | TcpStatus::FinWait1 | TcpStatus::FinWait2
+        ) {
+            return Ok(());
+        }
+        trace!(window = self.recv_param.window, "window update");
+        self.send_tcp_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    // receive buffer autotuning in the spirit of Linux's tcp_rcv_space_adjust: once a
+    // round trip, grow the buffer to twice what arrived during it, so that the window
+    // keeps ahead of the bandwidth-delay product instead of throttling the sender
+    pub fn autotune_recv_buffer(&mut self) {
+        if self.recv_buffer_locked {
+            return;
+        }
+        let start = match self.recv_space_seq {
+            Some(start) => start,
+            None => {
+                self.recv_space_seq = Some(self.recv_param.next);
+                self.recv_space_time = Instant::now();
+                return;
+            }
+        };
+        // a socket that only receives has no RTT samples of its own
+        let rtt = self.rtt.srtt().unwrap_or_else(|| self.rtt.rto());
+        if self.recv_space_time.elapsed() < rtt {
+            return;
+        }
+        let received = (self.recv_param.next - start) as usize;
+        self.recv_space_seq = Some(self.recv_param.next);
+        self.recv_space_time = Instant::now();
+        if received <= self.recv_space {
+            return;
+        }
+        self.recv_space = received;
+        let size = cmp::min(2 * received, MAX_AUTOTUNED_RECV_BUFFER);
+        if size > self.recv_buffer.len() {
+            debug!(size, "recv buffer autotuned");
+            if let Err(error) = self.set_recv_buffer_size(size) {
+                warn!(%error, "failed to resize recv buffer");
+            }
+        }
+    }
+
+    // remember [start, end) received above RCV.NXT, merged with the blocks it touches
+    pub fn add_out_of_order(&mut self, mut start: SeqNum, mut end: SeqNum) {
+        self.recv_param.out_of_order.retain(|&(left, right)| {
+            if right < start || end < left {
+                return true;
+            }
+            if left < start {
+                start = left;
+            }
+            end = end.max(right);
+            false
+        });
+        self.recv_param.out_of_order.insert(0, (start, end));
+    }
+
+    // in-order data has been received up to `next`: take in the blocks that connect to it.
+    // returns the new RCV.NXT
+    pub fn take_out_of_order(&mut self, mut next: SeqNum) -> SeqNum {
+        while let Some(position) = self
+            .recv_param
+            .out_of_order
+            .iter()
+            .position(|&(left, _)| left <= next)
+        {
+            let (_, right) = self.recv_param.out_of_order.remove(position);
+            next = next.max(right);
+        }
+        next
+    }
+
+    // report [start, end) received twice in the next ACK
+    pub fn report_duplicate(&mut self, start: SeqNum, end: SeqNum) {
+        if self.sack_permitted && start != end {
+            self.recv_param.duplicate = Some((start, end));
+        }
+    }
+
+    // mark retransmission queue entries covered by the peer's SACK blocks.
+    // returns the bytes newly SACKed
+    pub fn process_sack(&mut self, packet: &TCPPacket) -> usize {
+        let mut sacked = 0;
+        if !self.sack_permitted {
+            return sacked;
+        }
+        for option in packet.get_options() {
+            if let TcpOption::Sack(blocks) = option {
+                // a first block below the ACK or inside the second one is a D-SACK:
+                // the peer got that data twice, so resending it was unnecessary.
+                // it may also have been duplicated by the network, which is rare enough to ignore
+                if let Some(&(left, right)) = blocks.first() {
+                    if right <= packet.get_ack()
+                        || blocks.get(1).is_some_and(|&(outer_left, outer_right)| {
+                            outer_left <= left && right <= outer_right
+                        })
+                    {
+                        debug!(%left, %right, "D-SACK");
+                        self.spurious_retransmissions += 1;
+                        self.rack
+                            .on_dsack(self.send_param.unacked_seq, self.send_param.next);
+                        // segments are being reordered: wait for more duplicate ACKs
+                        self.dupack_threshold =
+                            cmp::min(self.dupack_threshold + 1, MAX_DUPACK_THRESHOLD);
+                    }
+                }
+                for item in self.retransmission_queue.iter_mut() {
+                    let start = item.packet.get_seq();
+                    let end = start + item.packet.segment_len();
+                    if !item.sacked
+                        && blocks
+                            .iter()
+                            .any(|&(left, right)| left <= start && end <= right)
+                    {
+                        item.sacked = true;
+                        sacked += item.packet.payload().len();
+                        self.rack.on_delivered(
+                            item.latest_transmission_time,
+                            end,
+                            item.transmission_count > 1,
+                            self.timers.now(),
+                        );
+                    }
+                }
+            }
+        }
+        sacked
+    }
+
+    // RACK tells delivered segments apart by SACK
+    pub fn rack_enabled(&self) -> bool {
+        self.sack_permitted && self.options.loss_detection == LossDetection::Rack
+    }
+
+    // the outstanding segments RACK deems lost. arms the reordering timer for those that
+    // were overtaken but may still arrive (RFC 8985 section 6.2)
+    pub fn detect_lost_segments(&mut self) -> Vec<SeqNum> {
+        let reo_wnd = self
+            .rack
+            .reo_wnd(self.rtt.srtt(), self.congestion.in_recovery());
+        let mut lost = Vec::new();
+        let mut timeout = None;
+        let now = self.timers.now();
+        for item in self.retransmission_queue.iter() {
+            let seq = item.packet.get_seq();
+            if item.sacked || seq < self.send_param.unacked_seq {
+                continue;
+            }
+            let end = seq + item.packet.segment_len();
+            match self
+                .rack
+                .time_to_loss(item.latest_transmission_time, end, reo_wnd, now)
+            {
+                Some(remaining) if remaining.is_zero() => lost.push(seq),
+                Some(remaining) => timeout = Some(cmp::max(timeout.unwrap_or_default(), remaining)),
+                None => {}
+            }
+        }
+        self.reorder_deadline = timeout.map(|timeout| self.timers.now() + timeout);
+        lost
+    }
+
+    // RCV.NXT <= seq < RCV.NXT + RCV.WND, or seq == RCV.NXT for a zero window
+    pub fn is_in_recv_window(&self, seq: SeqNum) -> bool {
+        let offset = seq - self.recv_param.next;
+        if self.recv_param.window == 0 {
+            offset == 0
+        } else {
+            offset < self.recv_param.window
+        }
+    }
+
+    // RFC 793 acceptability test: some part of the segment has to fall into the receive window
+    pub fn is_acceptable(&self, packet: &TCPPacket) -> bool {
+        let len = packet.segment_len();
+        if len == 0 {
+            return self.is_in_recv_window(packet.get_seq());
+        }
+        self.recv_param.window > 0
+            && (self.is_in_recv_window(packet.get_seq())
+                || self.is_in_recv_window(packet.get_seq() + (len - 1)))
+    }
+
+    // no data fits into a zero receive window, but the ACK of a segment at RCV.NXT still
+    // counts (RFC 793): the peer's ACKs would be lost for as long as we receive nothing
+    pub fn is_zero_window_ack(&self, packet: &TCPPacket) -> bool {
+        self.recv_param.window == 0
+            && packet.get_seq() == self.recv_param.next
+            && packet.get_flag() & (tcpflags::ACK | tcpflags::SYN) == tcpflags::ACK
+    }
+
+    // a FIN counts once everything before it has arrived. one beyond a hole is left for the
+    // retransmission that fills it, which carries the FIN again
+    pub fn is_fin_in_order(&self, packet: &TCPPacket) -> bool {
+        packet.get_flag() & tcpflags::FIN > 0
+            && packet.get_seq() + packet.payload().len() as u32 == self.recv_param.next
+    }
+
+    // an ACK that neither advances SND.UNA nor carries anything, while data is outstanding (RFC 5681)
+    pub fn is_duplicate_ack(&self, packet: &TCPPacket) -> bool {
+        packet.get_flag() & tcpflags::ACK > 0
+            && packet.get_ack() == self.send_param.unacked_seq
+            && self.send_param.unacked_seq != self.send_param.next
+            && packet.segment_len() == 0
+    }
+
+    // a window probe carries the next byte at SND.NXT. it isn't in flight: the persist timer,
+    // not the retransmission timer, sends it again until the peer has room for it
+    pub fn send_window_probe(&mut self, byte: u8) -> Result<usize> {
+        let sent_size = self.send_tcp_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[byte],
+        )?;
+        self.retransmission_queue.pop_back();
         Ok(sent_size)
     }
 
+    // an ACK beyond SND.NXT covers the byte of a window probe the peer has taken, which then
+    // counts as sent
+    pub fn take_window_probe(&mut self, packet: &TCPPacket) -> bool {
+        if self.probe_byte().is_none()
+            || packet.get_flag() & tcpflags::ACK == 0
+            || packet.get_ack() != self.send_param.next + 1
+        {
+            return false;
+        }
+        self.send_buffer.pop_front();
+        self.send_param.next += 1;
+        true
+    }
+
+    // the byte at SND.NXT
+    pub fn probe_byte(&self) -> Option<u8> {
+        self.send_buffer.front().copied()
+    }
+
+    // sent but not yet acknowledged
+    pub fn flight_size(&self) -> usize {
+        (self.send_param.next - self.send_param.unacked_seq) as usize
+    }
+
+    // data still in the network: the flight size less what the peer has SACKed
+    pub fn pipe(&self) -> usize {
+        let sacked: usize = self
+            .retransmission_queue
+            .iter()
+            .filter(|item| item.sacked && item.packet.get_seq() >= self.send_param.unacked_seq)
+            .map(|item| item.packet.payload().len())
+            .sum();
+        self.flight_size().saturating_sub(sacked)
+    }
+
+    // bytes that can be sent right now, bounded by the peer window, the congestion window
+    // and the send buffer, all of which the data in flight counts against.
+    // SND.WND itself only changes with the windows the peer advertises
+    pub fn sendable_size(&self) -> usize {
+        let in_flight = self.flight_size();
+        cmp::min(
+            (self.send_param.window as usize).saturating_sub(in_flight),
+            cmp::min(
+                self.congestion.cwnd().saturating_sub(in_flight),
+                self.options.send_buffer_size.saturating_sub(in_flight),
+            ),
+        )
+    }
+
+    // FIN has already been sent
+    pub fn is_write_shutdown(&self) -> bool {
+        matches!(
+            self.status,
+            TcpStatus::FinWait1
+                | TcpStatus::FinWait2
+                | TcpStatus::Closing
+                | TcpStatus::LastAck
+                | TcpStatus::TimeWait
+        )
+    }
+
+    // recorded in the connection's span as well
+    pub fn set_status(&mut self, status: TcpStatus) {
+        debug!(from = %self.status, to = %status, "state changed");
+        self.span.record("state", display(&status));
+        self.status = status;
+    }
+
     pub fn get_sock_id(&self) -> SockID {
         SockID(
             self.local_addr,
@@ -0,0 +1,186 @@
+use crate::pcap::Capture;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_TTL: u8 = 64;
+
+/// per-socket tunable, set with TCP::set_option and read back with TCP::get_option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketOption {
+    // send segments as soon as possible instead of coalescing small writes
+    NoDelay(bool),
+    // hold partial segments until uncorked or a full segment has gathered, to send a
+    // message assembled from several writes in as few segments as possible
+    Cork(bool),
+    // idle time before keepalive probes start, None disables them
+    KeepAlive(Option<Duration>),
+    Ttl(u8),
+    // fixes the receive buffer, which is autotuned until set; enlarging it advertises
+    // the new window right away
+    RecvBufferSize(usize),
+    // upper bound of bytes written but not yet acknowledged
+    SendBufferSize(usize),
+    // how long close() waits for the FIN handshake before resetting the connection.
+    // Some(Duration::ZERO) makes close() send RST right away.
+    Linger(Option<Duration>),
+    // takes effect immediately, the new algorithm starts from the initial window
+    CongestionControl(CongestionAlgorithm),
+    // abort the connection once sent data stays unacknowledged this long (RFC 5482).
+    // offered to the peer with the UTO option on SYN
+    UserTimeout(Option<Duration>),
+    // how lost segments are told from reordered ones, from the next ACK on
+    LossDetection(LossDetection),
+    // on a listener: answer MP_CAPABLE SYNs and take MP_JOIN subflows into the connections
+    // they name (RFC 8684). experimental, see stream::MultipathListener
+    Multipath(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CongestionAlgorithm {
+    Reno,
+    Cubic,
+    // paced by the estimated bottleneck bandwidth instead of backing off on loss
+    Bbr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LossDetection {
+    // fast retransmit once enough duplicate ACKs arrived (RFC 5681)
+    DuplicateAcks,
+    // RACK (RFC 8985): lost once segments sent sufficiently later were delivered.
+    // needs SACK, duplicate ACKs are counted without it
+    Rack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketOptionName {
+    NoDelay,
+    Cork,
+    KeepAlive,
+    Ttl,
+    RecvBufferSize,
+    SendBufferSize,
+    Linger,
+    CongestionControl,
+    UserTimeout,
+    LossDetection,
+    Multipath,
+}
+
+impl SocketOption {
+    pub fn name(&self) -> SocketOptionName {
+        match self {
+            SocketOption::NoDelay(_) => SocketOptionName::NoDelay,
+            SocketOption::Cork(_) => SocketOptionName::Cork,
+            SocketOption::KeepAlive(_) => SocketOptionName::KeepAlive,
+            SocketOption::Ttl(_) => SocketOptionName::Ttl,
+            SocketOption::RecvBufferSize(_) => SocketOptionName::RecvBufferSize,
+            SocketOption::SendBufferSize(_) => SocketOptionName::SendBufferSize,
+            SocketOption::Linger(_) => SocketOptionName::Linger,
+            SocketOption::CongestionControl(_) => SocketOptionName::CongestionControl,
+            SocketOption::UserTimeout(_) => SocketOptionName::UserTimeout,
+            SocketOption::LossDetection(_) => SocketOptionName::LossDetection,
+            SocketOption::Multipath(_) => SocketOptionName::Multipath,
+        }
+    }
+}
+
+/// a master key tuple (MKT) of TCP-AO (RFC 5925), shared with `peer`.
+/// an unspecified address as the peer makes it apply to any peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AoKey {
+    pub peer: IpAddr,
+    pub send_id: u8, // KeyID of the segments we sign with it
+    pub recv_id: u8, // KeyID of the segments the peer signs with it
+    pub algorithm: AoAlgorithm,
+    pub key: Vec<u8>,
+    // whether options other than TCP-AO itself are covered by the MAC
+    pub include_options: bool,
+}
+
+impl AoKey {
+    pub(crate) fn matches(&self, peer: Ipv4Addr) -> bool {
+        self.peer.is_unspecified() || self.peer == IpAddr::V4(peer)
+    }
+
+    // both could be used for the same connection
+    pub(crate) fn overlaps(&self, other: &AoKey) -> bool {
+        self.peer.is_unspecified() || other.peer.is_unspecified() || self.peer == other.peer
+    }
+}
+
+/// MAC and key derivation algorithm of an MKT (RFC 5926)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AoAlgorithm {
+    // HMAC-SHA-1-96, keys derived with KDF_HMAC_SHA1
+    HmacSha1,
+    // AES-128-CMAC-96, keys derived with KDF_AES_128_CMAC
+    AesCmac,
+}
+
+/// buffer sizes a socket is created with, the defaults where None.
+/// a receive buffer given here is not autotuned, and sizes the window scale of the SYN
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferSizes {
+    pub recv: Option<usize>,
+    pub send: Option<usize>,
+}
+
+/// how a listener takes its address. without `reuse_addr` any socket on the port conflicts,
+/// with it only another listener does, like SO_REUSEADDR on Linux: a server restarting over
+/// connections in TIME_WAIT can listen again right away.
+///
+/// listeners that all set `reuse_port` may share the same address, like SO_REUSEPORT: each
+/// connection goes to one of them by a hash of its addresses and ports, so that every thread
+/// of a server can accept from its own listener
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BindOptions {
+    pub reuse_addr: bool,
+    pub reuse_port: bool,
+}
+
+/// network interface a socket is bound to, with its IPv4 addresses as of the binding.
+/// the raw receiver doesn't tell which interface a packet came in on, so a segment counts
+/// as arriving on the device when it is addressed to one of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    pub addrs: Vec<Ipv4Addr>,
+}
+
+/// current option values of a socket; the receive buffer size is the length of recv_buffer
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub cork: bool,
+    pub keepalive: Option<Duration>,
+    pub ttl: u8,
+    pub send_buffer_size: usize,
+    pub linger: Option<Duration>,
+    pub congestion_control: CongestionAlgorithm,
+    pub user_timeout: Option<Duration>,
+    pub loss_detection: LossDetection,
+    pub multipath: bool,
+    pub device: Option<Device>,
+    pub capture: Option<Arc<Capture>>,
+}
+
+impl SocketOptions {
+    pub fn new(send_buffer_size: usize) -> Self {
+        Self {
+            nodelay: false,
+            cork: false,
+            keepalive: None,
+            ttl: DEFAULT_TTL,
+            send_buffer_size,
+            linger: None,
+            congestion_control: CongestionAlgorithm::Reno,
+            user_timeout: None,
+            loss_detection: LossDetection::DuplicateAcks,
+            multipath: false,
+            device: None,
+            capture: None,
+        }
+    }
+}
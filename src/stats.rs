@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// counts of segments and events, of the whole stack or of one socket, like the Tcp
+/// section of /proc/net/snmp. see TCP::stats and TCP::socket_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub segments_in: u64,
+    pub segments_out: u64, // retransmissions included
+    pub retransmissions: u64,
+    pub checksum_errors: u64,
+    pub resets_in: u64,
+    pub resets_out: u64,
+    // segments to one of our ports that no connection or listener takes, answered by RST.
+    // only ever counted stack-wide
+    pub no_socket: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    SegmentsIn,
+    SegmentsOut,
+    Retransmissions,
+    ChecksumErrors,
+    ResetsIn,
+    ResetsOut,
+    NoSocket,
+}
+
+// Stats that any thread bumps without holding a lock
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    segments_in: AtomicU64,
+    segments_out: AtomicU64,
+    retransmissions: AtomicU64,
+    checksum_errors: AtomicU64,
+    resets_in: AtomicU64,
+    resets_out: AtomicU64,
+    no_socket: AtomicU64,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bump(&self, counter: Counter) {
+        let count = match counter {
+            Counter::SegmentsIn => &self.segments_in,
+            Counter::SegmentsOut => &self.segments_out,
+            Counter::Retransmissions => &self.retransmissions,
+            Counter::ChecksumErrors => &self.checksum_errors,
+            Counter::ResetsIn => &self.resets_in,
+            Counter::ResetsOut => &self.resets_out,
+            Counter::NoSocket => &self.no_socket,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // each count read on its own, so they may be a segment apart from one another
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            segments_in: self.segments_in.load(Ordering::Relaxed),
+            segments_out: self.segments_out.load(Ordering::Relaxed),
+            retransmissions: self.retransmissions.load(Ordering::Relaxed),
+            checksum_errors: self.checksum_errors.load(Ordering::Relaxed),
+            resets_in: self.resets_in.load(Ordering::Relaxed),
+            resets_out: self.resets_out.load(Ordering::Relaxed),
+            no_socket: self.no_socket.load(Ordering::Relaxed),
+        }
+    }
+}
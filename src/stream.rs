@@ -0,0 +1,386 @@
+use crate::socket::{SockID, TcpInfo};
+use crate::sockopt::{BindOptions, BufferSizes, SocketOption, SocketOptionName};
+use crate::tcp::{DEFAULT_BACKLOG, TCP};
+use anyhow::Result;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// listening socket bound to local_addr, closed on drop
+pub struct TcpListener {
+    pub(crate) tcp: Arc<TCP>,
+    pub(crate) sock_id: SockID,
+}
+
+impl TcpListener {
+    pub fn bind(tcp: &Arc<TCP>, local_addr: SocketAddr) -> Result<Self> {
+        Self::bind_with(tcp, local_addr, DEFAULT_BACKLOG, BufferSizes::default())
+    }
+
+    pub fn bind_with(
+        tcp: &Arc<TCP>,
+        local_addr: SocketAddr,
+        backlog: usize,
+        buffers: BufferSizes,
+    ) -> Result<Self> {
+        Self::bind_with_options(tcp, local_addr, backlog, buffers, BindOptions::default())
+    }
+
+    pub fn bind_with_options(
+        tcp: &Arc<TCP>,
+        local_addr: SocketAddr,
+        backlog: usize,
+        buffers: BufferSizes,
+        options: BindOptions,
+    ) -> Result<Self> {
+        let sock_id = tcp.listen_with_options(local_addr, backlog, buffers, options)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn accept(&self) -> Result<TcpStream> {
+        let sock_id = self.tcp.accept(self.sock_id)?;
+        Ok(TcpStream {
+            tcp: self.tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<TcpStream> {
+        let sock_id = self.tcp.accept_timeout(self.sock_id, timeout)?;
+        Ok(TcpStream {
+            tcp: self.tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn try_accept(&self) -> Result<Option<TcpStream>> {
+        Ok(self.tcp.try_accept(self.sock_id)?.map(|sock_id| TcpStream {
+            tcp: self.tcp.clone(),
+            sock_id,
+        }))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.tcp.set_nonblocking(self.sock_id, nonblocking)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.tcp.local_addr(self.sock_id)
+    }
+
+    pub fn bind_device(&self, ifname: Option<&str>) -> Result<()> {
+        self.tcp.bind_device(self.sock_id, ifname)
+    }
+
+    pub fn set_option(&self, option: SocketOption) -> Result<()> {
+        self.tcp.set_option(self.sock_id, option)
+    }
+
+    pub fn get_option(&self, name: SocketOptionName) -> Result<SocketOption> {
+        self.tcp.get_option(self.sock_id, name)
+    }
+
+    // handle to register with poll::Poller
+    pub fn sock_id(&self) -> SockID {
+        self.sock_id
+    }
+}
+
+impl Drop for TcpListener {
+    // like std's, a failed close can't be returned from drop, only logged
+    fn drop(&mut self) {
+        if let Err(error) = self.tcp.close(self.sock_id) {
+            warn!(%error, "failed to close listener");
+        }
+    }
+}
+
+/// connected socket, closed on drop. unbuffered, like std's: wrap it in a BufReader for
+/// read_line and lines
+pub struct TcpStream {
+    pub(crate) tcp: Arc<TCP>,
+    pub(crate) sock_id: SockID,
+}
+
+impl TcpStream {
+    pub fn connect<A: ToSocketAddrs>(tcp: &Arc<TCP>, addr: A) -> Result<Self> {
+        Self::connect_with(tcp, addr, BufferSizes::default())
+    }
+
+    pub fn connect_with<A: ToSocketAddrs>(
+        tcp: &Arc<TCP>,
+        addr: A,
+        buffers: BufferSizes,
+    ) -> Result<Self> {
+        let sock_id = tcp.connect_with(addr, buffers)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    // connect from `local_addr`, see TCP::connect_from
+    pub fn connect_from(tcp: &Arc<TCP>, local_addr: SocketAddr, addr: SocketAddr) -> Result<Self> {
+        let sock_id = tcp.connect_from(local_addr, addr)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    // connect out of the network interface `ifname`, see TCP::connect_via
+    pub fn connect_via(tcp: &Arc<TCP>, ifname: &str, addr: SocketAddr) -> Result<Self> {
+        let sock_id = tcp.connect_via(ifname, addr)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn connect_timeout(tcp: &Arc<TCP>, addr: SocketAddr, timeout: Duration) -> Result<Self> {
+        let sock_id = tcp.connect_timeout(addr, timeout)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            sock_id,
+        })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.tcp.set_nonblocking(self.sock_id, nonblocking)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.tcp.local_addr(self.sock_id)
+    }
+
+    pub fn bind_device(&self, ifname: Option<&str>) -> Result<()> {
+        self.tcp.bind_device(self.sock_id, ifname)
+    }
+
+    pub fn set_option(&self, option: SocketOption) -> Result<()> {
+        self.tcp.set_option(self.sock_id, option)
+    }
+
+    pub fn get_option(&self, name: SocketOptionName) -> Result<SocketOption> {
+        self.tcp.get_option(self.sock_id, name)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.tcp.peer_addr(self.sock_id)
+    }
+
+    pub fn send_oob(&self, buffer: &[u8]) -> Result<usize> {
+        self.tcp.send_oob(self.sock_id, buffer)
+    }
+
+    pub fn recv_oob(&self) -> Result<u8> {
+        self.tcp.recv_oob(self.sock_id)
+    }
+
+    pub fn at_urgent_mark(&self) -> Result<bool> {
+        self.tcp.at_urgent_mark(self.sock_id)
+    }
+
+    // true disables Nagle's algorithm
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        self.set_option(SocketOption::NoDelay(nodelay))
+    }
+
+    pub fn nodelay(&self) -> Result<bool> {
+        match self.get_option(SocketOptionName::NoDelay)? {
+            SocketOption::NoDelay(nodelay) => Ok(nodelay),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "not a nodelay option").into()),
+        }
+    }
+
+    // batch the following writes into full segments until uncork
+    pub fn cork(&self) -> Result<()> {
+        self.set_option(SocketOption::Cork(true))
+    }
+
+    // send what the cork has gathered
+    pub fn uncork(&self) -> Result<()> {
+        self.set_option(SocketOption::Cork(false))
+    }
+
+    pub fn socket_info(&self) -> Result<TcpInfo> {
+        self.tcp.socket_info(self.sock_id)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.tcp.shutdown(self.sock_id, how)
+    }
+
+    // TCP-AO key change: the peer is asked to sign with the MKT of `recv_id`
+    pub fn set_ao_rnext(&self, recv_id: u8) -> Result<()> {
+        self.tcp.set_ao_rnext(self.sock_id, recv_id)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.tcp.set_read_timeout(self.sock_id, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.tcp.set_write_timeout(self.sock_id, timeout)
+    }
+
+    // handle to register with poll::Poller
+    pub fn sock_id(&self) -> SockID {
+        self.sock_id
+    }
+}
+
+// reads return 0 once the peer has closed the connection
+impl Read for &TcpStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.tcp.recv(self.sock_id, buffer).map_err(into_io_error)
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.tcp.send(self.sock_id, buffer).map_err(into_io_error)
+    }
+
+    // sends data held back by Nagle's algorithm or cork
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush(self.sock_id).map_err(into_io_error)
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buffer)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        (&*self).write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Drop for TcpStream {
+    // like std's, a failed close can't be returned from drop, only logged
+    fn drop(&mut self) {
+        if let Err(error) = self.tcp.close(self.sock_id) {
+            warn!(%error, "failed to close stream");
+        }
+    }
+}
+
+/// listening socket that accepts Multipath TCP connections (RFC 8684), closed on drop.
+/// experimental: no DSS checksums, DATA_FIN or reinjection of data lost with a subflow
+pub struct MultipathListener {
+    listener: TcpListener,
+}
+
+impl MultipathListener {
+    pub fn bind(tcp: &Arc<TCP>, local_addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(tcp, local_addr)?;
+        listener.set_option(SocketOption::Multipath(true))?;
+        Ok(Self { listener })
+    }
+
+    // a peer without MPTCP support gets a connection of a single subflow
+    pub fn accept(&self) -> Result<MultipathStream> {
+        let token = self.listener.tcp.mptcp_accept(self.listener.sock_id)?;
+        Ok(MultipathStream {
+            tcp: self.listener.tcp.clone(),
+            token,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Multipath TCP connection spread over subflows, closed on drop
+pub struct MultipathStream {
+    tcp: Arc<TCP>,
+    token: u32,
+}
+
+impl MultipathStream {
+    // the first subflow goes from the first of `local_addrs`, one more joins from each of
+    // the others
+    pub fn connect(tcp: &Arc<TCP>, local_addrs: &[IpAddr], addr: SocketAddr) -> Result<Self> {
+        let token = tcp.mptcp_connect(local_addrs, addr)?;
+        Ok(Self {
+            tcp: tcp.clone(),
+            token,
+        })
+    }
+
+    // the subflows still open, the first one first
+    pub fn subflows(&self) -> Result<Vec<SockID>> {
+        self.tcp.mptcp_subflows(self.token)
+    }
+}
+
+// reads return 0 once the peer has closed every subflow
+impl Read for &MultipathStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.tcp
+            .mptcp_recv(self.token, buffer)
+            .map_err(into_io_error)
+    }
+}
+
+impl Write for &MultipathStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.tcp
+            .mptcp_send(self.token, buffer)
+            .map_err(into_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for sock_id in self.subflows().map_err(into_io_error)? {
+            self.tcp.flush(sock_id).map_err(into_io_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for MultipathStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buffer)
+    }
+}
+
+impl Write for MultipathStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        (&*self).write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Drop for MultipathStream {
+    // like std's, a failed close can't be returned from drop, only logged
+    fn drop(&mut self) {
+        if let Err(error) = self.tcp.mptcp_close(self.token) {
+            warn!(%error, "failed to close multipath stream");
+        }
+    }
+}
+
+// keep io::Error raised inside the stack as is so that callers can match on its kind
+pub(crate) fn into_io_error(error: anyhow::Error) -> io::Error {
+    match error.downcast::<io::Error>() {
+        Ok(error) => error,
+        Err(error) => io::Error::other(error),
+    }
+}
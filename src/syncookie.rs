@@ -0,0 +1,140 @@
+use crate::seq::SeqNum;
+use crate::socket::SockID;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the counter in the top bits of a cookie advances this often
+const COUNTER_PERIOD_SECS: u64 = 64;
+const COUNTER_BITS: u32 = 6;
+// a cookie older than this many counter periods is refused
+const MAX_COOKIE_AGE: u32 = 2;
+const MSS_BITS: u32 = 2;
+const HASH_BITS: u32 = 32 - COUNTER_BITS - MSS_BITS;
+// the MSS a cookie can carry, as Linux's IPv4 table
+const MSS_TABLE: [u16; 1 << MSS_BITS] = [536, 1300, 1440, 1460];
+
+/// stateless SYN cookies: once a listener's SYN queue is full, a SYN is answered with a
+/// SYN-ACK whose ISN encodes what the connection needs, and no socket is kept for it. the
+/// ACK returning the cookie proves the peer got it, and the connection is built from it then.
+///
+/// layout of the ISN: 6 bits of a counter that advances every 64 seconds, 2 bits indexing
+/// the MSS, and 24 bits of a keyed hash of the rest, the connection's addresses and ports
+/// and the peer's ISN. window scaling, SACK and timestamps don't fit, so the connection
+/// goes without them.
+#[derive(Debug)]
+pub struct SynCookies {
+    key: RandomState,
+}
+
+impl SynCookies {
+    pub fn new() -> Self {
+        Self {
+            key: RandomState::new(),
+        }
+    }
+
+    // the ISN of a SYN-ACK to the SYN with `peer_isn` on `sock_id`, for segments of up to `mss`
+    pub fn generate(&self, sock_id: SockID, peer_isn: SeqNum, mss: u16) -> SeqNum {
+        let counter = counter_now();
+        let mss_index = MSS_TABLE
+            .iter()
+            .rposition(|&entry| entry <= mss)
+            .unwrap_or(0) as u32;
+        SeqNum(
+            counter << (MSS_BITS + HASH_BITS)
+                | mss_index << HASH_BITS
+                | self.hash(sock_id, peer_isn, counter, mss_index),
+        )
+    }
+
+    // the MSS encoded in `cookie` if it is one of ours, for this connection and recent enough
+    pub fn validate(&self, sock_id: SockID, peer_isn: SeqNum, cookie: SeqNum) -> Option<u16> {
+        let counter = cookie.0 >> (MSS_BITS + HASH_BITS);
+        let mss_index = (cookie.0 >> HASH_BITS) & ((1 << MSS_BITS) - 1);
+        let age = counter_now().wrapping_sub(counter) & ((1 << COUNTER_BITS) - 1);
+        if age > MAX_COOKIE_AGE
+            || cookie.0 & ((1 << HASH_BITS) - 1) != self.hash(sock_id, peer_isn, counter, mss_index)
+        {
+            return None;
+        }
+        Some(MSS_TABLE[mss_index as usize])
+    }
+
+    fn hash(&self, sock_id: SockID, peer_isn: SeqNum, counter: u32, mss_index: u32) -> u32 {
+        let hash = self.key.hash_one((sock_id, peer_isn.0, counter, mss_index));
+        hash as u32 & ((1 << HASH_BITS) - 1)
+    }
+}
+
+fn counter_now() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / COUNTER_PERIOD_SECS) as u32 & ((1 << COUNTER_BITS) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const SOCK_ID: SockID = SockID(
+        Ipv4Addr::new(192, 168, 0, 1),
+        Ipv4Addr::new(192, 0, 2, 1),
+        8080,
+        40000,
+    );
+    const PEER_ISN: SeqNum = SeqNum(0x1234_5678);
+
+    // a cookie as `generate` makes it, from `age` counter periods ago
+    fn cookie_of_age(cookies: &SynCookies, age: u32, mss_index: u32) -> SeqNum {
+        let counter = counter_now().wrapping_sub(age) & ((1 << COUNTER_BITS) - 1);
+        SeqNum(
+            counter << (MSS_BITS + HASH_BITS)
+                | mss_index << HASH_BITS
+                | cookies.hash(SOCK_ID, PEER_ISN, counter, mss_index),
+        )
+    }
+
+    #[test]
+    fn cookie_round_trips_with_its_mss() {
+        let cookies = SynCookies::new();
+        for (mss, encoded) in [
+            (1460, 1460),
+            (1500, 1460),
+            (1440, 1440),
+            (1400, 1300),
+            (536, 536),
+            // below the table: the smallest
+            (100, 536),
+        ] {
+            let cookie = cookies.generate(SOCK_ID, PEER_ISN, mss);
+            let mss_index = (cookie.0 >> HASH_BITS) & ((1 << MSS_BITS) - 1);
+            assert_eq!(MSS_TABLE[mss_index as usize], encoded);
+            assert_eq!(cookies.validate(SOCK_ID, PEER_ISN, cookie), Some(encoded));
+        }
+    }
+
+    #[test]
+    fn cookie_of_another_connection_is_refused() {
+        let cookies = SynCookies::new();
+        let cookie = cookies.generate(SOCK_ID, PEER_ISN, 1460);
+        let other_port = SockID(SOCK_ID.0, SOCK_ID.1, SOCK_ID.2, SOCK_ID.3 + 1);
+        assert_eq!(cookies.validate(other_port, PEER_ISN, cookie), None);
+        assert_eq!(cookies.validate(SOCK_ID, PEER_ISN + 1, cookie), None);
+        // nor is one of another listener's key
+        assert_eq!(SynCookies::new().validate(SOCK_ID, PEER_ISN, cookie), None);
+    }
+
+    #[test]
+    fn old_cookie_is_refused() {
+        let cookies = SynCookies::new();
+        // a period may end while the test runs, making the cookie older by one
+        let recent = cookie_of_age(&cookies, MAX_COOKIE_AGE - 1, 3);
+        assert_eq!(cookies.validate(SOCK_ID, PEER_ISN, recent), Some(1460));
+        let old = cookie_of_age(&cookies, MAX_COOKIE_AGE + 1, 3);
+        assert_eq!(cookies.validate(SOCK_ID, PEER_ISN, old), None);
+    }
+}
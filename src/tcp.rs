@@ -1,30 +1,68 @@
-use crate::packet::TCPPacket;
-use crate::socket::{SockID, Socket, TcpStatus};
+use crate::ao::Authentication;
+use crate::clock::{Clock, SystemClock};
+use crate::congestion::Congestion;
+#[cfg(feature = "io_uring")]
+use crate::device::UringSocket;
+use crate::device::{EthernetDevice, MemoryDevice, NetworkDevice};
+#[cfg(target_os = "linux")]
+use crate::device::{RawSocket, TunDevice};
+use crate::icmp::{self, IcmpError};
+use crate::ip;
+use crate::mptcp::{self, Join, Mapping, MptcpConnection, MptcpOption, Subflow};
+use crate::packet::{trace_segment, TCPPacket};
+use crate::pcap::{self, Capture, Direction};
+use crate::poll;
+use crate::route::{Route, RoutingTable};
+use crate::seq::SeqNum;
+use crate::socket::{FrtoState, SockID, Socket, TcpInfo, TcpStatus, DEFAULT_MSS};
+use crate::sockopt::{AoKey, BindOptions, BufferSizes, Device, SocketOption, SocketOptionName};
+use crate::stats::{Counter, Counters, Stats};
+use crate::syncookie::SynCookies;
 use crate::tcpflags;
+use crate::tcpoption::TcpOption;
+use crate::timer::{TimerKind, TimerQueue};
 use anyhow::{Context, Result};
-use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
-use pnet::transport::{self, TransportChannelType};
+use pnet::datalink;
+use pnet::packet::{tcp::TcpPacket, Packet};
 use rand::{rngs::ThreadRng, Rng};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
+use std::collections::{hash_map::RandomState, HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
-use std::time::{Duration, SystemTime};
-use std::{cmp, ops::Range, str, thread};
+#[cfg(feature = "tokio")]
+use std::task::Waker;
+use std::time::{Duration, Instant};
+use std::{cmp, hash::BuildHasher, ops::Range};
+use tracing::{debug, trace, warn, Span};
 
 const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const UNDETERMINED_PORT: u16 = 0;
 const MAX_TRANSMITTION: u8 = 5;
-const RETRANSMITTION_TIMEOUT: u64 = 3;
-const MSS: usize = 1460;
+// resends of the SYN before connect gives up, about two minutes with the RTO doubling each time
+const SYN_RETRIES: u8 = 6;
+// resends of the SYN-ACK before an embryonic connection is reaped
+const SYNACK_RETRIES: u8 = 5;
+pub(crate) const KEEPALIVE_INTERVAL: u64 = 75;
+const KEEPALIVE_PROBES: u8 = 9;
+const MSL: u64 = 30;
+const MAX_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+const DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(40);
+const CHALLENGE_ACK_LIMIT: u32 = 1000;
+// packets the receive thread takes from the device at once
+const RECV_BATCH: usize = 32;
+// for 1500-byte Ethernet, used when the route to a peer can't be looked up
+pub(crate) const MSS: usize = 1460;
+// IPv4 and TCP headers without options
+const HEADERS_SIZE: usize = 40;
+pub(crate) const DUPACK_THRESHOLD: u8 = 3;
+// listen backlog of TcpListener, as SOMAXCONN
+pub const DEFAULT_BACKLOG: usize = 128;
+// duplicate ACKs that each let a new segment out beyond cwnd (RFC 3042)
+const LIMITED_TRANSMIT_ACKS: u8 = 2;
 const PORT_RANGE: Range<u16> = 40000..60000;
 
-#[derive(Debug, Clone, PartialEq)]
-struct TCPEvent {
-    sock_id: SockID, // socket that triggered event
-    kind: TCPEventKind,
-}
-
 #[derive(Debug, Clone, PartialEq)]
 pub enum TCPEventKind {
     ConnectionCompleted,
@@ -33,580 +71,3421 @@ pub enum TCPEventKind {
     ConnectionClosed,
 }
 
-impl TCPEvent {
-    fn new(sock_id: SockID, kind: TCPEventKind) -> Self {
-        Self { sock_id, kind }
+#[derive(Debug, Default)]
+struct EventQueue {
+    pending: VecDeque<TCPEventKind>, // each kind queued at most once
+    waiters: usize,
+    removed: Option<io::ErrorKind>, // set when the socket goes away while someone waits
+}
+
+// RFC 5961 challenge ACKs sent in the current one-second window, shared by all sockets
+#[derive(Debug)]
+struct ChallengeAckLimit {
+    per_second: u32,
+    window_start: Instant,
+    sent: u32,
+}
+
+impl ChallengeAckLimit {
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.sent = 0;
+        }
+        if self.sent >= self.per_second {
+            return false;
+        }
+        self.sent += 1;
+        true
     }
 }
 
 pub struct TCP {
     sockets: RwLock<HashMap<SockID, Socket>>,
-    event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
+    event_condvar: (Mutex<HashMap<SockID, EventQueue>>, Condvar),
+    // bumped on every published event so that pollers can rescan readiness
+    readiness_condvar: (Mutex<u64>, Condvar),
+    challenge_acks: Mutex<ChallengeAckLimit>,
+    // MSS derived from the MTU of the interface toward each destination, looked up once
+    route_mss: Mutex<HashMap<Ipv4Addr, usize>>,
+    // where new connections take their source address from
+    routes: RwLock<RoutingTable>,
+    // request ECN on connect and agree to it on accept
+    ecn: AtomicBool,
+    syn_cookies: SynCookies,
+    // spreads connections over a group of listeners sharing an address
+    reuse_port_key: RandomState,
+    // embryonic connections of a listener beyond which SYNs are answered with cookies,
+    // if fewer than its backlog
+    syn_cookie_threshold: AtomicUsize,
+    timers: Arc<TimerQueue>,
+    // stack-wide, bumped by the sockets along with their own
+    counters: Arc<Counters>,
+    // Multipath TCP connections by our token
+    mptcp: Mutex<HashMap<u32, MptcpConnection>>,
+    // MKTs of TCP-AO, taken by connections with their peers when they open
+    ao_keys: Mutex<Vec<AoKey>>,
+    // where packets go out and come in, for sockets not bound to another interface
+    device: Arc<dyn NetworkDevice>,
+    // connections between two of our own sockets, whose segments never leave the stack
+    loopback: Arc<dyn NetworkDevice>,
+    // sockets on each local port, whose segments the device lets in
+    ports: Mutex<HashMap<u16, usize>>,
+    // tasks waiting on a socket, woken on every event published for it
+    #[cfg(feature = "tokio")]
+    wakers: Mutex<HashMap<SockID, Vec<Waker>>>,
 }
 
 impl TCP {
+    // on raw sockets of the kernel's IP layer
+    #[cfg(target_os = "linux")]
+    pub fn new() -> Arc<Self> {
+        let device = RawSocket::open().expect("failed to open raw sockets");
+        Self::with_device(device, RoutingTable::discover())
+    }
+
+    // on a BPF device on the interface of the default route
+    #[cfg(target_os = "macos")]
+    pub fn new() -> Arc<Self> {
+        let routes = RoutingTable::discover();
+        let ifname = routes
+            .lookup(Ipv4Addr::UNSPECIFIED)
+            .map(|route| route.device.clone())
+            .expect("no default route");
+        let device = EthernetDevice::bpf(&ifname).expect("failed to open BPF device");
+        Self::with_device(device, routes)
+    }
+
+    // on a BPF device on the interface `ifname`
+    #[cfg(target_os = "macos")]
+    pub fn with_bpf(ifname: &str) -> Result<Arc<Self>> {
+        let device = EthernetDevice::bpf(ifname)?;
+        Ok(Self::with_device(device, RoutingTable::discover()))
+    }
+
+    // on npcap on the adapter of the default route
+    #[cfg(windows)]
     pub fn new() -> Arc<Self> {
+        let routes = RoutingTable::discover();
+        let ifname = routes
+            .lookup(Ipv4Addr::UNSPECIFIED)
+            .map(|route| route.device.clone())
+            .expect("no default route");
+        let device = EthernetDevice::npcap(&ifname).expect("failed to open npcap adapter");
+        Self::with_device(device, routes)
+    }
+
+    // on npcap on the adapter `ifname`, named as pnet lists it
+    #[cfg(windows)]
+    pub fn with_npcap(ifname: &str) -> Result<Arc<Self>> {
+        let device = EthernetDevice::npcap(ifname)?;
+        Ok(Self::with_device(device, RoutingTable::discover()))
+    }
+
+    // on an AF_PACKET socket of the interface `ifname`, for hosts where raw IP sockets are
+    // restricted. sockets can't be bound to other interfaces
+    #[cfg(target_os = "linux")]
+    pub fn with_packet_socket(ifname: &str) -> Result<Arc<Self>> {
+        let device = EthernetDevice::packet_socket(ifname)?;
+        Ok(Self::with_device(device, RoutingTable::discover()))
+    }
+
+    // on the TUN device `name`, as a host of its own at `addr` behind it, apart from the
+    // kernel's stack. the kernel's address on the device is the way to the rest of the network
+    #[cfg(target_os = "linux")]
+    pub fn with_tun(name: &str, addr: Ipv4Addr, prefix_len: u8) -> Result<Arc<Self>> {
+        if prefix_len > 32 {
+            return Err(io_error(io::ErrorKind::InvalidInput, "prefix too long"));
+        }
+        let device = TunDevice::open(name)?;
+        Ok(Self::with_device(
+            device,
+            RoutingTable::on_link(name, addr, prefix_len, None),
+        ))
+    }
+
+    // on the TAP device `name`, as a host of its own at `addr` on its link, reaching beyond
+    // the prefix via `gateway`
+    #[cfg(target_os = "linux")]
+    pub fn with_tap(
+        name: &str,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+    ) -> Result<Arc<Self>> {
+        if prefix_len > 32 {
+            return Err(io_error(io::ErrorKind::InvalidInput, "prefix too long"));
+        }
+        let device = EthernetDevice::tap(name, addr, prefix_len, gateway)?;
+        Ok(Self::with_device(
+            device,
+            RoutingTable::on_link(name, addr, prefix_len, gateway),
+        ))
+    }
+
+    // on raw sockets too, but through an io_uring, which takes no system call per packet
+    // while busy
+    #[cfg(feature = "io_uring")]
+    pub fn with_io_uring() -> Result<Arc<Self>> {
+        let device = UringSocket::open()?;
+        Ok(Self::with_device(device, RoutingTable::discover()))
+    }
+
+    // on an AF_XDP socket on queue `queue_id` of the interface `ifname`, for benchmarks at
+    // high packet rates. the interface is the stack's alone
+    #[cfg(feature = "xdp")]
+    pub fn with_xdp(ifname: &str, queue_id: u32) -> Result<Arc<Self>> {
+        let device = EthernetDevice::xdp(ifname, queue_id)?;
+        Ok(Self::with_device(device, RoutingTable::discover()))
+    }
+
+    // on any device, with `routes` for the addresses it reaches, e.g. on one end of a
+    // MemoryDevice with a table `on_link` for tests
+    pub fn with_device(device: impl NetworkDevice + 'static, routes: RoutingTable) -> Arc<Self> {
+        Self::with_clock(device, routes, Arc::new(SystemClock))
+    }
+
+    // with the timers on `clock`. on a VirtualClock there is no timer thread: the timers
+    // run as the clock is advanced, on the thread that advances it
+    pub fn with_clock(
+        device: impl NetworkDevice + 'static,
+        routes: RoutingTable,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<Self> {
         let sockets = RwLock::new(HashMap::new());
         let tcp = Arc::new(Self {
             sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
+            event_condvar: (Mutex::new(HashMap::new()), Condvar::new()),
+            readiness_condvar: (Mutex::new(0), Condvar::new()),
+            challenge_acks: Mutex::new(ChallengeAckLimit {
+                per_second: CHALLENGE_ACK_LIMIT,
+                window_start: Instant::now(),
+                sent: 0,
+            }),
+            route_mss: Mutex::new(HashMap::new()),
+            routes: RwLock::new(routes),
+            ecn: AtomicBool::new(false),
+            syn_cookies: SynCookies::new(),
+            reuse_port_key: RandomState::new(),
+            syn_cookie_threshold: AtomicUsize::new(usize::MAX),
+            timers: Arc::new(TimerQueue::new(clock.clone())),
+            counters: Arc::new(Counters::new()),
+            mptcp: Mutex::new(HashMap::new()),
+            ao_keys: Mutex::new(Vec::new()),
+            device: Arc::new(device),
+            loopback: Arc::new(MemoryDevice::looped()),
+            ports: Mutex::new(HashMap::new()),
+            #[cfg(feature = "tokio")]
+            wakers: Mutex::new(HashMap::new()),
         });
+        // nothing is ours until a socket binds a port
+        tcp.filter_ports(&HashMap::new());
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
             // receiving thread
-            cloned_tcp.receive_handler().unwrap();
+            cloned_tcp.receive_handler(&*cloned_tcp.device).unwrap();
         });
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
-            // timer thread
-            cloned_tcp.timer();
+            // loopback receiving thread
+            cloned_tcp.receive_handler(&*cloned_tcp.loopback).unwrap();
         });
+        if clock.is_virtual() {
+            let weak_tcp = Arc::downgrade(&tcp);
+            clock.on_advance(Box::new(move || {
+                if let Some(tcp) = weak_tcp.upgrade() {
+                    tcp.run_timers(tcp.timers.take_expired());
+                }
+            }));
+        } else {
+            let cloned_tcp = tcp.clone();
+            std::thread::spawn(move || {
+                // timer thread
+                cloned_tcp.timer();
+            });
+        }
         tcp
     }
 
-    // timer for resending packet
+    // runs the timers of the sockets as they expire
     fn timer(&self) {
-        dbg!("begin timer thread");
+        debug!("timer thread started");
         loop {
-            let mut table = self.sockets.write().unwrap();
-            for (sock_id, socket) in table.iter_mut() {
-                while let Some(mut item) = socket.retransmission_queue.pop_front() {
-                    // remove already acked packets
-                    if socket.send_param.unacked_seq > item.packet.get_seq() {
-                        dbg!("successfully acked", item.packet.get_seq());
-                        socket.send_param.window += item.packet.payload().len() as u16;
-                        self.publish_event(*sock_id, TCPEventKind::Acked);
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && socket.status == TcpStatus::LastAck
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
+            let expired = self.timers.wait_expired();
+            self.run_timers(expired);
+        }
+    }
+
+    fn run_timers(&self, expired: Vec<(SockID, TimerKind)>) {
+        if expired.is_empty() {
+            return;
+        }
+        let mut table = self.sockets.write().unwrap();
+        let mut dead_sockets = Vec::new();
+        let mut expired_sockets = Vec::new();
+        let mut embryonic_sockets = Vec::new();
+        for (sock_id, kind) in expired {
+            let socket = match table.get_mut(&sock_id) {
+                Some(socket) => socket,
+                None => continue,
+            };
+            let _span = socket.span.clone().entered();
+            socket.armed_timers.remove(&kind);
+            match kind {
+                TimerKind::Retransmission => {
+                    if self.retransmission_timeout(sock_id, socket) {
+                        if socket.status == TcpStatus::SynRcvd {
+                            // nobody waits on an embryonic connection
+                            embryonic_sockets.push(sock_id);
+                        } else {
+                            dead_sockets.push(sock_id);
                         }
                         continue;
                     }
-
-                    if item.latest_transmission_time.elapsed().unwrap()
-                        < Duration::from_secs(RETRANSMITTION_TIMEOUT)
+                }
+                // unless the ACK has gone out along with data
+                TimerKind::DelayedAck if socket.delayed_ack.is_some() => {
+                    trace!(%sock_id, "delayed ACK");
+                    if let Err(error) = socket.send_tcp_packet(
+                        socket.send_param.next,
+                        socket.recv_param.next,
+                        tcpflags::ACK,
+                        &[],
+                    ) {
+                        warn!(%sock_id, %error, "failed to send delayed ACK");
+                    }
+                }
+                TimerKind::DelayedAck => {}
+                TimerKind::Persist => self.persist(socket),
+                TimerKind::Keepalive => {
+                    if self.keepalive(socket) {
+                        dead_sockets.push(sock_id);
+                    }
+                }
+                TimerKind::UserTimeout => {
+                    if socket.is_user_timed_out() {
+                        dead_sockets.push(sock_id);
+                    }
+                }
+                // segments the pacer kept back
+                TimerKind::Pacing => {
+                    if let Err(error) = socket.transmit(false) {
+                        warn!(%sock_id, %error, "failed to send paced segments");
+                    }
+                }
+                TimerKind::Reordering => {
+                    socket.reorder_deadline = None;
+                    if let Err(error) = self.rack_handler(socket) {
+                        warn!(%sock_id, %error, "failed to retransmit on the reordering timer");
+                    }
+                }
+                TimerKind::TailLossProbe => {
+                    if let Err(error) = self.tail_loss_probe(socket) {
+                        warn!(%sock_id, %error, "failed to send tail loss probe");
+                    }
+                }
+                TimerKind::TimeWait => {
+                    if socket.orphaned
+                        && socket
+                            .time_wait_expiry
+                            .is_some_and(|expiry| expiry <= self.timers.now())
                     {
-                        socket.retransmission_queue.push_front(item);
-                        break;
+                        expired_sockets.push(sock_id);
+                        continue;
                     }
+                }
+            }
+            socket.schedule_timers();
+        }
+        for sock_id in dead_sockets {
+            debug!(%sock_id, "connection timed out");
+            self.terminate(&mut table, sock_id, io::ErrorKind::TimedOut);
+        }
+        for sock_id in embryonic_sockets {
+            debug!(%sock_id, "handshake never completed, removed");
+            self.remove_socket(&mut table, sock_id);
+            self.discard_events(sock_id, io::ErrorKind::TimedOut);
+        }
+        for sock_id in expired_sockets {
+            debug!(%sock_id, "TIME_WAIT expired & removed");
+            self.remove_socket(&mut table, sock_id);
+            self.discard_events(sock_id, io::ErrorKind::NotConnected);
+        }
+    }
 
-                    // resend
-                    if item.transmission_count < MAX_TRANSMITTION {
-                        dbg!("retransmit");
-                        socket
-                            .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.remote_addr))
-                            .context("failed to retransmit")
-                            .unwrap();
-                        item.transmission_count += 1;
-                        item.latest_transmission_time = SystemTime::now();
-                        socket.retransmission_queue.push_back(item);
-                        break;
-                    } else {
-                        dbg!("reached MAX_TRANSMITTION");
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && (socket.status == TcpStatus::LastAck
-                                || socket.status == TcpStatus::FinWait1
-                                || socket.status == TcpStatus::FinWait2)
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-                    }
+    // resend the first segment that has waited an RTO for its ACK
+    // returns true once the SYN or the SYN-ACK has been resent as often as it may be
+    // without an answer
+    fn retransmission_timeout(&self, sock_id: SockID, socket: &mut Socket) -> bool {
+        let mut sacked = Vec::new();
+        while let Some(mut item) = socket.retransmission_queue.pop_front() {
+            // remove packets acknowledged in full
+            if item.end_seq() <= socket.send_param.unacked_seq {
+                trace!(%sock_id, seq = %item.packet.get_seq(), "successfully acked");
+                self.publish_event(sock_id, TCPEventKind::Acked);
+                if item.packet.get_flag() & tcpflags::FIN > 0 && socket.status == TcpStatus::LastAck
+                {
+                    self.publish_event(sock_id, TCPEventKind::ConnectionClosed);
                 }
+                continue;
+            }
+            if item.sacked {
+                sacked.push(item);
+                continue;
             }
 
-            drop(table);
-            thread::sleep(Duration::from_millis(100));
+            if self
+                .timers
+                .now()
+                .duration_since(item.latest_transmission_time)
+                .unwrap_or_default()
+                < socket.rtt.rto()
+            {
+                socket.retransmission_queue.push_front(item);
+                break;
+            }
+
+            let syn_retries = match socket.status {
+                TcpStatus::SynSent => Some(SYN_RETRIES),
+                TcpStatus::SynRcvd => Some(SYNACK_RETRIES),
+                _ => None,
+            };
+            let is_syn = syn_retries.is_some();
+            if syn_retries.is_some_and(|retries| item.transmission_count > retries) {
+                debug!(%sock_id, "no answer to SYN");
+                socket.retransmission_queue.push_front(item);
+                return true;
+            }
+
+            // resend
+            if is_syn || item.transmission_count < MAX_TRANSMITTION {
+                debug!(%sock_id, seq = %item.packet.get_seq(), "retransmit");
+                // a first timeout outside of fast recovery may be spurious: F-RTO keeps the
+                // rest of the window from being resent until the next ACKs tell
+                if item.transmission_count == 1 && !is_syn && !socket.congestion.in_recovery() {
+                    socket.frto = Some(FrtoState::FirstAck {
+                        recover: socket.send_param.next,
+                    });
+                    socket.congestion.save_undo();
+                } else {
+                    socket.frto = None;
+                    socket.congestion.discard_undo();
+                }
+                // a failed send counts as a lost transmission: the next timeout tries again
+                if let Err(error) = socket.send_segment(item.packet.packet(), false) {
+                    warn!(%sock_id, %error, "failed to retransmit");
+                }
+                socket.pacer.on_send(
+                    item.packet.payload().len(),
+                    socket.congestion.pacing_rate(),
+                    self.timers.now(),
+                );
+                item.transmission_count += 1;
+                socket.retransmissions += 1;
+                socket.count(Counter::Retransmissions);
+                item.latest_transmission_time = self.timers.now();
+                socket.rtt.backoff();
+                let flight_size = socket.flight_size();
+                socket.congestion.on_timeout(flight_size);
+                socket.retransmission_queue.push_back(item);
+                break;
+            } else {
+                debug!(%sock_id, seq = %item.packet.get_seq(), "reached MAX_TRANSMITTION");
+                if item.packet.get_flag() & tcpflags::FIN > 0
+                    && (socket.status == TcpStatus::LastAck
+                        || socket.status == TcpStatus::FinWait1
+                        || socket.status == TcpStatus::FinWait2
+                        || socket.status == TcpStatus::Closing)
+                {
+                    self.publish_event(sock_id, TCPEventKind::ConnectionClosed);
+                }
+            }
+        }
+
+        // sacked segments wait in front for the cumulative ACK
+        for item in sacked.into_iter().rev() {
+            socket.retransmission_queue.push_front(item);
+        }
+        false
+    }
+
+    // send a keepalive probe if the connection has been idle long enough.
+    // returns true once the peer failed to answer KEEPALIVE_PROBES probes.
+    fn keepalive(&self, socket: &mut Socket) -> bool {
+        let idle = match socket.options.keepalive {
+            Some(idle) if socket.status == TcpStatus::Established => idle,
+            _ => return false,
+        };
+        let next_probe =
+            idle + Duration::from_secs(KEEPALIVE_INTERVAL) * socket.keepalive_probes as u32;
+        if self
+            .timers
+            .now()
+            .duration_since(socket.last_activity)
+            .unwrap_or_default()
+            < next_probe
+        {
+            return false;
+        }
+        if socket.keepalive_probes >= KEEPALIVE_PROBES {
+            return true;
+        }
+        // an already acknowledged sequence number makes the peer answer with an ACK
+        if let Err(error) = socket.send_tcp_packet(
+            socket.send_param.next - 1,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        ) {
+            warn!(sock_id = %socket.get_sock_id(), %error, "failed to send keepalive probe");
+        }
+        socket.keepalive_probes += 1;
+        false
+    }
+
+    // probe a closed peer window while data waits and nothing is in flight, so that the
+    // ACK which reopens it can't get lost for good (RFC 1122 section 4.2.2.17). a probe
+    // carries the next byte of data: the peer answers it with its current window, or takes
+    // the byte once it has room. the connection is kept as long as it answers.
+    fn persist(&self, socket: &mut Socket) {
+        let byte = match socket.probe_byte() {
+            Some(byte)
+                if matches!(socket.status, TcpStatus::Established | TcpStatus::CloseWait)
+                    && socket.send_param.window == 0
+                    && socket.flight_size() == 0 =>
+            {
+                byte
+            }
+            _ => {
+                socket.persist_probes = 0;
+                socket.next_persist = None;
+                return;
+            }
+        };
+        let now = self.timers.now();
+        let rto = socket.rtt.rto();
+        if now < *socket.next_persist.get_or_insert(now + rto) {
+            return;
+        }
+        trace!(
+            sock_id = %socket.get_sock_id(),
+            probes = socket.persist_probes,
+            "zero window probe"
+        );
+        if let Err(error) = socket.send_window_probe(byte) {
+            warn!(sock_id = %socket.get_sock_id(), %error, "failed to send zero window probe");
         }
+        socket.persist_probes = socket.persist_probes.saturating_add(1);
+        let interval = rto.saturating_mul(2u32.saturating_pow(socket.persist_probes as u32));
+        socket.next_persist = Some(now + cmp::min(interval, MAX_PERSIST_INTERVAL));
     }
 
     // create listening socket
-    pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
-        let socket = Socket::new(
-            local_addr,
+    // up to `backlog` connections may wait in the handshake, and as many more to be
+    // accepted; SYNs beyond that are dropped, for the peer to retry
+    pub fn listen(&self, local_addr: SocketAddr, backlog: usize) -> Result<SockID> {
+        self.listen_with(local_addr, backlog, BufferSizes::default())
+    }
+
+    // listening socket whose accepted connections start with `buffers`
+    pub fn listen_with(
+        &self,
+        local_addr: SocketAddr,
+        backlog: usize,
+        buffers: BufferSizes,
+    ) -> Result<SockID> {
+        self.listen_with_options(local_addr, backlog, buffers, BindOptions::default())
+    }
+
+    // fails with AddrInUse if a socket bound to the address conflicts, see BindOptions
+    pub fn listen_with_options(
+        &self,
+        local_addr: SocketAddr,
+        backlog: usize,
+        buffers: BufferSizes,
+        options: BindOptions,
+    ) -> Result<SockID> {
+        let local_addr = ipv4(local_addr)?;
+        let mut socket = Socket::new(
+            *local_addr.ip(),
             UNDETERMINED_IP_ADDR,
-            local_port,
+            local_addr.port(),
             UNDETERMINED_PORT,
             TcpStatus::Listen,
+            buffers,
+            self.device.clone(),
+            self.timers.clone(),
+            self.counters.clone(),
         )?;
+        // like Linux, a backlog of 0 still lets a connection through
+        socket.backlog = cmp::max(backlog, 1);
+        socket.reuse_port = options.reuse_port;
         let mut lock = self.sockets.write().unwrap();
+        if let Some(bound) = lock.values().find(|bound| {
+            let shared = options.reuse_port
+                && bound.reuse_port
+                && bound.status == TcpStatus::Listen
+                && bound.local_addr == *local_addr.ip();
+            bound.local_port == local_addr.port()
+                && (bound.local_addr == *local_addr.ip()
+                    || bound.local_addr.is_unspecified()
+                    || local_addr.ip().is_unspecified())
+                && (!options.reuse_addr || bound.status == TcpStatus::Listen)
+                && !shared
+        }) {
+            debug!(sock_id = %bound.get_sock_id(), status = %bound.status, "address in use");
+            return Err(io_error(io::ErrorKind::AddrInUse, "address already in use"));
+        }
+        // the listeners of a group tell apart by the otherwise unused remote port
+        while lock.contains_key(&socket.get_sock_id()) {
+            socket.remote_port += 1;
+        }
         let sock_id = socket.get_sock_id();
-        lock.insert(sock_id, socket);
+        self.insert_socket(&mut lock, sock_id, socket);
+        self.clear_events(sock_id);
         Ok(sock_id)
     }
 
     // sock_id: id of listening socket
     pub fn accept(&self, sock_id: SockID) -> Result<SockID> {
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
+        self.accept_until(sock_id, None)
+    }
 
+    pub fn accept_timeout(&self, sock_id: SockID, timeout: Duration) -> Result<SockID> {
+        self.accept_until(sock_id, Some(Instant::now() + timeout))
+    }
+
+    // returns None immediately if no connection is waiting to be accepted
+    pub fn try_accept(&self, sock_id: SockID) -> Result<Option<SockID>> {
         let mut table = self.sockets.write().unwrap();
         Ok(table
             .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?
+            .ok_or_else(|| self.no_such_socket(sock_id))?
             .connection_established_queue
-            .pop_front()
-            .context("no connected socket")?)
-    }
-
-    pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
-        let mut rng = rand::thread_rng();
-        let mut socket = Socket::new(
-            get_source_addr_to(addr)?,
-            addr,
-            self.select_unused_port(&mut rng)?,
-            port,
-            TcpStatus::SynSent,
-        )?;
-        socket.send_param.initial_seq = rng.gen_range(1..1 << 31);
-        socket.send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])?;
-        socket.send_param.unacked_seq = socket.send_param.initial_seq;
-        socket.send_param.next = socket.send_param.initial_seq + 1;
-
-        let mut table = self.sockets.write().unwrap();
-        let sock_id = socket.get_sock_id();
-        table.insert(sock_id, socket);
-
-        // unlock & wait for event so that receiving thread can acquire lock
-        drop(table);
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
-        Ok(sock_id)
-    }
-
-    fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
-        for _ in 0..(PORT_RANGE.end - PORT_RANGE.start) {
-            let local_port = rng.gen_range(PORT_RANGE);
-            let table = self.sockets.read().unwrap();
-            if table.keys().all(|k| local_port != k.2) {
-                return Ok(local_port);
-            }
-        }
-        anyhow::bail!("no available port found.");
+            .pop_front())
     }
 
-    pub fn send(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
-        let mut cursor = 0;
-        while cursor < buffer.len() {
+    fn accept_until(&self, sock_id: SockID, deadline: Option<Instant>) -> Result<SockID> {
+        let _span = self.span_of(sock_id).entered();
+        loop {
             let mut table = self.sockets.write().unwrap();
-            let mut socket = table
+            let socket = table
                 .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
-            let mut send_size = cmp::min(
-                MSS,
-                cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-            );
-            while send_size == 0 {
-                dbg!("unable to slide send window");
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::Acked);
-                table = self.sockets.write().unwrap();
-                socket = table
-                    .get_mut(&sock_id)
-                    .context(format!("no such socket: {:?}", sock_id))?;
-                // recalculate window size
-                send_size = cmp::min(
-                    MSS,
-                    cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-                );
+                .ok_or_else(|| self.no_such_socket(sock_id))?;
+            if let Some(connected_socket) = socket.connection_established_queue.pop_front() {
+                return Ok(connected_socket);
+            }
+            if socket.nonblocking {
+                return Err(io_error(
+                    io::ErrorKind::WouldBlock,
+                    "no connection to accept",
+                ));
             }
-            dbg!("current window size", socket.send_param.window);
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &buffer[cursor..cursor + send_size],
-            )?;
-            cursor += send_size;
-            socket.send_param.next += send_size as u32;
-            socket.send_param.window -= send_size as u16;
             drop(table);
-            thread::sleep(Duration::from_millis(1));
+            if !self.wait_event_until(sock_id, TCPEventKind::ConnectionCompleted, deadline)? {
+                return Err(io_error(io::ErrorKind::TimedOut, "accept timed out"));
+            }
         }
+    }
+
+    // make send/recv/accept on this socket fail with WouldBlock instead of waiting
+    pub fn set_nonblocking(&self, sock_id: SockID, nonblocking: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .nonblocking = nonblocking;
         Ok(())
     }
 
-    fn receive_handler(&self) -> Result<()> {
-        dbg!("begin recv thread");
-        let (_, mut receiver) = transport::transport_channel(
-            65535,
-            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
-        )
-        .unwrap();
-        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
-        loop {
-            let (packet, remote_addr) = match packet_iter.next() {
-                Ok((p, r)) => (p, r),
-                Err(_) => continue,
-            };
-            let local_addr = packet.get_destination();
-            let tcp_packet = match TcpPacket::new(packet.payload()) {
-                Some(p) => p,
-                None => {
-                    continue;
-                }
-            };
-            let packet = TCPPacket::from(tcp_packet);
-            let remote_addr = match remote_addr {
-                IpAddr::V4(addr) => addr,
-                _ => continue,
-            };
-            let mut table = self.sockets.write().unwrap();
-            let socket = match table.get_mut(&SockID(
-                local_addr,
-                remote_addr,
-                packet.get_dest(),
-                packet.get_src(),
-            )) {
-                Some(socket) => socket, // connection established socket
-                None => match table.get_mut(&SockID(
-                    local_addr,
-                    UNDETERMINED_IP_ADDR,
-                    packet.get_dest(),
-                    UNDETERMINED_PORT,
-                )) {
-                    Some(socket) => socket, // listening socket
-                    None => continue,       // ignore else
-                },
-            };
-            if !packet.is_correct_checksum(local_addr, remote_addr) {
-                dbg!("invalid checksum");
-                continue;
+    // send the data held back by Nagle's algorithm or cork as far as the windows allow
+    pub fn flush(&self, sock_id: SockID) -> Result<()> {
+        let _span = self.span_of(sock_id).entered();
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .transmit(true)
+    }
+
+    pub fn set_option(&self, sock_id: SockID, option: SocketOption) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        match option {
+            SocketOption::NoDelay(nodelay) => {
+                socket.options.nodelay = nodelay;
+                socket.transmit(false)?;
             }
-            let sock_id = socket.get_sock_id();
-            if let Err(error) = match socket.status {
-                TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
-                TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
-                _ => {
-                    dbg!("not implemented state");
-                    Ok(())
+            SocketOption::Cork(cork) => {
+                socket.options.cork = cork;
+                socket.transmit(false)?;
+            }
+            SocketOption::KeepAlive(idle) => {
+                socket.options.keepalive = idle;
+                socket.schedule_timers();
+            }
+            SocketOption::Ttl(ttl) => {
+                socket.options.ttl = ttl;
+            }
+            SocketOption::RecvBufferSize(size) => {
+                let enlarged = size > socket.recv_buffer.len();
+                socket.set_recv_buffer_size(size)?;
+                socket.recv_buffer_locked = true;
+                if enlarged {
+                    socket.send_window_update()?;
                 }
-            } {
-                dbg!(error);
             }
+            SocketOption::SendBufferSize(size) => socket.options.send_buffer_size = size,
+            SocketOption::Linger(linger) => socket.options.linger = linger,
+            SocketOption::CongestionControl(algorithm) => {
+                socket.options.congestion_control = algorithm;
+                socket.congestion = Congestion::new(algorithm, socket.mss);
+            }
+            SocketOption::UserTimeout(timeout) => {
+                socket.options.user_timeout = timeout;
+                socket.schedule_timers();
+            }
+            SocketOption::LossDetection(detection) => socket.options.loss_detection = detection,
+            SocketOption::Multipath(multipath) => socket.options.multipath = multipath,
         }
+        Ok(())
     }
 
-    fn synsent_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("synsent handler");
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-            && packet.get_flag() & tcpflags::SYN > 0
-        {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.recv_param.initial_seq = packet.get_seq();
-            socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
-            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
-                socket.status = TcpStatus::Established;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
-                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            } else {
-                socket.status = TcpStatus::SynRcvd;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
+    pub fn get_option(&self, sock_id: SockID, name: SocketOptionName) -> Result<SocketOption> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        Ok(match name {
+            SocketOptionName::NoDelay => SocketOption::NoDelay(socket.options.nodelay),
+            SocketOptionName::Cork => SocketOption::Cork(socket.options.cork),
+            SocketOptionName::KeepAlive => SocketOption::KeepAlive(socket.options.keepalive),
+            SocketOptionName::Ttl => SocketOption::Ttl(socket.options.ttl),
+            SocketOptionName::RecvBufferSize => {
+                SocketOption::RecvBufferSize(socket.recv_buffer.len())
+            }
+            SocketOptionName::SendBufferSize => {
+                SocketOption::SendBufferSize(socket.options.send_buffer_size)
             }
+            SocketOptionName::Linger => SocketOption::Linger(socket.options.linger),
+            SocketOptionName::CongestionControl => {
+                SocketOption::CongestionControl(socket.options.congestion_control)
+            }
+            SocketOptionName::UserTimeout => SocketOption::UserTimeout(socket.user_timeout()),
+            SocketOptionName::LossDetection => {
+                SocketOption::LossDetection(socket.options.loss_detection)
+            }
+            SocketOptionName::Multipath => SocketOption::Multipath(socket.options.multipath),
+        })
+    }
+
+    pub fn socket_info(&self, sock_id: SockID) -> Result<TcpInfo> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        Ok(TcpInfo {
+            status: socket.status.clone(),
+            send_next: socket.send_param.next.into(),
+            send_unacked: socket.send_param.unacked_seq.into(),
+            recv_next: socket.recv_param.next.into(),
+            recv_window: socket.recv_param.window,
+            send_window: socket.send_param.window,
+            retransmissions: socket.retransmissions,
+            spurious_retransmissions: socket.spurious_retransmissions,
+            rtt: socket.rtt.srtt(),
+            rto: socket.rtt.rto(),
+            mss: socket.mss,
+            cwnd: socket.congestion.cwnd(),
+            ssthresh: socket.congestion.ssthresh(),
+            ecn: socket.ecn,
+            retransmission_queue_len: socket.retransmission_queue.len(),
+            recv_queue_bytes: socket.recv_buffer.len() - socket.recv_param.window as usize,
+            accept_queue_len: socket.connection_established_queue.len(),
+            ao_key_ids: socket.ao.as_ref().map(Authentication::key_ids),
+        })
+    }
+
+    // counts of the whole stack since it was created, like /proc/net/snmp
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    // counts of one socket since it was opened or accepted
+    pub fn socket_stats(&self, sock_id: SockID) -> Result<Stats> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        Ok(socket.stats())
+    }
+
+    pub fn local_addr(&self, sock_id: SockID) -> Result<SocketAddr> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        Ok(SocketAddrV4::new(socket.local_addr, socket.local_port).into())
+    }
+
+    pub fn peer_addr(&self, sock_id: SockID) -> Result<SocketAddr> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        if socket.status == TcpStatus::Listen {
+            return Err(io_error(
+                io::ErrorKind::NotConnected,
+                "listening socket has no peer",
+            ));
         }
+        Ok(SocketAddrV4::new(socket.remote_addr, socket.remote_port).into())
+    }
+
+    // upper bound of challenge ACKs sent per second over all connections (RFC 5961 section 7)
+    pub fn set_challenge_ack_limit(&self, per_second: u32) {
+        self.challenge_acks.lock().unwrap().per_second = per_second;
+    }
+
+    // answer SYNs with SYN cookies once `threshold` connections of a listener wait in the
+    // handshake, to outlast a SYN flood before the queue is even full.
+    // cookies are always used once the SYN queue is full
+    pub fn set_syn_cookie_threshold(&self, threshold: usize) {
+        self.syn_cookie_threshold
+            .store(threshold, Ordering::Relaxed);
+    }
+
+    // explicit congestion notification (RFC 3168) for connections set up from now on
+    pub fn set_ecn(&self, enabled: bool) {
+        self.ecn.store(enabled, Ordering::Relaxed);
+    }
+
+    // None makes recv wait indefinitely
+    pub fn set_read_timeout(&self, sock_id: SockID, timeout: Option<Duration>) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .read_timeout = timeout;
         Ok(())
     }
 
-    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
-        dbg!("ack accept", socket.send_param.unacked_seq);
-        while let Some(item) = socket.retransmission_queue.pop_front() {
-            if socket.send_param.unacked_seq > item.packet.get_seq() {
-                dbg!("successfully acked", item.packet.get_seq());
-                socket.send_param.window += item.packet.payload().len() as u16;
-                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
-            } else {
-                socket.retransmission_queue.push_front(item);
-                break;
+    // None makes send wait indefinitely for the window to open
+    pub fn set_write_timeout(&self, sock_id: SockID, timeout: Option<Duration>) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<SockID> {
+        self.connect_with(addr, BufferSizes::default())
+    }
+
+    // the buffer sizes have to be known before the SYN, which carries the window scale.
+    // hostnames go through the system resolver, and each address it returns is tried in
+    // turn until one connects. the error of the last one is returned if none does
+    pub fn connect_with<A: ToSocketAddrs>(&self, addr: A, buffers: BufferSizes) -> Result<SockID> {
+        let mut last_error = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.connect_to(addr, buffers) {
+                Ok(sock_id) => return Ok(sock_id),
+                Err(error) => {
+                    debug!(%addr, %error, "connect failed");
+                    last_error = Some(error);
+                }
             }
         }
+        Err(last_error.unwrap_or_else(|| {
+            io_error(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
     }
 
-    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("established handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            return Ok(());
-        }
-        if packet.get_flag() & tcpflags::ACK == 0 {
+    fn connect_to(&self, addr: SocketAddr, buffers: BufferSizes) -> Result<SockID> {
+        let sock_id = self.start_connect(addr, buffers)?;
+        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted)?;
+        Ok(sock_id)
+    }
+
+    // connect from `local_addr` rather than from the address the route to `addr` goes out of
+    // and a random ephemeral port. an unspecified address or port 0 still leaves that part
+    // to the stack
+    pub fn connect_from(&self, local_addr: SocketAddr, addr: SocketAddr) -> Result<SockID> {
+        self.connect_from_with(local_addr, addr, BufferSizes::default())
+    }
+
+    pub fn connect_from_with(
+        &self,
+        local_addr: SocketAddr,
+        addr: SocketAddr,
+        buffers: BufferSizes,
+    ) -> Result<SockID> {
+        let sock_id = self.start_connect_from(local_addr, addr, buffers)?;
+        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted)?;
+        Ok(sock_id)
+    }
+
+    // give up and drop the half-open socket if the handshake doesn't complete within timeout
+    pub fn connect_timeout(&self, addr: SocketAddr, timeout: Duration) -> Result<SockID> {
+        let sock_id = self.start_connect(addr, BufferSizes::default())?;
+        if !self.wait_event_timeout(sock_id, TCPEventKind::ConnectionCompleted, timeout)? {
+            let mut table = self.sockets.write().unwrap();
+            self.remove_socket(&mut table, sock_id);
+            self.discard_events(sock_id, io::ErrorKind::TimedOut);
+            return Err(io_error(io::ErrorKind::TimedOut, "connection timed out"));
+        }
+        Ok(sock_id)
+    }
+
+    // send SYN and register the socket without waiting for the handshake to complete
+    pub(crate) fn start_connect(&self, addr: SocketAddr, buffers: BufferSizes) -> Result<SockID> {
+        self.start_connect_from((Ipv4Addr::UNSPECIFIED, 0).into(), addr, buffers)
+    }
+
+    pub(crate) fn start_connect_from(
+        &self,
+        local_addr: SocketAddr,
+        addr: SocketAddr,
+        buffers: BufferSizes,
+    ) -> Result<SockID> {
+        let local_addr = ipv4(local_addr)?;
+        let addr = ipv4(addr)?;
+        let local_ip = if local_addr.ip().is_unspecified() {
+            self.source_addr_to(*addr.ip())?
+        } else {
+            *local_addr.ip()
+        };
+        self.open(
+            SocketAddrV4::new(local_ip, local_addr.port()),
+            *addr.ip(),
+            addr.port(),
+            buffers,
+            None,
+            None,
+        )
+    }
+
+    // connect out of the network interface `ifname`, from its first IPv4 address. the
+    // connection stays bound to the device, see bind_device
+    pub fn connect_via(&self, ifname: &str, addr: SocketAddr) -> Result<SockID> {
+        let addr = ipv4(addr)?;
+        let device = get_device(ifname)?;
+        let local_addr = *device
+            .addrs
+            .first()
+            .with_context(|| format!("no IPv4 address on {}", ifname))?;
+        let sock_id = self.open(
+            SocketAddrV4::new(local_addr, 0),
+            *addr.ip(),
+            addr.port(),
+            BufferSizes::default(),
+            None,
+            Some(device),
+        )?;
+        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted)?;
+        Ok(sock_id)
+    }
+
+    // SO_BINDTODEVICE: send only through the network interface `ifname` and ignore segments
+    // from other interfaces. connections accepted by a bound listener are bound too. None
+    // unbinds the socket
+    pub fn bind_device(&self, sock_id: SockID, ifname: Option<&str>) -> Result<()> {
+        let device = ifname.map(get_device).transpose()?;
+        let sender = match ifname {
+            Some(ifname) => self.device_for(ifname)?,
+            None => self.device.clone(),
+        };
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        socket.options.device = device;
+        socket.device = sender;
+        Ok(())
+    }
+
+    // write every packet the socket sends and receives from now on into a new .pcap file at
+    // `path`, for Wireshark. connections accepted by a capturing listener go into the same
+    // file. None stops capturing
+    pub fn capture(&self, sock_id: SockID, path: Option<&Path>) -> Result<()> {
+        let capture = path
+            .map(Capture::create)
+            .transpose()
+            .context("failed to create capture file")?;
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        socket.options.capture = capture.map(Arc::new);
+        Ok(())
+    }
+
+    // what a socket bound to `ifname` sends through
+    fn device_for(&self, ifname: &str) -> Result<Arc<dyn NetworkDevice>> {
+        Ok(self
+            .device
+            .bound_to(ifname)?
+            .unwrap_or_else(|| self.device.clone()))
+    }
+
+    // send SYN from `local_addr`, for a subflow of a multipath connection if one is given.
+    // port 0 picks an unused ephemeral port
+    fn open(
+        &self,
+        local_addr: SocketAddrV4,
+        addr: Ipv4Addr,
+        port: u16,
+        buffers: BufferSizes,
+        subflow: Option<Subflow>,
+        device: Option<Device>,
+    ) -> Result<SockID> {
+        let mut rng = rand::thread_rng();
+        let local_port = match local_addr.port() {
+            0 => self.select_unused_port(&mut rng)?,
+            local_port => local_port,
+        };
+        let sock_id = SockID(*local_addr.ip(), addr, local_port, port);
+        if self.sockets.read().unwrap().contains_key(&sock_id) {
+            return Err(io_error(
+                io::ErrorKind::AddrInUse,
+                "connection already exists",
+            ));
+        }
+        let mut socket = Socket::new(
+            *local_addr.ip(),
+            addr,
+            local_port,
+            port,
+            TcpStatus::SynSent,
+            buffers,
+            self.device.clone(),
+            self.timers.clone(),
+            self.counters.clone(),
+        )?;
+        let _span = socket.span.clone().entered();
+        socket.local_mss = self.mss_to(addr);
+        socket.mss = socket.local_mss;
+        if let Some(device) = device {
+            socket.device = self.device_for(&device.name)?;
+            socket.options.device = Some(device);
+        }
+        // to a listener of ours, instead of out and back in through the kernel
+        let own_listener = self
+            .listener_for(
+                &self.sockets.read().unwrap(),
+                addr,
+                port,
+                *local_addr.ip(),
+                local_port,
+            )
+            .is_some();
+        if own_listener && self.routes.read().unwrap().is_local(addr) {
+            debug!(%sock_id, "connection to ourselves");
+            socket.device = self.loopback.clone();
+        }
+        socket.mptcp = subflow;
+        socket.ao = self.ao_for(addr);
+        socket.send_param.initial_seq = SeqNum(rng.gen());
+        let mut flag = tcpflags::SYN;
+        if self.ecn.load(Ordering::Relaxed) {
+            // ECN-setup SYN
+            socket.ecn = true;
+            flag |= tcpflags::ECE | tcpflags::CWR;
+        }
+        socket.send_tcp_packet(socket.send_param.initial_seq, SeqNum(0), flag, &[])?;
+        socket.send_param.unacked_seq = socket.send_param.initial_seq;
+        socket.send_param.next = socket.send_param.initial_seq + 1;
+
+        let mut table = self.sockets.write().unwrap();
+        let sock_id = socket.get_sock_id();
+        self.insert_socket(&mut table, sock_id, socket);
+        self.clear_events(sock_id);
+        Ok(sock_id)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn status(&self, sock_id: SockID) -> Result<TcpStatus> {
+        let table = self.sockets.read().unwrap();
+        Ok(table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .status
+            .clone())
+    }
+
+    // a destination without a route may have come with an interface or address added since
+    // the table was last discovered
+    fn source_addr_to(&self, addr: Ipv4Addr) -> Result<Ipv4Addr> {
+        if let Some(source) = self.routes.read().unwrap().source_for(addr) {
+            return Ok(source);
+        }
+        let mut routes = self.routes.write().unwrap();
+        routes.refresh();
+        let source = routes
+            .source_for(addr)
+            .ok_or_else(|| io_error(io::ErrorKind::HostUnreachable, "no route to host"))?;
+        trace!(%addr, %source, "source addr");
+        Ok(source)
+    }
+
+    // a route of our own over the discovered ones, for the source address of connections
+    // to its destinations. replaces an installed route to the same prefix
+    pub fn add_route(&self, route: Route) -> Result<()> {
+        if route.prefix_len > 32 {
+            return Err(io_error(io::ErrorKind::InvalidInput, "prefix too long"));
+        }
+        self.routes.write().unwrap().add(route);
+        Ok(())
+    }
+
+    pub fn remove_route(&self, destination: Ipv4Addr, prefix_len: u8) -> Result<()> {
+        if !self.routes.write().unwrap().remove(destination, prefix_len) {
+            return Err(io_error(io::ErrorKind::NotFound, "no such route"));
+        }
+        Ok(())
+    }
+
+    // installed routes first, then the discovered ones
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.read().unwrap().routes()
+    }
+
+    // discover local addresses and the default route again, e.g. after an interface came up
+    pub fn refresh_routes(&self) {
+        self.routes.write().unwrap().refresh();
+    }
+
+    // largest segment that fits the MTU of the outgoing interface without fragmentation
+    fn mss_to(&self, addr: Ipv4Addr) -> usize {
+        *self
+            .route_mss
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_insert_with(|| match self.routes.read().unwrap().mtu_to(addr) {
+                Ok(mtu) => mtu.saturating_sub(HEADERS_SIZE),
+                Err(error) => {
+                    warn!(%addr, %error, "failed to get mtu");
+                    MSS
+                }
+            })
+    }
+
+    fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
+        for _ in 0..(PORT_RANGE.end - PORT_RANGE.start) {
+            let local_port = rng.gen_range(PORT_RANGE);
+            let table = self.sockets.read().unwrap();
+            if table.keys().all(|k| local_port != k.2) {
+                return Ok(local_port);
+            }
+        }
+        anyhow::bail!("no available port found.");
+    }
+
+    // copy `buffer` into the send buffer, from where it goes out as the windows allow.
+    // waits only for room in the buffer, and returns less than buffer.len() only when
+    // nonblocking or on a write timeout
+    pub fn send(&self, sock_id: SockID, buffer: &[u8]) -> Result<usize> {
+        let _span = self.span_of(sock_id).entered();
+        let deadline = {
+            let table = self.sockets.read().unwrap();
+            table
+                .get(&sock_id)
+                .ok_or_else(|| self.no_such_socket(sock_id))?
+                .write_timeout
+                .map(|timeout| Instant::now() + timeout)
+        };
+        let mut cursor = 0;
+        loop {
+            let mut table = self.sockets.write().unwrap();
+            let socket = table
+                .get_mut(&sock_id)
+                .ok_or_else(|| self.no_such_socket(sock_id))?;
+            if socket.is_write_shutdown() {
+                return Err(io_error(io::ErrorKind::BrokenPipe, "socket is shut down"));
+            }
+            let size = cmp::min(socket.send_buffer_space(), buffer.len() - cursor);
+            socket.send_buffer.extend(&buffer[cursor..cursor + size]);
+            cursor += size;
+            socket.transmit(false)?;
+            if cursor == buffer.len() {
+                return Ok(cursor);
+            }
+            trace!(%sock_id, "send buffer is full");
+            if socket.nonblocking {
+                if cursor > 0 {
+                    return Ok(cursor);
+                }
+                return Err(io_error(io::ErrorKind::WouldBlock, "send buffer is full"));
+            }
+            drop(table);
+            if !self.wait_event_until(sock_id, TCPEventKind::Acked, deadline)? {
+                if cursor > 0 {
+                    return Ok(cursor);
+                }
+                return Err(io_error(io::ErrorKind::TimedOut, "send timed out"));
+            }
+        }
+    }
+
+    fn receive_handler(&self, device: &dyn NetworkDevice) -> Result<()> {
+        debug!("recv thread started");
+        let mut storage = vec![0; RECV_BATCH * 65535];
+        let mut buffers: Vec<&mut [u8]> = storage.chunks_mut(65535).collect();
+        let mut lens = [0; RECV_BATCH];
+        loop {
+            let count = match device.recv_packets(&mut buffers, &mut lens) {
+                Ok(count) => count,
+                // the other end of a memory link is gone for good
+                Err(error) if error.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(error) => {
+                    warn!(%error, "failed to receive packets");
+                    continue;
+                }
+            };
+            for (buffer, &len) in buffers.iter().zip(&lens).take(count) {
+                self.packet_handler(&buffer[..len]);
+            }
+        }
+    }
+
+    pub(crate) fn packet_handler(&self, packet: &[u8]) {
+        let header = match ip::parse(packet) {
+            Some(header) => header,
+            None => return,
+        };
+        let payload = &packet[header.header_len..header.total_len];
+        match header.protocol {
+            ip::TCP => self.tcp_handler(header.dst, header.src, header.ecn, payload, packet),
+            ip::ICMP => self.icmp_handler(payload),
+            _ => {}
+        }
+    }
+
+    // feed the TCP packets of a .pcap file to the stack one after another, as if they had
+    // just arrived, regardless of their timestamps: a captured session turned into a
+    // reproducible one. those a cooked capture marks as sent by the capturing host are
+    // skipped. returns how many were fed
+    pub fn replay(&self, path: &Path) -> Result<usize> {
+        let records = pcap::read(path).context("failed to read capture file")?;
+        let mut fed = 0;
+        for record in records {
+            if record.direction == Some(Direction::Outgoing) {
+                continue;
+            }
+            if ip::parse(&record.packet).is_none_or(|header| header.protocol != ip::TCP) {
+                continue;
+            }
+            self.packet_handler(&record.packet);
+            fed += 1;
+        }
+        Ok(fed)
+    }
+
+    fn tcp_handler(
+        &self,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        ecn_field: u8,
+        segment: &[u8],
+        ip_packet: &[u8],
+    ) {
+        let packet = match TcpPacket::new(segment) {
+            Some(tcp_packet) => TCPPacket::from(tcp_packet),
+            None => return,
+        };
+        let mut table = self.sockets.write().unwrap();
+        let socket = match table.get_mut(&SockID(
+            local_addr,
+            remote_addr,
+            packet.get_dest(),
+            packet.get_src(),
+        )) {
+            Some(socket) => socket, // connection established socket
+            None => match self
+                .listener_for(
+                    &table,
+                    local_addr,
+                    packet.get_dest(),
+                    remote_addr,
+                    packet.get_src(),
+                )
+                .and_then(|sock_id| table.get_mut(&sock_id))
+            {
+                Some(socket) => socket, // listening socket
+                None => {
+                    // reset segments for connections that no longer exist on our ports,
+                    // leaving other ports on the host to the kernel
+                    if let Some(socket) = table.values_mut().find(|socket| {
+                        (socket.local_addr == local_addr || socket.local_addr.is_unspecified())
+                            && socket.local_port == packet.get_dest()
+                    }) {
+                        self.counters.bump(Counter::SegmentsIn);
+                        if packet.is_correct_checksum(local_addr, remote_addr) {
+                            self.counters.bump(Counter::NoSocket);
+                            if let Err(error) = socket.send_reset(local_addr, remote_addr, &packet)
+                            {
+                                warn!(%error, "failed to send RST");
+                            }
+                        } else {
+                            self.counters.bump(Counter::ChecksumErrors);
+                        }
+                    }
+                    return;
+                }
+            },
+        };
+        let _span = socket.span.clone().entered();
+        socket.capture(ip_packet, Direction::Incoming);
+        trace_segment(segment, Direction::Incoming);
+        socket.count(Counter::SegmentsIn);
+        if !socket.accepts_on(local_addr) {
+            trace!(%local_addr, "not from the bound device");
+            return;
+        }
+        if !packet.is_correct_checksum(local_addr, remote_addr) {
+            debug!(sock_id = %socket.get_sock_id(), "invalid checksum");
+            socket.count(Counter::ChecksumErrors);
+            return;
+        }
+        if !socket.authenticate(&packet) {
+            debug!(
+                sock_id = %socket.get_sock_id(),
+                seq = %packet.get_seq(),
+                "TCP-AO: segment not authenticated"
+            );
+            return;
+        }
+        if packet.get_flag() & tcpflags::RST > 0 {
+            socket.count(Counter::ResetsIn);
+        }
+        socket.last_activity = self.timers.now();
+        if socket.keepalive_probes > 0 {
+            // an answer to a keepalive probe: the next one is a whole idle time away again
+            socket.keepalive_probes = 0;
+            socket.schedule_timers();
+        }
+        socket.process_ecn(&packet, ecn_field);
+        socket.process_dss(&packet);
+        let sock_id = socket.get_sock_id();
+        if let Err(error) = match socket.status {
+            TcpStatus::Listen => {
+                self.listen_handler(table, sock_id, &packet, local_addr, remote_addr)
+            }
+            TcpStatus::SynSent => self.synsent_handler(table, sock_id, &packet),
+            // the peer sent its SYN again, so our SYN-ACK is likely lost
+            TcpStatus::SynRcvd
+                if packet.get_flag() & (tcpflags::SYN | tcpflags::ACK) == tcpflags::SYN
+                    && packet.get_seq() == socket.recv_param.initial_seq =>
+            {
+                debug!(%sock_id, "duplicate SYN, SYN-ACK resent");
+                let iss = socket.send_param.initial_seq;
+                self.retransmit(socket, iss)
+            }
+            _ if socket.is_old_duplicate(&packet) => {
+                debug!(%sock_id, seq = %packet.get_seq(), "PAWS: old duplicate");
+                socket
+                    .send_tcp_packet(
+                        socket.send_param.next,
+                        socket.recv_param.next,
+                        tcpflags::ACK,
+                        &[],
+                    )
+                    .map(|_| ())
+            }
+            // retransmitted FINs in TIME_WAIT restart the 2MSL timer in timewait_handler
+            _ if socket.status != TcpStatus::TimeWait && !socket.is_acceptable(&packet) => {
+                debug!(%sock_id, seq = %packet.get_seq(), "unacceptable segment");
+                if packet.get_flag() & tcpflags::RST > 0 {
+                    Ok(())
+                } else if socket.is_zero_window_ack(&packet) {
+                    self.zero_window_handler(socket, &packet)
+                } else {
+                    let end = packet.get_seq() + packet.payload().len() as u32;
+                    if end <= socket.recv_param.next {
+                        socket.report_duplicate(packet.get_seq(), end);
+                    }
+                    socket
+                        .send_tcp_packet(
+                            socket.send_param.next,
+                            socket.recv_param.next,
+                            tcpflags::ACK,
+                            &[],
+                        )
+                        .map(|_| ())
+                }
+            }
+            // ahead of SYN_RCVD, whose handler doesn't look at RST
+            _ if packet.get_flag() & tcpflags::RST > 0 => {
+                self.reset_handler(table, sock_id, &packet)
+            }
+            TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
+            // SYN on a synchronized connection, whatever its sequence number (RFC 5961 section 4)
+            _ if packet.get_flag() & tcpflags::SYN > 0 => {
+                debug!(%sock_id, "SYN on synchronized connection");
+                self.send_challenge_ack(socket)
+            }
+            TcpStatus::Established => self.established_handler(socket, &packet),
+            TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
+            TcpStatus::Closing => self.closing_handler(socket, &packet),
+            TcpStatus::TimeWait => self.timewait_handler(socket, &packet),
+            _ => {
+                warn!(%sock_id, status = %socket.status, "not implemented state");
+                Ok(())
+            }
+        } {
+            warn!(%sock_id, %error, "failed to handle segment");
+        }
+    }
+
+    // errors reported by routers and the peer's host about segments we sent
+    fn icmp_handler(&self, payload: &[u8]) {
+        let message = match icmp::parse(payload) {
+            Some(message) => message,
+            None => return,
+        };
+        debug!(?message, "icmp");
+        let mut table = self.sockets.write().unwrap();
+        let socket = match table.get_mut(&message.sock_id) {
+            Some(socket) => socket,
+            None => return,
+        };
+        // only an error about data in flight is believed (RFC 5927 section 4.1)
+        if message.seq < socket.send_param.unacked_seq || socket.send_param.next <= message.seq {
+            debug!(sock_id = %message.sock_id, seq = %message.seq, "icmp error out of window");
+            return;
+        }
+        match message.error {
+            IcmpError::FragmentationNeeded { mtu } => {
+                let mss = (mtu as usize).saturating_sub(HEADERS_SIZE);
+                self.route_mss
+                    .lock()
+                    .unwrap()
+                    .insert(socket.remote_addr, mss);
+                if let Err(error) = socket.reduce_mss(mss) {
+                    warn!(sock_id = %message.sock_id, %error, "failed to reduce mss");
+                }
+            }
+            // fail connect() right away instead of retransmitting SYN until it times out.
+            // not an authenticated connection, which an ICMP message can't vouch for
+            // (RFC 5925 section 7.8)
+            IcmpError::Unreachable(kind)
+                if socket.status == TcpStatus::SynSent && socket.ao.is_none() =>
+            {
+                debug!(sock_id = %message.sock_id, %kind, "connection failed");
+                self.terminate(&mut table, message.sock_id, kind);
+            }
+            // a soft error on a synchronized connection, which may well recover
+            // (RFC 1122 section 4.2.3.9, RFC 5927 section 4.2)
+            IcmpError::Unreachable(kind) => {
+                debug!(sock_id = %message.sock_id, %kind, "icmp error ignored");
+            }
+        }
+    }
+
+    // RST on a synchronized connection
+    fn reset_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        trace!(%sock_id, "reset handler");
+        let socket = table.get_mut(&sock_id).unwrap();
+        if !socket.is_in_recv_window(packet.get_seq()) {
+            debug!(%sock_id, seq = %packet.get_seq(), "RST out of window");
+            return Ok(());
+        }
+        if socket.status == TcpStatus::TimeWait {
+            // ignore RST in TIME_WAIT (RFC 1337)
+            return Ok(());
+        }
+        if packet.get_seq() != socket.recv_param.next {
+            // a blind attacker has to guess RCV.NXT exactly, the real peer answers the ACK
+            // with a RST carrying it (RFC 5961 section 3)
+            debug!(%sock_id, seq = %packet.get_seq(), "RST not at RCV.NXT");
+            return self.send_challenge_ack(socket);
+        }
+        match socket.status {
+            TcpStatus::SynRcvd => {
+                // nobody has accepted the connection yet: just forget it
+                self.remove_socket(&mut table, sock_id);
+                self.discard_events(sock_id, io::ErrorKind::ConnectionReset);
+            }
+            _ => {
+                socket.set_status(TcpStatus::Closed);
+                self.terminate(&mut table, sock_id, io::ErrorKind::ConnectionReset);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_challenge_ack(&self, socket: &mut Socket) -> Result<()> {
+        if !self.challenge_acks.lock().unwrap().allow() {
+            debug!(sock_id = %socket.get_sock_id(), "challenge ACK rate limited");
+            return Ok(());
+        }
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    fn synsent_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        trace!(%sock_id, "synsent handler");
+        let socket = table.get_mut(&sock_id).unwrap();
+        if packet.get_flag() & tcpflags::RST > 0 {
+            // only a RST acknowledging our SYN is acceptable
+            if packet.get_flag() & tcpflags::ACK > 0
+                && socket.send_param.unacked_seq < packet.get_ack()
+                && packet.get_ack() <= socket.send_param.next
+            {
+                debug!(%sock_id, "connection refused");
+                self.terminate(&mut table, sock_id, io::ErrorKind::ConnectionRefused);
+            }
+            return Ok(());
+        }
+        if packet.get_flag() & tcpflags::ACK > 0
+            && socket.send_param.unacked_seq <= packet.get_ack()
+            && packet.get_ack() <= socket.send_param.next
+            && packet.get_flag() & tcpflags::SYN > 0
+        {
+            socket.recv_param.next = packet.get_seq() + 1;
+            socket.recv_param.initial_seq = packet.get_seq();
+            socket.send_param.unacked_seq = packet.get_ack();
+            socket.negotiate_options(packet);
+            // an ECN-setup SYN-ACK has ECE but not CWR
+            socket.ecn &= packet.get_flag() & (tcpflags::ECE | tcpflags::CWR) == tcpflags::ECE;
+            socket.set_send_window(packet);
+            if socket.mptcp.is_some() && !self.mptcp_syn_ack(sock_id, socket, packet) {
+                debug!(%sock_id, "MP_JOIN failed");
+                socket.send_reset(socket.local_addr, socket.remote_addr, packet)?;
+                self.remove_socket(&mut table, sock_id);
+                self.discard_events(sock_id, io::ErrorKind::ConnectionRefused);
+                return Ok(());
+            }
+            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
+                // the SYN is acknowledged. Karn's algorithm leaves a resent one unmeasured
+                if let Some(syn) = socket.retransmission_queue.pop_front() {
+                    match self
+                        .timers
+                        .now()
+                        .duration_since(syn.latest_transmission_time)
+                    {
+                        Ok(rtt) if syn.transmission_count == 1 => {
+                            socket.rtt.sample(rtt);
+                            socket.congestion.on_rtt_sample(rtt, self.timers.now());
+                        }
+                        _ => socket.rtt.reset_after_syn_timeout(),
+                    }
+                }
+                socket.set_status(TcpStatus::Established);
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
+            } else {
+                socket.set_status(TcpStatus::SynRcvd);
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // `sacked` bytes were newly SACKed by the same ACK
+    fn delete_acked_segment_from_retransmission_queue(
+        &self,
+        socket: &mut Socket,
+        ts_rtt: Option<Duration>,
+        sacked: usize,
+    ) -> Result<()> {
+        trace!(una = %socket.send_param.unacked_seq, "ack accept");
+        let mut rtt_sample = None;
+        let mut acked_bytes = 0;
+        while let Some(item) = socket.retransmission_queue.pop_front() {
+            // one SND.UNA got into the middle of stays queued for the rest of it
+            if item.end_seq() <= socket.send_param.unacked_seq {
+                trace!(seq = %item.packet.get_seq(), "successfully acked");
+                acked_bytes += item.packet.payload().len();
+                if !item.sacked {
+                    socket.rack.on_delivered(
+                        item.latest_transmission_time,
+                        item.end_seq(),
+                        item.transmission_count > 1,
+                        self.timers.now(),
+                    );
+                }
+                // Karn's algorithm: the ACK of a retransmitted segment is ambiguous
+                if item.transmission_count == 1 {
+                    rtt_sample = self
+                        .timers
+                        .now()
+                        .duration_since(item.latest_transmission_time)
+                        .ok();
+                }
+                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            } else {
+                socket.retransmission_queue.push_front(item);
+                break;
+            }
+        }
+        // timestamps measure retransmitted segments as well
+        if let Some(rtt) = ts_rtt.or(rtt_sample) {
+            socket.rtt.sample(rtt);
+            socket.congestion.on_rtt_sample(rtt, self.timers.now());
+            trace!(rto = ?socket.rtt.rto(), "rto");
+        }
+        let in_recovery = socket.congestion.in_recovery();
+        if acked_bytes > 0
+            && socket.congestion.on_ack(
+                acked_bytes,
+                socket.send_param.unacked_seq,
+                socket.send_param.next,
+                self.timers.now(),
+            )
+        {
+            debug!(una = %socket.send_param.unacked_seq, "partial ack");
+            // RACK finds the lost segments by itself
+            if !socket.rack_enabled() {
+                self.retransmit_unacked(socket)?;
+            }
+        }
+        if in_recovery && !socket.congestion.in_recovery() {
+            socket.rack.on_recovery_exit();
+        }
+        let (pipe, flight_size) = (socket.pipe(), socket.flight_size());
+        socket
+            .congestion
+            .on_recovery_delivered(acked_bytes + sacked, pipe, flight_size);
+        if socket.rack.on_probe_acked(socket.send_param.unacked_seq) {
+            debug!("tail loss probe repaired a loss");
+            let flight_size = socket.flight_size();
+            socket.congestion.on_tail_loss(flight_size);
+        }
+        trace!(cwnd = socket.congestion.cwnd(), "cwnd");
+        if socket
+            .send_param
+            .urgent
+            .is_some_and(|urgent| urgent <= socket.send_param.unacked_seq)
+        {
+            // the urgent data has arrived
+            socket.send_param.urgent = None;
+        }
+        socket.transmit(false)?;
+        Ok(())
+    }
+
+    // the state's handler takes the ACK, window and options of a segment kept out by a zero
+    // receive window, without its data
+    fn zero_window_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        let header = packet.without_payload();
+        match socket.status {
+            TcpStatus::Established => self.established_handler(socket, &header)?,
+            TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &header)?,
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &header)?,
+            _ => {}
+        }
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    // resend the segment at SND.UNA without waiting for its retransmission timeout
+    // once DUPACK_THRESHOLD duplicate ACKs suggest it was lost
+    // `sacked` bytes were newly SACKed by it
+    fn duplicate_ack_handler(&self, socket: &mut Socket, sacked: usize) -> Result<()> {
+        socket.duplicate_acks = socket.duplicate_acks.saturating_add(1);
+        trace!(count = socket.duplicate_acks, "duplicate ack");
+        if socket.congestion.in_recovery() {
+            // without SACK, a duplicate ACK stands for one segment that left the network
+            let delivered = if socket.sack_permitted {
+                sacked
+            } else {
+                socket.mss
+            };
+            let (pipe, flight_size) = (socket.pipe(), socket.flight_size());
+            socket
+                .congestion
+                .on_recovery_delivered(delivered, pipe, flight_size);
+            socket.transmit(false)?;
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            return Ok(());
+        }
+        if socket.duplicate_acks <= LIMITED_TRANSMIT_ACKS {
+            socket.limited_transmit()?;
+        }
+        // RACK tells lost segments by time instead
+        if socket.duplicate_acks != socket.dupack_threshold || socket.rack_enabled() {
+            return Ok(());
+        }
+        let flight_size = socket.flight_size();
+        if socket.congestion.on_fast_retransmit(
+            flight_size,
+            socket.send_param.unacked_seq,
+            socket.send_param.next,
+        ) {
+            debug!(una = %socket.send_param.unacked_seq, "fast retransmit");
+            self.retransmit_unacked(socket)?;
+        }
+        Ok(())
+    }
+
+    // the segment SND.UNA falls into, which the peer may have acknowledged in part
+    fn retransmit_unacked(&self, socket: &mut Socket) -> Result<()> {
+        let unacked_seq = socket.send_param.unacked_seq;
+        match socket
+            .retransmission_queue
+            .iter()
+            .find(|item| unacked_seq < item.end_seq())
+        {
+            Some(item) => self.retransmit(socket, item.packet.get_seq()),
+            None => Ok(()),
+        }
+    }
+
+    // resend the queued segment starting at `seq`
+    fn retransmit(&self, socket: &mut Socket, seq: SeqNum) -> Result<()> {
+        if let Some(index) = socket
+            .retransmission_queue
+            .iter()
+            .position(|item| item.packet.get_seq() == seq)
+        {
+            let segment = socket.retransmission_queue[index].packet.packet();
+            socket
+                .send_segment(segment, false)
+                .context("failed to retransmit")?;
+            let item = &mut socket.retransmission_queue[index];
+            socket.pacer.on_send(
+                item.packet.payload().len(),
+                socket.congestion.pacing_rate(),
+                self.timers.now(),
+            );
+            socket.congestion.on_send(item.packet.payload().len());
+            item.transmission_count += 1;
+            item.latest_transmission_time = self.timers.now();
+            socket.retransmissions += 1;
+            socket.count(Counter::Retransmissions);
+        }
+        Ok(())
+    }
+
+    // F-RTO (RFC 5682): after a timeout, new data goes out on the first ACK instead of the
+    // rest of the window. a second ACK that advances SND.UNA then shows the original segments
+    // were only delayed, and the window reduction is undone. `advanced` is false for a
+    // duplicate ACK
+    fn frto_handler(&self, socket: &mut Socket, advanced: bool) -> Result<()> {
+        let state = match socket.frto.take() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        match state {
+            FrtoState::FirstAck { recover } => {
+                // the retransmission was needed, or everything outstanding is acknowledged
+                if !advanced || socket.send_param.unacked_seq >= recover {
+                    socket.congestion.discard_undo();
+                    return Ok(());
+                }
+                let window =
+                    (socket.send_param.window as usize).saturating_sub(socket.flight_size());
+                let size = cmp::min(2 * socket.mss, cmp::min(window, socket.send_buffer.len()));
+                if size == 0 {
+                    // nothing new to tell by
+                    socket.congestion.discard_undo();
+                    return Ok(());
+                }
+                let payload: Vec<u8> = socket.send_buffer.drain(..size).collect();
+                for segment in payload.chunks(socket.mss) {
+                    socket.send_data(segment)?;
+                }
+                socket.frto = Some(FrtoState::SecondAck);
+            }
+            FrtoState::SecondAck if advanced => {
+                debug!(una = %socket.send_param.unacked_seq, "F-RTO: spurious timeout");
+                socket.spurious_retransmissions += 1;
+                socket.congestion.undo();
+            }
+            FrtoState::SecondAck => {
+                // lost after all: go on resending the window
+                socket.congestion.discard_undo();
+                self.retransmit_unacked(socket)?;
+            }
+        }
+        Ok(())
+    }
+
+    // resend the segments RACK deems lost, entering fast recovery for the first loss of a
+    // window. runs on every ACK and when the reordering timer expires
+    fn rack_handler(&self, socket: &mut Socket) -> Result<()> {
+        let lost = socket.detect_lost_segments();
+        socket.schedule_timers();
+        if lost.is_empty() {
+            return Ok(());
+        }
+        let flight_size = socket.flight_size();
+        if socket.congestion.on_loss_detected(
+            flight_size,
+            socket.send_param.unacked_seq,
+            socket.send_param.next,
+        ) {
+            debug!(una = %socket.send_param.unacked_seq, "RACK: fast recovery");
+        }
+        for seq in lost {
+            debug!(%seq, "RACK: lost");
+            self.retransmit(socket, seq)?;
+        }
+        Ok(())
+    }
+
+    // nothing was heard for a probe timeout while data is outstanding: the tail of a burst
+    // may have been lost, which no duplicate ACK would reveal. send one segment, new data if
+    // the peer window allows, else the last one again. that repairs a lone lost segment by
+    // itself, and the ACK it draws lets RACK find a longer lost tail, well before the
+    // retransmission timeout (RFC 8985 section 7)
+    fn tail_loss_probe(&self, socket: &mut Socket) -> Result<()> {
+        if socket
+            .tail_loss_probe_deadline()
+            .is_none_or(|deadline| deadline > self.timers.now())
+        {
+            return Ok(());
+        }
+        let window = (socket.send_param.window as usize).saturating_sub(socket.flight_size());
+        let size = cmp::min(socket.mss, cmp::min(window, socket.send_buffer.len()));
+        if size > 0 {
+            debug!(seq = %socket.send_param.next, "tail loss probe: new data");
+            let payload: Vec<u8> = socket.send_buffer.drain(..size).collect();
+            socket.send_data(&payload)?;
+            socket.rack.on_probe_sent(socket.send_param.next, false);
+            return Ok(());
+        }
+        let seq = match socket
+            .retransmission_queue
+            .iter()
+            .rev()
+            .find(|item| !item.sacked)
+        {
+            Some(item) => item.packet.get_seq(),
+            None => return Ok(()),
+        };
+        debug!(%seq, "tail loss probe: retransmit");
+        self.retransmit(socket, seq)?;
+        socket.rack.on_probe_sent(socket.send_param.next, true);
+        Ok(())
+    }
+
+    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        trace!("established handler");
+        if socket.take_window_probe(packet) {
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+        }
+        let ts_rtt = socket.process_timestamps(packet);
+        let sacked = socket.process_sack(packet);
+        socket.process_ecn_echo(packet);
+        if socket.send_param.unacked_seq < packet.get_ack()
+            && packet.get_ack() <= socket.send_param.next
+        {
+            socket.send_param.unacked_seq = packet.get_ack();
+            socket.duplicate_acks = 0;
+            self.delete_acked_segment_from_retransmission_queue(socket, ts_rtt, sacked)?;
+            self.frto_handler(socket, true)?;
+        } else if socket.send_param.next < packet.get_ack() {
+            return Ok(());
+        } else if socket.is_duplicate_ack(packet) {
+            self.frto_handler(socket, false)?;
+            self.duplicate_ack_handler(socket, sacked)?;
+        }
+        if socket.rack_enabled() {
+            self.rack_handler(socket)?;
+        }
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            return Ok(());
+        }
+        if socket.update_send_window(packet) {
+            // possibly the answer to a zero window probe
+            socket.transmit(false)?;
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+        }
+        if !packet.payload().is_empty() {
+            self.process_payload(socket, &packet)?;
+        }
+        if socket.is_fin_in_order(packet) {
+            socket.recv_param.next += 1;
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            socket.set_status(TcpStatus::CloseWait);
+            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        }
+        Ok(())
+    }
+
+    // the listener on `local_port` a segment goes to. a group of listeners sharing the
+    // address takes turns by the hash of the segment's addresses and ports, so every segment
+    // of a connection reaches the same one
+    fn listener_for(
+        &self,
+        table: &HashMap<SockID, Socket>,
+        local_addr: Ipv4Addr,
+        local_port: u16,
+        remote_addr: Ipv4Addr,
+        remote_port: u16,
+    ) -> Option<SockID> {
+        let listeners = |addr: Ipv4Addr| -> Vec<SockID> {
+            table
+                .keys()
+                .filter(|sock_id| {
+                    sock_id.0 == addr
+                        && sock_id.1 == UNDETERMINED_IP_ADDR
+                        && sock_id.2 == local_port
+                })
+                .copied()
+                .collect()
+        };
+        // one bound to the address itself before one on 0.0.0.0
+        let mut group = listeners(local_addr);
+        if group.is_empty() {
+            group = listeners(UNDETERMINED_IP_ADDR);
+        }
+        if group.len() <= 1 {
+            return group.pop();
+        }
+        group.sort();
+        let hash = self
+            .reuse_port_key
+            .hash_one((local_addr, local_port, remote_addr, remote_port));
+        Some(group[hash as usize % group.len()])
+    }
+
+    fn listen_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        listening_socket_id: SockID,
+        packet: &TCPPacket,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+    ) -> Result<()> {
+        trace!(sock_id = %listening_socket_id, "listen handler");
+        let mut ao = self.ao_for(remote_addr);
+        let syn_queue_len = table
+            .values()
+            .filter(|socket| {
+                socket.status == TcpStatus::SynRcvd
+                    && socket.listening_socket == Some(listening_socket_id)
+            })
+            .count();
+        // the connection takes the address the SYN came to, which a wildcard listener lacks
+        let sock_id = SockID(
+            local_addr,
+            remote_addr,
+            listening_socket_id.2,
+            packet.get_src(),
+        );
+        // the SYN of one of our own sockets
+        let looped = table.contains_key(&sock_id.reversed());
+        let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        if let Some(ao) = &mut ao {
+            // a peer with MKTs opens a connection with an authenticated SYN and nothing else
+            if packet.get_flag() & (tcpflags::SYN | tcpflags::ACK | tcpflags::RST) != tcpflags::SYN
+                || !ao.verify(
+                    packet.packet(),
+                    SocketAddrV4::new(remote_addr, packet.get_src()),
+                    SocketAddrV4::new(local_addr, listening_socket.local_port),
+                    packet.get_seq(),
+                    SeqNum(0),
+                )
+            {
+                debug!(%remote_addr, "TCP-AO: segment not authenticated");
+                return Ok(());
+            }
+        }
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            if packet.get_flag() & (tcpflags::SYN | tcpflags::RST) == 0 {
+                if let Some(mss) =
+                    self.syn_cookies
+                        .validate(sock_id, packet.get_seq() - 1, packet.get_ack() - 1)
+                {
+                    return self.syn_cookie_handler(
+                        table,
+                        listening_socket_id,
+                        sock_id,
+                        packet,
+                        mss,
+                    );
+                }
+            }
+            // nothing to acknowledge on a listening socket
+            return listening_socket.send_reset(local_addr, remote_addr, packet);
+        }
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            if listening_socket.connection_established_queue.len() >= listening_socket.backlog {
+                debug!(%remote_addr, "accept queue full, SYN dropped");
+                return Ok(());
+            }
+            let flooded = cmp::min(
+                listening_socket.backlog,
+                self.syn_cookie_threshold.load(Ordering::Relaxed),
+            );
+            // the cookie has no room for TCP-AO
+            if syn_queue_len >= flooded && ao.is_none() {
+                let peer_mss = packet
+                    .get_options()
+                    .into_iter()
+                    .find_map(|option| match option {
+                        TcpOption::MaxSegmentSize(mss) => Some(mss),
+                        _ => None,
+                    })
+                    .unwrap_or(DEFAULT_MSS as u16);
+                let mss = cmp::min(peer_mss, self.mss_to(remote_addr) as u16);
+                let cookie = self.syn_cookies.generate(sock_id, packet.get_seq(), mss);
+                return listening_socket.send_syn_cookie(
+                    local_addr,
+                    remote_addr,
+                    packet,
+                    cookie,
+                    mss,
+                );
+            }
+            // passive open
+            let mut connection_socket =
+                self.new_connection_socket(listening_socket, sock_id, TcpStatus::SynRcvd)?;
+            if looped {
+                connection_socket.device = self.loopback.clone();
+            }
+            connection_socket.ao = ao;
+            connection_socket.recv_param.next = packet.get_seq() + 1;
+            connection_socket.recv_param.initial_seq = packet.get_seq();
+            connection_socket.local_mss = self.mss_to(remote_addr);
+            if connection_socket.options.multipath {
+                match packet.get_mptcp_option() {
+                    Some(MptcpOption::Capable {
+                        sender_key: None,
+                        checksum_required: false,
+                        ..
+                    }) => {
+                        let connections = self.mptcp.lock().unwrap();
+                        let key = mptcp::new_key(|token| connections.contains_key(&token));
+                        connection_socket.mptcp = Some(Subflow::new(key));
+                    }
+                    Some(MptcpOption::JoinSyn { token, nonce, .. }) => {
+                        let connections = self.mptcp.lock().unwrap();
+                        let keys = connections
+                            .get(&token)
+                            .filter(|connection| !connection.fallback)
+                            .and_then(|connection| {
+                                Some((connection.local_key, connection.remote_key?))
+                            });
+                        match keys {
+                            Some((local_key, remote_key)) => {
+                                let join = Join {
+                                    address_id: 0,
+                                    local_nonce: rand::random(),
+                                    remote_nonce: nonce,
+                                };
+                                connection_socket.mptcp =
+                                    Some(Subflow::joining(local_key, remote_key, join));
+                            }
+                            None => {
+                                debug!(token, "MP_JOIN for an unknown connection");
+                                return connection_socket.send_reset(
+                                    connection_socket.local_addr,
+                                    remote_addr,
+                                    packet,
+                                );
+                            }
+                        }
+                    }
+                    // a peer requiring DSS checksums gets plain TCP
+                    _ => {}
+                }
+            }
+            connection_socket.negotiate_options(packet);
+            connection_socket.send_param.initial_seq = SeqNum(rand::thread_rng().gen());
+            connection_socket.set_send_window(packet);
+            let mut flag = tcpflags::SYN | tcpflags::ACK;
+            if self.ecn.load(Ordering::Relaxed)
+                && packet.get_flag() & (tcpflags::ECE | tcpflags::CWR)
+                    == tcpflags::ECE | tcpflags::CWR
+            {
+                connection_socket.ecn = true;
+                flag |= tcpflags::ECE;
+            }
+            connection_socket.send_tcp_packet(
+                connection_socket.send_param.initial_seq,
+                connection_socket.recv_param.next,
+                flag,
+                &[],
+            )?;
+            connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
+            connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
+            debug!(
+                sock_id = %connection_socket.get_sock_id(),
+                status = %connection_socket.status,
+                "status: listen ->"
+            );
+            let sock_id = connection_socket.get_sock_id();
+            self.insert_socket(&mut table, sock_id, connection_socket);
+            self.clear_events(sock_id);
+        }
+        Ok(())
+    }
+
+    // a socket for a connection to `listening_socket`, with its buffer sizes and options
+    fn new_connection_socket(
+        &self,
+        listening_socket: &Socket,
+        sock_id: SockID,
+        status: TcpStatus,
+    ) -> Result<Socket> {
+        let mut socket = Socket::new(
+            sock_id.0,
+            sock_id.1,
+            sock_id.2,
+            sock_id.3,
+            status,
+            BufferSizes {
+                recv: listening_socket
+                    .recv_buffer_locked
+                    .then_some(listening_socket.recv_buffer.len()),
+                send: Some(listening_socket.options.send_buffer_size),
+            },
+            listening_socket.device.clone(),
+            self.timers.clone(),
+            self.counters.clone(),
+        )?;
+        // accepted sockets inherit the options of the listener
+        socket.options = listening_socket.options.clone();
+        socket.listening_socket = Some(listening_socket.get_sock_id());
+        Ok(socket)
+    }
+
+    // the ACK of a SYN-ACK carrying a valid SYN cookie: the connection is established
+    // right away, with the MSS from the cookie and none of the other options
+    fn syn_cookie_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        listening_socket_id: SockID,
+        sock_id: SockID,
+        packet: &TCPPacket,
+        mss: u16,
+    ) -> Result<()> {
+        let listening_socket = &table[&listening_socket_id];
+        if listening_socket.connection_established_queue.len() >= listening_socket.backlog {
+            debug!(%sock_id, "accept queue full, SYN cookie dropped");
+            return Ok(());
+        }
+        debug!(%sock_id, "valid SYN cookie");
+        let mut socket =
+            self.new_connection_socket(listening_socket, sock_id, TcpStatus::Established)?;
+        if table.contains_key(&sock_id.reversed()) {
+            socket.device = self.loopback.clone();
+        }
+        socket.recv_param.initial_seq = packet.get_seq() - 1;
+        socket.recv_param.next = packet.get_seq();
+        socket.send_param.initial_seq = packet.get_ack() - 1;
+        socket.send_param.next = packet.get_ack();
+        socket.send_param.unacked_seq = packet.get_ack();
+        socket.local_mss = self.mss_to(sock_id.1);
+        socket.mss = mss as usize;
+        socket.congestion = Congestion::new(socket.options.congestion_control, socket.mss);
+        socket.set_send_window(packet);
+        self.insert_socket(&mut table, sock_id, socket);
+        self.clear_events(sock_id);
+        if !packet.payload().is_empty() {
+            let socket = table.get_mut(&sock_id).unwrap();
+            self.process_payload(socket, packet)?;
+        }
+        let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        listening_socket
+            .connection_established_queue
+            .push_back(sock_id);
+        self.publish_event(listening_socket_id, TCPEventKind::ConnectionCompleted);
+        Ok(())
+    }
+
+    fn synrcvd_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        trace!(%sock_id, "synrcvd handler");
+        let accept_queue_full = table[&sock_id]
+            .listening_socket
+            .and_then(|id| table.get(&id))
+            .is_some_and(|ls| ls.connection_established_queue.len() >= ls.backlog);
+        let socket = table.get_mut(&sock_id).unwrap();
+        // another subflow of a connection that was accepted already
+        let joining = socket
+            .mptcp
+            .as_ref()
+            .is_some_and(|subflow| subflow.join.is_some());
+
+        if packet.get_flag() & tcpflags::ACK > 0
+            && socket.send_param.unacked_seq <= packet.get_ack()
+            && packet.get_ack() <= socket.send_param.next
+        {
+            if accept_queue_full && !joining {
+                // the handshake completes once the application has made room
+                debug!(%sock_id, "accept queue full, ACK dropped");
+                return Ok(());
+            }
+            socket.recv_param.next = packet.get_seq();
+            socket.send_param.unacked_seq = packet.get_ack();
+            // the first window that can be scaled
+            socket.set_send_window(packet);
+            if let Some(rtt) = socket.process_timestamps(packet) {
+                socket.rtt.sample(rtt);
+                socket.congestion.on_rtt_sample(rtt, self.timers.now());
+            }
+            if socket.mptcp.is_some() && !self.mptcp_handshake_ack(sock_id, socket, packet) {
+                debug!(%sock_id, "MP_JOIN failed");
+                socket.send_reset(socket.local_addr, socket.remote_addr, packet)?;
+                self.remove_socket(&mut table, sock_id);
+                self.discard_events(sock_id, io::ErrorKind::ConnectionRefused);
+                return Ok(());
+            }
+            socket.set_status(TcpStatus::Established);
+            if joining {
+                // the peer sends on the subflow once this ACK tells it the join went through
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+                self.notify_readiness(sock_id);
+                return Ok(());
+            }
+            if let Some(id) = socket.listening_socket {
+                let ls = table.get_mut(&id).unwrap();
+                ls.connection_established_queue.push_back(sock_id);
+                self.publish_event(ls.get_sock_id(), TCPEventKind::ConnectionCompleted);
+            }
+        }
+        Ok(())
+    }
+
+    // the SYN-ACK to a subflow we opened: the peer's key, or its proof that it knows ours.
+    // false if a join can't go ahead
+    fn mptcp_syn_ack(&self, sock_id: SockID, socket: &mut Socket, packet: &TCPPacket) -> bool {
+        let mut connections = self.mptcp.lock().unwrap();
+        let subflow = socket.mptcp.as_mut().unwrap();
+        let connection = match connections.get_mut(&subflow.token) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        match (&mut subflow.join, packet.get_mptcp_option()) {
+            (
+                None,
+                Some(MptcpOption::Capable {
+                    sender_key: Some(remote_key),
+                    checksum_required: false,
+                    ..
+                }),
+            ) => {
+                subflow.remote_key = Some(remote_key);
+                connection.set_remote_key(remote_key);
+            }
+            (None, _) => {
+                debug!(%sock_id, "peer doesn't support MPTCP, plain TCP");
+                connection.fallback = true;
+                socket.mptcp = None;
+                return true;
+            }
+            (Some(join), Some(MptcpOption::JoinSynAck { hmac, nonce, .. })) => {
+                join.remote_nonce = nonce;
+                if !subflow.verify_syn_ack(hmac) {
+                    return false;
+                }
+            }
+            (Some(_), _) => return false,
+        }
+        subflow.handshake_ack_pending = true;
+        subflow.data_ack = Some(connection.recv_next);
+        true
+    }
+
+    // the third ACK of a subflow the peer opened. a first subflow carrying both keys makes
+    // a connection, one without falls back to plain TCP. false if a join can't go ahead
+    fn mptcp_handshake_ack(
+        &self,
+        sock_id: SockID,
+        socket: &mut Socket,
+        packet: &TCPPacket,
+    ) -> bool {
+        let mut connections = self.mptcp.lock().unwrap();
+        let subflow = socket.mptcp.as_mut().unwrap();
+        match (subflow.join.is_some(), packet.get_mptcp_option()) {
+            (true, Some(MptcpOption::JoinAck { hmac })) if subflow.verify_ack(&hmac) => {
+                match connections.get_mut(&subflow.token) {
+                    Some(connection) => {
+                        connection.subflows.push(sock_id);
+                        subflow.data_ack = Some(connection.recv_next);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (true, _) => false,
+            (
+                false,
+                Some(MptcpOption::Capable {
+                    sender_key: Some(remote_key),
+                    receiver_key: Some(local_key),
+                    checksum_required: false,
+                }),
+            ) if local_key == subflow.local_key => {
+                let mut connection = MptcpConnection::new(local_key);
+                connection.set_remote_key(remote_key);
+                connection.subflows.push(sock_id);
+                subflow.remote_key = Some(remote_key);
+                subflow.data_ack = Some(connection.recv_next);
+                connections.insert(subflow.token, connection);
+                true
+            }
+            (false, _) => {
+                debug!(%sock_id, "MPTCP handshake incomplete, plain TCP");
+                socket.mptcp = None;
+                true
+            }
+        }
+    }
+
+    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        // an acceptable segment may still start with data received before
+        let already_received = (socket.recv_param.next - packet.get_seq()) as i32;
+        let (seq, payload) = if already_received > 0 {
+            let skip = cmp::min(already_received as usize, packet.payload().len());
+            socket.report_duplicate(packet.get_seq(), packet.get_seq() + skip as u32);
+            (socket.recv_param.next, &packet.payload()[skip..])
+        } else {
+            (packet.get_seq(), packet.payload())
+        };
+        if socket.read_shutdown {
+            // nobody will read it anymore: acknowledge in-order data and throw it away
+            if seq == socket.recv_param.next {
+                socket.recv_param.next += payload.len() as u32;
+            }
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            return Ok(());
+        }
+        // nothing is taken beyond the window, whatever the peer sent past it is trimmed off
+        let offset = (seq - socket.recv_param.next) as usize;
+        let room = (socket.recv_param.window as usize).saturating_sub(offset);
+        let copy_size = cmp::min(payload.len(), room);
+        let offset = offset + socket.recv_buffer.len() - socket.recv_param.window as usize;
+        socket.recv_buffer[offset..offset + copy_size].copy_from_slice(&payload[..copy_size]);
+
+        // out-of-order data and data that fills a hole are acknowledged right away
+        // (RFC 5681 section 4.2), as is anything received twice. so is a segment that
+        // overran the window, to tell the peer how much room is really left
+        let mut ack_now = socket.recv_param.duplicate.is_some() || copy_size < payload.len();
+        if seq == socket.recv_param.next {
+            let next = socket.take_out_of_order(seq + copy_size as u32);
+            ack_now |= next != seq + copy_size as u32;
+            socket.recv_param.window -= next - seq;
+            socket.recv_param.next = next;
+            socket.autotune_recv_buffer();
+        } else if copy_size > 0 {
+            ack_now = true;
+            let end = seq + copy_size as u32;
+            if socket
+                .recv_param
+                .out_of_order
+                .iter()
+                .any(|&(left, right)| left <= seq && end <= right)
+            {
+                socket.report_duplicate(seq, end);
+            }
+            socket.add_out_of_order(seq, end);
+        }
+        if copy_size < payload.len() {
+            trace!(
+                trimmed = payload.len() - copy_size,
+                "segment beyond the window trimmed"
+            );
+        }
+        if ack_now || socket.unacked_segments > 0 {
+            // at least every second segment is acknowledged
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+        } else {
+            socket.unacked_segments += 1;
+            socket
+                .delayed_ack
+                .get_or_insert(self.timers.now() + DELAYED_ACK_TIMEOUT);
+            socket.schedule_timers();
+        }
+        socket.process_urgent(packet);
+        self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        Ok(())
+    }
+
+    pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
+        let _span = self.span_of(sock_id).entered();
+        let mut table = self.sockets.write().unwrap();
+        let mut socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        if socket.read_shutdown {
+            return Ok(0);
+        }
+        let deadline = socket.read_timeout.map(|timeout| Instant::now() + timeout);
+        let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+        while received_size == 0 {
+            match socket.status {
+                TcpStatus::CloseWait
+                | TcpStatus::Closing
+                | TcpStatus::LastAck
+                | TcpStatus::TimeWait => break,
+                _ => {}
+            }
+            if socket.nonblocking {
+                return Err(io_error(io::ErrorKind::WouldBlock, "no data to receive"));
+            }
+            drop(table);
+            trace!(%sock_id, "waiting incoming data");
+            if !self.wait_event_until(sock_id, TCPEventKind::DataArrived, deadline)? {
+                return Err(io_error(io::ErrorKind::TimedOut, "recv timed out"));
+            }
+            table = self.sockets.write().unwrap();
+            socket = table
+                .get_mut(&sock_id)
+                .ok_or_else(|| self.no_such_socket(sock_id))?;
+            if socket.read_shutdown {
+                return Ok(0);
+            }
+            received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+        }
+        socket.take_received(buffer)
+    }
+
+    // send `buffer` as urgent data: the peer is told where it ends ahead of the data itself
+    pub fn send_oob(&self, sock_id: SockID, buffer: &[u8]) -> Result<usize> {
+        let _span = self.span_of(sock_id).entered();
+        {
+            let mut table = self.sockets.write().unwrap();
+            let socket = table
+                .get_mut(&sock_id)
+                .ok_or_else(|| self.no_such_socket(sock_id))?;
+            socket.send_param.urgent =
+                Some(socket.send_param.next + (socket.send_buffer.len() + buffer.len()) as u32);
+        }
+        let size = self.send(sock_id, buffer)?;
+        // urgent data is not held back
+        self.flush(sock_id)?;
+        Ok(size)
+    }
+
+    // take the last urgent byte received. it is delivered inline by recv as well
+    pub fn recv_oob(&self, sock_id: SockID) -> Result<u8> {
+        let _span = self.span_of(sock_id).entered();
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .urgent_byte
+            .take()
+            .ok_or_else(|| io_error(io::ErrorKind::WouldBlock, "no urgent data"))
+    }
+
+    // true if the next byte recv returns is the last urgent byte, like SIOCATMARK
+    pub fn at_urgent_mark(&self, sock_id: SockID) -> Result<bool> {
+        let table = self.sockets.read().unwrap();
+        Ok(table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .at_urgent_mark())
+    }
+
+    pub fn close(&self, sock_id: SockID) -> Result<()> {
+        let _span = self.span_of(sock_id).entered();
+        let mut table = self.sockets.write().unwrap();
+        let mut socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        if socket.status == TcpStatus::Listen {
+            // nothing to tell the (nonexistent) peer
+            self.remove_socket(&mut table, sock_id);
+            self.discard_events(sock_id, io::ErrorKind::NotConnected);
+            return Ok(());
+        }
+        let linger = socket.options.linger;
+        if linger == Some(Duration::ZERO) {
+            drop(table);
+            return self.abort(sock_id);
+        }
+        match socket.status {
+            TcpStatus::Established | TcpStatus::CloseWait => self.send_fin(socket)?,
+            // FIN already sent by shutdown(Write)
+            TcpStatus::FinWait1
+            | TcpStatus::FinWait2
+            | TcpStatus::Closing
+            | TcpStatus::LastAck
+            | TcpStatus::TimeWait => {}
+            _ => return Ok(()),
+        }
+        drop(table);
+        match linger {
+            Some(timeout) => {
+                if !self.wait_event_timeout(sock_id, TCPEventKind::ConnectionClosed, timeout)? {
+                    debug!(%sock_id, "linger timed out");
+                    return self.abort(sock_id);
+                }
+            }
+            None => self.wait_event(sock_id, TCPEventKind::ConnectionClosed)?,
+        }
+        let mut table = self.sockets.write().unwrap();
+        if let Some(socket) = table.get_mut(&sock_id) {
+            // the tuple stays taken until 2MSL have passed so that
+            // delayed segments of this connection can't reach a new one
+            if socket
+                .time_wait_expiry
+                .is_some_and(|expiry| expiry > self.timers.now())
+            {
+                socket.orphaned = true;
+                socket.schedule_timers();
+                debug!(%sock_id, "closed & left in TIME_WAIT");
+                return Ok(());
+            }
+        }
+        self.remove_socket(&mut table, sock_id);
+        self.discard_events(sock_id, io::ErrorKind::NotConnected);
+        debug!(%sock_id, "closed & removed");
+        Ok(())
+    }
+
+    // abortive close: send RST and drop the socket along with its retransmission queue
+    // without going through the FIN handshake
+    pub fn abort(&self, sock_id: SockID) -> Result<()> {
+        let _span = self.span_of(sock_id).entered();
+        let mut table = self.sockets.write().unwrap();
+        let mut socket = self
+            .remove_socket(&mut table, sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        drop(table);
+        self.discard_events(sock_id, io::ErrorKind::ConnectionAborted);
+        debug!(%sock_id, "aborted & removed");
+        if socket.status != TcpStatus::Listen {
+            socket.send_tcp_packet(socket.send_param.next, SeqNum(0), tcpflags::RST, &[])?;
+        }
+        Ok(())
+    }
+
+    // drop every socket on the spot, without a word to the peers and with their timers
+    // cancelled, as if none had ever been opened
+    pub(crate) fn clear(&self) {
+        let mut table = self.sockets.write().unwrap();
+        let sock_ids: Vec<SockID> = table.keys().copied().collect();
+        for sock_id in sock_ids {
+            if let Some(socket) = self.remove_socket(&mut table, sock_id) {
+                for kind in socket.armed_timers.keys() {
+                    self.timers.cancel(sock_id, *kind);
+                }
+            }
+            self.discard_events(sock_id, io::ErrorKind::ConnectionAborted);
+        }
+        self.mptcp.lock().unwrap().clear();
+    }
+
+    // Shutdown::Write sends FIN but keeps receiving until the peer closes (half-close).
+    // Shutdown::Read makes recv return 0 and discards data arriving afterwards.
+    // the socket stays in the table until close() is called.
+    pub fn shutdown(&self, sock_id: SockID, how: Shutdown) -> Result<()> {
+        let _span = self.span_of(sock_id).entered();
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        match socket.status {
+            TcpStatus::Established
+            | TcpStatus::CloseWait
+            | TcpStatus::FinWait1
+            | TcpStatus::FinWait2
+            | TcpStatus::Closing
+            | TcpStatus::LastAck
+            | TcpStatus::TimeWait => {}
+            _ => {
+                return Err(io_error(
+                    io::ErrorKind::NotConnected,
+                    "socket is not connected",
+                ))
+            }
+        }
+        if how != Shutdown::Write && !socket.read_shutdown {
+            socket.read_shutdown = true;
+            socket.recv_param.window = socket.recv_buffer.len() as u32;
+            self.publish_event(sock_id, TCPEventKind::DataArrived);
+        }
+        if how != Shutdown::Read && !socket.is_write_shutdown() {
+            self.send_fin(socket)?;
+        }
+        Ok(())
+    }
+
+    // the FIN follows the data still in the send buffer
+    fn send_fin(&self, socket: &mut Socket) -> Result<()> {
+        socket.fin_pending = true;
+        socket.transmit(true)?;
+        socket.set_status(match socket.status {
+            TcpStatus::CloseWait => TcpStatus::LastAck,
+            _ => TcpStatus::FinWait1,
+        });
+        Ok(())
+    }
+
+    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        trace!("closewait | lastack handler");
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            return Ok(());
+        }
+        if socket.take_window_probe(packet) {
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+        }
+        let ts_rtt = socket.process_timestamps(packet);
+        let sacked = socket.process_sack(packet);
+        socket.process_ecn_echo(packet);
+        // an ACK of what is acknowledged already or of what was never sent moves nothing
+        if socket.send_param.unacked_seq < packet.get_ack()
+            && packet.get_ack() <= socket.send_param.next
+        {
+            socket.send_param.unacked_seq = packet.get_ack();
+            socket.duplicate_acks = 0;
+            // the writer of a half-closed connection waits on these like any other
+            self.delete_acked_segment_from_retransmission_queue(socket, ts_rtt, sacked)?;
+            self.frto_handler(socket, true)?;
+        } else if socket.send_param.next < packet.get_ack() {
+            return Ok(());
+        } else if socket.is_duplicate_ack(packet) {
+            self.frto_handler(socket, false)?;
+            self.duplicate_ack_handler(socket, sacked)?;
+        }
+        if socket.rack_enabled() {
+            self.rack_handler(socket)?;
+        }
+        if socket.update_send_window(packet) {
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+        }
+        socket.transmit(false)?;
+        if socket.status == TcpStatus::LastAck
+            && !socket.fin_pending
+            && socket.send_param.next == socket.send_param.unacked_seq
+        {
+            // our FIN is acknowledged
+            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        trace!("finwait handler");
+        let ts_rtt = socket.process_timestamps(packet);
+        let sacked = socket.process_sack(packet);
+        socket.process_ecn_echo(packet);
+        if socket.send_param.unacked_seq < packet.get_ack()
+            && packet.get_ack() <= socket.send_param.next
+        {
+            socket.send_param.unacked_seq = packet.get_ack();
+            socket.duplicate_acks = 0;
+            self.delete_acked_segment_from_retransmission_queue(socket, ts_rtt, sacked)?;
+            self.frto_handler(socket, true)?;
+        } else if socket.send_param.next < packet.get_ack() {
+            return Ok(());
+        } else if socket.is_duplicate_ack(packet) {
+            self.frto_handler(socket, false)?;
+            self.duplicate_ack_handler(socket, sacked)?;
+        }
+        if socket.rack_enabled() {
+            self.rack_handler(socket)?;
+        }
+        if packet.get_flag() & tcpflags::ACK == 0 {
             return Ok(());
         }
+        if socket.update_send_window(packet) {
+            // the FIN may still wait behind buffered data
+            socket.transmit(false)?;
+        }
         if !packet.payload().is_empty() {
             self.process_payload(socket, &packet)?;
         }
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next = packet.get_seq() + 1;
+
+        if socket.status == TcpStatus::FinWait1
+            && !socket.fin_pending
+            && socket.send_param.next == socket.send_param.unacked_seq
+        {
+            socket.set_status(TcpStatus::FinWait2);
+        }
+
+        if socket.is_fin_in_order(packet) {
+            socket.recv_param.next += 1;
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
                 tcpflags::ACK,
                 &[],
             )?;
-            socket.status = TcpStatus::CloseWait;
+            // wake up readers of a half-closed socket too
             self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+            if socket.status == TcpStatus::FinWait1 {
+                // simultaneous close: the peer's FIN crossed ours
+                socket.set_status(TcpStatus::Closing);
+            } else {
+                self.enter_time_wait(socket);
+            }
         }
         Ok(())
     }
 
-    fn listen_handler(
-        &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
-        listening_socket_id: SockID,
-        packet: &TCPPacket,
-        remote_addr: Ipv4Addr,
-    ) -> Result<()> {
-        dbg!("listen handler");
-        if packet.get_flag() & tcpflags::ACK > 0 {
-            // RST
-            return Ok(());
-        }
-        let listening_socket = table.get_mut(&listening_socket_id).unwrap();
-        if packet.get_flag() & tcpflags::SYN > 0 {
-            // passive open
-            let mut connection_socket = Socket::new(
-                listening_socket.local_addr,
-                remote_addr,
-                listening_socket.local_port,
-                packet.get_src(),
-                TcpStatus::SynRcvd,
-            )?;
-            connection_socket.recv_param.next = packet.get_seq() + 1;
-            connection_socket.recv_param.initial_seq = packet.get_seq();
-            connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
-            connection_socket.send_param.window = packet.get_window_size();
-            connection_socket.send_tcp_packet(
-                connection_socket.send_param.initial_seq,
-                connection_socket.recv_param.next,
-                tcpflags::SYN | tcpflags::ACK,
+    fn closing_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        trace!("closing handler");
+        let ts_rtt = socket.process_timestamps(packet);
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            // the peer hasn't seen our ACK of its FIN yet
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
                 &[],
             )?;
-            connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
-            connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
-            connection_socket.listening_socket = Some(listening_socket.get_sock_id());
-            dbg!("status: listen -> ", &connection_socket.status);
-            table.insert(connection_socket.get_sock_id(), connection_socket);
         }
-        Ok(())
-    }
-
-    fn synrcvd_handler(
-        &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
-        sock_id: SockID,
-        packet: &TCPPacket,
-    ) -> Result<()> {
-        dbg!("synrcvd handler");
-        let socket = table.get_mut(&sock_id).unwrap();
-
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            return Ok(());
+        }
+        if socket.send_param.unacked_seq < packet.get_ack()
             && packet.get_ack() <= socket.send_param.next
         {
-            socket.recv_param.next = packet.get_seq();
             socket.send_param.unacked_seq = packet.get_ack();
-            socket.status = TcpStatus::Established;
-            dbg!("status: synrcvd ->", &socket.status);
-            if let Some(id) = socket.listening_socket {
-                let ls = table.get_mut(&id).unwrap();
-                ls.connection_established_queue.push_back(sock_id);
-                self.publish_event(ls.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            }
+            self.delete_acked_segment_from_retransmission_queue(socket, ts_rtt, 0)?;
+        }
+        if !socket.fin_pending && socket.send_param.next == socket.send_param.unacked_seq {
+            // our FIN is acknowledged
+            self.enter_time_wait(socket);
         }
         Ok(())
     }
 
-    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32);
-
-        if packet.get_seq() == socket.recv_param.next {
-            socket.recv_param.next = socket.recv_param.tail;
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
-        }
-        if copy_size > 0 {
+    fn enter_time_wait(&self, socket: &mut Socket) {
+        socket.set_status(TcpStatus::TimeWait);
+        socket.time_wait_expiry = Some(self.timers.now() + Duration::from_secs(2 * MSL));
+        self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+    }
+
+    fn timewait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        trace!("timewait handler");
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            // our last ACK was lost and the peer retransmitted its FIN
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
                 tcpflags::ACK,
                 &[],
             )?;
-        } else {
-            dbg!("recv buffer overflow");
+            socket.time_wait_expiry = Some(self.timers.now() + Duration::from_secs(2 * MSL));
         }
-        self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
         Ok(())
     }
 
-    pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
+    // TCP-AO: connections with `key.peer` opened from now on are signed with the first MKT
+    // they match and take only segments one of their MKTs authenticates. connections with
+    // the peer that are authenticated already take it as well, to move to it later
+    pub fn add_ao_key(&self, key: AoKey) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
-        let mut socket = table
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?;
-        let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
-        while received_size == 0 {
-            match socket.status {
-                TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::TimeWait => break,
-                _ => {}
+        let mut keys = self.ao_keys.lock().unwrap();
+        if keys.iter().any(|other| {
+            other.overlaps(&key) && (other.send_id == key.send_id || other.recv_id == key.recv_id)
+        }) {
+            return Err(io_error(
+                io::ErrorKind::AlreadyExists,
+                "an MKT with the same KeyID exists for the peer",
+            ));
+        }
+        for socket in table.values_mut() {
+            if let Some(ao) = socket
+                .ao
+                .as_mut()
+                .filter(|_| key.matches(socket.remote_addr))
+            {
+                ao.add_key(key.clone());
             }
-            drop(table);
-            dbg!("waiting incoming data");
-            self.wait_event(sock_id, TCPEventKind::DataArrived);
-            table = self.sockets.write().unwrap();
-            socket = table
-                .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
-            received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
         }
-        let copy_size = cmp::min(buffer.len(), received_size);
-        buffer[..copy_size].copy_from_slice(&socket.recv_buffer[..copy_size]);
-        socket.recv_buffer.copy_within(copy_size.., 0);
-        socket.recv_param.window += copy_size as u16;
-        Ok(copy_size)
+        keys.push(key);
+        Ok(())
     }
 
-    pub fn close(&self, sock_id: SockID) -> Result<()> {
+    // fails while a connection still signs with the MKT
+    pub fn remove_ao_key(&self, peer: IpAddr, send_id: u8) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
-        let mut socket = table
+        let mut keys = self.ao_keys.lock().unwrap();
+        let position = keys
+            .iter()
+            .position(|key| key.peer == peer && key.send_id == send_id)
+            .ok_or_else(|| io_error(io::ErrorKind::NotFound, "no such MKT"))?;
+        let key = &keys[position];
+        let sockets = || {
+            table
+                .values()
+                .filter(|socket| key.matches(socket.remote_addr))
+                .filter_map(|socket| socket.ao.as_ref())
+        };
+        if sockets().any(|ao| ao.key_ids().0 == send_id) {
+            return Err(io_error(
+                io::ErrorKind::InvalidInput,
+                "the MKT still signs a connection",
+            ));
+        }
+        for socket in table.values_mut() {
+            if let Some(ao) = socket
+                .ao
+                .as_mut()
+                .filter(|_| key.matches(socket.remote_addr))
+            {
+                ao.remove_key(send_id);
+            }
+        }
+        keys.remove(position);
+        Ok(())
+    }
+
+    // ask the peer of an authenticated connection to sign with the MKT of `recv_id` from now
+    // on. the peer in turn has us move to another MKT with its RNextKeyID
+    pub fn set_ao_rnext(&self, sock_id: SockID, recv_id: u8) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let ao = table
             .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?;
-        socket.send_tcp_packet(
-            socket.send_param.next,
-            socket.recv_param.next,
-            tcpflags::FIN | tcpflags::ACK,
-            &[],
+            .ok_or_else(|| self.no_such_socket(sock_id))?
+            .ao
+            .as_mut()
+            .ok_or_else(|| {
+                io_error(
+                    io::ErrorKind::InvalidInput,
+                    "the connection isn't authenticated",
+                )
+            })?;
+        if !ao.set_rnext(recv_id) {
+            return Err(io_error(io::ErrorKind::NotFound, "no such MKT"));
+        }
+        Ok(())
+    }
+
+    // the TCP-AO state of a new connection with `peer`, None if no MKT matches
+    fn ao_for(&self, peer: Ipv4Addr) -> Option<Authentication> {
+        let keys = self.ao_keys.lock().unwrap();
+        Authentication::new(
+            keys.iter()
+                .filter(|key| key.matches(peer))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    // open a Multipath TCP connection from the first of `local_addrs` and join a subflow
+    // from each of the others. returns our token of the connection
+    pub fn mptcp_connect(&self, local_addrs: &[IpAddr], addr: SocketAddr) -> Result<u32> {
+        let addr = ipv4(addr)?;
+        let local_addrs = local_addrs
+            .iter()
+            .map(|&local_addr| ipv4_addr(local_addr))
+            .collect::<Result<Vec<_>>>()?;
+        let (&first, others) = local_addrs
+            .split_first()
+            .context("no local address to connect from")?;
+        let subflow = {
+            let mut connections = self.mptcp.lock().unwrap();
+            let key = mptcp::new_key(|token| connections.contains_key(&token));
+            connections.insert(mptcp::token(key), MptcpConnection::new(key));
+            Subflow::new(key)
+        };
+        let token = subflow.token;
+        let result = self
+            .open(
+                SocketAddrV4::new(first, 0),
+                *addr.ip(),
+                addr.port(),
+                BufferSizes::default(),
+                Some(subflow),
+                None,
+            )
+            .and_then(|sock_id| {
+                if let Some(connection) = self.mptcp.lock().unwrap().get_mut(&token) {
+                    connection.subflows.push(sock_id);
+                }
+                self.wait_event(sock_id, TCPEventKind::ConnectionCompleted)
+            });
+        if let Err(error) = result {
+            self.mptcp.lock().unwrap().remove(&token);
+            return Err(error);
+        }
+        for &local_addr in others {
+            if let Err(error) = self.mptcp_join(token, local_addr) {
+                warn!(%local_addr, %error, "failed to join a subflow");
+            }
+        }
+        Ok(token)
+    }
+
+    // open another subflow from `local_addr` to where the first one goes. it carries data
+    // once the peer has confirmed the join
+    fn mptcp_join(&self, token: u32, local_addr: Ipv4Addr) -> Result<()> {
+        let (subflow, addr, port) = {
+            let table = self.sockets.read().unwrap();
+            let mut connections = self.mptcp.lock().unwrap();
+            let connection = connections.get_mut(&token).ok_or_else(no_such_connection)?;
+            let remote_key = match connection.remote_key {
+                Some(remote_key) if !connection.fallback => remote_key,
+                _ => {
+                    return Err(io_error(
+                        io::ErrorKind::Unsupported,
+                        "the peer doesn't support MPTCP",
+                    ))
+                }
+            };
+            let first = connection
+                .subflows
+                .first()
+                .and_then(|sock_id| table.get(sock_id))
+                .ok_or_else(no_such_connection)?;
+            let (addr, port) = (first.remote_addr, first.remote_port);
+            let join = Join {
+                address_id: connection.next_address_id(),
+                local_nonce: rand::random(),
+                remote_nonce: 0,
+            };
+            let subflow = Subflow::joining(connection.local_key, remote_key, join);
+            (subflow, addr, port)
+        };
+        let sock_id = self.open(
+            SocketAddrV4::new(local_addr, 0),
+            addr,
+            port,
+            BufferSizes::default(),
+            Some(subflow),
+            None,
         )?;
-        socket.send_param.next += 1;
-        match socket.status {
-            TcpStatus::Established => {
-                socket.status = TcpStatus::FinWait1;
+        if let Some(connection) = self.mptcp.lock().unwrap().get_mut(&token) {
+            connection.subflows.push(sock_id);
+        }
+        Ok(())
+    }
+
+    // accept on a listener with SocketOption::Multipath set. a peer without MPTCP gets a
+    // connection of a single plain TCP subflow
+    pub fn mptcp_accept(&self, listener: SockID) -> Result<u32> {
+        let sock_id = self.accept(listener)?;
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.no_such_socket(sock_id))?;
+        if let Some(subflow) = &socket.mptcp {
+            return Ok(subflow.token);
+        }
+        let mut connections = self.mptcp.lock().unwrap();
+        let key = mptcp::new_key(|token| connections.contains_key(&token));
+        let mut connection = MptcpConnection::new(key);
+        connection.fallback = true;
+        connection.subflows.push(sock_id);
+        connections.insert(mptcp::token(key), connection);
+        Ok(mptcp::token(key))
+    }
+
+    // write `buffer` to the connection, chunk by chunk onto the subflow that gets it out
+    // soonest. waits only for room in the send buffers, like send
+    pub fn mptcp_send(&self, token: u32, buffer: &[u8]) -> Result<usize> {
+        let mut cursor = 0;
+        loop {
+            let generation = self.readiness_generation();
+            let mut table = self.sockets.write().unwrap();
+            let mut connections = self.mptcp.lock().unwrap();
+            let connection = connections.get_mut(&token).ok_or_else(no_such_connection)?;
+            connection
+                .subflows
+                .retain(|sock_id| table.contains_key(sock_id));
+            let first = *connection
+                .subflows
+                .first()
+                .ok_or_else(|| io_error(io::ErrorKind::NotConnected, "no subflow left"))?;
+            if connection.fallback {
+                drop(connections);
                 drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
+                return Ok(cursor + self.send(first, &buffer[cursor..])?);
             }
-            TcpStatus::CloseWait => {
-                socket.status = TcpStatus::LastAck;
+            while cursor < buffer.len() {
+                let sock_id = match pick_subflow(&table, &connection.subflows) {
+                    Some(sock_id) => sock_id,
+                    None => break,
+                };
+                let socket = table.get_mut(&sock_id).unwrap();
+                // what the congestion window lets out right away, the rest goes to the
+                // next pick
+                let room = socket
+                    .sendable_size()
+                    .saturating_sub(socket.send_buffer.len());
+                let size = cmp::min(
+                    cmp::min(socket.send_buffer_space(), buffer.len() - cursor),
+                    cmp::min(if room > 0 { room } else { usize::MAX }, u16::MAX as usize),
+                );
+                let subflow_seq = SeqNum(
+                    socket.send_param.next + socket.send_buffer.len() as u32
+                        - socket.send_param.initial_seq,
+                );
+                socket.mptcp.as_mut().unwrap().map(Mapping {
+                    data_seq: connection.send_next,
+                    subflow_seq,
+                    len: size as u16,
+                });
+                connection.send_next = connection.send_next.wrapping_add(size as u64);
+                socket.send_buffer.extend(&buffer[cursor..cursor + size]);
+                cursor += size;
+                socket.transmit(false)?;
+            }
+            if cursor == buffer.len() {
+                return Ok(cursor);
+            }
+            trace!("no subflow has room");
+            drop(connections);
+            drop(table);
+            self.wait_readiness_change(generation, None);
+        }
+    }
+
+    // read what has arrived in order at the connection level. returns 0 once every subflow
+    // has received the peer's FIN and all of it was read
+    pub fn mptcp_recv(&self, token: u32, buffer: &mut [u8]) -> Result<usize> {
+        loop {
+            let generation = self.readiness_generation();
+            let mut table = self.sockets.write().unwrap();
+            let mut connections = self.mptcp.lock().unwrap();
+            let connection = connections.get_mut(&token).ok_or_else(no_such_connection)?;
+            connection
+                .subflows
+                .retain(|sock_id| table.contains_key(sock_id));
+            for sock_id in connection.subflows.clone() {
+                pull_subflow(connection, table.get_mut(&sock_id).unwrap())?;
+            }
+            if connection.fallback {
+                let first = *connection
+                    .subflows
+                    .first()
+                    .ok_or_else(|| io_error(io::ErrorKind::NotConnected, "no subflow left"))?;
+                drop(connections);
                 drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
+                return self.recv(first, buffer);
             }
-            TcpStatus::Listen => {
-                table.remove(&sock_id);
+            for sock_id in &connection.subflows {
+                if let Some(subflow) = table.get_mut(sock_id).unwrap().mptcp.as_mut() {
+                    subflow.data_ack = Some(connection.recv_next);
+                }
+            }
+            if !connection.received.is_empty() {
+                let size = cmp::min(buffer.len(), connection.received.len());
+                for (slot, byte) in buffer.iter_mut().zip(connection.received.drain(..size)) {
+                    *slot = byte;
+                }
+                return Ok(size);
+            }
+            let finished = connection.subflows.iter().all(|sock_id| {
+                let socket = &table[sock_id];
+                matches!(socket.status, TcpStatus::SynSent | TcpStatus::SynRcvd)
+                    || (socket.fin_received()
+                        && socket.recv_buffer.len() == socket.recv_param.window as usize)
+            });
+            if finished {
+                return Ok(0);
+            }
+            drop(connections);
+            drop(table);
+            self.wait_readiness_change(generation, None);
+        }
+    }
+
+    // close every subflow and forget the connection
+    pub fn mptcp_close(&self, token: u32) -> Result<()> {
+        let connection = self
+            .mptcp
+            .lock()
+            .unwrap()
+            .remove(&token)
+            .ok_or_else(no_such_connection)?;
+        for sock_id in connection.subflows {
+            if let Err(error) = self.close(sock_id) {
+                warn!(%sock_id, %error, "failed to close a subflow");
             }
-            _ => return Ok(()),
         }
         Ok(())
     }
 
-    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("closewait | lastack handler");
-        socket.send_param.unacked_seq = packet.get_ack();
+    pub fn mptcp_subflows(&self, token: u32) -> Result<Vec<SockID>> {
+        let connections = self.mptcp.lock().unwrap();
+        Ok(connections
+            .get(&token)
+            .ok_or_else(no_such_connection)?
+            .subflows
+            .clone())
+    }
+
+    // consume an event of `kind` for the socket, waiting until one is published.
+    // events of other kinds or for other sockets are left queued for their own waiters.
+    // fails if the socket is removed from the table while waiting.
+    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) -> Result<()> {
+        self.wait_event_until(sock_id, kind, None)?;
         Ok(())
     }
 
-    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("finwait handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            return Ok(());
+    // returns false if no event arrived within timeout
+    fn wait_event_timeout(
+        &self,
+        sock_id: SockID,
+        kind: TCPEventKind,
+        timeout: Duration,
+    ) -> Result<bool> {
+        self.wait_event_until(sock_id, kind, Some(Instant::now() + timeout))
+    }
+
+    fn wait_event_until(
+        &self,
+        sock_id: SockID,
+        kind: TCPEventKind,
+        deadline: Option<Instant>,
+    ) -> Result<bool> {
+        let (lock, cvar) = &self.event_condvar;
+        let mut events = lock.lock().unwrap();
+        events.entry(sock_id).or_default().waiters += 1;
+        let result = loop {
+            // the entry is kept while there are waiters
+            let queue = events.get_mut(&sock_id).unwrap();
+            if let Some(reason) = queue.removed {
+                break Err(io_error(reason, "socket was closed while waiting"));
+            }
+            if let Some(position) = queue.pending.iter().position(|k| *k == kind) {
+                queue.pending.remove(position);
+                trace!(%sock_id, ?kind, "event");
+                break Ok(true);
+            }
+            events = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Ok(false);
+                    }
+                    cvar.wait_timeout(events, deadline - now).unwrap().0
+                }
+                None => cvar.wait(events).unwrap(),
+            };
+        };
+        let queue = events.get_mut(&sock_id).unwrap();
+        queue.waiters -= 1;
+        if queue.waiters == 0 && queue.removed.is_some() {
+            events.remove(&sock_id);
         }
-        if packet.get_flag() & tcpflags::ACK == 0 {
-            return Ok(());
+        result
+    }
+
+    fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
+        let (lock, cvar) = &self.event_condvar;
+        let mut events = lock.lock().unwrap();
+        let queue = events.entry(sock_id).or_default();
+        if !queue.pending.contains(&kind) {
+            queue.pending.push_back(kind);
         }
-        if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
+        cvar.notify_all();
+        drop(events);
+        self.notify_readiness(sock_id);
+    }
+
+    #[cfg_attr(not(feature = "tokio"), allow(unused_variables))]
+    fn notify_readiness(&self, sock_id: SockID) {
+        let (lock, cvar) = &self.readiness_condvar;
+        let mut generation = lock.lock().unwrap();
+        *generation += 1;
+        cvar.notify_all();
+        drop(generation);
+
+        #[cfg(feature = "tokio")]
+        if let Some(wakers) = self.wakers.lock().unwrap().remove(&sock_id) {
+            wakers.into_iter().for_each(Waker::wake);
         }
+    }
 
-        if socket.status == TcpStatus::FinWait1
-            && socket.send_param.next == socket.send_param.unacked_seq
-        {
-            socket.status = TcpStatus::FinWait2;
-            dbg!("status: finwait1 ->", &socket.status);
+    #[cfg(feature = "tokio")]
+    pub(crate) fn register_waker(&self, sock_id: SockID, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        let wakers = wakers.entry(sock_id).or_default();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
         }
+    }
 
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next += 1;
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &[],
-            )?;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+    // sockets go in and out of the table through these two, which count the sockets on each
+    // local port. the device is told whenever a port comes into use or goes out of it
+    fn insert_socket(&self, table: &mut HashMap<SockID, Socket>, sock_id: SockID, socket: Socket) {
+        if table.insert(sock_id, socket).is_none() {
+            let mut ports = self.ports.lock().unwrap();
+            let count = ports.entry(sock_id.2).or_default();
+            *count += 1;
+            if *count == 1 {
+                self.filter_ports(&ports);
+            }
         }
-        Ok(())
     }
 
-    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) {
-        let (lock, cvar) = &self.event_condvar;
-        let mut event = lock.lock().unwrap();
-        loop {
-            if let Some(ref e) = *event {
-                if e.sock_id == sock_id && e.kind == kind {
-                    break;
-                }
+    fn remove_socket(
+        &self,
+        table: &mut HashMap<SockID, Socket>,
+        sock_id: SockID,
+    ) -> Option<Socket> {
+        let socket = table.remove(&sock_id)?;
+        let mut ports = self.ports.lock().unwrap();
+        if let Some(count) = ports.get_mut(&sock_id.2) {
+            *count -= 1;
+            if *count == 0 {
+                ports.remove(&sock_id.2);
+                self.filter_ports(&ports);
             }
-            event = cvar.wait(event).unwrap();
         }
-        dbg!(&event);
-        *event = None;
+        Some(socket)
     }
 
-    fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
+    fn filter_ports(&self, ports: &HashMap<u16, usize>) {
+        let ports: Vec<u16> = ports.keys().copied().collect();
+        if let Err(error) = self.device.filter_ports(&ports) {
+            warn!(%error, "failed to filter ports");
+        }
+    }
+
+    // tear a connection down on the stack's initiative (refused, reset, timed out).
+    // blocked callers, or else the next call on the socket, fail with `reason`.
+    fn terminate(
+        &self,
+        table: &mut HashMap<SockID, Socket>,
+        sock_id: SockID,
+        reason: io::ErrorKind,
+    ) {
+        self.remove_socket(table, sock_id);
         let (lock, cvar) = &self.event_condvar;
-        let mut e = lock.lock().unwrap();
-        *e = Some(TCPEvent::new(sock_id, kind));
+        let mut events = lock.lock().unwrap();
+        let queue = events.entry(sock_id).or_default();
+        queue.pending.clear();
+        queue.removed = Some(reason);
         cvar.notify_all();
+        drop(events);
+        self.notify_readiness(sock_id);
+    }
+
+    // the span of the connection a user call works on, none if the socket is unknown
+    fn span_of(&self, sock_id: SockID) -> Span {
+        self.sockets
+            .read()
+            .unwrap()
+            .get(&sock_id)
+            .map_or_else(Span::none, |socket| socket.span.clone())
+    }
+
+    // error for a socket missing from the table, carrying the reason it was terminated.
+    // the reason is reported once, after that the socket is simply unknown.
+    fn no_such_socket(&self, sock_id: SockID) -> anyhow::Error {
+        let mut events = self.event_condvar.0.lock().unwrap();
+        if let Some(reason) = events.get(&sock_id).and_then(|queue| queue.removed) {
+            if events[&sock_id].waiters == 0 {
+                events.remove(&sock_id);
+            }
+            return io_error(reason, "connection was terminated");
+        }
+        anyhow::anyhow!("no such socket: {:?}", sock_id)
+    }
+
+    // drop what is left from a previous socket with the same id
+    fn clear_events(&self, sock_id: SockID) {
+        let mut events = self.event_condvar.0.lock().unwrap();
+        if events.get(&sock_id).is_some_and(|queue| queue.waiters == 0) {
+            events.remove(&sock_id);
+        }
+    }
+
+    // forget pending events of a socket removed from the table.
+    // threads still waiting on it wake up with an error of `reason`.
+    fn discard_events(&self, sock_id: SockID, reason: io::ErrorKind) {
+        let (lock, cvar) = &self.event_condvar;
+        let mut events = lock.lock().unwrap();
+        if let Some(queue) = events.get_mut(&sock_id) {
+            if queue.waiters > 0 {
+                queue.pending.clear();
+                queue.removed = Some(reason);
+                cvar.notify_all();
+            } else {
+                events.remove(&sock_id);
+            }
+        }
+        drop(events);
+        // let pollers and waiting tasks observe that the socket is gone
+        self.notify_readiness(sock_id);
+    }
+
+    // current readiness of the socket as poll::READABLE | poll::WRITABLE | poll::ERROR
+    pub(crate) fn readiness(&self, sock_id: SockID) -> u8 {
+        let table = self.sockets.read().unwrap();
+        match table.get(&sock_id) {
+            Some(socket) => socket.readiness(),
+            None => poll::ERROR,
+        }
+    }
+
+    pub(crate) fn readiness_generation(&self) -> u64 {
+        *self.readiness_condvar.0.lock().unwrap()
+    }
+
+    // block until an event is published after `generation` was taken, or the timeout expires
+    pub(crate) fn wait_readiness_change(&self, generation: u64, timeout: Option<Duration>) {
+        let (lock, cvar) = &self.readiness_condvar;
+        let current = lock.lock().unwrap();
+        match timeout {
+            Some(timeout) => drop(
+                cvar.wait_timeout_while(current, timeout, |current| *current == generation)
+                    .unwrap(),
+            ),
+            None => drop(
+                cvar.wait_while(current, |current| *current == generation)
+                    .unwrap(),
+            ),
+        }
+    }
+}
+
+// the stack speaks IPv4 only
+fn ipv4_addr(addr: IpAddr) -> Result<Ipv4Addr> {
+    match addr {
+        IpAddr::V4(addr) => Ok(addr),
+        IpAddr::V6(_) => Err(io_error(
+            io::ErrorKind::Unsupported,
+            "IPv6 is not supported",
+        )),
+    }
+}
+
+fn ipv4(addr: SocketAddr) -> Result<SocketAddrV4> {
+    Ok(SocketAddrV4::new(ipv4_addr(addr.ip())?, addr.port()))
+}
+
+fn no_such_connection() -> anyhow::Error {
+    io_error(io::ErrorKind::NotConnected, "no such multipath connection")
+}
+
+// the subflow for the next chunk of data: one whose congestion window lets it out right
+// away over one whose doesn't, and the shortest round trip among those
+fn pick_subflow(table: &HashMap<SockID, Socket>, subflows: &[SockID]) -> Option<SockID> {
+    subflows
+        .iter()
+        .copied()
+        .filter(|sock_id| {
+            let socket = &table[sock_id];
+            matches!(socket.status, TcpStatus::Established | TcpStatus::CloseWait)
+                && socket.send_buffer_space() > 0
+                && socket.mptcp.as_ref().is_some_and(Subflow::can_send)
+        })
+        .min_by_key(|sock_id| {
+            let socket = &table[sock_id];
+            (
+                socket.sendable_size() <= socket.send_buffer.len(),
+                socket.rtt.srtt().unwrap_or(Duration::MAX),
+            )
+        })
+}
+
+// move the data of a subflow whose mappings have arrived into the connection
+fn pull_subflow(connection: &mut MptcpConnection, socket: &mut Socket) -> Result<()> {
+    loop {
+        let unread = socket.recv_buffer.len() - socket.recv_param.window as usize;
+        if unread == 0 {
+            return Ok(());
+        }
+        // the first unread byte, relative to the peer's ISN. RCV.NXT is past the FIN
+        let subflow_seq = SeqNum(
+            socket.recv_param.next
+                - socket.fin_received() as u32
+                - unread as u32
+                - socket.recv_param.initial_seq,
+        );
+        let subflow = match socket.mptcp.as_mut() {
+            Some(subflow) => subflow,
+            None => return Ok(()),
+        };
+        match subflow.data_seq_at(subflow_seq) {
+            Some((data_seq, len)) => {
+                if !connection.accepts(data_seq) {
+                    return Ok(());
+                }
+                let mut bytes = vec![0; cmp::min(len, unread)];
+                socket.take_received(&mut bytes)?;
+                connection.insert(data_seq, bytes);
+            }
+            None if subflow.is_unmapped() && connection.subflows.len() == 1 => {
+                debug!(sock_id = %socket.get_sock_id(), "data without a mapping, plain TCP");
+                connection.fallback = true;
+                socket.mptcp = None;
+                return Ok(());
+            }
+            None => return Ok(()),
+        }
     }
 }
 
-fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ip route get {} | grep src", addr))
-        .output()?;
-    let mut output = str::from_utf8(&output.stdout)?
-        .trim()
-        .split_ascii_whitespace();
-    while let Some(s) = output.next() {
-        if s == "src" {
-            break;
-        }
-    }
-    let ip = output.next().context("failed to get src ip")?;
-    dbg!("source addr", ip);
-    ip.parse().context("failed to parse source ip")
+// io::Error wrapped so that stream wrappers can hand the kind back to callers
+fn io_error(kind: io::ErrorKind, reason: &str) -> anyhow::Error {
+    io::Error::new(kind, reason.to_string()).into()
+}
+
+fn get_device(ifname: &str) -> Result<Device> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == ifname)
+        .ok_or_else(|| io_error(io::ErrorKind::NotFound, "no such device"))?;
+    let addrs = interface
+        .ips
+        .iter()
+        .filter_map(|network| match network.ip() {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    Ok(Device {
+        name: interface.name,
+        addrs,
+    })
 }
@@ -0,0 +1,167 @@
+use crate::mptcp::{self, MptcpOption};
+use crate::seq::SeqNum;
+use std::cmp;
+use std::time::Duration;
+
+pub const END: u8 = 0;
+pub const NOP: u8 = 1;
+pub const MAX_SEGMENT_SIZE: u8 = 2;
+pub const WINDOW_SCALE: u8 = 3;
+pub const SACK_PERMITTED: u8 = 4;
+pub const SACK: u8 = 5;
+pub const TIMESTAMPS: u8 = 8;
+pub const USER_TIMEOUT: u8 = 28;
+pub const AUTHENTICATION: u8 = 29;
+pub const MPTCP: u8 = 30;
+
+// granularity bit of the user timeout option: minutes instead of seconds
+const UTO_MINUTES: u16 = 1 << 15;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    // largest segment the sender is willing to receive, only on SYN
+    MaxSegmentSize(u16),
+    // shift count applied to every window the sender advertises after the SYN (RFC 7323)
+    WindowScale(u8),
+    SackPermitted,
+    // received blocks above the cumulative ACK as [left edge, right edge)
+    Sack(Vec<(SeqNum, SeqNum)>),
+    // TSval of the sender and the latest TSval it received (RFC 7323)
+    Timestamps {
+        value: u32,
+        echo_reply: u32,
+    },
+    // how long the sender waits for unacknowledged data before giving up (RFC 5482)
+    UserTimeout(Duration),
+    // Multipath TCP (RFC 8684), whose subtypes mptcp.rs handles
+    Mptcp(MptcpOption),
+    // TCP-AO (RFC 5925): the MKTs the sender signs with and wants to receive with, and the
+    // MAC, which ao.rs computes over the segment
+    Authentication {
+        key_id: u8,
+        rnext_key_id: u8,
+        mac: Vec<u8>,
+    },
+}
+
+// options we don't know are skipped, a malformed length ends parsing
+pub fn parse(mut bytes: &[u8]) -> Vec<TcpOption> {
+    let mut options = Vec::new();
+    while let Some(&kind) = bytes.first() {
+        match kind {
+            END => break,
+            NOP => {
+                bytes = &bytes[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let len = match bytes.get(1) {
+            Some(&len) if len >= 2 && len as usize <= bytes.len() => len as usize,
+            _ => break,
+        };
+        let data = &bytes[2..len];
+        match kind {
+            MAX_SEGMENT_SIZE if data.len() == 2 => {
+                options.push(TcpOption::MaxSegmentSize(u16::from_be_bytes([
+                    data[0], data[1],
+                ])))
+            }
+            WINDOW_SCALE if data.len() == 1 => options.push(TcpOption::WindowScale(data[0])),
+            SACK_PERMITTED => options.push(TcpOption::SackPermitted),
+            SACK => options.push(TcpOption::Sack(
+                data.chunks_exact(8)
+                    .map(|block| (read_seq(&block[..4]), read_seq(&block[4..])))
+                    .collect(),
+            )),
+            TIMESTAMPS if data.len() == 8 => options.push(TcpOption::Timestamps {
+                value: read_seq(&data[..4]).0,
+                echo_reply: read_seq(&data[4..]).0,
+            }),
+            USER_TIMEOUT if data.len() == 2 => {
+                let value = u16::from_be_bytes([data[0], data[1]]);
+                let timeout = (value & !UTO_MINUTES) as u64;
+                options.push(TcpOption::UserTimeout(if value & UTO_MINUTES > 0 {
+                    Duration::from_secs(timeout * 60)
+                } else {
+                    Duration::from_secs(timeout)
+                }))
+            }
+            AUTHENTICATION if data.len() >= 2 => options.push(TcpOption::Authentication {
+                key_id: data[0],
+                rnext_key_id: data[1],
+                mac: data[2..].to_vec(),
+            }),
+            MPTCP => {
+                if let Some(option) = mptcp::parse(data) {
+                    options.push(TcpOption::Mptcp(option));
+                }
+            }
+            _ => {}
+        }
+        bytes = &bytes[len..];
+    }
+    options
+}
+
+// aligned with NOPs in front of each option and END at the tail to a multiple of 4 bytes
+pub fn serialize(options: &[TcpOption]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for option in options {
+        match option {
+            TcpOption::MaxSegmentSize(mss) => {
+                bytes.extend_from_slice(&[MAX_SEGMENT_SIZE, 4]);
+                bytes.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                bytes.extend_from_slice(&[NOP, WINDOW_SCALE, 3, *shift])
+            }
+            TcpOption::SackPermitted => bytes.extend_from_slice(&[NOP, NOP, SACK_PERMITTED, 2]),
+            TcpOption::Sack(blocks) => {
+                bytes.extend_from_slice(&[NOP, NOP, SACK, 2 + 8 * blocks.len() as u8]);
+                for (left, right) in blocks {
+                    bytes.extend_from_slice(&left.0.to_be_bytes());
+                    bytes.extend_from_slice(&right.0.to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { value, echo_reply } => {
+                bytes.extend_from_slice(&[NOP, NOP, TIMESTAMPS, 10]);
+                bytes.extend_from_slice(&value.to_be_bytes());
+                bytes.extend_from_slice(&echo_reply.to_be_bytes());
+            }
+            TcpOption::UserTimeout(timeout) => {
+                // 15 bits of seconds, or of minutes for longer timeouts
+                let seconds = timeout.as_secs();
+                let value = if seconds < UTO_MINUTES as u64 {
+                    seconds as u16
+                } else {
+                    UTO_MINUTES | cmp::min(seconds / 60, (UTO_MINUTES - 1) as u64) as u16
+                };
+                bytes.extend_from_slice(&[NOP, NOP, USER_TIMEOUT, 4]);
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+            TcpOption::Mptcp(option) => mptcp::serialize(option, &mut bytes),
+            TcpOption::Authentication {
+                key_id,
+                rnext_key_id,
+                mac,
+            } => {
+                bytes.extend_from_slice(&[
+                    AUTHENTICATION,
+                    4 + mac.len() as u8,
+                    *key_id,
+                    *rnext_key_id,
+                ]);
+                bytes.extend_from_slice(mac);
+            }
+        }
+    }
+    while bytes.len() % 4 != 0 {
+        bytes.push(END);
+    }
+    bytes
+}
+
+fn read_seq(bytes: &[u8]) -> SeqNum {
+    SeqNum(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
@@ -0,0 +1,317 @@
+use crate::clock::Clock;
+use crate::socket::SockID;
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+const TICK: Duration = Duration::from_millis(10);
+const SLOT_BITS: u32 = 6;
+const SLOTS: u64 = 1 << SLOT_BITS;
+// 64^4 ticks of 10ms is about 46 hours, farther timers are filed at the edge and refiled
+const LEVELS: usize = 4;
+
+/// the timers a socket can arm, one of each kind at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerKind {
+    Retransmission,
+    DelayedAck,
+    Persist,
+    Keepalive,
+    UserTimeout,
+    // held-back data may leave once the pacer releases it
+    Pacing,
+    TimeWait,
+    // RACK: a segment overtaken by a later one is lost unless it shows up by then
+    Reordering,
+    TailLossProbe,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    sock_id: SockID,
+    kind: TimerKind,
+    tick: u64,
+}
+
+/// hierarchical timing wheel: a slot of level n spans 64^n ticks, and its timers cascade
+/// into the finer level below once the wheel reaches it. arming, moving and expiring a
+/// timer costs the same however many are armed, and the thread driving the wheel wakes
+/// up only for ticks that have something to do.
+#[derive(Debug)]
+struct TimerWheel {
+    start: SystemTime, // tick 0
+    current_tick: u64, // every tick up to here has been processed
+    levels: Vec<Vec<Vec<Entry>>>,
+    // the tick each armed timer expires at. a timer moved later keeps its entry, which is
+    // filed again when it comes up; entries of cancelled timers and of timers moved earlier
+    // are dropped then
+    armed: HashMap<(SockID, TimerKind), u64>,
+}
+
+impl TimerWheel {
+    fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            current_tick: 0,
+            levels: vec![vec![Vec::new(); SLOTS as usize]; LEVELS],
+            armed: HashMap::new(),
+        }
+    }
+
+    // the first tick at or after `deadline`, so that a timer never fires early
+    fn tick_at(&self, deadline: SystemTime) -> u64 {
+        let elapsed = deadline.duration_since(self.start).unwrap_or_default();
+        elapsed.as_nanos().div_ceil(TICK.as_nanos()) as u64
+    }
+
+    // the last tick that has come by `now`
+    fn tick_passed(&self, now: SystemTime) -> u64 {
+        let elapsed = now.duration_since(self.start).unwrap_or_default();
+        (elapsed.as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn time_of(&self, tick: u64) -> SystemTime {
+        self.start + Duration::from_nanos(TICK.as_nanos() as u64 * tick)
+    }
+
+    fn arm(&mut self, sock_id: SockID, kind: TimerKind, deadline: SystemTime) {
+        let tick = cmp::max(self.tick_at(deadline), self.current_tick + 1);
+        // keepalives move on every segment, without filing anything
+        if self
+            .armed
+            .insert((sock_id, kind), tick)
+            .is_some_and(|armed| armed <= tick)
+        {
+            return;
+        }
+        self.file(Entry {
+            sock_id,
+            kind,
+            tick,
+        });
+    }
+
+    fn cancel(&mut self, sock_id: SockID, kind: TimerKind) {
+        self.armed.remove(&(sock_id, kind));
+    }
+
+    // put an entry into the slot covering its tick, at the coarsest level it needs
+    fn file(&mut self, entry: Entry) {
+        let delta = entry.tick.saturating_sub(self.current_tick);
+        let level = (0..LEVELS)
+            .find(|&level| delta < SLOTS << (SLOT_BITS * level as u32))
+            .unwrap_or(LEVELS - 1);
+        let max_tick = self.current_tick + (SLOTS << (SLOT_BITS * (LEVELS as u32 - 1))) - 1;
+        let tick = entry.tick.clamp(self.current_tick, max_tick);
+        let slot = (tick >> (SLOT_BITS * level as u32)) % SLOTS;
+        self.levels[level][slot as usize].push(entry);
+    }
+
+    // process every tick up to `tick`, returning the timers that expired
+    fn advance(&mut self, tick: u64) -> Vec<(SockID, TimerKind)> {
+        let mut expired = Vec::new();
+        while self.current_tick < tick {
+            self.current_tick += 1;
+            let now = self.current_tick;
+            // coarser slots whose span starts now move down a level, the coarsest first
+            let cascading = (1..LEVELS)
+                .take_while(|&level| now.is_multiple_of(1 << (SLOT_BITS * level as u32)))
+                .last();
+            if let Some(top) = cascading {
+                for level in (1..=top).rev() {
+                    let slot = (now >> (SLOT_BITS * level as u32)) % SLOTS;
+                    for entry in std::mem::take(&mut self.levels[level][slot as usize]) {
+                        self.file(entry);
+                    }
+                }
+            }
+            let slot = now % SLOTS;
+            for entry in std::mem::take(&mut self.levels[0][slot as usize]) {
+                let armed = match self.armed.get(&(entry.sock_id, entry.kind)) {
+                    Some(&armed) if armed >= entry.tick => armed,
+                    _ => continue,
+                };
+                if armed > now {
+                    // moved later, or beyond the reach of the wheel when it was filed
+                    self.file(Entry {
+                        tick: armed,
+                        ..entry
+                    });
+                    continue;
+                }
+                self.armed.remove(&(entry.sock_id, entry.kind));
+                expired.push((entry.sock_id, entry.kind));
+            }
+        }
+        expired
+    }
+
+    // the next tick worth waking up for: a filled slot of the finest level, or else the
+    // next cascade of the coarser ones
+    fn next_tick(&self) -> Option<u64> {
+        if self.armed.is_empty() {
+            return None;
+        }
+        let now = self.current_tick;
+        let filled = (1..=SLOTS)
+            .map(|offset| now + offset)
+            .find(|tick| !self.levels[0][(tick % SLOTS) as usize].is_empty());
+        let cascade = self.levels[1..]
+            .iter()
+            .flatten()
+            .any(|slot| !slot.is_empty())
+            .then_some((now / SLOTS + 1) * SLOTS);
+        filled.into_iter().chain(cascade).min()
+    }
+}
+
+/// the timers of every socket on `clock`, driven by the timer thread, or by whoever moves
+/// a virtual clock with `take_expired`
+pub struct TimerQueue {
+    wheel: Mutex<TimerWheel>,
+    changed: Condvar,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimerQueue {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            wheel: Mutex::new(TimerWheel::new(clock.now())),
+            changed: Condvar::new(),
+            clock,
+        }
+    }
+
+    // the time on the clock the timers run on
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    // arm the `kind` timer of the socket, moving it if it is armed already
+    pub fn schedule(&self, sock_id: SockID, kind: TimerKind, deadline: SystemTime) {
+        let mut wheel = self.wheel.lock().unwrap();
+        wheel.arm(sock_id, kind, deadline);
+        // the sleeping timer thread may have to wake up earlier now
+        self.changed.notify_one();
+    }
+
+    pub fn cancel(&self, sock_id: SockID, kind: TimerKind) {
+        self.wheel.lock().unwrap().cancel(sock_id, kind);
+    }
+
+    // take the timers that have expired by now, without waiting
+    pub fn take_expired(&self) -> Vec<(SockID, TimerKind)> {
+        let mut wheel = self.wheel.lock().unwrap();
+        let tick = wheel.tick_passed(self.clock.now());
+        wheel.advance(tick)
+    }
+
+    // wait until some timers expire and take them, on a clock that moves by itself
+    pub fn wait_expired(&self) -> Vec<(SockID, TimerKind)> {
+        let mut wheel = self.wheel.lock().unwrap();
+        loop {
+            let tick = wheel.tick_passed(self.clock.now());
+            let expired = wheel.advance(tick);
+            if !expired.is_empty() {
+                return expired;
+            }
+            wheel = match wheel.next_tick() {
+                Some(next) => {
+                    let timeout = wheel
+                        .time_of(next)
+                        .duration_since(self.clock.now())
+                        .unwrap_or_default();
+                    self.changed.wait_timeout(wheel, timeout).unwrap().0
+                }
+                None => self.changed.wait(wheel).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::UNIX_EPOCH;
+
+    const SOCK_ID: SockID = SockID(
+        Ipv4Addr::new(192, 168, 0, 1),
+        Ipv4Addr::new(192, 0, 2, 1),
+        8080,
+        40000,
+    );
+
+    fn arm(wheel: &mut TimerWheel, kind: TimerKind, tick: u64) {
+        let deadline = wheel.time_of(tick);
+        wheel.arm(SOCK_ID, kind, deadline);
+    }
+
+    // the timers that fire on the way to `until`, each with its tick, one tick at a time
+    fn run(wheel: &mut TimerWheel, until: u64) -> Vec<(TimerKind, u64)> {
+        let mut fired = Vec::new();
+        while wheel.current_tick < until {
+            let tick = wheel.current_tick + 1;
+            fired.extend(
+                wheel
+                    .advance(tick)
+                    .into_iter()
+                    .map(|(_, kind)| (kind, tick)),
+            );
+        }
+        fired
+    }
+
+    #[test]
+    fn timers_fire_on_their_tick_at_every_level() {
+        let mut wheel = TimerWheel::new(UNIX_EPOCH);
+        let timers = [
+            (TimerKind::Retransmission, 63),
+            (TimerKind::DelayedAck, 64),
+            (TimerKind::Persist, 4095),
+            (TimerKind::Keepalive, 4096),
+            (TimerKind::UserTimeout, SLOTS.pow(3) - 1),
+            (TimerKind::TimeWait, SLOTS.pow(3)),
+        ];
+        for (kind, tick) in timers {
+            arm(&mut wheel, kind, tick);
+        }
+        assert_eq!(run(&mut wheel, SLOTS.pow(3) + 1), timers);
+        assert_eq!(wheel.next_tick(), None);
+    }
+
+    #[test]
+    fn moved_and_cancelled_timers() {
+        let mut wheel = TimerWheel::new(UNIX_EPOCH);
+        arm(&mut wheel, TimerKind::Retransmission, 100);
+        arm(&mut wheel, TimerKind::Retransmission, 5000);
+        arm(&mut wheel, TimerKind::DelayedAck, 4200);
+        arm(&mut wheel, TimerKind::DelayedAck, 150);
+        arm(&mut wheel, TimerKind::Persist, 120);
+        wheel.cancel(SOCK_ID, TimerKind::Persist);
+        assert_eq!(
+            run(&mut wheel, 6000),
+            [
+                (TimerKind::DelayedAck, 150),
+                (TimerKind::Retransmission, 5000)
+            ]
+        );
+    }
+
+    #[test]
+    fn timer_beyond_the_horizon_fires_on_time() {
+        let mut wheel = TimerWheel::new(UNIX_EPOCH);
+        let tick = SLOTS.pow(LEVELS as u32) + 1000;
+        arm(&mut wheel, TimerKind::Keepalive, tick);
+        assert!(wheel.advance(tick - 1).is_empty());
+        assert_eq!(wheel.advance(tick), [(SOCK_ID, TimerKind::Keepalive)]);
+    }
+
+    #[test]
+    fn deadline_between_ticks_rounds_up() {
+        let mut wheel = TimerWheel::new(UNIX_EPOCH);
+        wheel.arm(SOCK_ID, TimerKind::Pacing, wheel.time_of(10) + TICK / 2);
+        assert_eq!(run(&mut wheel, 20), [(TimerKind::Pacing, 11)]);
+    }
+}
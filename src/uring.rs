@@ -0,0 +1,467 @@
+use crate::bpf;
+use crate::device::{NetworkDevice, RawSocket};
+use crate::io::RawIpSocket;
+use crate::ip;
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{self, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+// submissions and completions in flight at most: the receives always armed, one per buffer
+// of each receiving socket, and the sends not completed yet
+const RECV_BUFFERS: usize = 16;
+const SEND_SLOTS: usize = 64;
+const RING_ENTRIES: u32 = 256;
+const BUFFER_SIZE: usize = 65535;
+// idle time after which the kernel's submission thread sleeps until woken by io_uring_enter
+const SQ_THREAD_IDLE_MS: u32 = 1000;
+
+const IORING_SETUP_SQPOLL: u32 = 1 << 1;
+const IORING_SQ_NEED_WAKEUP: u32 = 1 << 0;
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_ENTER_SQ_WAKEUP: u32 = 1 << 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_OP_SENDMSG: u8 = 9;
+const IORING_OP_RECV: u8 = 27;
+
+// user data of an entry: what it is for in the high bits, which buffer or slot in the rest
+const RECV: u64 = 1 << 32;
+const SEND: u64 = 2 << 32;
+const INDEX: u64 = (1 << 32) - 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// a region mapped from the ring's fd
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.addr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+// the mappings are shared with the kernel only, and used under the device's locks
+unsafe impl Send for Mapping {}
+
+/// the submission queue, filled by senders and the receiving thread
+struct Submissions {
+    ring: Mapping,
+    sqes: Mapping,
+    offsets: SqringOffsets,
+}
+
+/// the completion queue, drained by whoever holds it
+struct Completions {
+    ring: Mapping,
+    offsets: CqringOffsets,
+    // packets completed but not taken yet
+    received: VecDeque<Vec<u8>>,
+    // send slots whose data the kernel is done with
+    free: Vec<usize>,
+    // receive buffers the submission queue had no room to re-arm, retried by the next reap
+    unarmed: Vec<usize>,
+}
+
+/// a sendmsg in flight: all it points at stays put until its completion
+struct SendSlot {
+    data: Vec<u8>,
+    addr: libc::sockaddr_in,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+unsafe impl Send for SendSlot {}
+
+/// raw sockets driven through an io_uring. a kernel thread polls the submission queue, so
+/// sending takes no system call while it is busy, and completions are taken from the ring
+/// in batches, with a system call only to wait for them
+pub struct UringSocket {
+    fd: OwnedFd,
+    sender: RawIpSocket,
+    receivers: [RawIpSocket; 2],
+    submissions: Mutex<Submissions>,
+    completions: Mutex<Completions>,
+    recv_buffers: Vec<Mutex<Box<[u8]>>>,
+    send_slots: Vec<Mutex<SendSlot>>,
+}
+
+impl UringSocket {
+    pub fn open() -> Result<Self> {
+        let mut params = Params {
+            flags: IORING_SETUP_SQPOLL,
+            sq_thread_idle: SQ_THREAD_IDLE_MS,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                RING_ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("failed to set up io_uring");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+        let sq_len =
+            params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let submissions = Submissions {
+            ring: Mapping::new(&fd, sq_len, IORING_OFF_SQ_RING)?,
+            sqes: Mapping::new(
+                &fd,
+                params.sq_entries as usize * mem::size_of::<Sqe>(),
+                IORING_OFF_SQES,
+            )?,
+            offsets: params.sq_off,
+        };
+        let completions = Completions {
+            ring: Mapping::new(&fd, cq_len, IORING_OFF_CQ_RING)?,
+            offsets: params.cq_off,
+            received: VecDeque::new(),
+            free: (0..SEND_SLOTS).collect(),
+            unarmed: Vec::new(),
+        };
+        let sender = RawIpSocket::open(libc::IPPROTO_RAW).context("failed to open raw socket")?;
+        let receivers = [
+            RawIpSocket::open(libc::IPPROTO_TCP).context("failed to open raw socket")?,
+            RawIpSocket::open(libc::IPPROTO_ICMP).context("failed to open raw socket")?,
+        ];
+        let device = Self {
+            fd,
+            sender,
+            receivers,
+            submissions: Mutex::new(submissions),
+            completions: Mutex::new(completions),
+            recv_buffers: (0..RECV_BUFFERS * 2)
+                .map(|_| Mutex::new(vec![0; BUFFER_SIZE].into_boxed_slice()))
+                .collect(),
+            send_slots: (0..SEND_SLOTS)
+                .map(|_| {
+                    Mutex::new(SendSlot {
+                        data: Vec::new(),
+                        addr: unsafe { mem::zeroed() },
+                        iov: unsafe { mem::zeroed() },
+                        msg: unsafe { mem::zeroed() },
+                    })
+                })
+                .collect(),
+        };
+        let mut submissions = device.submissions.lock().unwrap();
+        for index in 0..device.recv_buffers.len() {
+            device.arm_recv(&mut submissions, index)?;
+        }
+        drop(submissions);
+        Ok(device)
+    }
+
+    // the buffer `index` waits for the next packet of its socket
+    fn arm_recv(&self, submissions: &mut Submissions, index: usize) -> io::Result<()> {
+        let mut buffer = self.recv_buffers[index].lock().unwrap();
+        let sqe = Sqe {
+            opcode: IORING_OP_RECV,
+            fd: self.receivers[index / RECV_BUFFERS].as_raw_fd(),
+            addr: buffer.as_mut_ptr() as u64,
+            len: buffer.len() as u32,
+            user_data: RECV | index as u64,
+            ..Default::default()
+        };
+        self.submit(submissions, sqe)
+    }
+
+    fn submit(&self, submissions: &mut Submissions, sqe: Sqe) -> io::Result<()> {
+        let offsets = &submissions.offsets;
+        let ring = &submissions.ring;
+        let head = unsafe { &*ring.at::<AtomicU32>(offsets.head) }.load(Ordering::Acquire);
+        let tail_ref = unsafe { &*ring.at::<AtomicU32>(offsets.tail) };
+        let tail = tail_ref.load(Ordering::Relaxed);
+        let entries = unsafe { *ring.at::<u32>(offsets.ring_entries) };
+        if tail.wrapping_sub(head) >= entries {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "submission queue full",
+            ));
+        }
+        let mask = unsafe { *ring.at::<u32>(offsets.ring_mask) };
+        let slot = tail & mask;
+        unsafe {
+            ptr::write(submissions.sqes.at::<Sqe>(0).add(slot as usize), sqe);
+            *ring.at::<u32>(offsets.array).add(slot as usize) = slot;
+        }
+        tail_ref.store(tail.wrapping_add(1), Ordering::Release);
+        // the submission thread may have gone to sleep before seeing the new tail
+        atomic::fence(Ordering::SeqCst);
+        let flags = unsafe { &*ring.at::<AtomicU32>(offsets.flags) }.load(Ordering::Relaxed);
+        if flags & IORING_SQ_NEED_WAKEUP != 0 {
+            self.enter(0, IORING_ENTER_SQ_WAKEUP)?;
+        }
+        Ok(())
+    }
+
+    fn enter(&self, min_complete: u32, flags: u32) -> io::Result<()> {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd.as_raw_fd(),
+                0,
+                min_complete,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0,
+            )
+        };
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            if !matches!(
+                error.raw_os_error(),
+                Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)
+            ) {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    // go through the completions there are: packets received are copied out and their
+    // buffers armed again, the slots of sends are freed
+    fn reap(&self, completions: &mut Completions) -> io::Result<()> {
+        let offsets = &completions.offsets;
+        let ring = &completions.ring;
+        let head_ref = unsafe { &*ring.at::<AtomicU32>(offsets.head) };
+        let tail = unsafe { &*ring.at::<AtomicU32>(offsets.tail) }.load(Ordering::Acquire);
+        let mask = unsafe { *ring.at::<u32>(offsets.ring_mask) };
+        let mut head = head_ref.load(Ordering::Relaxed);
+        let mut rearm = mem::take(&mut completions.unarmed);
+        while head != tail {
+            let cqe =
+                unsafe { ptr::read(ring.at::<Cqe>(offsets.cqes).add((head & mask) as usize)) };
+            head = head.wrapping_add(1);
+            let index = (cqe.user_data & INDEX) as usize;
+            if cqe.user_data & !INDEX == SEND {
+                if cqe.res < 0 {
+                    warn!(
+                        error = %io::Error::from_raw_os_error(-cqe.res),
+                        "io_uring: send failed"
+                    );
+                }
+                completions.free.push(index);
+                continue;
+            }
+            if cqe.res < 0 {
+                warn!(
+                    error = %io::Error::from_raw_os_error(-cqe.res),
+                    "io_uring: recv failed"
+                );
+            } else {
+                let buffer = self.recv_buffers[index].lock().unwrap();
+                completions
+                    .received
+                    .push_back(buffer[..cqe.res as usize].to_vec());
+            }
+            rearm.push(index);
+        }
+        head_ref.store(head, Ordering::Release);
+        if !rearm.is_empty() {
+            let mut submissions = self.submissions.lock().unwrap();
+            let mut rearm = rearm.into_iter();
+            while let Some(index) = rearm.next() {
+                let result = self.arm_recv(&mut submissions, index);
+                if is_queue_full(&result) {
+                    completions.unarmed.push(index);
+                }
+                if result.is_err() {
+                    completions.unarmed.extend(rearm);
+                    return result;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // a send slot, reaping completions if none is free
+    fn free_slot(&self) -> io::Result<Option<usize>> {
+        let mut completions = self.completions.lock().unwrap();
+        if completions.free.is_empty() {
+            self.reap(&mut completions)?;
+        }
+        Ok(completions.free.pop())
+    }
+}
+
+// submit fails with WouldBlock only before the entry is queued
+fn is_queue_full(result: &io::Result<()>) -> bool {
+    result
+        .as_ref()
+        .is_err_and(|error| error.kind() == io::ErrorKind::WouldBlock)
+}
+
+impl NetworkDevice for UringSocket {
+    // with all slots in flight, the packet goes out with a system call of its own
+    fn send_packet(&self, packet: &[u8]) -> io::Result<()> {
+        let dst = ip::destination(packet).ok_or(io::ErrorKind::InvalidInput)?;
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_addr.s_addr = u32::from(dst).to_be();
+        let index = match self.free_slot()? {
+            Some(index) => index,
+            None => return self.sender.send(packet),
+        };
+        let mut slot = self.send_slots[index].lock().unwrap();
+        let slot = &mut *slot;
+        slot.data.clear();
+        slot.data.extend_from_slice(packet);
+        slot.addr = addr;
+        slot.iov = libc::iovec {
+            iov_base: slot.data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: slot.data.len(),
+        };
+        slot.msg.msg_name = &mut slot.addr as *mut libc::sockaddr_in as *mut libc::c_void;
+        slot.msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        slot.msg.msg_iov = &mut slot.iov;
+        slot.msg.msg_iovlen = 1;
+        let sqe = Sqe {
+            opcode: IORING_OP_SENDMSG,
+            fd: self.sender.as_raw_fd(),
+            addr: &slot.msg as *const libc::msghdr as u64,
+            len: 1,
+            user_data: SEND | index as u64,
+            ..Default::default()
+        };
+        let result = self.submit(&mut self.submissions.lock().unwrap(), sqe);
+        if is_queue_full(&result) {
+            // the kernel never saw the slot
+            self.completions.lock().unwrap().free.push(index);
+        }
+        result
+    }
+
+    fn recv_packet(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut completions = self.completions.lock().unwrap();
+                if completions.received.is_empty() {
+                    self.reap(&mut completions)?;
+                }
+                if let Some(packet) = completions.received.pop_front() {
+                    let len = packet.len().min(buffer.len());
+                    buffer[..len].copy_from_slice(&packet[..len]);
+                    return Ok(len);
+                }
+            }
+            self.enter(1, IORING_ENTER_GETEVENTS)?;
+        }
+    }
+
+    fn filter_ports(&self, ports: &[u16]) -> io::Result<()> {
+        bpf::attach_port_filter(self.receivers[0].as_raw_fd(), ports)
+    }
+
+    // SO_BINDTODEVICE on a sender of its own
+    fn bound_to(&self, ifname: &str) -> Result<Option<Arc<dyn NetworkDevice>>> {
+        Ok(Some(Arc::new(RawSocket::bound_to(ifname)?)))
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender};
+use std::io;
+use std::sync::Mutex;
+
+/// npcap (or WinPcap) on one adapter, through pnet's datalink channel: the frames arriving
+/// on it come in, and a frame sent goes out as it is. building takes the npcap SDK's
+/// Packet.lib, running the npcap driver
+pub struct NpcapDevice {
+    sender: Mutex<Box<dyn DataLinkSender>>,
+    receiver: Mutex<Box<dyn DataLinkReceiver>>,
+}
+
+impl NpcapDevice {
+    // `ifname` as pnet names adapters, \Device\NPF_{GUID}
+    pub fn open(ifname: &str) -> Result<Self> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == ifname)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such device"))?;
+        match datalink::channel(&interface, Default::default())
+            .context("failed to open npcap adapter")?
+        {
+            Channel::Ethernet(sender, receiver) => Ok(Self {
+                sender: Mutex::new(sender),
+                receiver: Mutex::new(receiver),
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "not an Ethernet adapter").into()),
+        }
+    }
+
+    pub fn recv(&self, frame: &mut [u8]) -> io::Result<usize> {
+        let mut receiver = self.receiver.lock().unwrap();
+        let received = receiver.next()?;
+        let len = received.len().min(frame.len());
+        frame[..len].copy_from_slice(&received[..len]);
+        Ok(len)
+    }
+
+    pub fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.sender
+            .lock()
+            .unwrap()
+            .send_to(frame, None)
+            .unwrap_or_else(|| Err(io::ErrorKind::WriteZero.into()))
+    }
+}
@@ -0,0 +1,503 @@
+use anyhow::{Context, Result};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use tracing::debug;
+
+// the UMEM: FRAME_COUNT frames of FRAME_SIZE bytes, the first RING_SIZE of them given to the
+// kernel to receive into and the rest ours to send from. each ring has room for all of its
+// frames, so none of them ever overflows
+const FRAME_SIZE: usize = 2048;
+const FRAME_COUNT: usize = 4096;
+const RING_SIZE: u32 = 2048;
+
+// bpf(2)
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct BpfInsn {
+    code: u8,
+    regs: u8, // source register in the high nibble, destination in the low one
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    BpfInsn {
+        code,
+        regs: (src << 4) | dst,
+        off,
+        imm,
+    }
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<OwnedFd> {
+    let result = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>()) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(result as libc::c_int) })
+}
+
+/// the frames, shared with the kernel and the driver
+struct Umem {
+    area: *mut u8,
+}
+
+/// one of the four rings of an AF_XDP socket, mapped from the kernel. we produce into the
+/// fill and TX rings and consume from the RX and completion rings
+struct Ring {
+    map: *mut u8,
+    len: usize,
+    producer: usize,
+    consumer: usize,
+    desc: usize,
+}
+
+// the mappings are used only under the locks of the socket that owns them
+unsafe impl Send for Umem {}
+unsafe impl Sync for Umem {}
+unsafe impl Send for Ring {}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.area as *mut libc::c_void, FRAME_COUNT * FRAME_SIZE) };
+    }
+}
+
+impl Ring {
+    fn map(
+        fd: &OwnedFd,
+        offsets: &libc::xdp_ring_offset,
+        pgoff: libc::off_t,
+        entry_size: usize,
+    ) -> io::Result<Self> {
+        let len = offsets.desc as usize + RING_SIZE as usize * entry_size;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            map: map as *mut u8,
+            len,
+            producer: offsets.producer as usize,
+            consumer: offsets.consumer as usize,
+            desc: offsets.desc as usize,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.add(self.producer) as *const AtomicU32) }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.add(self.consumer) as *const AtomicU32) }
+    }
+
+    // the slot of `index`, of u64 frame addresses on the fill and completion rings and of
+    // xdp_desc on the RX and TX rings
+    fn entry<T>(&self, index: u32) -> *mut T {
+        let slot = (index & (RING_SIZE - 1)) as usize;
+        unsafe { self.map.add(self.desc + slot * mem::size_of::<T>()) as *mut T }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map as *mut libc::c_void, self.len) };
+    }
+}
+
+struct RxRings {
+    rx: Ring,
+    fill: Ring,
+}
+
+struct TxRings {
+    tx: Ring,
+    completion: Ring,
+    // frames of ours not on the TX ring
+    free: Vec<u64>,
+}
+
+impl TxRings {
+    // take back the frames the kernel has sent
+    fn reclaim(&mut self) {
+        let producer = self.completion.producer().load(Ordering::Acquire);
+        let mut index = self.completion.consumer().load(Ordering::Relaxed);
+        while index != producer {
+            self.free
+                .push(unsafe { *self.completion.entry::<u64>(index) });
+            index = index.wrapping_add(1);
+        }
+        self.completion
+            .consumer()
+            .store(producer, Ordering::Release);
+    }
+}
+
+/// AF_XDP socket on one queue of an interface, with an XDP program redirecting every frame
+/// of the queue to it. the program is detached when the socket is dropped
+pub struct XdpSocket {
+    fd: OwnedFd,
+    umem: Umem,
+    rx: Mutex<RxRings>,
+    tx: Mutex<TxRings>,
+    _map: OwnedFd,
+    _link: OwnedFd,
+}
+
+impl XdpSocket {
+    pub fn open(ifindex: u32, queue_id: u32) -> Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("failed to open xdp socket");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let area = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                FRAME_COUNT * FRAME_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if area == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error()).context("failed to allocate umem");
+        }
+        let umem = Umem {
+            area: area as *mut u8,
+        };
+        let mut reg: libc::xdp_umem_reg = unsafe { mem::zeroed() };
+        reg.addr = umem.area as u64;
+        reg.len = (FRAME_COUNT * FRAME_SIZE) as u64;
+        reg.chunk_size = FRAME_SIZE as u32;
+        set_option(&fd, libc::XDP_UMEM_REG, &reg).context("failed to register umem")?;
+        for ring in [
+            libc::XDP_UMEM_FILL_RING,
+            libc::XDP_UMEM_COMPLETION_RING,
+            libc::XDP_RX_RING,
+            libc::XDP_TX_RING,
+        ] {
+            set_option(&fd, ring, &RING_SIZE).context("failed to size xdp ring")?;
+        }
+        let mut offsets: libc::xdp_mmap_offsets = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::xdp_mmap_offsets>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_XDP,
+                libc::XDP_MMAP_OFFSETS,
+                &mut offsets as *mut libc::xdp_mmap_offsets as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error()).context("failed to get ring offsets");
+        }
+        let desc_size = mem::size_of::<libc::xdp_desc>();
+        let addr_size = mem::size_of::<u64>();
+        let rx = Ring::map(&fd, &offsets.rx, libc::XDP_PGOFF_RX_RING, desc_size)?;
+        let tx = Ring::map(&fd, &offsets.tx, libc::XDP_PGOFF_TX_RING, desc_size)?;
+        let fill = Ring::map(
+            &fd,
+            &offsets.fr,
+            libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+            addr_size,
+        )?;
+        let completion = Ring::map(
+            &fd,
+            &offsets.cr,
+            libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+            addr_size,
+        )?;
+        for index in 0..RING_SIZE {
+            unsafe { *fill.entry::<u64>(index) = index as u64 * FRAME_SIZE as u64 };
+        }
+        fill.producer().store(RING_SIZE, Ordering::Release);
+        let free = (RING_SIZE as usize..FRAME_COUNT)
+            .map(|frame| (frame * FRAME_SIZE) as u64)
+            .collect();
+
+        // zero-copy if the driver supports it, copy mode otherwise
+        let mut addr: libc::sockaddr_xdp = unsafe { mem::zeroed() };
+        addr.sxdp_family = libc::AF_XDP as u16;
+        addr.sxdp_ifindex = ifindex;
+        addr.sxdp_queue_id = queue_id;
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_xdp as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error()).context("failed to bind xdp socket");
+        }
+
+        let mut map_attr = MapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: 4,
+            value_size: 4,
+            max_entries: queue_id + 1,
+            map_flags: 0,
+        };
+        let map = bpf(BPF_MAP_CREATE, &mut map_attr).context("failed to create xskmap")?;
+        let socket_fd = fd.as_raw_fd() as u32;
+        let mut update = MapUpdateAttr {
+            map_fd: map.as_raw_fd() as u32,
+            key: &queue_id as *const u32 as u64,
+            value: &socket_fd as *const u32 as u64,
+            flags: 0,
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &mut update).context("failed to add xdp socket to xskmap")?;
+        let program = load_program(&map).context("failed to load xdp program")?;
+        let mut link_attr = LinkCreateAttr {
+            prog_fd: program.as_raw_fd() as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: 0,
+        };
+        let link = bpf(BPF_LINK_CREATE, &mut link_attr).context("failed to attach xdp program")?;
+        Ok(Self {
+            fd,
+            umem,
+            rx: Mutex::new(RxRings { rx, fill }),
+            tx: Mutex::new(TxRings {
+                tx,
+                completion,
+                free,
+            }),
+            _map: map,
+            _link: link,
+        })
+    }
+
+    // waits for the next frame and copies it out, its UMEM frame going back to the fill ring
+    pub fn recv(&self, frame: &mut [u8]) -> io::Result<usize> {
+        let rings = self.rx.lock().unwrap();
+        loop {
+            let consumer = rings.rx.consumer().load(Ordering::Relaxed);
+            if rings.rx.producer().load(Ordering::Acquire) == consumer {
+                self.poll(libc::POLLIN)?;
+                continue;
+            }
+            let desc = unsafe { ptr::read(rings.rx.entry::<libc::xdp_desc>(consumer)) };
+            let len = (desc.len as usize).min(frame.len());
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.umem.area.add(desc.addr as usize),
+                    frame.as_mut_ptr(),
+                    len,
+                )
+            };
+            rings
+                .rx
+                .consumer()
+                .store(consumer.wrapping_add(1), Ordering::Release);
+            let producer = rings.fill.producer().load(Ordering::Relaxed);
+            unsafe {
+                *rings.fill.entry::<u64>(producer) = desc.addr - desc.addr % FRAME_SIZE as u64
+            };
+            rings
+                .fill
+                .producer()
+                .store(producer.wrapping_add(1), Ordering::Release);
+            return Ok(len);
+        }
+    }
+
+    // a frame is dropped, like on a congested link, while all of ours are still in flight
+    pub fn send(&self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame larger than a umem frame",
+            ));
+        }
+        let mut rings = self.tx.lock().unwrap();
+        rings.reclaim();
+        let addr = match rings.free.pop() {
+            Some(addr) => addr,
+            None => {
+                debug!("xdp: no frame to send from");
+                return self.kick();
+            }
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                self.umem.area.add(addr as usize),
+                frame.len(),
+            )
+        };
+        let producer = rings.tx.producer().load(Ordering::Relaxed);
+        unsafe {
+            *rings.tx.entry::<libc::xdp_desc>(producer) = libc::xdp_desc {
+                addr,
+                len: frame.len() as u32,
+                options: 0,
+            }
+        };
+        rings
+            .tx
+            .producer()
+            .store(producer.wrapping_add(1), Ordering::Release);
+        self.kick()
+    }
+
+    // have the kernel go through the TX ring, which it doesn't on its own in copy mode
+    fn kick(&self) -> io::Result<()> {
+        let result = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                ptr::null(),
+                0,
+            )
+        };
+        if result < 0 {
+            let error = io::Error::last_os_error();
+            // busy with the ring already
+            if !matches!(
+                error.raw_os_error(),
+                Some(libc::EAGAIN | libc::EBUSY | libc::ENOBUFS)
+            ) {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&self, events: libc::c_short) -> io::Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn set_option<T>(fd: &OwnedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// redirects each frame to the socket of its queue in `map`, and lets the kernel have it if
+// there is none
+fn load_program(map: &OwnedFd) -> io::Result<OwnedFd> {
+    let program = [
+        // r2 = ctx->rx_queue_index
+        insn(0x61, 2, 1, 16, 0),
+        // r1 = map, a 64-bit immediate over two instructions
+        insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map.as_raw_fd()),
+        insn(0, 0, 0, 0, 0),
+        // r3 = XDP_PASS, the action without a socket
+        insn(0xb7, 3, 0, 0, XDP_PASS),
+        // r0 = bpf_redirect_map(r1, r2, r3)
+        insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        insn(0x95, 0, 0, 0, 0),
+    ];
+    let license = b"GPL\0";
+    let mut name = [0; 16];
+    name[..10].copy_from_slice(b"toytcp_xsk");
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+        kern_version: 0,
+        prog_flags: 0,
+        prog_name: name,
+        prog_ifindex: 0,
+        expected_attach_type: BPF_XDP,
+    };
+    bpf(BPF_PROG_LOAD, &mut attr)
+}